@@ -0,0 +1,50 @@
+//! Compares reading the input through a buffer against memory-mapping it.
+//! Run with `cargo bench --bench input`.
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use transaction_parser::generate::{Generator, GeneratorConfig};
+use transaction_parser::process_transactions;
+
+/// Generated once per run, large enough for read overhead to show
+fn input_file() -> PathBuf {
+    let path = std::env::temp_dir().join("transaction_parser_bench_input.csv");
+    let generator = Generator::new(GeneratorConfig {
+        rows: 500_000,
+        seed: 42,
+        ..GeneratorConfig::default()
+    });
+    generator
+        .write_csv(std::io::BufWriter::new(File::create(&path).unwrap()))
+        .unwrap();
+    path
+}
+
+fn input(c: &mut Criterion) {
+    let path = input_file();
+    let len = std::fs::metadata(&path).unwrap().len();
+    let mut group = c.benchmark_group("input");
+    group.throughput(Throughput::Bytes(len));
+    group.sample_size(10);
+    group.bench_function("buffered", |b| {
+        b.iter(|| {
+            let file = BufReader::new(File::open(&path).unwrap());
+            process_transactions(&mut csv::Reader::from_reader(file))
+        })
+    });
+    group.bench_function("mmap", |b| {
+        b.iter(|| {
+            let file = File::open(&path).unwrap();
+            // SAFETY: nothing else writes the benchmark input while it is mapped
+            let map = unsafe { Mmap::map(&file) }.unwrap();
+            process_transactions(&mut csv::Reader::from_reader(&map[..]))
+        })
+    });
+    group.finish();
+    std::fs::remove_file(path).unwrap();
+}
+
+criterion_group!(benches, input);
+criterion_main!(benches);
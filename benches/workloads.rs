@@ -0,0 +1,104 @@
+//! Engine and parser throughput on representative workloads, to catch regressions before
+//! a release. Run with `cargo bench --bench workloads`, or e.g. `-- engine/dispute_heavy`
+//! for one of them.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rust_decimal::Decimal;
+use transaction_parser::engine::minor::MinorTransaction;
+use transaction_parser::generate::{Generator, GeneratorConfig};
+use transaction_parser::model::ClientId;
+use transaction_parser::{
+    process_transactions, Engine, MinorUnitsEngine, Transaction, TransactionType, TxId,
+};
+
+const ROWS: u64 = 200_000;
+
+/// Name and transactions of every workload
+fn workloads() -> Vec<(&'static str, Vec<Transaction>)> {
+    let generated = |clients, dispute_rate| {
+        Generator::new(GeneratorConfig {
+            clients,
+            rows: ROWS,
+            dispute_rate,
+            chargeback_rate: 0.2,
+            seed: 42,
+        })
+        .collect()
+    };
+    let deposits = (0..ROWS)
+        .map(|i| Transaction {
+            transaction_type: TransactionType::Deposit,
+            client: (i % 1000) as ClientId + 1,
+            tx: i as TxId + 1,
+            amount: Some(Decimal::new(i as i64 % 100_000 + 1, 4)),
+        })
+        .collect();
+    vec![
+        ("pure_deposits", deposits),
+        // About a third of the rows open, resolve or charge back a dispute
+        ("dispute_heavy", generated(1000, 0.2)),
+        ("many_clients", generated(50_000, 0.01)),
+        ("hot_account", generated(1, 0.01)),
+    ]
+}
+
+/// The transactions as an input file
+fn to_csv(transactions: &[Transaction]) -> Vec<u8> {
+    let mut data = b"type,client,tx,amount\n".to_vec();
+    for transaction in transactions {
+        data.extend_from_slice(transaction.to_string().as_bytes());
+        data.push(b'\n');
+    }
+    data
+}
+
+fn engine(c: &mut Criterion) {
+    let mut group = c.benchmark_group("engine");
+    group.throughput(Throughput::Elements(ROWS));
+    group.sample_size(10);
+    for (name, transactions) in workloads() {
+        group.bench_with_input(
+            BenchmarkId::new("decimal", name),
+            &transactions,
+            |b, txs| {
+                b.iter(|| {
+                    let mut engine = Engine::new();
+                    for transaction in txs {
+                        let _ = engine.apply(*transaction);
+                    }
+                    engine
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("minor_units", name),
+            &transactions,
+            |b, txs| {
+                b.iter(|| {
+                    let mut engine = MinorUnitsEngine::new();
+                    for transaction in txs {
+                        let _ = engine.apply(MinorTransaction::new(transaction).unwrap());
+                    }
+                    engine
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Parsing and applying a whole file, as the command line tool does
+fn parser(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parser");
+    group.sample_size(10);
+    for (name, transactions) in workloads() {
+        let data = to_csv(&transactions);
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), &data, |b, data| {
+            b.iter(|| process_transactions(&mut csv::Reader::from_reader(&data[..])))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, engine, parser);
+criterion_main!(benches);
@@ -0,0 +1,16 @@
+//! Feeds raw bytes through the CSV reader and the engine.
+//! Catches panics on malformed input and arithmetic overflow on adversarial amounts.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use transaction_parser::process_transactions;
+
+fuzz_target!(|data: &[u8]| {
+    let mut reader = csv::Reader::from_reader(data);
+    let accounts = process_transactions(&mut reader);
+    for account in accounts.values() {
+        // Serializing computes the total, which must not overflow either
+        let mut writer = csv::Writer::from_writer(vec![]);
+        writer.serialize(account).unwrap();
+    }
+});
@@ -0,0 +1,20 @@
+//! Feeds structured, well-formed transaction streams through the engine
+//! and checks the invariants documented in the crate root.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_decimal::Decimal;
+use transaction_parser::{process_transactions, Transaction};
+
+fuzz_target!(|transactions: Vec<Transaction>| {
+    let mut data = String::from("type,client,tx,amount\n");
+    for t in &transactions {
+        let amount = t.amount.map(|a| a.to_string()).unwrap_or_default();
+        data.push_str(&format!("{},{},{},{}\n", t.transaction_type, t.client, t.tx, amount));
+    }
+    let accounts = process_transactions(&mut csv::Reader::from_reader(data.as_bytes()));
+    for account in accounts.values() {
+        assert_eq!(account.total(), account.available + account.held);
+        assert!(account.held >= Decimal::ZERO);
+    }
+});
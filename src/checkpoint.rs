@@ -0,0 +1,288 @@
+//! Checkpoints of a long run over one growing input, such as a large backfill through
+//! `--follow`, so an interrupted run continues where it stopped.
+//!
+//! A checkpoint is a [`ResumeToken`], where the input was read up to, and the state of the
+//! engine after exactly the rows before it. Resuming restores the state and reads on from
+//! the token, so every row is applied once however often the run is interrupted. The token
+//! fingerprints the input before its offset, so a checkpoint isn't resumed against a
+//! different or rewritten file.
+//!
+//! Checkpoints are written in the binary format of [`EngineState::write_to`], behind their
+//! own magic and the token. Only the accounts and transactions are saved, so an engine holding
+//! more, see [`Engine::unsaved_state`], can't be checkpointed. Observers, e.g. a
+//! [`crate::HoldRelease`], start over on resuming.
+use crate::engine::state::EngineState;
+use crate::engine::Engine;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+const MAGIC: &[u8; 8] = b"TPCHECK1";
+
+/// Bytes at the start of the input and before the offset a [`ResumeToken`] fingerprints
+const FINGERPRINTED: u64 = 4096;
+
+/// Where a run stopped reading its input: the byte offset, line and record the next row
+/// starts at, and a fingerprint of the input up to there
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResumeToken {
+    pub offset: u64,
+    pub line: u64,
+    pub record: u64,
+    pub fingerprint: [u8; 32],
+}
+
+impl ResumeToken {
+    /// The token of `offset` in `input`, which is read to fingerprint it
+    pub fn new(input: impl Read + Seek, offset: u64, line: u64, record: u64) -> io::Result<Self> {
+        Ok(ResumeToken {
+            offset,
+            line,
+            record,
+            fingerprint: fingerprint(input, offset)?,
+        })
+    }
+
+    /// The token of the position of a [`crate::CsvSource`] over `input`
+    #[cfg(feature = "csv")]
+    pub fn from_position(input: impl Read + Seek, position: &csv::Position) -> io::Result<Self> {
+        ResumeToken::new(input, position.byte(), position.line(), position.record())
+    }
+
+    /// The position to [`crate::CsvSource::seek`] to
+    #[cfg(feature = "csv")]
+    pub fn position(&self) -> csv::Position {
+        let mut position = csv::Position::new();
+        position
+            .set_byte(self.offset)
+            .set_line(self.line)
+            .set_record(self.record);
+        position
+    }
+
+    /// Whether `input` still holds what it held up to the offset when the token was taken.
+    /// Appending to it is fine, anything else it doesn't match.
+    pub fn matches(&self, input: impl Read + Seek) -> io::Result<bool> {
+        match fingerprint(input, self.offset) {
+            Ok(fingerprint) => Ok(fingerprint == self.fingerprint),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// SHA-256 of the offset, the first and the last [`FINGERPRINTED`] bytes before it
+fn fingerprint(mut input: impl Read + Seek, offset: u64) -> io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    hasher.update(offset.to_le_bytes());
+    for start in [0, offset.saturating_sub(FINGERPRINTED)] {
+        let mut bytes = vec![0; (offset - start).min(FINGERPRINTED) as usize];
+        input.seek(SeekFrom::Start(start))?;
+        input.read_exact(&mut bytes)?;
+        hasher.update(&bytes);
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// Writes the checkpoint of `engine`, which applied the rows before `token`, with the text
+/// client and tx ids read so far, see [`EngineState::client_names`] and
+/// [`EngineState::tx_names`]. Fails without writing anything if the engine holds state a
+/// checkpoint doesn't keep, see [`Engine::unsaved_state`].
+pub fn write_checkpoint(
+    token: &ResumeToken,
+    engine: &Engine,
+    names: [&BTreeMap<u64, String>; 2],
+    mut output: impl Write,
+) -> io::Result<()> {
+    if let Some(unsaved) = engine.unsaved_state() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("a checkpoint can't keep {}", unsaved),
+        ));
+    }
+    output.write_all(MAGIC)?;
+    for field in [token.offset, token.line, token.record] {
+        output.write_all(&field.to_le_bytes())?;
+    }
+    output.write_all(&token.fingerprint)?;
+    engine.write_state_with_names(names, output)
+}
+
+/// Reads a checkpoint written by [`write_checkpoint`]
+pub fn read_checkpoint(mut input: impl Read) -> io::Result<(ResumeToken, EngineState)> {
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a checkpoint file",
+        ));
+    }
+    let mut read_u64 = || {
+        let mut bytes = [0; 8];
+        input
+            .read_exact(&mut bytes)
+            .map(|()| u64::from_le_bytes(bytes))
+    };
+    let (offset, line, record) = (read_u64()?, read_u64()?, read_u64()?);
+    let mut fingerprint = [0; 32];
+    input.read_exact(&mut fingerprint)?;
+    let token = ResumeToken {
+        offset,
+        line,
+        record,
+        fingerprint,
+    };
+    Ok((token, EngineState::read_from(input)?))
+}
+
+/// Reads a checkpoint and checks it was saved for `input`, refusing it if the input changed
+/// before where it was read up to. Appending to the input is fine.
+pub fn resume_checkpoint(
+    checkpoint: impl Read,
+    input: impl Read + Seek,
+) -> io::Result<(ResumeToken, EngineState)> {
+    let (token, state) = read_checkpoint(checkpoint)?;
+    if !token.matches(input)? {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "saved for another input, it changed before line {}",
+                token.line
+            ),
+        ));
+    }
+    Ok((token, state))
+}
+
+#[cfg(all(test, feature = "csv"))]
+mod tests {
+    use super::*;
+    use crate::engine::eviction::Eviction;
+    use crate::engine::UnknownReference;
+    use crate::io::csv::ParseOptions;
+    use crate::io::source::{CsvSource, TransactionSource};
+    use crate::model::{Transaction, TransactionType};
+    use rust_decimal::Decimal;
+    use std::io::Cursor;
+
+    const INPUT: &str = "\
+type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+dispute,1,1,
+withdrawal,2,3,1.0
+resolve,1,1,
+";
+
+    #[test]
+    fn resumed_runs_apply_every_row_once() {
+        let options = ParseOptions::default();
+        let source = |input: &str| {
+            CsvSource::new(
+                csv::Reader::from_reader(Cursor::new(input.to_string())),
+                &options,
+            )
+            .unwrap()
+        };
+        let mut uninterrupted = Engine::new();
+        uninterrupted
+            .apply_source(source(INPUT), options.mode)
+            .unwrap();
+
+        // Interrupted after three rows
+        let mut engine = Engine::new();
+        let mut first = source(INPUT);
+        for _ in 0..3 {
+            engine
+                .apply(first.next_transaction().unwrap().unwrap())
+                .unwrap();
+        }
+        let token = ResumeToken::from_position(Cursor::new(INPUT), first.position()).unwrap();
+        let mut checkpoint = vec![];
+        write_checkpoint(&token, &engine, [&BTreeMap::new(); 2], &mut checkpoint).unwrap();
+
+        let (token, state) = read_checkpoint(&checkpoint[..]).unwrap();
+        assert_eq!(token.line, 5);
+        assert!(token.matches(Cursor::new(INPUT)).unwrap());
+        let mut resumed = Engine::new();
+        resumed.restore(state);
+        let mut rest = source(INPUT);
+        rest.seek(token.position()).unwrap();
+        let transaction = rest.next_transaction().unwrap().unwrap();
+        assert_eq!(transaction.tx, 3);
+        assert_eq!(rest.line(), Some(5));
+        resumed.apply(transaction).unwrap();
+        resumed.apply_source(rest, options.mode).unwrap();
+        assert_eq!(resumed.accounts(), uninterrupted.accounts());
+    }
+
+    #[test]
+    fn engines_holding_more_than_a_state_are_refused() {
+        let token = ResumeToken::new(Cursor::new(INPUT), 0, 1, 0).unwrap();
+        let checkpoint = |engine: &Engine| {
+            let mut output = vec![];
+            let written = write_checkpoint(&token, engine, [&BTreeMap::new(); 2], &mut output);
+            (written.map_err(|err| err.to_string()), output.is_empty())
+        };
+        let mut engine = Engine::new();
+        engine.set_unknown_reference(UnknownReference::Defer);
+        let row = |transaction_type, amount| Transaction {
+            transaction_type,
+            client: 1,
+            tx: 7,
+            amount,
+        };
+        engine.apply(row(TransactionType::Dispute, None)).unwrap();
+        assert_eq!(
+            checkpoint(&engine),
+            (
+                Err(
+                    "a checkpoint can't keep disputes waiting for another row of their transaction"
+                        .to_string()
+                ),
+                true
+            )
+        );
+        engine
+            .apply(row(TransactionType::Deposit, Some(Decimal::ONE)))
+            .unwrap();
+        assert_eq!(checkpoint(&engine), (Ok(()), false));
+
+        engine.set_eviction(Eviction::KeepLast(10));
+        assert!(checkpoint(&engine).0.is_err());
+    }
+
+    #[test]
+    fn tokens_only_match_their_input() {
+        let token = ResumeToken::new(Cursor::new(INPUT), 60, 4, 3).unwrap();
+        let appended = format!("{}deposit,3,4,1.0\n", INPUT);
+        assert!(token.matches(Cursor::new(appended)).unwrap());
+        let changed = INPUT.replace("10.0", "90.0");
+        assert!(!token.matches(Cursor::new(changed)).unwrap());
+        assert!(!token.matches(Cursor::new(&INPUT[..40])).unwrap());
+    }
+
+    #[test]
+    fn checkpoints_of_changed_input_are_refused() {
+        let token = ResumeToken::new(Cursor::new(INPUT), 60, 4, 3).unwrap();
+        let mut checkpoint = vec![];
+        write_checkpoint(
+            &token,
+            &Engine::new(),
+            [&BTreeMap::new(); 2],
+            &mut checkpoint,
+        )
+        .unwrap();
+
+        let (resumed, _) = resume_checkpoint(&checkpoint[..], Cursor::new(INPUT)).unwrap();
+        assert_eq!(resumed.line, 4);
+        let changed = INPUT.replace("5.0", "6.0");
+        let err = resume_checkpoint(&checkpoint[..], Cursor::new(changed)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "saved for another input, it changed before line 4"
+        );
+    }
+}
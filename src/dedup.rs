@@ -0,0 +1,145 @@
+//! Recognising input that was processed before, e.g. a daily file uploaded twice.
+//!
+//! Content is identified by a 128-bit FNV-1a hash. It is stable across runs and platforms
+//! but not cryptographic, it guards against accidents rather than tampering. The hashes can
+//! be kept in a file between runs, one per line as 32 hex digits.
+use std::collections::HashSet;
+use std::io::{self, BufRead, Read, Write};
+
+const FNV_OFFSET: u128 = 0x6c62272e07bb014262b821756295c58d;
+const FNV_PRIME: u128 = 0x0000000001000000000000000000013b;
+
+/// Hash identifying `content`
+pub fn content_hash(content: &[u8]) -> u128 {
+    content.iter().fold(FNV_OFFSET, |hash, &byte| {
+        (hash ^ byte as u128).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// [`content_hash`] of everything `reader` reads, without holding it in memory
+pub fn read_hash(mut reader: impl Read) -> io::Result<u128> {
+    let mut hash = FNV_OFFSET;
+    let mut buffer = [0; 8192];
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => return Ok(hash),
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        hash = buffer[..read].iter().fold(hash, |hash, &byte| {
+            (hash ^ byte as u128).wrapping_mul(FNV_PRIME)
+        });
+    }
+}
+
+/// Hashes of the content seen so far
+#[derive(Debug, Clone, Default)]
+pub struct SeenContent {
+    hashes: HashSet<u128>,
+}
+
+impl SeenContent {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records `content`, false if the same content was recorded before
+    pub fn insert(&mut self, content: &[u8]) -> bool {
+        self.hashes.insert(content_hash(content))
+    }
+
+    pub fn contains(&self, content: &[u8]) -> bool {
+        self.hashes.contains(&content_hash(content))
+    }
+
+    /// Records the content behind `hash`, false if it was recorded before
+    pub fn insert_hash(&mut self, hash: u128) -> bool {
+        self.hashes.insert(hash)
+    }
+
+    pub fn contains_hash(&self, hash: u128) -> bool {
+        self.hashes.contains(&hash)
+    }
+
+    /// Reads hashes written by [`SeenContent::write_to`], blank lines skipped
+    pub fn read_from(reader: impl BufRead) -> io::Result<Self> {
+        let mut hashes = HashSet::new();
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let hash = u128::from_str_radix(line, 16)
+                .ok()
+                .filter(|_| line.len() == 32)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("line {}: `{}` isn't a content hash", index + 1, line),
+                    )
+                })?;
+            hashes.insert(hash);
+        }
+        Ok(SeenContent { hashes })
+    }
+
+    /// Writes the hashes one per line, sorted so the same set is always the same file
+    pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+        let mut hashes: Vec<_> = self.hashes.iter().collect();
+        hashes.sort_unstable();
+        for hash in hashes {
+            writeln!(writer, "{:032x}", hash)?;
+        }
+        writer.flush()
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dedup::{content_hash, read_hash, SeenContent};
+
+    #[test]
+    fn same_content_is_seen_once() {
+        let mut seen = SeenContent::new();
+        assert!(seen.insert(b"type,client,tx,amount\ndeposit,1,1,1.0\n"));
+        assert!(seen.insert(b"type,client,tx,amount\ndeposit,1,1,2.0\n"));
+        assert!(!seen.insert(b"type,client,tx,amount\ndeposit,1,1,1.0\n"));
+        assert_eq!(seen.len(), 2);
+        // The hash must not change between releases, saved hashes would no longer match
+        assert_eq!(content_hash(b""), 0x6c62272e07bb014262b821756295c58d);
+        assert_eq!(content_hash(b"a"), 0xd228cb696f1a8caf78912b704e4a8964);
+    }
+
+    #[test]
+    fn seen_content_survives_a_file() {
+        let content = vec![b'x'; 20_000];
+        assert_eq!(read_hash(&content[..]).unwrap(), content_hash(&content));
+
+        let mut seen = SeenContent::new();
+        seen.insert(&content);
+        seen.insert(b"");
+        let mut file = vec![];
+        seen.write_to(&mut file).unwrap();
+        let file = String::from_utf8(file).unwrap();
+        assert_eq!(file.lines().count(), 2);
+        assert!(file.contains("6c62272e07bb014262b821756295c58d\n"));
+
+        let read = SeenContent::read_from(format!("{}\n", file).as_bytes()).unwrap();
+        assert!(read.contains(&content));
+        assert!(read.contains_hash(content_hash(b"")));
+        assert_eq!(read.len(), 2);
+
+        let err = SeenContent::read_from("6c62\n".as_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), "line 1: `6c62` isn't a content hash");
+    }
+}
@@ -0,0 +1,216 @@
+//! Where the time and memory of a run went, e.g. to tell a slow disk from a slow parse.
+//!
+//! [`Diagnostics`] adds up the time spent in each [`Phase`] of a run while enabled, and costs
+//! nothing but a branch while it isn't. [`Diagnostics::write_report`] writes them out with the
+//! throughput and the peak memory of the process.
+use serde_json::json;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// A part of a run timed on its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Sorting the input by time
+    Sort,
+    /// Reading rows
+    Parse,
+    /// Applying rows to the engine
+    Apply,
+    /// Parsing and applying together, when they can't be told apart
+    Process,
+    /// Writing the results
+    Write,
+}
+
+impl Phase {
+    const ALL: [Phase; 5] = [
+        Phase::Sort,
+        Phase::Parse,
+        Phase::Apply,
+        Phase::Process,
+        Phase::Write,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Sort => "sort",
+            Phase::Parse => "parse",
+            Phase::Apply => "apply",
+            Phase::Process => "parse and apply",
+            Phase::Write => "write",
+        }
+    }
+}
+
+/// The time each phase of a run took, counted from its creation
+#[derive(Debug, Clone)]
+pub struct Diagnostics {
+    enabled: bool,
+    started: Instant,
+    rows: Option<u64>,
+    phases: [Duration; 5],
+}
+
+impl Diagnostics {
+    /// Counts nothing unless `enabled`
+    pub fn new(enabled: bool) -> Self {
+        Diagnostics {
+            enabled,
+            started: Instant::now(),
+            rows: None,
+            phases: [Duration::ZERO; 5],
+        }
+    }
+
+    /// Runs `f`, adding the time it took to `phase` when enabled
+    pub fn time<T>(&mut self, phase: Phase, f: impl FnOnce() -> T) -> T {
+        let started = self.start();
+        let result = f();
+        self.stop(started, phase);
+        result
+    }
+
+    /// The time a phase starts, when enabled
+    pub fn start(&self) -> Option<Instant> {
+        self.enabled.then(Instant::now)
+    }
+
+    /// Adds the time since `started` to `phase`
+    pub fn stop(&mut self, started: Option<Instant>, phase: Phase) {
+        if let Some(started) = started {
+            self.phases[phase as usize] += started.elapsed();
+        }
+    }
+
+    /// The rows read, when the input was read row by row
+    pub fn set_rows(&mut self, rows: u64) {
+        self.rows = Some(rows);
+    }
+
+    /// The time spent in `phase` so far
+    pub fn phase(&self, phase: Phase) -> Duration {
+        self.phases[phase as usize]
+    }
+
+    /// Writes the diagnostics of processing `path` to `output`, as text or as one JSON line
+    /// `{"diagnostics":{..}}`
+    pub fn write_report(&self, path: &Path, json: bool, mut output: impl Write) -> io::Result<()> {
+        let total = self.started.elapsed();
+        let bytes = fs::metadata(path).map_or(0, |metadata| metadata.len());
+        let per_second = |amount: f64| amount / total.as_secs_f64().max(f64::EPSILON);
+        let phases = Phase::ALL
+            .into_iter()
+            .map(|phase| (phase.name(), self.phase(phase)))
+            .filter(|(_, time)| !time.is_zero());
+        let peak_memory = peak_memory();
+        if json {
+            let mut object = json!({
+                "file": path,
+                "bytes": bytes,
+                "rows": self.rows,
+                "rows_per_second": self.rows.map(|rows| per_second(rows as f64).round()),
+                "seconds": total.as_secs_f64(),
+                "peak_memory_bytes": peak_memory,
+            });
+            for (phase, time) in phases {
+                object[format!("{}_seconds", phase.replace(' ', "_"))] = time.as_secs_f64().into();
+            }
+            return writeln!(output, "{}", json!({ "diagnostics": object }));
+        }
+        const MIB: f64 = 1024.0 * 1024.0;
+        writeln!(output, "{}: diagnostics", path.display())?;
+        writeln!(
+            output,
+            "  input: {:.1} MiB, {:.1} MiB/s",
+            bytes as f64 / MIB,
+            per_second(bytes as f64) / MIB
+        )?;
+        if let Some(rows) = self.rows {
+            writeln!(
+                output,
+                "  rows: {}, {:.0} rows/s",
+                rows,
+                per_second(rows as f64)
+            )?;
+        }
+        writeln!(output, "  total: {:.3}s", total.as_secs_f64())?;
+        for (phase, time) in phases {
+            writeln!(
+                output,
+                "  {}: {:.3}s ({:.0}%)",
+                phase,
+                time.as_secs_f64(),
+                100.0 * time.as_secs_f64() / total.as_secs_f64().max(f64::EPSILON)
+            )?;
+        }
+        match peak_memory {
+            Some(peak) => writeln!(output, "  peak memory: {:.1} MiB", peak as f64 / MIB),
+            None => writeln!(output, "  peak memory: unknown"),
+        }
+    }
+}
+
+/// Peak resident memory of the process in bytes, where the OS tells it (Linux)
+pub fn peak_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kilobytes: u64 = line
+        .trim_start_matches("VmHWM:")
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn phases_are_only_timed_when_enabled() {
+        let mut disabled = Diagnostics::new(false);
+        assert_eq!(disabled.time(Phase::Parse, || 7), 7);
+        assert_eq!(disabled.start(), None);
+        assert_eq!(disabled.phase(Phase::Parse), Duration::ZERO);
+
+        let mut enabled = Diagnostics::new(true);
+        enabled.time(Phase::Parse, || thread::sleep(Duration::from_millis(5)));
+        let writing = enabled.start();
+        enabled.stop(writing, Phase::Write);
+        assert!(enabled.phase(Phase::Parse) >= Duration::from_millis(5));
+        assert_eq!(enabled.phase(Phase::Apply), Duration::ZERO);
+    }
+
+    #[test]
+    fn reports_list_the_phases_that_took_time() {
+        let mut diagnostics = Diagnostics::new(true);
+        diagnostics.time(Phase::Process, || thread::sleep(Duration::from_millis(2)));
+        diagnostics.set_rows(3);
+        let path = Path::new("missing.csv");
+
+        let mut text = vec![];
+        diagnostics.write_report(path, false, &mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(
+            text.starts_with("missing.csv: diagnostics\n  input: 0.0 MiB"),
+            "{text}"
+        );
+        assert!(text.contains("\n  rows: 3, "), "{text}");
+        assert!(text.contains("\n  parse and apply: "), "{text}");
+        assert!(!text.contains("sort"), "{text}");
+
+        let mut line = vec![];
+        diagnostics.write_report(path, true, &mut line).unwrap();
+        let object: serde_json::Value = serde_json::from_slice(&line).unwrap();
+        let diagnostics = &object["diagnostics"];
+        assert_eq!(diagnostics["file"], "missing.csv");
+        assert_eq!(diagnostics["rows"], 3);
+        assert!(diagnostics["parse_and_apply_seconds"].as_f64().unwrap() > 0.0);
+        assert!(diagnostics.get("sort_seconds").is_none());
+    }
+}
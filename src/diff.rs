@@ -0,0 +1,155 @@
+//! Differences between two sets of accounts, e.g. the output of two engine versions
+//! on the same input.
+use crate::engine::{sorted_accounts, AccountMap};
+use crate::model::{Account, ClientId};
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+/// A balance that differs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct AmountChange {
+    pub before: Decimal,
+    pub after: Decimal,
+    /// `after - before`
+    pub delta: Decimal,
+}
+
+/// A locked flag that differs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct FlagChange {
+    pub before: bool,
+    pub after: bool,
+}
+
+/// How one account differs, serialized with a `change` field naming the variant,
+/// e.g. `{"change":"changed","client":1,"held":{"before":"1","after":"0","delta":"-1"}}`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "change", rename_all = "lowercase")]
+pub enum AccountDiff {
+    /// Only in the second set
+    Added {
+        client: ClientId,
+        available: Decimal,
+        held: Decimal,
+        locked: bool,
+    },
+    /// Only in the first set
+    Removed {
+        client: ClientId,
+        available: Decimal,
+        held: Decimal,
+        locked: bool,
+    },
+    /// In both, with only the fields that differ
+    Changed {
+        client: ClientId,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        available: Option<AmountChange>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        held: Option<AmountChange>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        locked: Option<FlagChange>,
+    },
+}
+
+/// Accounts added, removed or changed from `before` to `after`, ordered by client id.
+/// Only balances and locked flags are compared, amounts by value so `1.5` equals `1.5000`.
+pub fn diff_accounts(before: &AccountMap, after: &AccountMap) -> Vec<AccountDiff> {
+    let mut diffs = vec![];
+    for account in sorted_accounts(before) {
+        match after.get(&account.client) {
+            Some(other) => diffs.extend(changed(account, other)),
+            None => diffs.push(AccountDiff::Removed {
+                client: account.client,
+                available: account.available,
+                held: account.held,
+                locked: account.locked(),
+            }),
+        }
+    }
+    for account in sorted_accounts(after) {
+        if !before.contains_key(&account.client) {
+            diffs.push(AccountDiff::Added {
+                client: account.client,
+                available: account.available,
+                held: account.held,
+                locked: account.locked(),
+            });
+        }
+    }
+    diffs.sort_by_key(|diff| match diff {
+        AccountDiff::Added { client, .. }
+        | AccountDiff::Removed { client, .. }
+        | AccountDiff::Changed { client, .. } => *client,
+    });
+    diffs
+}
+
+fn changed(before: &Account, after: &Account) -> Option<AccountDiff> {
+    let amount = |before: Decimal, after: Decimal| {
+        (before != after).then(|| AmountChange {
+            before,
+            after,
+            delta: after - before,
+        })
+    };
+    let available = amount(before.available, after.available);
+    let held = amount(before.held, after.held);
+    let locked = (before.locked() != after.locked()).then_some(FlagChange {
+        before: before.locked(),
+        after: after.locked(),
+    });
+    if available.is_none() && held.is_none() && locked.is_none() {
+        return None;
+    }
+    Some(AccountDiff::Changed {
+        client: before.client,
+        available,
+        held,
+        locked,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::diff::{diff_accounts, AccountDiff};
+    use crate::model::{Account, Status};
+    use crate::AccountMap;
+    use rust_decimal::Decimal;
+
+    fn accounts(rows: &[(crate::model::ClientId, i64, i64, bool)]) -> AccountMap {
+        rows.iter()
+            .map(|&(client, available, held, locked)| {
+                let mut account = Account::new(client);
+                account.available = Decimal::new(available, 1);
+                account.held = Decimal::new(held, 1);
+                account.status = match locked {
+                    true => Status::Frozen,
+                    false => Status::Active,
+                };
+                (client, account)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn accounts_are_added_removed_and_changed() {
+        let before = accounts(&[(1, 10, 0, false), (2, 5, 5, false), (3, 1, 0, false)]);
+        let mut after = accounts(&[(2, 5, 0, true), (3, 1, 0, false), (4, 2, 0, false)]);
+        // Equal by value
+        after.get_mut(&3).unwrap().available = Decimal::new(1000, 4);
+        let diffs = diff_accounts(&before, &after);
+        assert_eq!(diffs.len(), 3);
+        assert!(matches!(diffs[0], AccountDiff::Removed { client: 1, .. }));
+        assert!(matches!(diffs[2], AccountDiff::Added { client: 4, .. }));
+        let json: Vec<String> = diffs
+            .iter()
+            .map(|diff| serde_json::to_string(diff).unwrap())
+            .collect();
+        assert_eq!(
+            json[1],
+            r#"{"change":"changed","client":2,"held":{"before":"0.5","after":"0.0","delta":"-0.5"},"locked":{"before":false,"after":true}}"#
+        );
+        assert!(diff_accounts(&after, &after).is_empty());
+    }
+}
@@ -0,0 +1,257 @@
+//! Encrypting the files that hold customer balances: saved states, snapshots, accounts and
+//! the logs of updates.
+//!
+//! Files are encrypted with [age](https://age-encryption.org) to [`EncryptionRecipients`],
+//! the public keys `age1...` that `age-keygen` prints, so whatever writes them never holds a
+//! secret. They decrypt with the [`DecryptionKey`] of any of those recipients, the identity
+//! `AGE-SECRET-KEY-1...`, here or with `age --decrypt -i key.txt`.
+use age::x25519::{Identity, Recipient};
+use age::{Decryptor, Encryptor};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::iter;
+use std::str::FromStr;
+
+/// Start of every age file
+const HEADER: &[u8] = b"age-encryption.org/v1\n";
+
+/// The age public keys files are encrypted to, any of their identities decrypts them
+#[derive(Clone)]
+pub struct EncryptionRecipients {
+    recipients: Vec<Recipient>,
+}
+
+impl EncryptionRecipients {
+    /// Encrypts everything written to `output`.
+    /// [`EncryptedWriter::finish`] has to be called for the file to be complete.
+    pub fn encrypt<W: Write>(&self, output: W) -> io::Result<EncryptedWriter<W>> {
+        let recipients = self.recipients.iter().map(|recipient| recipient as _);
+        let encryptor = Encryptor::with_recipients(recipients).map_err(io::Error::other)?;
+        Ok(EncryptedWriter(encryptor.wrap_output(output)?))
+    }
+}
+
+/// Public keys `age1...`, one per line of a recipients file, skipping blank lines and `#`
+/// comments
+impl FromStr for EncryptionRecipients {
+    type Err = InvalidRecipient;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let recipients = s
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| Recipient::from_str(line).map_err(|_| InvalidRecipient))
+            .collect::<Result<Vec<_>, _>>()?;
+        match recipients.is_empty() {
+            true => Err(InvalidRecipient),
+            false => Ok(EncryptionRecipients { recipients }),
+        }
+    }
+}
+
+/// All the recipients of each
+impl FromIterator<EncryptionRecipients> for EncryptionRecipients {
+    fn from_iter<I: IntoIterator<Item = EncryptionRecipients>>(iter: I) -> Self {
+        let recipients = iter.into_iter().flat_map(|each| each.recipients).collect();
+        EncryptionRecipients { recipients }
+    }
+}
+
+impl fmt::Debug for EncryptionRecipients {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.recipients.iter().map(ToString::to_string))
+            .finish()
+    }
+}
+
+/// An age identity to decrypt files with
+pub struct DecryptionKey {
+    identity: Identity,
+}
+
+impl DecryptionKey {
+    /// Environment variable with the key itself, for the command line tool
+    pub const ENV: &'static str = "TRANSACTION_PARSER_KEY";
+
+    /// The public key of the identity, to encrypt files it can decrypt
+    pub fn recipients(&self) -> EncryptionRecipients {
+        EncryptionRecipients {
+            recipients: vec![self.identity.to_public()],
+        }
+    }
+
+    /// Decrypts `input`, failing if it wasn't encrypted to this key.
+    /// Reading fails on data that was tampered with or cut short.
+    pub fn decrypt<R: Read>(&self, input: R) -> io::Result<impl Read> {
+        let decryptor = Decryptor::new(input).map_err(decrypt_error)?;
+        decryptor
+            .decrypt(iter::once(&self.identity as _))
+            .map_err(decrypt_error)
+    }
+}
+
+fn decrypt_error(err: age::DecryptError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// The key of an identity file: the first line that isn't blank or a `#` comment
+impl FromStr for DecryptionKey {
+    type Err = InvalidKey;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let key = s
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .ok_or(InvalidKey)?;
+        let identity = Identity::from_str(key).map_err(|_| InvalidKey)?;
+        Ok(DecryptionKey { identity })
+    }
+}
+
+/// Kept out of logs
+impl fmt::Debug for DecryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DecryptionKey({})", self.identity.to_public())
+    }
+}
+
+/// Text that isn't an age secret key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidKey;
+
+impl fmt::Display for InvalidKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("not an age secret key, expected AGE-SECRET-KEY-1...")
+    }
+}
+
+impl std::error::Error for InvalidKey {}
+
+/// Text that isn't a list of age public keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidRecipient;
+
+impl fmt::Display for InvalidRecipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("not an age public key, expected age1...")
+    }
+}
+
+impl std::error::Error for InvalidRecipient {}
+
+/// Whether `content` starts like an age file, encrypted by
+/// [`EncryptionRecipients::encrypt`] or `age`
+pub fn is_encrypted(content: &[u8]) -> bool {
+    content.starts_with(HEADER)
+}
+
+/// Encrypts what is written to it, see [`EncryptionRecipients::encrypt`]
+pub struct EncryptedWriter<W: Write>(age::stream::StreamWriter<W>);
+
+impl<W: Write> EncryptedWriter<W> {
+    /// Writes the last chunk, returns the output
+    pub fn finish(self) -> io::Result<W> {
+        self.0.finish()
+    }
+}
+
+impl<W: Write> Write for EncryptedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Generated for these tests only, an identity file as age-keygen writes it
+    const KEY: &str = "\
+# created: 2024-06-01T00:00:00Z
+# public key: age1nvum4tvu8xuuc0qsd6r9ctpymx608wgck7tc7qgwmfvprlh0qqusperec0
+AGE-SECRET-KEY-1LUUTKXVGX4WZD7F72V5S44SUQQ0G80P763GNQY3H7GEVCMXE6N3QQUVEKK
+";
+
+    const RECIPIENT: &str = "age1nvum4tvu8xuuc0qsd6r9ctpymx608wgck7tc7qgwmfvprlh0qqusperec0";
+
+    #[test]
+    fn round_trips_through_the_key() {
+        let key: DecryptionKey = KEY.parse().unwrap();
+        let recipients: EncryptionRecipients = RECIPIENT.parse().unwrap();
+        let plain =
+            "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n".repeat(5000);
+        let mut writer = recipients.encrypt(vec![]).unwrap();
+        writer.write_all(plain.as_bytes()).unwrap();
+        let encrypted = writer.finish().unwrap();
+        assert!(is_encrypted(&encrypted));
+        assert!(!encrypted.windows(10).any(|window| window == b"1.5000,0.0"));
+
+        let mut decrypted = String::new();
+        key.decrypt(&encrypted[..])
+            .unwrap()
+            .read_to_string(&mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, plain);
+
+        // Cut short, e.g. by a full disk
+        let mut truncated = key.decrypt(&encrypted[..encrypted.len() - 100]).unwrap();
+        assert!(truncated.read_to_end(&mut vec![]).is_err());
+    }
+
+    #[test]
+    fn other_keys_cant_decrypt() {
+        let key: DecryptionKey = KEY.parse().unwrap();
+        let other = DecryptionKey {
+            identity: Identity::generate(),
+        };
+        let mut writer = other.recipients().encrypt(vec![]).unwrap();
+        writer.write_all(b"secret").unwrap();
+        let encrypted = writer.finish().unwrap();
+        let err = key.decrypt(&encrypted[..]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            "AGE-SECRET-KEY-1".parse::<DecryptionKey>().unwrap_err(),
+            InvalidKey
+        );
+    }
+
+    #[test]
+    fn any_recipient_decrypts() {
+        let key: DecryptionKey = KEY.parse().unwrap();
+        let other = DecryptionKey {
+            identity: Identity::generate(),
+        };
+        let file = format!("# recovery key\n{}\n\n", other.recipients().recipients[0]);
+        let recipients: EncryptionRecipients = [file.parse().unwrap(), key.recipients()]
+            .into_iter()
+            .collect();
+        let mut writer = recipients.encrypt(vec![]).unwrap();
+        writer.write_all(b"secret").unwrap();
+        let encrypted = writer.finish().unwrap();
+        for key in [key, other] {
+            let mut decrypted = vec![];
+            key.decrypt(&encrypted[..])
+                .unwrap()
+                .read_to_end(&mut decrypted)
+                .unwrap();
+            assert_eq!(decrypted, b"secret");
+        }
+
+        // Secret keys aren't public ones
+        assert_eq!(
+            KEY.parse::<EncryptionRecipients>().unwrap_err(),
+            InvalidRecipient
+        );
+        assert_eq!(
+            "# nothing".parse::<EncryptionRecipients>().unwrap_err(),
+            InvalidRecipient
+        );
+    }
+}
@@ -0,0 +1,2038 @@
+//! Applies transactions to accounts and keeps what disputes need to reference them.
+use crate::ids::IdNames;
+use crate::io::source::TransactionSource;
+use crate::io::{ErrorCode, ParseMode, RowError};
+use crate::model::{
+    Account, AccountUpdate, AppliedDispute, ClientId, Status, Transaction, TransactionType, TxId,
+    UnknownTransaction,
+};
+use crate::report::ProcessReport;
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::fmt;
+use tracing::{debug, info, warn};
+
+pub mod alert;
+pub mod eviction;
+mod index;
+pub mod invariants;
+pub mod limits;
+pub mod minor;
+pub mod policy;
+pub mod release;
+pub mod schedule;
+#[cfg(feature = "dashmap")]
+pub mod shared;
+pub mod state;
+mod undo;
+mod waiting;
+
+use eviction::{Eviction, Evictor};
+pub use index::TransactionIndex;
+use limits::{tighter, AmountLimits, RiskTiers, WithdrawalWindow};
+use policy::{DisputePolicy, DisputeState, DisputedTx, StandardDisputePolicy};
+use undo::{Notice, UndoLog};
+use waiting::Waiting;
+
+/// Hasher of the accounts and transactions maps.
+/// SipHash unless the `fxhash` or `ahash` feature picks a faster one.
+#[cfg(feature = "fxhash")]
+pub type BuildHasher = rustc_hash::FxBuildHasher;
+#[cfg(all(feature = "ahash", not(feature = "fxhash")))]
+pub type BuildHasher = ahash::RandomState;
+#[cfg(not(any(feature = "fxhash", feature = "ahash")))]
+pub type BuildHasher = std::collections::hash_map::RandomState;
+
+/// Accounts by client id
+pub type AccountMap = HashMap<ClientId, Account, BuildHasher>;
+
+/// Accounts ordered by client id
+pub(crate) fn sorted_accounts(accounts: &AccountMap) -> Vec<&Account> {
+    let mut sorted: Vec<&Account> = accounts.values().collect();
+    sorted.sort_unstable_by_key(|account| account.client);
+    sorted
+}
+
+/// Why the engine refused to apply a transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    /// A deposit or withdrawal reused an already seen tx id
+    DuplicateTx(TxId),
+    /// A deposit or withdrawal of a negative amount, which would move funds the other way
+    NegativeAmount(Decimal),
+    /// A [`TransactionValidator`] refused the transaction, with its reason
+    Vetoed(String),
+    /// A Dispute of a tx id that is already under dispute,
+    /// with [`Engine::set_report_repeated_disputes`]
+    AlreadyDisputed(TxId),
+    /// A Dispute, Resolve or Chargeback of a tx id that was already charged back,
+    /// or anything referencing a reversed chargeback
+    ChargedBack(TxId),
+    /// A ChargebackReversal of a tx id that isn't the client's charged back transaction
+    NotChargedBack(TxId),
+    /// An unlock of a client whose account isn't locked
+    NotLocked(ClientId),
+    /// An administrative row that doesn't apply to an account of `status`, e.g. an unlock
+    /// of a closed account, see [`Status::after`]
+    WrongStatus {
+        transaction_type: TransactionType,
+        status: Status,
+    },
+    /// A withdrawal or dispute hold of more than the `available` funds,
+    /// with [`NegativeBalanceBehavior::Reject`]
+    InsufficientFunds { available: Decimal, amount: Decimal },
+    /// Applying the transaction would overflow one of the account's balances
+    ArithmeticOverflow,
+    /// A Dispute, Resolve or Chargeback referenced a tx id that hasn't been seen,
+    /// with [`UnknownReference::Reject`]
+    UnknownTx(TxId),
+    /// A deposit or withdrawal over its [`AmountLimits`]
+    OverLimit { amount: Decimal, limit: Decimal },
+    /// A withdrawal taking the client's withdrawals of the last 24 hours, `withdrawn`
+    /// with it, over the limit of [`Engine::set_daily_withdrawal_limit`]
+    DailyWithdrawalLimit { withdrawn: Decimal, limit: Decimal },
+    /// A deposit or withdrawal of a client whose account is locked,
+    /// with [`Engine::set_reject_locked`]
+    LockedAccount(ClientId),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::DuplicateTx(tx) => write!(f, "duplicate tx id {}", tx),
+            Rejection::NegativeAmount(amount) => write!(f, "negative amount {}", amount),
+            Rejection::Vetoed(reason) => f.write_str(reason),
+            Rejection::AlreadyDisputed(tx) => write!(f, "tx id {} is already under dispute", tx),
+            Rejection::ChargedBack(tx) => write!(f, "tx id {} was charged back", tx),
+            Rejection::NotChargedBack(tx) => write!(f, "tx id {} wasn't charged back", tx),
+            Rejection::NotLocked(client) => write!(f, "client {} isn't locked", client),
+            Rejection::WrongStatus {
+                transaction_type,
+                status,
+            } => write!(
+                f,
+                "{} doesn't apply to an account with status {}",
+                transaction_type, status
+            ),
+            Rejection::InsufficientFunds { available, amount } => write!(
+                f,
+                "insufficient funds: {} available for {}",
+                available, amount
+            ),
+            Rejection::ArithmeticOverflow => f.write_str("balances would overflow"),
+            Rejection::UnknownTx(tx) => write!(f, "unknown tx id {}", tx),
+            Rejection::OverLimit { amount, limit } => {
+                write!(f, "amount {} is over the limit of {}", amount, limit)
+            }
+            Rejection::DailyWithdrawalLimit { withdrawn, limit } => write!(
+                f,
+                "withdrawals of {} in 24 hours would be over the limit of {}",
+                withdrawn, limit
+            ),
+            Rejection::LockedAccount(client) => write!(f, "account of client {} is locked", client),
+        }
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+impl Rejection {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Rejection::DuplicateTx(_) => ErrorCode::DuplicateTx,
+            Rejection::NegativeAmount(_) => ErrorCode::BadAmount,
+            Rejection::Vetoed(_) => ErrorCode::Vetoed,
+            Rejection::AlreadyDisputed(_) => ErrorCode::AlreadyDisputed,
+            Rejection::ChargedBack(_) => ErrorCode::ChargedBack,
+            Rejection::NotChargedBack(_) => ErrorCode::NotChargedBack,
+            Rejection::NotLocked(_) => ErrorCode::NotLocked,
+            Rejection::WrongStatus { .. } => ErrorCode::WrongStatus,
+            Rejection::InsufficientFunds { .. } => ErrorCode::InsufficientFunds,
+            Rejection::ArithmeticOverflow => ErrorCode::Overflow,
+            Rejection::UnknownTx(_) => ErrorCode::UnknownRefTx,
+            Rejection::OverLimit { .. } => ErrorCode::AmountLimit,
+            Rejection::DailyWithdrawalLimit { .. } => ErrorCode::WithdrawalLimit,
+            Rejection::LockedAccount(_) => ErrorCode::LockedAccount,
+        }
+    }
+}
+
+/// The status the administrative row `transaction_type` leaves the account of `client` with,
+/// see [`Status::after`]
+pub(crate) fn status_after(
+    client: ClientId,
+    status: Status,
+    transaction_type: TransactionType,
+) -> Result<Status, Rejection> {
+    match (transaction_type, status) {
+        (TransactionType::Unlock, Status::Active) => Err(Rejection::NotLocked(client)),
+        _ => status
+            .after(transaction_type)
+            .ok_or(Rejection::WrongStatus {
+                transaction_type,
+                status,
+            }),
+    }
+}
+
+/// Hooks into the engine, e.g. for metrics, alerting or persistence.
+/// All methods do nothing by default.
+pub trait EngineObserver {
+    /// A transaction changed an account
+    fn on_applied(&mut self, _update: &AccountUpdate) {}
+
+    /// The engine refused to apply a transaction
+    fn on_rejected(&mut self, _transaction: &Transaction, _rejection: &Rejection) {}
+
+    /// The chargeback of `tx` locked `account`, called after [`EngineObserver::on_applied`]
+    fn on_account_locked(&mut self, _account: &Account, _tx: TxId) {}
+
+    /// A row of an unknown transaction type was skipped, see [`crate::UnknownTypes::Skip`]
+    fn on_unknown_type(&mut self, _transaction: &UnknownTransaction) {}
+}
+
+/// Business rules checked before a transaction is applied, e.g. amount caps or allowed clients
+pub trait TransactionValidator {
+    /// `Err` with a reason keeps `transaction` from being applied.
+    /// `account` is the client's account before the transaction, if it has one yet.
+    fn validate(
+        &mut self,
+        transaction: &Transaction,
+        account: Option<&Account>,
+    ) -> Result<(), String>;
+}
+
+impl<F> TransactionValidator for F
+where
+    F: FnMut(&Transaction, Option<&Account>) -> Result<(), String>,
+{
+    fn validate(
+        &mut self,
+        transaction: &Transaction,
+        account: Option<&Account>,
+    ) -> Result<(), String> {
+        self(transaction, account)
+    }
+}
+
+/// Applies parsed transactions to accounts, in the order they occurred
+pub struct Engine {
+    accounts: AccountMap,
+    // Deposits and withdrawals by tx id,
+    // to use with Dispute/ Resolve/ Chargeback transactions
+    transactions: TransactionIndex,
+    observers: Vec<Box<dyn EngineObserver>>,
+    validators: Vec<Box<dyn TransactionValidator>>,
+    dispute_policy: Box<dyn DisputePolicy>,
+    unknown_reference: UnknownReference,
+    report_repeated_disputes: bool,
+    allow_redisputes: bool,
+    unlock_on_reversal: bool,
+    reject_locked: bool,
+    negative_balances: NegativeBalanceBehavior,
+    amount_limits: AmountLimits,
+    daily_withdrawal_limit: Option<Decimal>,
+    risk_tiers: RiskTiers,
+    withdrawals: WithdrawalWindow,
+    // Seconds since the Unix epoch, see set_time
+    now: Option<i64>,
+    retry_out_of_order: bool,
+    // Disputes deferred or parked until another row of the tx they reference
+    waiting: Waiting,
+    eviction: Evictor,
+    // Names of text tx ids, see set_tx_names
+    tx_names: Option<IdNames>,
+    // What the rows since begin changed, see undo
+    undo: Option<UndoLog>,
+}
+
+/// What happens to a Dispute, Resolve or Chargeback referencing a tx id that hasn't been seen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownReference {
+    /// Ignore it without a trace, apart from a debug log
+    #[default]
+    Ignore,
+    /// Reject it as [`Rejection::UnknownTx`], so it is collected in the report
+    /// or aborts the run depending on the [`crate::ParseMode`]
+    Reject,
+    /// Keep it until a deposit or withdrawal with that tx id arrives, then apply it.
+    /// For unordered feeds where a dispute can precede its transaction.
+    /// Disputes whose transaction never arrives wait with those of
+    /// [`Engine::set_retry_out_of_order`], bounded by [`Engine::set_eviction`].
+    Defer,
+}
+
+/// What happens to a withdrawal or a dispute hold taking more than the available funds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NegativeBalanceBehavior {
+    /// Reject it as [`Rejection::InsufficientFunds`]
+    Reject,
+    /// Withdraw or hold only what is available, nothing if the available funds are negative
+    ClampToZero,
+    /// Let the available funds go negative
+    #[default]
+    Allow,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Engine {
+            accounts: AccountMap::default(),
+            transactions: TransactionIndex::default(),
+            observers: vec![],
+            validators: vec![],
+            dispute_policy: Box::new(StandardDisputePolicy),
+            unknown_reference: UnknownReference::default(),
+            report_repeated_disputes: false,
+            allow_redisputes: true,
+            unlock_on_reversal: false,
+            reject_locked: false,
+            negative_balances: NegativeBalanceBehavior::default(),
+            amount_limits: AmountLimits::default(),
+            daily_withdrawal_limit: None,
+            risk_tiers: RiskTiers::default(),
+            withdrawals: WithdrawalWindow::new(),
+            now: None,
+            retry_out_of_order: false,
+            waiting: Waiting::default(),
+            eviction: Evictor::default(),
+            tx_names: None,
+            undo: None,
+        }
+    }
+}
+
+impl fmt::Debug for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Engine")
+            .field("accounts", &self.accounts)
+            .field("transactions", &self.transactions)
+            .field("observers", &self.observers.len())
+            .field("validators", &self.validators.len())
+            .field("unknown_reference", &self.unknown_reference)
+            .field("report_repeated_disputes", &self.report_repeated_disputes)
+            .field("allow_redisputes", &self.allow_redisputes)
+            .field("unlock_on_reversal", &self.unlock_on_reversal)
+            .field("reject_locked", &self.reject_locked)
+            .field("negative_balances", &self.negative_balances)
+            .field("amount_limits", &self.amount_limits)
+            .field("daily_withdrawal_limit", &self.daily_withdrawal_limit)
+            .field("risk_tiers", &self.risk_tiers)
+            .field("withdrawals", &self.withdrawals)
+            .field("now", &self.now)
+            .field("retry_out_of_order", &self.retry_out_of_order)
+            .field("waiting", &self.waiting)
+            .field("eviction", &self.eviction)
+            .field("tx_names", &self.tx_names)
+            .field("undo", &self.undo)
+            .finish()
+    }
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Engine::default()
+    }
+
+    /// Register `observer` to be called for every transaction applied from now on
+    pub fn add_observer(&mut self, observer: impl EngineObserver + 'static) {
+        self.observers.push(Box::new(observer));
+    }
+
+    /// Register `validator` to check every transaction before it is applied.
+    /// Validators run in the order they were added, the first veto wins.
+    pub fn add_validator(&mut self, validator: impl TransactionValidator + 'static) {
+        self.validators.push(Box::new(validator));
+    }
+
+    /// Replace the [`StandardDisputePolicy`] deciding what Dispute, Resolve and Chargeback do.
+    /// The engine invariants are only guaranteed for the standard policy.
+    pub fn set_dispute_policy(&mut self, policy: impl DisputePolicy + 'static) {
+        self.dispute_policy = Box::new(policy);
+    }
+
+    /// Choose what happens to disputes of tx ids that haven't been seen yet
+    pub fn set_unknown_reference(&mut self, unknown_reference: UnknownReference) {
+        self.unknown_reference = unknown_reference;
+    }
+
+    /// Reject disputes of a transaction that is already under dispute as
+    /// [`Rejection::AlreadyDisputed`] instead of ignoring them
+    pub fn set_report_repeated_disputes(&mut self, report: bool) {
+        self.report_repeated_disputes = report;
+    }
+
+    /// Park Resolve and Chargeback rows the dispute policy ignores while their transaction
+    /// isn't under dispute yet, e.g. a resolve ahead of its dispute in a reordered feed, and
+    /// retry them in the order they arrived whenever another row referencing the same tx is
+    /// applied. Rows that can't apply whatever comes later, like a second dispute of a
+    /// disputed transaction, are ignored or rejected right away as without retries. Rows that never apply wait
+    /// with those of [`UnknownReference::Defer`] until [`Engine::retry_waiting`], which
+    /// processing a whole stream calls at its end, or until [`Engine::set_eviction`] drops them.
+    pub fn set_retry_out_of_order(&mut self, retry: bool) {
+        self.retry_out_of_order = retry;
+    }
+
+    /// Whether a transaction whose dispute was resolved can be disputed again, holding
+    /// its funds anew. Allowed by default, forbidden re-disputes are ignored.
+    pub fn set_allow_redisputes(&mut self, allow: bool) {
+        self.allow_redisputes = allow;
+    }
+
+    /// Whether a ChargebackReversal makes the account the chargeback froze active again,
+    /// a closed account or one under review stays so. Off by default, the account stays
+    /// frozen with its funds back.
+    pub fn set_unlock_on_reversal(&mut self, unlock: bool) {
+        self.unlock_on_reversal = unlock;
+    }
+
+    /// Reject deposits and withdrawals of clients whose account is locked as
+    /// [`Rejection::LockedAccount`], so a locked account keeps its balances. Off by default,
+    /// they are applied as for any account.
+    pub fn set_reject_locked(&mut self, reject: bool) {
+        self.reject_locked = reject;
+    }
+
+    /// Choose what happens to withdrawals and dispute holds taking more than the available
+    /// funds. Allowed by default, fees and chargebacks can take the funds negative regardless.
+    pub fn set_negative_balance_behavior(&mut self, behavior: NegativeBalanceBehavior) {
+        self.negative_balances = behavior;
+    }
+
+    /// Reject deposits and withdrawals over `limits` as [`Rejection::OverLimit`],
+    /// before validators see them
+    pub fn set_amount_limits(&mut self, limits: AmountLimits) {
+        self.amount_limits = limits;
+    }
+
+    /// Reject withdrawals that would take what a client withdrew in the last 24 hours
+    /// over `limit` as [`Rejection::DailyWithdrawalLimit`], `None` to lift it. Only applies
+    /// to withdrawals made after [`Engine::set_time`], the engine doesn't know the time
+    /// otherwise. The withdrawals of the window aren't part of an [`state::EngineState`].
+    pub fn set_daily_withdrawal_limit(&mut self, limit: Option<Decimal>) {
+        self.daily_withdrawal_limit = limit;
+    }
+
+    /// Apply the limits of each client's risk tier on top of the engine's,
+    /// the tighter one wins
+    pub fn set_risk_tiers(&mut self, tiers: RiskTiers) {
+        self.risk_tiers = tiers;
+    }
+
+    /// What time it is, in seconds since the Unix epoch, for the transactions applied next,
+    /// e.g. from a timestamp of the input. Only time-based limits use it.
+    pub fn set_time(&mut self, now: i64) {
+        self.now = Some(now);
+    }
+
+    /// Whether rules of the engine go by [`Engine::set_time`]: a daily withdrawal limit,
+    /// the engine's or a risk tier's, or an [`Eviction::Window`]
+    pub fn needs_time(&self) -> bool {
+        self.daily_withdrawal_limit.is_some()
+            || self.risk_tiers.has_daily_limits()
+            || self.eviction.timed()
+    }
+
+    /// Cap the deposits and withdrawals kept for disputes to reference, and the rows waiting
+    /// for them, see [`eviction`] for what is lost. The transactions already kept are evicted
+    /// in tx id order.
+    pub fn set_eviction(&mut self, eviction: Eviction) {
+        self.eviction.set(eviction, &self.transactions);
+    }
+
+    /// Keep in `names`, those of [`crate::IdFormat::Text`] tx ids, only the names of
+    /// transactions disputes can still reference: from now on the names of rows the engine
+    /// doesn't keep, and of those it evicts, are forgotten, so the names stay within the
+    /// transactions kept.
+    pub fn set_tx_names(&mut self, names: IdNames) {
+        names.retain(|tx| self.transactions.contains(tx) || self.waiting.contains(tx));
+        self.tx_names = Some(names);
+    }
+
+    /// Deposits and withdrawals evicted so far
+    pub fn evicted_transactions(&self) -> u64 {
+        self.eviction.evicted_count()
+    }
+
+    /// Disputes, resolves, chargebacks and reversals of evicted transactions so far,
+    /// which were ignored or rejected, and the rows the eviction dropped while they waited
+    pub fn lost_to_eviction(&self) -> u64 {
+        self.eviction.lost()
+    }
+
+    /// Update the client's account with `transaction`.
+    /// Transactions that would break the engine invariants are ignored or rejected.
+    /// Returns the amount moved when a Dispute, Resolve or Chargeback moved funds.
+    pub fn apply(&mut self, transaction: Transaction) -> Result<Option<AppliedDispute>, Rejection> {
+        let applied = self.apply_observed(transaction, false);
+        if applied.is_ok() {
+            self.replay_waiting(transaction.tx, false, &mut |_| {});
+        }
+        self.forget_name(transaction.tx);
+        applied.map(|(dispute, _)| dispute)
+    }
+
+    /// Same as [`Engine::apply`], returning the client's new balances
+    /// if the transaction changed them.
+    /// Deferred disputes it unblocks are only reported to observers, see [`Engine::apply_each`].
+    pub fn apply_with_update(
+        &mut self,
+        transaction: Transaction,
+    ) -> Result<Option<AccountUpdate>, Rejection> {
+        let applied = self.apply_observed(transaction, true);
+        if applied.is_ok() {
+            self.replay_waiting(transaction.tx, false, &mut |_| {});
+        }
+        self.forget_name(transaction.tx);
+        applied.map(|(_, update)| update)
+    }
+
+    /// Same as [`Engine::apply`], calling `on_update` with the new balances
+    /// for `transaction` and then for every deferred or parked dispute it unblocked
+    pub fn apply_each(
+        &mut self,
+        transaction: Transaction,
+        mut on_update: impl FnMut(AccountUpdate),
+    ) -> Result<(), Rejection> {
+        let applied = self.apply_observed(transaction, true);
+        if let Ok((_, update)) = applied {
+            if let Some(update) = update {
+                on_update(update);
+            }
+            self.replay_waiting(transaction.tx, true, &mut on_update);
+        }
+        self.forget_name(transaction.tx);
+        applied.map(|_| ())
+    }
+
+    /// Make the account of `client` a chargeback froze active again after manual review, like
+    /// an unlock row with tx id 0: observers see the update, e.g. to keep an audit trail.
+    /// Rejected as [`Rejection::NotLocked`] if the account isn't locked and as
+    /// [`Rejection::WrongStatus`] if it is closed or under review.
+    pub fn unlock(&mut self, client: ClientId) -> Result<(), Rejection> {
+        let unlock = Transaction {
+            transaction_type: TransactionType::Unlock,
+            client,
+            tx: 0,
+            amount: None,
+        };
+        self.apply_observed(unlock, false).map(|_| ())
+    }
+
+    /// Applies already parsed `transactions` in order with [`Engine::apply`],
+    /// returning the result of each at its index. A rejection doesn't stop the batch.
+    pub fn process_batch(
+        &mut self,
+        transactions: Vec<Transaction>,
+    ) -> Vec<Result<Option<AppliedDispute>, Rejection>> {
+        transactions
+            .into_iter()
+            .map(|transaction| self.apply(transaction))
+            .collect()
+    }
+
+    /// Applies the transactions of any source, e.g. a database cursor, and reports like
+    /// [`crate::ParseMode::Collecting`]: source errors and rejections are skipped and kept
+    /// in [`ProcessReport::errors`] with the position of the item as line, starting at 1.
+    pub fn process_iter<E: fmt::Display>(
+        mut self,
+        transactions: impl Iterator<Item = Result<Transaction, E>>,
+    ) -> ProcessReport {
+        let items = transactions.enumerate().map(|(index, item)| {
+            let line = index as u64 + 1;
+            let item = item.map_err(|err| RowError {
+                line,
+                record: String::new(),
+                code: ErrorCode::MalformedRow,
+                message: err.to_string(),
+            });
+            (line, item)
+        });
+        let errors = self
+            .process_lines(items, ParseMode::Collecting)
+            .unwrap_or_else(|_| unreachable!("only strict mode fails"));
+        ProcessReport {
+            errors,
+            ..self.into()
+        }
+    }
+
+    /// Same as [`Engine::process_iter`] for a [`TransactionSource`],
+    /// rejections are reported with the line the source read the transaction from
+    pub fn process_source(self, source: impl TransactionSource) -> ProcessReport {
+        self.process_source_with(source, ParseMode::Collecting)
+            .unwrap_or_else(|_| unreachable!("only strict mode fails"))
+    }
+
+    /// Same as [`Engine::process_source`] with source errors and rejections handled by `mode`.
+    /// Only [`ParseMode::Strict`] returns an error.
+    pub fn process_source_with(
+        mut self,
+        source: impl TransactionSource,
+        mode: ParseMode,
+    ) -> Result<ProcessReport, RowError> {
+        let errors = self.apply_source(source, mode)?;
+        Ok(ProcessReport {
+            errors,
+            ..self.into()
+        })
+    }
+
+    /// Applies all transactions of `source` and keeps the engine for more,
+    /// e.g. to process several files in turn. Returns the errors [`ParseMode::Collecting`] collects,
+    /// only [`ParseMode::Strict`] returns an error.
+    pub fn apply_source(
+        &mut self,
+        mut source: impl TransactionSource,
+        mode: ParseMode,
+    ) -> Result<Vec<RowError>, RowError> {
+        let mut index = 0;
+        let items = std::iter::from_fn(|| {
+            let item = source.next_transaction()?;
+            index += 1;
+            Some((source.line().unwrap_or(index), item))
+        });
+        self.process_lines(items, mode)
+    }
+
+    pub(crate) fn process_lines(
+        &mut self,
+        items: impl Iterator<Item = (u64, Result<Transaction, RowError>)>,
+        mode: ParseMode,
+    ) -> Result<Vec<RowError>, RowError> {
+        let mut errors = vec![];
+        for (line, item) in items {
+            let error = match item {
+                Ok(transaction) => match self.apply(transaction) {
+                    Ok(_) => continue,
+                    Err(rejection) => RowError {
+                        line,
+                        record: transaction.to_string(),
+                        code: rejection.code(),
+                        message: rejection.to_string(),
+                    },
+                },
+                Err(error) => error,
+            };
+            mode.reject(error, &mut errors)?;
+        }
+        Ok(errors)
+    }
+
+    /// Retries the rows waiting for `tx`, in the order they arrived, as long as one of them
+    /// applies. A deposit or withdrawal applies the disputes deferred until it was seen.
+    fn replay_waiting(&mut self, tx: TxId, track: bool, on_update: &mut dyn FnMut(AccountUpdate)) {
+        if self.waiting.is_empty() {
+            return;
+        }
+        loop {
+            let rows = self.waiting.take(tx);
+            if rows.is_empty() {
+                break;
+            }
+            let mut applied = false;
+            for transaction in rows {
+                // Rows that still don't apply wait again, rejections only reach the observers
+                if let Ok((dispute, update)) = self.apply_observed(transaction, track) {
+                    applied |= dispute.is_some();
+                    if let Some(update) = update {
+                        on_update(update);
+                    }
+                }
+            }
+            if !applied {
+                break;
+            }
+        }
+        self.waiting.done(tx);
+    }
+
+    /// Gives every waiting row a last try, see [`UnknownReference::Defer`] and
+    /// [`Engine::set_retry_out_of_order`], and returns the rows that still don't apply ordered
+    /// by tx id, forgetting them
+    pub fn retry_waiting(&mut self) -> Vec<Transaction> {
+        let mut txs = self.waiting.txs();
+        txs.sort_unstable();
+        for tx in &txs {
+            self.replay_waiting(*tx, false, &mut |_| {});
+        }
+        let mut unapplied = vec![];
+        for tx in txs {
+            unapplied.extend(self.waiting.remove(tx));
+            self.forget_name(tx);
+        }
+        for transaction in &unapplied {
+            warn!(
+                client = transaction.client,
+                tx = transaction.tx,
+                "waiting {} never applied",
+                transaction.transaction_type
+            );
+        }
+        unapplied
+    }
+
+    /// Keeps `transaction` until another row of its tx applies, within the eviction
+    fn wait(&mut self, transaction: Transaction) {
+        self.waiting.push(transaction, self.now);
+        self.evict_waiting();
+    }
+
+    /// Drops the rows waiting past the eviction, with the names of their tx ids
+    /// unless the transaction is kept
+    fn evict_waiting(&mut self) {
+        let (names, transactions) = (&self.tx_names, &self.transactions);
+        self.eviction
+            .evict_waiting(&mut self.waiting, self.now, |tx| {
+                if let Some(names) = names.as_ref().filter(|_| !transactions.contains(tx)) {
+                    names.forget(tx);
+                }
+            });
+    }
+
+    /// Forgets the name of `tx`, see [`Engine::set_tx_names`], unless disputes can still
+    /// reference it
+    fn forget_name(&self, tx: TxId) {
+        if let Some(names) = &self.tx_names {
+            if !self.transactions.contains(tx) && !self.waiting.contains(tx) {
+                names.forget(tx);
+            }
+        }
+    }
+
+    /// Applies `transaction` and notifies the observers.
+    /// The update is only worked out if `track` is set or there are observers.
+    fn apply_observed(
+        &mut self,
+        transaction: Transaction,
+        track: bool,
+    ) -> Result<(Option<AppliedDispute>, Option<AccountUpdate>), Rejection> {
+        let client = transaction.client;
+        if let Some(undo) = &mut self.undo {
+            undo.record(client, transaction.tx, &self.accounts, &self.transactions);
+        }
+        let transaction = self.clamp_withdrawal(transaction);
+        let state = |account: Option<&Account>| {
+            account.map_or((Decimal::ZERO, Decimal::ZERO, Status::Active), |a| {
+                (a.available, a.held, a.status)
+            })
+        };
+        let track = track || !self.observers.is_empty();
+        let before = track.then(|| state(self.accounts.get(&client)));
+        let dispute = match self.apply_transaction(transaction) {
+            Ok(dispute) => dispute,
+            Err(rejection) => {
+                self.notify(|| Notice::Rejected(transaction, rejection.clone()));
+                return Err(rejection);
+            }
+        };
+        let Some(before) = before else {
+            return Ok((dispute, None));
+        };
+        let account = &self.accounts[&client];
+        let (available, held, status) = state(Some(account));
+        if (available, held, status) == before {
+            return Ok((dispute, None));
+        }
+        let update = AccountUpdate {
+            client,
+            tx: transaction.tx,
+            transaction_type: transaction.transaction_type,
+            amount: dispute.map_or(transaction.amount(), |dispute| dispute.amount),
+            fee: dispute.map_or(Decimal::ZERO, |dispute| dispute.fee),
+            available,
+            held,
+            status,
+        };
+        let locked = (transaction.transaction_type == TransactionType::Chargeback
+            && status.locked())
+        .then(|| account.clone());
+        self.notify(|| Notice::Applied(update, locked));
+        Ok((dispute, Some(update)))
+    }
+
+    fn apply_transaction(
+        &mut self,
+        transaction: Transaction,
+    ) -> Result<Option<AppliedDispute>, Rejection> {
+        // Whatever the negative balance behavior, a negative withdrawal can't add funds
+        if let Some(amount) = transaction.negative_amount() {
+            return Err(Rejection::NegativeAmount(amount));
+        }
+        let tier = self.risk_tiers.limits(transaction.client);
+        let limit = tighter(
+            self.amount_limits.limit(transaction.transaction_type),
+            tier.and_then(|tier| tier.amounts.limit(transaction.transaction_type)),
+        );
+        let daily_limit = tighter(
+            self.daily_withdrawal_limit,
+            tier.and_then(|tier| tier.daily_withdrawal),
+        );
+        if let Some(limit) = limit {
+            let amount = transaction.amount();
+            if amount > limit {
+                return Err(Rejection::OverLimit { amount, limit });
+            }
+        }
+        for validator in &mut self.validators {
+            let account = self.accounts.get(&transaction.client);
+            validator
+                .validate(&transaction, account)
+                .map_err(Rejection::Vetoed)?;
+        }
+        // tx ids are globally unique, a repeated one is malformed
+        if matches!(
+            transaction.transaction_type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        ) && (self.transactions.contains(transaction.tx)
+            || self.eviction.evicted(transaction.tx))
+        {
+            return Err(Rejection::DuplicateTx(transaction.tx));
+        }
+        let window = daily_limit
+            .zip(self.now)
+            .filter(|_| transaction.transaction_type == TransactionType::Withdrawal);
+        if let Some((limit, now)) = window {
+            let withdrawn = self
+                .withdrawals
+                .withdrawn(transaction.client, now)
+                .saturating_add(transaction.amount());
+            if withdrawn > limit {
+                return Err(Rejection::DailyWithdrawalLimit { withdrawn, limit });
+            }
+        }
+        match transaction.transaction_type {
+            TransactionType::ChargebackReversal => {
+                return self.reverse_chargeback(transaction).map(Some);
+            }
+            transaction_type if transaction_type.changes_status() => {
+                let client = transaction.client;
+                let current = self
+                    .accounts
+                    .get(&client)
+                    .map_or(Status::Active, |account| account.status);
+                let status = status_after(client, current, transaction_type)?;
+                // Compliance may act on a client before any funds arrive
+                let account = self
+                    .accounts
+                    .entry(client)
+                    .or_insert_with(|| Account::new(client));
+                account.status = status;
+                info!(client, tx = transaction.tx, %status, "account status changed");
+                return Ok(None);
+            }
+            TransactionType::Deposit | TransactionType::Withdrawal
+                if self.reject_locked
+                    && self
+                        .accounts
+                        .get(&transaction.client)
+                        .is_some_and(Account::locked) =>
+            {
+                return Err(Rejection::LockedAccount(transaction.client));
+            }
+            _ => {}
+        }
+        if transaction.transaction_type == TransactionType::Withdrawal {
+            let available = self
+                .accounts
+                .get(&transaction.client)
+                .map_or(Decimal::ZERO, |account| account.available);
+            Self::fund(self.negative_balances, available, transaction.amount())?;
+        }
+
+        // Get an account or Create a new account with 0 balance
+        // Then Update it
+        let account = self
+            .accounts
+            .entry(transaction.client)
+            .or_insert_with(|| Account::new(transaction.client));
+        if let TransactionType::Deposit | TransactionType::Withdrawal = transaction.transaction_type
+        {
+            if !account.apply_transfer(&transaction) {
+                return Err(Rejection::ArithmeticOverflow);
+            }
+            if let Some((_, now)) = window {
+                self.withdrawals
+                    .record(transaction.client, now, transaction.amount());
+            }
+            self.transactions.insert(
+                transaction.tx,
+                DisputedTx {
+                    transaction_type: transaction.transaction_type,
+                    client: transaction.client,
+                    amount: transaction.amount(),
+                    held: Decimal::ZERO,
+                    state: DisputeState::Processed,
+                },
+            );
+            self.eviction.added(transaction.tx, self.now);
+            let (names, waiting) = (&self.tx_names, &self.waiting);
+            self.eviction.evict(&mut self.transactions, self.now, |tx| {
+                if let Some(names) = names.as_ref().filter(|_| !waiting.contains(tx)) {
+                    names.forget(tx);
+                }
+            });
+            self.evict_waiting();
+            return Ok(None);
+        }
+
+        // The referenced transaction is looked up by tx id.
+        // A transaction can only be disputed by its own client,
+        // the dispute policy decides the rest.
+        let (client, tx, transaction_type) = (
+            transaction.client,
+            transaction.tx,
+            transaction.transaction_type,
+        );
+        let Some(record) = self.transactions.get_mut(tx) else {
+            if self.eviction.evicted(tx) {
+                debug!(client, tx, "{} of evicted tx ignored", transaction_type);
+                self.eviction.lose();
+                return Ok(None);
+            }
+            match self.unknown_reference {
+                UnknownReference::Ignore => {
+                    debug!(client, tx, "{} of unknown tx ignored", transaction_type);
+                }
+                UnknownReference::Reject => return Err(Rejection::UnknownTx(tx)),
+                UnknownReference::Defer => {
+                    debug!(client, tx, "{} of unknown tx deferred", transaction_type);
+                    self.wait(transaction);
+                }
+            }
+            return Ok(None);
+        };
+        if record.client != client {
+            debug!(
+                client,
+                tx,
+                owner = record.client,
+                "{} of another client's tx ignored",
+                transaction_type
+            );
+            return Ok(None);
+        }
+        // A chargeback is final, the funds are gone unless it is reversed
+        if matches!(
+            record.state,
+            DisputeState::ChargedBack | DisputeState::Reversed
+        ) {
+            return Err(Rejection::ChargedBack(tx));
+        }
+        if transaction_type == TransactionType::Dispute
+            && record.state == DisputeState::Resolved
+            && !self.allow_redisputes
+        {
+            debug!(client, tx, "re-dispute of a resolved tx ignored");
+            return Ok(None);
+        }
+        let Some(action) = self.dispute_policy.decide(&transaction, record) else {
+            // Only a dispute still to come can make a resolve or chargeback apply
+            let awaits_dispute = match record.state {
+                DisputeState::Processed => true,
+                DisputeState::Resolved => self.allow_redisputes,
+                _ => false,
+            };
+            if self.retry_out_of_order
+                && awaits_dispute
+                && matches!(
+                    transaction_type,
+                    TransactionType::Resolve | TransactionType::Chargeback
+                )
+            {
+                debug!(client, tx, "{} parked until it applies", transaction_type);
+                self.wait(transaction);
+                return Ok(None);
+            }
+            if self.report_repeated_disputes
+                && transaction_type == TransactionType::Dispute
+                && record.state == DisputeState::Disputed
+            {
+                return Err(Rejection::AlreadyDisputed(tx));
+            }
+            debug!(client, tx, "{} ignored by dispute policy", transaction_type);
+            return Ok(None);
+        };
+        let amount = match transaction_type {
+            TransactionType::Dispute => {
+                Self::fund(self.negative_balances, account.available, action.amount)?
+            }
+            _ => action.amount,
+        };
+        let dispute = AppliedDispute {
+            transaction_type: transaction.transaction_type,
+            client: transaction.client,
+            tx: transaction.tx,
+            amount,
+            fee: action.fee,
+        };
+        // Both the move and the fee have to fit, or neither is applied
+        let mut updated = account.clone();
+        if !updated.apply_dispute(&dispute)
+            || !updated.apply(TransactionType::Withdrawal, action.fee)
+        {
+            return Err(Rejection::ArithmeticOverflow);
+        }
+        updated.set_disputed(tx, action.state == DisputeState::Disputed);
+        *account = updated;
+        record.held = match transaction_type {
+            TransactionType::Dispute => record.held + amount,
+            // Kept for a reversal to give back
+            TransactionType::Chargeback => amount,
+            _ => (record.held - amount).max(Decimal::ZERO),
+        };
+        record.state = action.state;
+        if transaction_type == TransactionType::Chargeback {
+            info!(client, tx, "account locked by chargeback");
+        }
+        Ok(Some(dispute))
+    }
+
+    /// `transaction` with the amount of a withdrawal lowered to the available funds,
+    /// with [`NegativeBalanceBehavior::ClampToZero`]
+    fn clamp_withdrawal(&self, transaction: Transaction) -> Transaction {
+        if self.negative_balances != NegativeBalanceBehavior::ClampToZero
+            || transaction.transaction_type != TransactionType::Withdrawal
+        {
+            return transaction;
+        }
+        let available = self
+            .accounts
+            .get(&transaction.client)
+            .map_or(Decimal::ZERO, |account| account.available);
+        Transaction {
+            amount: transaction
+                .amount
+                .map(|amount| amount.min(available.max(Decimal::ZERO))),
+            ..transaction
+        }
+    }
+
+    /// How much of `amount` may be taken from `available` funds
+    fn fund(
+        behavior: NegativeBalanceBehavior,
+        available: Decimal,
+        amount: Decimal,
+    ) -> Result<Decimal, Rejection> {
+        match behavior {
+            NegativeBalanceBehavior::Reject if amount > available => {
+                Err(Rejection::InsufficientFunds { available, amount })
+            }
+            NegativeBalanceBehavior::ClampToZero => Ok(amount.min(available.max(Decimal::ZERO))),
+            _ => Ok(amount),
+        }
+    }
+
+    /// Gives the client back what the chargeback of the referenced transaction took
+    /// from the held funds, strictly: the transaction must be the client's own
+    /// and charged back, or the reversal is rejected as [`Rejection::NotChargedBack`]
+    fn reverse_chargeback(
+        &mut self,
+        transaction: Transaction,
+    ) -> Result<AppliedDispute, Rejection> {
+        let (client, tx) = (transaction.client, transaction.tx);
+        if self.eviction.evicted(tx) {
+            self.eviction.lose();
+        }
+        let record = self
+            .transactions
+            .get_mut(tx)
+            .filter(|record| record.client == client && record.state == DisputeState::ChargedBack)
+            .ok_or(Rejection::NotChargedBack(tx))?;
+        let account = self
+            .accounts
+            .get_mut(&client)
+            .ok_or(Rejection::NotChargedBack(tx))?;
+        let reversal = AppliedDispute {
+            transaction_type: TransactionType::ChargebackReversal,
+            client,
+            tx,
+            amount: record.held,
+            fee: Decimal::ZERO,
+        };
+        if !account.apply_dispute(&reversal) {
+            return Err(Rejection::ArithmeticOverflow);
+        }
+        record.held = Decimal::ZERO;
+        record.state = DisputeState::Reversed;
+        // A closure or a review stays in place
+        if self.unlock_on_reversal && account.status == Status::Frozen {
+            account.status = Status::Active;
+        }
+        info!(client, tx, "chargeback reversed");
+        Ok(reversal)
+    }
+
+    /// Tell the observers a row of an unknown transaction type was skipped
+    pub fn skip_unknown(&mut self, transaction: &UnknownTransaction) {
+        debug!(
+            client = transaction.client,
+            tx = transaction.tx,
+            "skipped row of unknown type `{}`",
+            transaction.transaction_type
+        );
+        self.notify(|| Notice::UnknownType(transaction.clone()));
+    }
+
+    pub fn accounts(&self) -> &AccountMap {
+        &self.accounts
+    }
+
+    /// Accounts ordered by client id, unlike iterating [`Engine::accounts`]
+    /// the order is the same on every run
+    pub fn accounts_sorted(&self) -> Vec<&Account> {
+        sorted_accounts(&self.accounts)
+    }
+
+    /// Deposit or withdrawal `tx` as disputes see it, with its dispute state
+    pub fn transaction(&self, tx: TxId) -> Option<&DisputedTx> {
+        self.transactions.get(tx)
+    }
+
+    /// Drops the accounts and transactions of the clients `keep` refuses,
+    /// e.g. those of other shards
+    pub(crate) fn retain_clients(&mut self, keep: impl Fn(ClientId) -> bool) {
+        self.accounts.retain(|client, _| keep(*client));
+        self.transactions
+            .retain(|_, transaction| keep(transaction.client));
+    }
+
+    /// The tx id and client of every deposit and withdrawal kept for disputes
+    pub(crate) fn transaction_clients(&self) -> impl Iterator<Item = (TxId, ClientId)> + '_ {
+        self.transactions
+            .iter()
+            .map(|(tx, transaction)| (tx, transaction.client))
+    }
+
+    pub fn into_accounts(self) -> AccountMap {
+        self.accounts
+    }
+}
+
+#[cfg(all(test, feature = "csv"))]
+mod tests {
+    use crate::engine::eviction::Eviction;
+    use crate::engine::policy::DisputeState;
+    use crate::engine::{
+        Engine, EngineObserver, NegativeBalanceBehavior, Rejection, UnknownReference,
+    };
+    use crate::ids::{text_id, IdFormat, IdNames};
+    use crate::io::csv::{
+        process_records, process_transactions, process_transactions_with_engine, ParseMode,
+        ParseOptions,
+    };
+    use crate::io::ErrorCode;
+    use crate::model::{
+        Account, AccountEvent, AccountUpdate, AppliedDispute, Status, Transaction, TransactionType,
+    };
+    use rust_decimal::prelude::Zero;
+    use rust_decimal::Decimal;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn engine_reports_referenced_amount() {
+        let mut engine = Engine::new();
+        let deposit = Transaction {
+            transaction_type: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some(Decimal::new(1, 0)),
+        };
+        assert_eq!(engine.apply(deposit), Ok(None));
+        let dispute = Transaction {
+            transaction_type: TransactionType::Dispute,
+            client: 1,
+            tx: 1,
+            amount: None,
+        };
+        assert_eq!(
+            engine.apply(dispute),
+            Ok(Some(AppliedDispute {
+                transaction_type: TransactionType::Dispute,
+                client: 1,
+                tx: 1,
+                amount: Decimal::new(1, 0),
+                fee: Decimal::ZERO,
+            }))
+        );
+        // Another client's dispute doesn't move anything
+        assert_eq!(
+            engine.apply(Transaction {
+                client: 2,
+                ..dispute
+            }),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn batches_report_each_transaction() {
+        let mut engine = Engine::new();
+        let deposit = Transaction {
+            transaction_type: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some(Decimal::new(3, 0)),
+        };
+        let dispute = Transaction {
+            transaction_type: TransactionType::Dispute,
+            amount: None,
+            ..deposit
+        };
+        let results = engine.process_batch(vec![deposit, deposit, dispute]);
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0], Ok(None));
+        assert_eq!(results[1], Err(Rejection::DuplicateTx(1)));
+        assert_eq!(
+            results[2].clone().unwrap().unwrap().amount,
+            Decimal::new(3, 0)
+        );
+        assert_eq!(engine.accounts()[&1].held, Decimal::new(3, 0));
+    }
+
+    #[test]
+    fn any_iterator_drives_the_engine() {
+        let deposit = Transaction {
+            transaction_type: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some(Decimal::new(2, 0)),
+        };
+        let items = vec![Ok(deposit), Err("connection reset"), Ok(deposit)];
+        let report = Engine::new().process_iter(items.into_iter());
+        assert_eq!(report.accounts[&1].available, Decimal::new(2, 0));
+        let errors: Vec<(u64, &str)> = report
+            .errors
+            .iter()
+            .map(|error| (error.line, error.message.as_str()))
+            .collect();
+        assert_eq!(errors, [(2, "connection reset"), (3, "duplicate tx id 1")]);
+        assert_eq!(report.errors[1].record, "deposit,1,1,2");
+    }
+
+    #[derive(Default)]
+    struct Counts {
+        applied: usize,
+        rejected: usize,
+        locked: usize,
+    }
+
+    struct Counter(Rc<RefCell<Counts>>);
+
+    impl EngineObserver for Counter {
+        fn on_applied(&mut self, _update: &AccountUpdate) {
+            self.0.borrow_mut().applied += 1;
+        }
+
+        fn on_rejected(&mut self, _transaction: &Transaction, _rejection: &Rejection) {
+            self.0.borrow_mut().rejected += 1;
+        }
+
+        fn on_account_locked(&mut self, account: &Account, _tx: crate::model::TxId) {
+            assert!(account.locked());
+            self.0.borrow_mut().locked += 1;
+        }
+    }
+
+    #[test]
+    fn observers_are_notified() {
+        let counts = Rc::new(RefCell::new(Counts::default()));
+        let mut engine = Engine::new();
+        engine.add_observer(Counter(counts.clone()));
+        let transaction = |transaction_type, tx, amount| Transaction {
+            transaction_type,
+            client: 1,
+            tx,
+            amount,
+        };
+        let deposit = transaction(TransactionType::Deposit, 1, Some(Decimal::new(1, 0)));
+        engine.apply(deposit).unwrap();
+        assert!(engine.apply(deposit).is_err());
+        engine
+            .apply(transaction(TransactionType::Dispute, 1, None))
+            .unwrap();
+        // Nothing to resolve, no update
+        engine
+            .apply(transaction(TransactionType::Resolve, 2, None))
+            .unwrap();
+        engine
+            .apply(transaction(TransactionType::Chargeback, 1, None))
+            .unwrap();
+        let counts = counts.borrow();
+        assert_eq!((counts.applied, counts.rejected, counts.locked), (3, 1, 1));
+    }
+
+    #[test]
+    fn rolled_back_rows_leave_no_trace() {
+        let counts = Rc::new(RefCell::new(Counts::default()));
+        let mut engine = Engine::new();
+        engine.add_observer(Counter(counts.clone()));
+        let transaction = |transaction_type, client, tx, amount: Option<i64>| Transaction {
+            transaction_type,
+            client,
+            tx,
+            amount: amount.map(Decimal::from),
+        };
+        engine
+            .apply(transaction(TransactionType::Deposit, 1, 1, Some(5)))
+            .unwrap();
+        let before = engine.state();
+
+        engine.begin();
+        for row in [
+            transaction(TransactionType::Deposit, 1, 2, Some(7)),
+            transaction(TransactionType::Dispute, 1, 1, None),
+            transaction(TransactionType::Chargeback, 1, 1, None),
+            transaction(TransactionType::Deposit, 2, 3, Some(1)),
+        ] {
+            engine.apply(row).unwrap();
+        }
+        assert!(engine
+            .apply(transaction(TransactionType::Deposit, 2, 3, Some(1)))
+            .is_err());
+        assert_eq!(counts.borrow().applied, 1);
+        assert_eq!(engine.rollback(), Ok(()));
+        assert_eq!(engine.state(), before);
+        let seen = |counts: &Counts| (counts.applied, counts.rejected, counts.locked);
+        assert_eq!(seen(&counts.borrow()), (1, 0, 0));
+
+        // Committed rows reach the observers then
+        engine.begin();
+        engine
+            .apply(transaction(TransactionType::Dispute, 1, 1, None))
+            .unwrap();
+        engine
+            .apply(transaction(TransactionType::Chargeback, 1, 1, None))
+            .unwrap();
+        assert_eq!(seen(&counts.borrow()), (1, 0, 0));
+        engine.commit();
+        assert_eq!(seen(&counts.borrow()), (3, 0, 1));
+        assert!(engine.accounts()[&1].locked());
+
+        // Rows waiting for another can't be taken back
+        engine.set_unknown_reference(UnknownReference::Defer);
+        engine.begin();
+        engine
+            .apply(transaction(TransactionType::Dispute, 1, 9, None))
+            .unwrap();
+        assert!(engine.rollback().is_err());
+    }
+
+    #[test]
+    fn validators_can_veto() {
+        let data = "type,client,tx,amount
+deposit,1,1,50
+deposit,1,2,500
+deposit,2,3,1";
+        let mut engine = Engine::new();
+        engine.add_validator(|transaction: &Transaction, _: Option<&Account>| {
+            match transaction.amount {
+                Some(amount) if amount > Decimal::new(100, 0) => {
+                    Err(format!("amount {} over the limit", amount))
+                }
+                _ => Ok(()),
+            }
+        });
+        engine.add_validator(|transaction: &Transaction, _: Option<&Account>| {
+            match transaction.client {
+                1 => Ok(()),
+                client => Err(format!("client {} not allowed", client)),
+            }
+        });
+        let options = ParseOptions {
+            mode: ParseMode::Collecting,
+            ..ParseOptions::default()
+        };
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let report = process_transactions_with_engine(&mut reader, &options, engine).unwrap();
+        assert_eq!(report.accounts[&1].available, Decimal::new(50, 0));
+        let messages: Vec<&str> = report.errors.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            ["amount 500 over the limit", "client 2 not allowed"]
+        );
+    }
+
+    #[test]
+    fn deferred_disputes_apply_once_their_transaction_arrives() {
+        let data = "type,client,tx,amount
+dispute,1,1,
+deposit,1,2,1.0
+deposit,1,1,5.0
+withdrawal,1,3,1.0";
+        let mut updates = vec![];
+        let mut engine = Engine::new();
+        engine.set_unknown_reference(UnknownReference::Defer);
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let report = process_records(&mut reader, &ParseOptions::default(), engine, |update| {
+            updates.push(update.transaction_type)
+        })
+        .unwrap();
+        let account = &report.accounts[&1];
+        assert_eq!(account.held, Decimal::new(5, 0));
+        assert_eq!(account.available, Decimal::ZERO);
+        assert_eq!(
+            updates,
+            [
+                TransactionType::Deposit,
+                TransactionType::Deposit,
+                TransactionType::Dispute,
+                TransactionType::Withdrawal,
+            ]
+        );
+
+        // Ignored by default
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let accounts = process_transactions(&mut reader);
+        assert_eq!(accounts[&1].held, Decimal::zero());
+    }
+
+    #[test]
+    fn resolved_transactions_can_be_disputed_again() {
+        let data = "type,client,tx,amount
+deposit,1,1,5.0
+dispute,1,1,
+resolve,1,1,
+dispute,1,1,";
+        let process = |allow| {
+            let mut engine = Engine::new();
+            engine.set_allow_redisputes(allow);
+            let mut reader = csv::Reader::from_reader(data.as_bytes());
+            process_records(&mut reader, &ParseOptions::default(), engine, |_| {}).unwrap()
+        };
+        let report = process(true);
+        assert_eq!(report.accounts[&1].held, Decimal::new(5, 0));
+        assert_eq!(report.accounts[&1].available, Decimal::ZERO);
+        let mut engine = Engine::new();
+        engine.restore(report.into_state());
+        assert_eq!(
+            engine.transaction(1).map(|record| record.state),
+            Some(DisputeState::Disputed)
+        );
+
+        let report = process(false);
+        assert_eq!(report.accounts[&1].held, Decimal::ZERO);
+        assert_eq!(report.accounts[&1].available, Decimal::new(5, 0));
+        let mut engine = Engine::new();
+        engine.restore(report.into_state());
+        assert_eq!(
+            engine.transaction(1).map(|record| record.state),
+            Some(DisputeState::Resolved)
+        );
+    }
+
+    #[test]
+    fn reversed_chargebacks_give_the_funds_back() {
+        let data = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,5.0
+dispute,1,2,
+chargeback,1,2,
+chargeback_reversal,1,1,
+chargeback_reversal,2,2,
+chargeback_reversal,1,2,
+chargeback_reversal,1,2,
+dispute,1,2,";
+        let process = |unlock| {
+            let mut engine = Engine::new();
+            engine.set_unlock_on_reversal(unlock);
+            let options = ParseOptions {
+                mode: ParseMode::Collecting,
+                ..ParseOptions::default()
+            };
+            let mut reader = csv::Reader::from_reader(data.as_bytes());
+            process_records(&mut reader, &options, engine, |_| {}).unwrap()
+        };
+        let report = process(false);
+        // The held 5 the chargeback took are back
+        assert_eq!(report.accounts[&1].available, Decimal::new(15, 0));
+        assert_eq!(report.accounts[&1].held, Decimal::ZERO);
+        assert!(report.accounts[&1].locked());
+        let errors: Vec<_> = report
+            .errors
+            .iter()
+            .map(|error| (error.line, error.message.as_str()))
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                (6, "tx id 1 wasn't charged back"),
+                (7, "tx id 2 wasn't charged back"),
+                (9, "tx id 2 wasn't charged back"),
+                (10, "tx id 2 was charged back"),
+            ]
+        );
+        assert!(!process(true).accounts[&1].locked());
+    }
+
+    #[test]
+    fn locked_accounts_can_reject_transfers() {
+        let data = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,5.0
+dispute,1,2,
+chargeback,1,2,
+deposit,1,3,1.0
+withdrawal,1,4,1.0
+deposit,2,5,1.0";
+        let process = |reject| {
+            let mut engine = Engine::new();
+            engine.set_reject_locked(reject);
+            let options = ParseOptions {
+                mode: ParseMode::Collecting,
+                ..ParseOptions::default()
+            };
+            let mut reader = csv::Reader::from_reader(data.as_bytes());
+            process_records(&mut reader, &options, engine, |_| {}).unwrap()
+        };
+        let report = process(true);
+        assert_eq!(report.accounts[&1].available, Decimal::new(10, 0));
+        assert_eq!(report.accounts[&2].available, Decimal::ONE);
+        let errors: Vec<_> = report
+            .errors
+            .iter()
+            .map(|error| (error.line, error.code, error.message.as_str()))
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                (6, ErrorCode::LockedAccount, "account of client 1 is locked"),
+                (7, ErrorCode::LockedAccount, "account of client 1 is locked"),
+            ]
+        );
+        // Applied by default
+        let report = process(false);
+        assert_eq!(report.accounts[&1].available, Decimal::new(10, 0));
+        assert!(report.errors.is_empty());
+    }
+
+    #[test]
+    fn locked_accounts_can_be_unlocked() {
+        let data = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,1.0
+dispute,1,1,
+chargeback,1,1,
+unlock,1,7,
+unlock,1,8,
+unlock,2,9,";
+        let mut engine = Engine::new();
+        let mut events = vec![];
+        let mut errors = vec![];
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        for row in reader.deserialize::<Transaction>() {
+            let transaction = row.unwrap();
+            let applied = engine.apply_each(transaction, |update| events.extend(update.events()));
+            if let Err(rejection) = applied {
+                errors.push(rejection);
+            }
+        }
+        assert!(!engine.accounts()[&1].locked());
+        assert_eq!(
+            events.last(),
+            Some(&AccountEvent::Unlocked { client: 1, tx: 7 })
+        );
+        assert_eq!(errors, [Rejection::NotLocked(1), Rejection::NotLocked(2)]);
+        assert_eq!(errors[0].to_string(), "client 1 isn't locked");
+
+        let chargeback = |transaction_type| Transaction {
+            transaction_type,
+            client: 1,
+            tx: 2,
+            amount: None,
+        };
+        engine.apply(chargeback(TransactionType::Dispute)).unwrap();
+        engine
+            .apply(chargeback(TransactionType::Chargeback))
+            .unwrap();
+        assert!(engine.accounts()[&1].locked());
+        engine.unlock(1).unwrap();
+        assert!(!engine.accounts()[&1].locked());
+        assert_eq!(engine.unlock(1), Err(Rejection::NotLocked(1)));
+    }
+
+    #[test]
+    fn status_rows_move_accounts_between_statuses() {
+        let data = "type,client,tx,amount
+deposit,1,1,10.0
+review,1,2,
+unlock,1,3,
+deposit,1,4,1.0
+reinstate,1,5,
+reinstate,1,6,
+close,1,7,
+unlock,1,8,
+review,1,9,
+close,1,10,
+review,2,11,";
+        let options = ParseOptions {
+            mode: ParseMode::Collecting,
+            ..ParseOptions::default()
+        };
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let report = process_records(&mut reader, &options, Engine::new(), |_| {}).unwrap();
+        assert_eq!(report.accounts[&1].status, Status::Closed);
+        assert_eq!(report.accounts[&1].available, Decimal::new(11, 0));
+        // Compliance can review a client without funds
+        assert_eq!(report.accounts[&2].status, Status::UnderReview);
+        let errors: Vec<_> = report
+            .errors
+            .iter()
+            .map(|error| (error.line, error.code, error.message.as_str()))
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                (
+                    4,
+                    ErrorCode::WrongStatus,
+                    "unlock doesn\'t apply to an account with status under_review"
+                ),
+                (
+                    7,
+                    ErrorCode::WrongStatus,
+                    "reinstate doesn\'t apply to an account with status active"
+                ),
+                (
+                    9,
+                    ErrorCode::WrongStatus,
+                    "unlock doesn\'t apply to an account with status closed"
+                ),
+                (
+                    10,
+                    ErrorCode::WrongStatus,
+                    "review doesn\'t apply to an account with status closed"
+                ),
+                (
+                    11,
+                    ErrorCode::WrongStatus,
+                    "close doesn\'t apply to an account with status closed"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn unlocks_only_unlock_frozen_accounts() {
+        let mut engine = Engine::new();
+        let row = |transaction_type, tx| Transaction {
+            transaction_type,
+            client: 1,
+            tx,
+            amount: None,
+        };
+        engine.apply(row(TransactionType::Review, 1)).unwrap();
+        assert_eq!(
+            engine.unlock(1),
+            Err(Rejection::WrongStatus {
+                transaction_type: TransactionType::Unlock,
+                status: Status::UnderReview
+            })
+        );
+        engine.apply(row(TransactionType::Reinstate, 2)).unwrap();
+        assert_eq!(engine.unlock(1), Err(Rejection::NotLocked(1)));
+        engine.apply(row(TransactionType::Close, 3)).unwrap();
+        assert!(engine.unlock(1).is_err());
+        assert_eq!(engine.accounts()[&1].status, Status::Closed);
+    }
+
+    #[test]
+    fn negative_balances_can_be_rejected_or_clamped() {
+        let data = "type,client,tx,amount
+deposit,1,1,10.0
+withdrawal,1,2,4.0
+dispute,1,1,
+withdrawal,1,3,1.0
+deposit,2,4,1.0
+withdrawal,2,5,3.0";
+        let process = |behavior| {
+            let mut engine = Engine::new();
+            engine.set_negative_balance_behavior(behavior);
+            let options = ParseOptions {
+                mode: ParseMode::Collecting,
+                ..ParseOptions::default()
+            };
+            let mut reader = csv::Reader::from_reader(data.as_bytes());
+            process_records(&mut reader, &options, engine, |_| {}).unwrap()
+        };
+        let balances = |report: &crate::ProcessReport, client| {
+            let account = &report.accounts[&client];
+            (account.available, account.held)
+        };
+
+        let report = process(NegativeBalanceBehavior::Allow);
+        assert_eq!(balances(&report, 1), (Decimal::new(-5, 0), Decimal::TEN));
+        assert_eq!(balances(&report, 2), (Decimal::new(-2, 0), Decimal::ZERO));
+        assert!(report.errors.is_empty());
+
+        let report = process(NegativeBalanceBehavior::Reject);
+        assert_eq!(balances(&report, 1), (Decimal::new(5, 0), Decimal::ZERO));
+        assert_eq!(balances(&report, 2), (Decimal::ONE, Decimal::ZERO));
+        let errors: Vec<_> = report
+            .errors
+            .iter()
+            .map(|error| (error.line, error.message.as_str()))
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                (4, "insufficient funds: 6.0 available for 10.0"),
+                (7, "insufficient funds: 1.0 available for 3.0"),
+            ]
+        );
+
+        // The dispute holds the 6 left, the next withdrawal finds nothing
+        let report = process(NegativeBalanceBehavior::ClampToZero);
+        assert_eq!(balances(&report, 1), (Decimal::ZERO, Decimal::new(6, 0)));
+        assert_eq!(balances(&report, 2), (Decimal::ZERO, Decimal::ZERO));
+        let mut engine = Engine::new();
+        engine.restore(report.into_state());
+        assert_eq!(engine.transaction(5).unwrap().amount, Decimal::ONE);
+    }
+
+    #[test]
+    fn negative_amounts_are_rejected() {
+        for behavior in [
+            NegativeBalanceBehavior::Reject,
+            NegativeBalanceBehavior::ClampToZero,
+            NegativeBalanceBehavior::Allow,
+        ] {
+            let mut engine = Engine::new();
+            engine.set_negative_balance_behavior(behavior);
+            let row = |transaction_type, tx, amount| Transaction {
+                transaction_type,
+                client: 1,
+                tx,
+                amount: Some(Decimal::from(amount)),
+            };
+            engine.apply(row(TransactionType::Deposit, 1, 10)).unwrap();
+            assert_eq!(
+                engine.apply(row(TransactionType::Deposit, 2, -50)),
+                Err(Rejection::NegativeAmount(Decimal::from(-50)))
+            );
+            let withdrawal = engine.apply(row(TransactionType::Withdrawal, 3, -100));
+            assert_eq!(withdrawal.unwrap_err().code(), ErrorCode::BadAmount);
+            assert_eq!(
+                engine.accounts()[&1].available,
+                Decimal::TEN,
+                "{behavior:?}"
+            );
+            // Neither is kept, the tx ids can still be used
+            engine
+                .apply(row(TransactionType::Withdrawal, 3, 1))
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn out_of_order_disputes_are_retried() {
+        let data = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,5.0
+chargeback,1,1,
+resolve,1,2,
+dispute,1,1,
+resolve,1,3,";
+        let process = |retry| {
+            let mut engine = Engine::new();
+            engine.set_retry_out_of_order(retry);
+            let mut reader = csv::Reader::from_reader(data.as_bytes());
+            process_records(&mut reader, &ParseOptions::default(), engine, |_| {}).unwrap()
+        };
+        let report = process(false);
+        assert_eq!(report.accounts[&1].held, Decimal::TEN);
+        assert!(!report.accounts[&1].locked());
+
+        // The chargeback applies once the dispute arrives, the resolve of tx 2 never does
+        let report = process(true);
+        assert_eq!(report.accounts[&1].held, Decimal::ZERO);
+        assert!(report.accounts[&1].locked());
+
+        let mut engine = Engine::new();
+        engine.set_retry_out_of_order(true);
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        for row in reader.deserialize::<Transaction>() {
+            engine.apply(row.unwrap()).unwrap();
+        }
+        let unapplied: Vec<_> = engine
+            .retry_waiting()
+            .iter()
+            .map(|transaction| (transaction.transaction_type, transaction.tx))
+            .collect();
+        assert_eq!(unapplied, [(TransactionType::Resolve, 2)]);
+        assert!(engine.retry_waiting().is_empty());
+
+        // A second dispute never applies, it isn't kept to hold the funds again after the resolve
+        let data = "type,client,tx,amount
+deposit,1,1,10.0
+dispute,1,1,
+dispute,1,1,
+resolve,1,1,";
+        for retry in [false, true] {
+            let mut engine = Engine::new();
+            engine.set_retry_out_of_order(retry);
+            let mut reader = csv::Reader::from_reader(data.as_bytes());
+            let report =
+                process_records(&mut reader, &ParseOptions::default(), engine, |_| {}).unwrap();
+            assert_eq!(report.accounts[&1].held, Decimal::ZERO);
+            assert_eq!(report.accounts[&1].available, Decimal::TEN);
+        }
+    }
+
+    #[test]
+    fn deferred_and_parked_rows_wait_within_the_eviction() {
+        let data = "type,client,tx,amount
+dispute,1,7,
+deposit,1,1,1.0
+resolve,1,1,
+dispute,1,8,
+dispute,1,9,
+deposit,1,7,7.0
+deposit,1,8,8.0";
+        let mut engine = Engine::new();
+        engine.set_unknown_reference(UnknownReference::Defer);
+        engine.set_retry_out_of_order(true);
+        engine.set_eviction(Eviction::KeepLast(3));
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        for row in reader.deserialize::<Transaction>() {
+            engine.apply(row.unwrap()).unwrap();
+        }
+        // Four rows waited at once, the dispute of tx 7 went first
+        assert_eq!(engine.lost_to_eviction(), 1);
+        assert_eq!(engine.accounts()[&1].held, Decimal::from(8));
+        let unapplied: Vec<_> = engine
+            .retry_waiting()
+            .iter()
+            .map(|transaction| (transaction.transaction_type, transaction.tx))
+            .collect();
+        assert_eq!(
+            unapplied,
+            [(TransactionType::Resolve, 1), (TransactionType::Dispute, 9)]
+        );
+    }
+
+    #[test]
+    fn unknown_references_can_be_rejected() {
+        let data = "type,client,tx,amount
+deposit,1,1,1.0
+dispute,1,2,
+resolve,1,1,";
+        let process = |unknown_reference, mode| {
+            let mut engine = Engine::new();
+            engine.set_unknown_reference(unknown_reference);
+            let options = ParseOptions {
+                mode,
+                ..ParseOptions::default()
+            };
+            let mut reader = csv::Reader::from_reader(data.as_bytes());
+            process_transactions_with_engine(&mut reader, &options, engine)
+        };
+
+        let report = process(UnknownReference::Ignore, ParseMode::Collecting).unwrap();
+        assert!(report.errors.is_empty());
+
+        let report = process(UnknownReference::Reject, ParseMode::Collecting).unwrap();
+        let lines: Vec<u64> = report.errors.iter().map(|error| error.line).collect();
+        assert_eq!(lines, [3]);
+        assert_eq!(report.errors[0].message, "unknown tx id 2");
+
+        let error = process(UnknownReference::Reject, ParseMode::Strict).unwrap_err();
+        assert_eq!(error.line, 3);
+    }
+
+    #[test]
+    fn evicted_transactions_lose_their_disputes() {
+        let data = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,2,2.0
+dispute,1,2,
+deposit,1,3,3.0
+deposit,1,4,4.0
+dispute,1,1,
+resolve,1,2,
+deposit,1,1,5.0
+dispute,1,9,";
+        let mut engine = Engine::new();
+        engine.set_eviction(Eviction::KeepLast(2));
+        engine.set_unknown_reference(UnknownReference::Reject);
+        let options = ParseOptions {
+            mode: ParseMode::Collecting,
+            ..ParseOptions::default()
+        };
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let report = process_transactions_with_engine(&mut reader, &options, engine).unwrap();
+        // 2 was under dispute when it would have gone, 1 went instead
+        let mut kept: Vec<_> = report.transactions.iter().map(|(tx, _)| tx).collect();
+        kept.sort_unstable();
+        assert_eq!(kept, [2, 4]);
+        assert_eq!(report.lost_to_eviction, 1);
+        assert_eq!(report.accounts[&1].available, Decimal::from(10));
+        let messages: Vec<_> = report.errors.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(messages, ["duplicate tx id 1", "unknown tx id 9"]);
+    }
+
+    #[test]
+    fn tx_names_stay_within_the_transactions_kept() {
+        let data = "type,client,tx,amount
+deposit,1,a,1.0
+deposit,1,b,2.0
+dispute,1,b,
+dispute,1,x,
+withdrawal,1,c,100.0
+deposit,1,d,4.0
+deposit,1,e,5.0
+dispute,1,y,";
+        let names = IdNames::new();
+        names.extend([(text_id("old"), "old".to_string())]);
+        let mut engine = Engine::new();
+        engine.set_eviction(Eviction::KeepLast(2));
+        engine.set_negative_balance_behavior(NegativeBalanceBehavior::Reject);
+        engine.set_unknown_reference(UnknownReference::Defer);
+        engine.set_tx_names(names.clone());
+        let options = ParseOptions {
+            mode: ParseMode::Collecting,
+            tx_ids: IdFormat::Text(names.clone()),
+            ..ParseOptions::default()
+        };
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let report = process_transactions_with_engine(&mut reader, &options, engine).unwrap();
+        // a and d were evicted past the disputed b, c rejected, x and y never applied
+        assert_eq!(report.transactions.len(), 2);
+        let mut kept: Vec<_> = names.to_map().into_values().collect();
+        kept.sort_unstable();
+        assert_eq!(kept, ["b", "e"]);
+    }
+
+    #[test]
+    fn repeated_disputes_hold_once() {
+        let data = "type,client,tx,amount
+deposit,1,1,2.0
+dispute,1,1,
+dispute,1,1,
+resolve,1,1,
+dispute,1,1,";
+        let process = |report_repeated| {
+            let mut engine = Engine::new();
+            engine.set_report_repeated_disputes(report_repeated);
+            let options = ParseOptions {
+                mode: ParseMode::Collecting,
+                ..ParseOptions::default()
+            };
+            let mut reader = csv::Reader::from_reader(data.as_bytes());
+            process_transactions_with_engine(&mut reader, &options, engine).unwrap()
+        };
+        let report = process(false);
+        let account = &report.accounts[&1];
+        assert_eq!(account.held, Decimal::new(2, 0));
+        assert_eq!(account.available, Decimal::zero());
+        assert!(report.errors.is_empty());
+
+        let report = process(true);
+        assert_eq!(report.accounts[&1].held, Decimal::new(2, 0));
+        let lines: Vec<u64> = report.errors.iter().map(|error| error.line).collect();
+        assert_eq!(lines, [4]);
+        assert_eq!(report.errors[0].message, "tx id 1 is already under dispute");
+    }
+
+    #[test]
+    fn nothing_moves_after_a_chargeback() {
+        let data = "type,client,tx,amount
+deposit,1,1,2.0
+deposit,1,2,1.0
+dispute,1,1,
+chargeback,1,1,
+resolve,1,1,
+dispute,1,1,
+resolve,1,1,";
+        let options = ParseOptions {
+            mode: ParseMode::Collecting,
+            ..ParseOptions::default()
+        };
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let report =
+            process_transactions_with_engine(&mut reader, &options, Engine::new()).unwrap();
+        let account = &report.accounts[&1];
+        assert_eq!(account.held, Decimal::zero());
+        assert_eq!(account.total(), Decimal::new(1, 0));
+        let lines: Vec<u64> = report.errors.iter().map(|error| error.line).collect();
+        assert_eq!(lines, [6, 7, 8]);
+        assert_eq!(report.errors[0].message, "tx id 1 was charged back");
+    }
+
+    #[test]
+    fn accounts_sorted_by_client() {
+        let mut engine = Engine::new();
+        for (tx, client) in [3, 1, 2, 1].into_iter().enumerate() {
+            let deposit = Transaction {
+                transaction_type: TransactionType::Deposit,
+                client,
+                tx: tx as crate::model::TxId,
+                amount: Some(Decimal::new(1, 0)),
+            };
+            engine.apply(deposit).unwrap();
+        }
+        let clients: Vec<crate::model::ClientId> =
+            engine.accounts_sorted().iter().map(|a| a.client).collect();
+        assert_eq!(clients, [1, 2, 3]);
+    }
+
+    #[test]
+    fn resolve_without_dispute_is_ignored() {
+        let data = "type,client,tx,amount
+deposit,1,1,1.0
+resolve,1,1,
+chargeback,1,1,";
+        let accounts = process_transactions(&mut csv::Reader::from_reader(data.as_bytes()));
+        let account = accounts.get(&1).unwrap();
+        assert_eq!(account.available, Decimal::new(1, 0));
+        assert_eq!(account.held, Decimal::zero());
+        assert!(!account.locked());
+    }
+
+    #[test]
+    fn dispute_of_other_clients_transaction_is_ignored() {
+        let data = "type,client,tx,amount
+deposit,1,1,1.0
+dispute,2,1,";
+        let accounts = process_transactions(&mut csv::Reader::from_reader(data.as_bytes()));
+        assert_eq!(accounts.get(&1).unwrap().held, Decimal::zero());
+        assert_eq!(accounts.get(&2).unwrap().held, Decimal::zero());
+    }
+
+    #[test]
+    fn overflowing_total_is_rejected() {
+        let data = "type,client,tx,amount
+deposit,1,1,79228162514264337593543950335
+dispute,1,1,
+deposit,1,2,1
+dispute,1,2,";
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let options = ParseOptions {
+            mode: ParseMode::Collecting,
+            ..ParseOptions::default()
+        };
+        let report =
+            process_transactions_with_engine(&mut reader, &options, Engine::new()).unwrap();
+        let account = &report.accounts[&1];
+        assert_eq!(account.available, Decimal::zero());
+        assert_eq!(account.total(), Decimal::MAX);
+        // The rejected deposit can't be disputed either
+        let lines: Vec<u64> = report.errors.iter().map(|error| error.line).collect();
+        assert_eq!(lines, [4]);
+        assert_eq!(report.errors[0].message, "balances would overflow");
+    }
+
+    #[test]
+    fn duplicate_tx_id_is_ignored() {
+        let data = "type,client,tx,amount
+deposit,1,1,1.0
+dispute,1,1,
+deposit,1,1,5.0
+chargeback,1,1,";
+        let accounts = process_transactions(&mut csv::Reader::from_reader(data.as_bytes()));
+        let account = accounts.get(&1).unwrap();
+        assert_eq!(account.held, Decimal::zero());
+        assert_eq!(account.total(), Decimal::zero());
+    }
+}
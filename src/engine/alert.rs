@@ -0,0 +1,72 @@
+//! Observers raising alerts about accounts while transactions are applied.
+use crate::engine::EngineObserver;
+use crate::model::{AccountUpdate, ClientId};
+use rust_decimal::Decimal;
+use std::collections::HashSet;
+
+/// Calls back when the available funds of an account drop below a limit, which usually means
+/// a bad upstream file. Fires once per drop, the account has to get back to the limit
+/// before it fires again.
+pub struct BalanceAlert<F> {
+    limit: Decimal,
+    callback: F,
+    // Clients currently below the limit
+    below: HashSet<ClientId>,
+}
+
+impl<F: FnMut(&AccountUpdate)> BalanceAlert<F> {
+    /// Calls `callback` with the update that took an account below `limit`
+    pub fn new(limit: Decimal, callback: F) -> Self {
+        BalanceAlert {
+            limit,
+            callback,
+            below: HashSet::new(),
+        }
+    }
+}
+
+impl<F: FnMut(&AccountUpdate)> EngineObserver for BalanceAlert<F> {
+    fn on_applied(&mut self, update: &AccountUpdate) {
+        if update.available >= self.limit {
+            self.below.remove(&update.client);
+        } else if self.below.insert(update.client) {
+            (self.callback)(update);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::alert::BalanceAlert;
+    use crate::model::{Transaction, TransactionType};
+    use crate::Engine;
+    use rust_decimal::Decimal;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn fires_once_per_drop_below_the_limit() {
+        let alerts = Arc::new(Mutex::new(vec![]));
+        let mut engine = Engine::new();
+        let seen = alerts.clone();
+        engine.add_observer(BalanceAlert::new(Decimal::ZERO, move |update| {
+            seen.lock().unwrap().push(update.tx)
+        }));
+        let rows = [
+            (TransactionType::Deposit, 1, 5),
+            (TransactionType::Withdrawal, 2, 7),
+            (TransactionType::Withdrawal, 3, 1),
+            (TransactionType::Deposit, 4, 10),
+            (TransactionType::Withdrawal, 5, 9),
+        ];
+        for (transaction_type, tx, amount) in rows {
+            let transaction = Transaction {
+                transaction_type,
+                client: 1,
+                tx,
+                amount: Some(Decimal::new(amount, 0)),
+            };
+            engine.apply(transaction).unwrap();
+        }
+        assert_eq!(*alerts.lock().unwrap(), [2, 5]);
+    }
+}
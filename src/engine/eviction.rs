@@ -0,0 +1,243 @@
+//! Capping how many deposits and withdrawals the engine keeps for disputes to reference.
+//!
+//! Every deposit and withdrawal is kept by default, so memory grows with the input. An
+//! [`Eviction`] drops the oldest once there are too many or they are too old, at the price
+//! of the disputes that come later: a Dispute, Resolve or Chargeback of an evicted
+//! transaction is ignored and counted, see [`crate::Engine::lost_to_eviction`], and
+//! a ChargebackReversal of one is rejected. Transactions under dispute are never evicted,
+//! their held funds can always be released or charged back.
+//!
+//! Rows waiting for another row of their transaction are bounded the same way, see
+//! [`crate::engine::waiting`].
+//!
+//! Evicted tx ids are remembered as ranges, a handful of them for ascending tx ids,
+//! to tell their disputes from those of unknown transactions and to still reject
+//! a deposit or withdrawal reusing one as a duplicate.
+use crate::engine::policy::DisputeState;
+use crate::engine::waiting::Waiting;
+use crate::engine::TransactionIndex;
+use crate::model::TxId;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+/// Which deposits and withdrawals the engine keeps, see [`crate::Engine::set_eviction`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Eviction {
+    /// Keep them all
+    #[default]
+    Never,
+    /// Keep the most recent this many, besides those under dispute
+    KeepLast(usize),
+    /// Keep those added within this long of [`crate::Engine::set_time`], e.g. the time
+    /// the card scheme allows for disputes. Transactions added before the engine knew
+    /// the time, or restored from a state, count as added when it first learns it.
+    Window(Duration),
+}
+
+/// Order the transactions were added in and what was evicted
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Evictor {
+    eviction: Eviction,
+    // Tx ids in the order they were added, with the time they were added at if known
+    order: VecDeque<(Option<i64>, TxId)>,
+    // Evicted tx ids, first to last of each range
+    evicted: BTreeMap<TxId, TxId>,
+    evicted_count: u64,
+    lost: u64,
+}
+
+impl Evictor {
+    /// Evicts by `eviction` from now on, starting with the transactions of `index`
+    /// in tx id order
+    pub(crate) fn set(&mut self, eviction: Eviction, index: &TransactionIndex) {
+        self.eviction = eviction;
+        self.restart(index);
+    }
+
+    /// Forgets the order of the transactions for those of `index`, e.g. a restored state
+    pub(crate) fn restart(&mut self, index: &TransactionIndex) {
+        self.order.clear();
+        if self.eviction == Eviction::Never {
+            return;
+        }
+        let mut txs: Vec<TxId> = index.iter().map(|(tx, _)| tx).collect();
+        txs.sort_unstable();
+        self.order.extend(txs.into_iter().map(|tx| (None, tx)));
+    }
+
+    /// Notes that `tx` was added to the index at `now`
+    pub(crate) fn added(&mut self, tx: TxId, now: Option<i64>) {
+        if self.eviction != Eviction::Never {
+            self.order.push_back((now, tx));
+        }
+    }
+
+    /// Removes from `index` what falls out of the eviction as of `now`, calling `evicted`
+    /// with the tx id of each
+    pub(crate) fn evict(
+        &mut self,
+        index: &mut TransactionIndex,
+        now: Option<i64>,
+        mut evicted: impl FnMut(TxId),
+    ) {
+        let oldest = match (self.eviction, now) {
+            (Eviction::Window(window), Some(now)) => {
+                for (added, _) in &mut self.order {
+                    if added.is_some() {
+                        break;
+                    }
+                    *added = Some(now);
+                }
+                Some(now.saturating_sub(i64::try_from(window.as_secs()).unwrap_or(i64::MAX)))
+            }
+            (Eviction::KeepLast(_), _) => None,
+            _ => return,
+        };
+        // Every transaction is looked at once at most, those under dispute go to the back
+        for _ in 0..self.order.len() {
+            let Some(&(added, tx)) = self.order.front() else {
+                break;
+            };
+            let expired = match self.eviction {
+                Eviction::KeepLast(keep) => index.len() > keep,
+                _ => added < oldest,
+            };
+            if !expired {
+                break;
+            }
+            self.order.pop_front();
+            match index.get(tx).map(|record| record.state) {
+                Some(DisputeState::Disputed) => self.order.push_back((now, tx)),
+                Some(_) => {
+                    index.remove(tx);
+                    self.mark(tx);
+                    evicted(tx);
+                }
+                // Dropped from the index some other way
+                None => {}
+            }
+        }
+    }
+
+    /// Drops the rows waiting longest from `waiting` while there are more than the
+    /// transactions kept or they waited longer than the window as of `now`, counting them as
+    /// lost. `dropped` is called with the tx id of each row dropped.
+    pub(crate) fn evict_waiting(
+        &mut self,
+        waiting: &mut Waiting,
+        now: Option<i64>,
+        dropped: impl FnMut(TxId),
+    ) {
+        let dropped = match (self.eviction, now) {
+            (Eviction::KeepLast(keep), _) => waiting.drop_oldest(now, |len, _| len > keep, dropped),
+            (Eviction::Window(window), Some(now)) => {
+                let oldest =
+                    now.saturating_sub(i64::try_from(window.as_secs()).unwrap_or(i64::MAX));
+                waiting.drop_oldest(Some(now), |_, since| since < Some(oldest), dropped)
+            }
+            _ => return,
+        };
+        self.lost += dropped as u64;
+    }
+
+    /// Whether `tx` was evicted
+    pub(crate) fn evicted(&self, tx: TxId) -> bool {
+        self.evicted
+            .range(..=tx)
+            .next_back()
+            .is_some_and(|(_, last)| tx <= *last)
+    }
+
+    /// Counts a transaction referencing the evicted `tx`
+    pub(crate) fn lose(&mut self) {
+        self.lost += 1;
+    }
+
+    /// Whether it evicts at all
+    pub(crate) fn evicts(&self) -> bool {
+        self.eviction != Eviction::Never
+    }
+
+    /// Whether it evicts by the time, see [`Eviction::Window`]
+    pub(crate) fn timed(&self) -> bool {
+        matches!(self.eviction, Eviction::Window(_))
+    }
+
+    pub(crate) fn evicted_count(&self) -> u64 {
+        self.evicted_count
+    }
+
+    pub(crate) fn lost(&self) -> u64 {
+        self.lost
+    }
+
+    fn mark(&mut self, tx: TxId) {
+        self.evicted_count += 1;
+        let before = self
+            .evicted
+            .range(..tx)
+            .next_back()
+            .filter(|(_, last)| last.checked_add(1) == Some(tx))
+            .map(|(first, _)| *first);
+        let after = tx
+            .checked_add(1)
+            .and_then(|next| self.evicted.remove(&next));
+        let first = before.unwrap_or(tx);
+        self.evicted.insert(first, after.unwrap_or(tx));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::policy::DisputedTx;
+    use crate::model::TransactionType;
+    use rust_decimal::Decimal;
+
+    fn index(txs: impl IntoIterator<Item = TxId>) -> TransactionIndex {
+        txs.into_iter()
+            .map(|tx| {
+                let record = DisputedTx {
+                    transaction_type: TransactionType::Deposit,
+                    client: 1,
+                    amount: Decimal::ONE,
+                    held: Decimal::ZERO,
+                    state: DisputeState::Processed,
+                };
+                (tx, record)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn evicted_tx_ids_are_kept_as_ranges() {
+        let mut evictor = Evictor::default();
+        for tx in [5, 7, 6, 1, 2, 10] {
+            evictor.mark(tx);
+        }
+        assert_eq!(
+            evictor.evicted.iter().collect::<Vec<_>>(),
+            [(&1, &2), (&5, &7), (&10, &10)]
+        );
+        assert!([1, 2, 5, 6, 7, 10].iter().all(|tx| evictor.evicted(*tx)));
+        assert!(![0, 3, 4, 8, 9, 11].iter().any(|tx| evictor.evicted(*tx)));
+        assert_eq!(evictor.evicted_count(), 6);
+    }
+
+    #[test]
+    fn keeps_disputed_transactions_past_the_window() {
+        let mut transactions = index(1..=4);
+        transactions.get_mut(2).unwrap().state = DisputeState::Disputed;
+        let mut evictor = Evictor::default();
+        evictor.set(Eviction::Window(Duration::from_secs(10)), &transactions);
+        evictor.evict(&mut transactions, Some(100), |_| {});
+        assert_eq!(transactions.len(), 4);
+        transactions.insert(5, index([5]).remove(5).unwrap());
+        evictor.added(5, Some(105));
+        evictor.evict(&mut transactions, Some(111), |_| {});
+        let kept: Vec<_> = transactions.iter().map(|(tx, _)| tx).collect();
+        assert_eq!(kept.len(), 2);
+        assert!(transactions.contains(2) && transactions.contains(5));
+        assert!(evictor.evicted(1) && evictor.evicted(4) && !evictor.evicted(2));
+    }
+}
@@ -0,0 +1,257 @@
+//! Where the engine keeps the deposits and withdrawals disputes can reference.
+//!
+//! Tx ids mostly come ascending and without large gaps, so records are kept in a flat `Vec`
+//! indexed by their distance from the first tx id seen: no hashing, no per-entry overhead,
+//! and neighbouring transactions next to each other in memory. Ids that would leave the `Vec`
+//! more than half empty, far ahead of the others or before the first, go into a hash map.
+use crate::engine::policy::DisputedTx;
+use crate::engine::BuildHasher;
+use crate::model::TxId;
+use std::collections::HashMap;
+
+/// Free slots the dense part may grow by beyond twice the records it holds
+const SLACK: usize = 1024;
+
+/// Deposits and withdrawals by tx id, what Dispute, Resolve and Chargeback reference.
+/// Records are [`DisputedTx`] unless an engine keeps its own, see [`crate::engine::minor`].
+#[derive(Debug, Clone)]
+pub struct TransactionIndex<T = DisputedTx> {
+    // Tx id of dense[0]
+    base: TxId,
+    dense: Vec<Option<T>>,
+    // Records in dense
+    dense_len: usize,
+    // Slots at the start of dense known to be empty, e.g. after evictions
+    lead: usize,
+    // Everything too far from the dense tx ids
+    sparse: HashMap<TxId, T, BuildHasher>,
+}
+
+impl<T> Default for TransactionIndex<T> {
+    fn default() -> Self {
+        TransactionIndex {
+            base: 0,
+            dense: vec![],
+            dense_len: 0,
+            lead: 0,
+            sparse: HashMap::default(),
+        }
+    }
+}
+
+impl<T> TransactionIndex<T> {
+    pub fn new() -> Self {
+        TransactionIndex::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.dense_len + self.sparse.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, tx: TxId) -> Option<&T> {
+        match self.slot(tx).and_then(|slot| self.dense[slot].as_ref()) {
+            Some(record) => Some(record),
+            None => self.sparse.get(&tx),
+        }
+    }
+
+    pub fn get_mut(&mut self, tx: TxId) -> Option<&mut T> {
+        match self.slot(tx) {
+            Some(slot) if self.dense[slot].is_some() => self.dense[slot].as_mut(),
+            _ => self.sparse.get_mut(&tx),
+        }
+    }
+
+    pub fn contains(&self, tx: TxId) -> bool {
+        self.get(tx).is_some()
+    }
+
+    /// Adds or replaces the record of `tx`, returning the one it replaced
+    pub fn insert(&mut self, tx: TxId, record: T) -> Option<T> {
+        if self.is_empty() {
+            self.base = tx;
+        }
+        let Some(slot) = self.slot(tx).or_else(|| self.grow(tx)) else {
+            return self.sparse.insert(tx, record);
+        };
+        // Inserted into the hash map before the dense part grew over it
+        let replaced = self.dense[slot]
+            .replace(record)
+            .or_else(|| self.sparse.remove(&tx));
+        if self.dense[slot].is_some() && replaced.is_none() {
+            self.dense_len += 1;
+        }
+        self.lead = self.lead.min(slot);
+        replaced
+    }
+
+    /// Removes the record of `tx`. The dense part drops its empty start once that is
+    /// more than half of it, so removing the oldest transactions gives the memory back.
+    pub fn remove(&mut self, tx: TxId) -> Option<T> {
+        let Some(slot) = self.slot(tx).filter(|slot| self.dense[*slot].is_some()) else {
+            return self.sparse.remove(&tx);
+        };
+        let removed = self.dense[slot].take();
+        self.dense_len -= 1;
+        while self.lead < self.dense.len() && self.dense[self.lead].is_none() {
+            self.lead += 1;
+        }
+        if self.lead > self.dense.len() / 2 {
+            self.dense.drain(..self.lead);
+            self.base += self.lead as TxId;
+            self.lead = 0;
+        }
+        removed
+    }
+
+    /// Keeps only the records `keep` returns true for
+    pub fn retain(&mut self, mut keep: impl FnMut(TxId, &T) -> bool) {
+        let base = self.base;
+        for (slot, record) in self.dense.iter_mut().enumerate() {
+            if record
+                .as_ref()
+                .is_some_and(|record| !keep(base + slot as TxId, record))
+            {
+                *record = None;
+                self.dense_len -= 1;
+            }
+        }
+        self.sparse.retain(|tx, record| keep(*tx, record));
+    }
+
+    /// All records, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (TxId, &T)> {
+        let base = self.base;
+        self.dense
+            .iter()
+            .enumerate()
+            .filter_map(move |(slot, record)| Some((base + slot as TxId, record.as_ref()?)))
+            .chain(self.sparse.iter().map(|(tx, record)| (*tx, record)))
+    }
+
+    /// Position of `tx` in the dense part, if it is there
+    fn slot(&self, tx: TxId) -> Option<usize> {
+        let slot = usize::try_from(tx.checked_sub(self.base)?).ok()?;
+        (slot < self.dense.len()).then_some(slot)
+    }
+
+    /// Grows the dense part up to `tx` unless that leaves it more than half empty
+    fn grow(&mut self, tx: TxId) -> Option<usize> {
+        let slot = usize::try_from(tx.checked_sub(self.base)?).ok()?;
+        if slot > 2 * self.dense_len + SLACK {
+            return None;
+        }
+        self.dense.resize_with(slot + 1, || None);
+        Some(slot)
+    }
+}
+
+impl<T: PartialEq> PartialEq for TransactionIndex<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len()
+            && self
+                .iter()
+                .all(|(tx, record)| other.get(tx) == Some(record))
+    }
+}
+
+impl<T: Eq> Eq for TransactionIndex<T> {}
+
+impl<T> Extend<(TxId, T)> for TransactionIndex<T> {
+    fn extend<I: IntoIterator<Item = (TxId, T)>>(&mut self, records: I) {
+        for (tx, record) in records {
+            self.insert(tx, record);
+        }
+    }
+}
+
+impl<T> FromIterator<(TxId, T)> for TransactionIndex<T> {
+    fn from_iter<I: IntoIterator<Item = (TxId, T)>>(records: I) -> Self {
+        let mut index = TransactionIndex::new();
+        index.extend(records);
+        index
+    }
+}
+
+impl<T: 'static> IntoIterator for TransactionIndex<T> {
+    type Item = (TxId, T);
+    type IntoIter = Box<dyn Iterator<Item = (TxId, T)>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        let base = self.base;
+        let dense = self
+            .dense
+            .into_iter()
+            .enumerate()
+            .filter_map(move |(slot, record)| Some((base + slot as TxId, record?)));
+        Box::new(dense.chain(self.sparse))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::policy::DisputeState;
+    use crate::model::{ClientId, TransactionType};
+    use rust_decimal::Decimal;
+
+    fn record(client: ClientId) -> DisputedTx {
+        DisputedTx {
+            transaction_type: TransactionType::Deposit,
+            client,
+            amount: Decimal::ONE,
+            held: Decimal::ZERO,
+            state: DisputeState::Processed,
+        }
+    }
+
+    #[test]
+    fn dense_and_sparse_tx_ids_behave_the_same() {
+        let mut index = TransactionIndex::new();
+        let ids = [100, 101, 103, 99, 1_000_000, 102, 5000, 1100, 1_000_000];
+        for (i, tx) in ids.into_iter().enumerate() {
+            let replaced = index.insert(tx, record(i as ClientId));
+            assert_eq!(replaced.is_some(), tx == 1_000_000 && i == 8);
+        }
+        assert_eq!(index.len(), 8);
+        // 99 came before the first id and 5000 too far ahead of the others, 1100 fit after all
+        assert_eq!(index.sparse.len(), 3);
+        assert_eq!(index.get(99), Some(&record(3)));
+        assert_eq!(index.get(1100), Some(&record(7)));
+        assert_eq!(index.get(1_000_000), Some(&record(8)));
+        assert_eq!(index.get(104), None);
+        assert!(!index.contains(98));
+
+        index.get_mut(5000).unwrap().state = DisputeState::Disputed;
+        index.get_mut(101).unwrap().state = DisputeState::Disputed;
+        index.retain(|_, record| record.state == DisputeState::Disputed);
+        let mut kept: Vec<_> = index.iter().map(|(tx, _)| tx).collect();
+        kept.sort_unstable();
+        assert_eq!(kept, [101, 5000]);
+        assert_eq!(index.len(), 2);
+        let removed = index.remove(5000).unwrap();
+        assert_eq!(index.remove(5000), None);
+        index.insert(5000, removed);
+
+        let copy: TransactionIndex = index.clone().into_iter().collect();
+        assert_eq!(copy, index);
+    }
+
+    #[test]
+    fn removing_the_oldest_shrinks_the_dense_part() {
+        let mut index: TransactionIndex = (0..3000).map(|tx| (tx, record(1))).collect();
+        for tx in 0..2000 {
+            assert!(index.remove(tx).is_some());
+        }
+        assert!(index.dense.len() <= 2000);
+        assert_eq!(index.len(), 1000);
+        assert_eq!(index.get(2000), Some(&record(1)));
+        assert_eq!(index.get(1999), None);
+        index.insert(3000, record(2));
+        assert_eq!(index.sparse.len(), 0);
+    }
+}
@@ -0,0 +1,279 @@
+//! Checking the engine's invariants after every applied transaction, for debugging.
+use crate::engine::{AccountMap, EngineObserver};
+use crate::model::{Account, AccountUpdate, ClientId, TransactionType};
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::fmt;
+
+/// An update that broke an invariant, with the account as it was before
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// Which invariant
+    pub message: String,
+    pub update: AccountUpdate,
+    pub before: Account,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (update, before) = (&self.update, &self.before);
+        write!(
+            f,
+            "{} by {} tx {} of {} for client {}: available {} held {} locked {} before, \
+             available {} held {} locked {} after",
+            self.message,
+            update.transaction_type,
+            update.tx,
+            update.amount,
+            update.client,
+            before.available,
+            before.held,
+            before.locked(),
+            update.available,
+            update.held,
+            update.locked()
+        )
+    }
+}
+
+/// Checks every update against the account before it and calls back on a violation:
+/// held funds never go negative, a locked account stays locked unless a chargeback reversal,
+/// an unlock or a reinstatement unlocks it and only a chargeback, a review or a closure locks,
+/// the balances of a locked account don't change but by a chargeback reversal, and funds are
+/// conserved: held funds move by exactly the amount of the transaction, and available funds
+/// too, except that the dispute policy may take fees from them. A chargeback takes only the
+/// held funds.
+///
+/// Clients are expected to start out without funds, see [`InvariantCheck::with_accounts`]
+/// for an engine that was restored.
+pub struct InvariantCheck<F> {
+    accounts: HashMap<ClientId, Account>,
+    on_violation: F,
+}
+
+impl<F: FnMut(&Violation)> InvariantCheck<F> {
+    pub fn new(on_violation: F) -> Self {
+        InvariantCheck {
+            accounts: HashMap::new(),
+            on_violation,
+        }
+    }
+
+    /// Starts out from `accounts` instead of empty ones
+    pub fn with_accounts(mut self, accounts: &AccountMap) -> Self {
+        self.accounts.extend(
+            accounts
+                .iter()
+                .map(|(client, account)| (*client, account.clone())),
+        );
+        self
+    }
+}
+
+/// What `update` should have moved: available and held funds
+fn expected_moves(update: &AccountUpdate) -> (Decimal, Decimal) {
+    let amount = update.amount;
+    match update.transaction_type {
+        TransactionType::Deposit => (amount, Decimal::ZERO),
+        TransactionType::Withdrawal => (-amount, Decimal::ZERO),
+        TransactionType::Dispute => (-amount, amount),
+        TransactionType::Resolve => (amount, -amount),
+        TransactionType::Chargeback => (Decimal::ZERO, -amount),
+        TransactionType::ChargebackReversal => (amount, Decimal::ZERO),
+        TransactionType::Unlock
+        | TransactionType::Review
+        | TransactionType::Reinstate
+        | TransactionType::Close => (Decimal::ZERO, Decimal::ZERO),
+    }
+}
+
+fn violated(update: &AccountUpdate, before: &Account) -> Option<String> {
+    let (available, held) = expected_moves(update);
+    let fees_allowed = matches!(
+        update.transaction_type,
+        TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
+    );
+    let moved = update.available - before.available;
+    let reopening = matches!(
+        update.transaction_type,
+        TransactionType::ChargebackReversal | TransactionType::Unlock | TransactionType::Reinstate
+    );
+    if update.held < Decimal::ZERO {
+        Some("held funds went negative".to_string())
+    } else if before.locked() && !update.locked() && !reopening {
+        Some("a locked account was unlocked".to_string())
+    } else if before.locked()
+        && !reopening
+        && (update.available != before.available || update.held != before.held)
+    {
+        Some("the balances of a locked account changed".to_string())
+    } else if update.locked()
+        && !before.locked()
+        && !matches!(
+            update.transaction_type,
+            TransactionType::Chargeback | TransactionType::Review | TransactionType::Close
+        )
+    {
+        Some(
+            "an account was locked by something else than a chargeback, review or closure"
+                .to_string(),
+        )
+    } else if update.held - before.held != held {
+        Some(format!(
+            "held funds moved by {} instead of {}",
+            update.held - before.held,
+            held
+        ))
+    } else if moved != available && !(fees_allowed && moved < available) {
+        Some(format!(
+            "available funds moved by {} instead of {}",
+            moved, available
+        ))
+    } else {
+        None
+    }
+}
+
+impl<F: FnMut(&Violation)> EngineObserver for InvariantCheck<F> {
+    fn on_applied(&mut self, update: &AccountUpdate) {
+        let account = self
+            .accounts
+            .entry(update.client)
+            .or_insert_with(|| Account::new(update.client));
+        if let Some(message) = violated(update, account) {
+            let violation = Violation {
+                message,
+                update: *update,
+                before: account.clone(),
+            };
+            (self.on_violation)(&violation);
+        }
+        account.available = update.available;
+        account.held = update.held;
+        account.status = update.status;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::invariants::{InvariantCheck, Violation};
+    use crate::engine::policy::{DisputeAction, DisputePolicy, DisputedTx, StandardDisputePolicy};
+    use crate::model::{Account, AccountUpdate, Status, Transaction, TransactionType, TxId};
+    use crate::{Engine, EngineObserver};
+    use rust_decimal::Decimal;
+    use std::sync::{Arc, Mutex};
+
+    /// Pays a fee to the client instead of charging it
+    struct NegativeFee;
+
+    impl DisputePolicy for NegativeFee {
+        fn decide(
+            &mut self,
+            transaction: &Transaction,
+            referenced: &DisputedTx,
+        ) -> Option<DisputeAction> {
+            let action = StandardDisputePolicy.decide(transaction, referenced)?;
+            Some(DisputeAction {
+                fee: -Decimal::ONE,
+                ..action
+            })
+        }
+    }
+
+    fn run(engine: &mut Engine, rows: &[(TransactionType, TxId, i64)]) {
+        for &(transaction_type, tx, amount) in rows {
+            let _ = engine.apply(Transaction {
+                transaction_type,
+                client: 1,
+                tx,
+                amount: Some(Decimal::new(amount, 0)).filter(|_| amount > 0),
+            });
+        }
+    }
+
+    #[test]
+    fn the_standard_engine_keeps_its_invariants() {
+        let mut engine = Engine::new();
+        engine.add_observer(InvariantCheck::new(|violation: &Violation| {
+            panic!("{}", violation)
+        }));
+        run(
+            &mut engine,
+            &[
+                (TransactionType::Deposit, 1, 10),
+                (TransactionType::Withdrawal, 2, 15),
+                (TransactionType::Dispute, 1, 0),
+                (TransactionType::Resolve, 1, 0),
+                (TransactionType::Dispute, 1, 0),
+                (TransactionType::Chargeback, 1, 0),
+            ],
+        );
+    }
+
+    #[test]
+    fn violations_are_reported_with_the_account_before() {
+        let violations = Arc::new(Mutex::new(vec![]));
+        let seen = violations.clone();
+        let mut engine = Engine::new();
+        engine.set_dispute_policy(NegativeFee);
+        engine.add_observer(InvariantCheck::new(move |violation: &Violation| {
+            seen.lock().unwrap().push(violation.clone())
+        }));
+        run(
+            &mut engine,
+            &[
+                (TransactionType::Deposit, 1, 10),
+                (TransactionType::Dispute, 1, 0),
+            ],
+        );
+        let violations = violations.lock().unwrap();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].before.available, Decimal::new(10, 0));
+        assert_eq!(
+            violations[0].to_string(),
+            "available funds moved by -9 instead of -10 by dispute tx 1 of 10 for client 1: \
+             available 10 held 0 locked false before, available 1 held 10 locked false after"
+        );
+
+        // The engine applies deposits to frozen accounts unless told to reject them
+        let messages = Arc::new(Mutex::new(vec![]));
+        let seen = messages.clone();
+        let mut engine = Engine::new();
+        engine.add_observer(InvariantCheck::new(move |violation: &Violation| {
+            seen.lock().unwrap().push(violation.message.clone())
+        }));
+        run(
+            &mut engine,
+            &[
+                (TransactionType::Deposit, 1, 10),
+                (TransactionType::Dispute, 1, 0),
+                (TransactionType::Chargeback, 1, 0),
+                (TransactionType::Deposit, 2, 1),
+            ],
+        );
+        assert_eq!(
+            *messages.lock().unwrap(),
+            ["the balances of a locked account changed"]
+        );
+
+        // Unlocking is caught even though the engine never does it
+        let mut check = InvariantCheck::new(|violation: &Violation| {
+            assert_eq!(violation.message, "a locked account was unlocked")
+        });
+        let mut locked = Account::new(1);
+        locked.status = Status::Frozen;
+        let mut accounts = crate::AccountMap::default();
+        accounts.insert(1, locked);
+        check = check.with_accounts(&accounts);
+        check.on_applied(&AccountUpdate {
+            client: 1,
+            tx: 2,
+            transaction_type: TransactionType::Deposit,
+            amount: Decimal::ONE,
+            fee: Decimal::ZERO,
+            available: Decimal::ONE,
+            held: Decimal::ZERO,
+            status: Status::Active,
+        });
+    }
+}
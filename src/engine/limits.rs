@@ -0,0 +1,343 @@
+//! Compliance limits on the amounts of single transactions and of withdrawals over time,
+//! engine-wide or per risk tier of the client.
+use crate::model::{ClientId, TransactionType};
+use rust_decimal::Decimal;
+use std::collections::{HashMap, VecDeque};
+#[cfg(feature = "csv")]
+use std::io;
+#[cfg(feature = "csv")]
+use std::str::FromStr;
+
+/// Largest amounts a deposit or withdrawal may have, see [`crate::Engine::set_amount_limits`].
+/// Transactions over a limit are rejected as [`crate::Rejection::OverLimit`] before they
+/// touch any balance.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AmountLimits {
+    /// Limit of every deposit and withdrawal
+    pub max: Option<Decimal>,
+    pub deposit: Option<Decimal>,
+    pub withdrawal: Option<Decimal>,
+}
+
+impl AmountLimits {
+    /// The limit of `transaction_type`: the lower of its own and [`AmountLimits::max`],
+    /// `None` without limits or for disputes, resolves and chargebacks, which move
+    /// what was already accepted
+    pub fn limit(&self, transaction_type: TransactionType) -> Option<Decimal> {
+        let own = match transaction_type {
+            TransactionType::Deposit => self.deposit,
+            TransactionType::Withdrawal => self.withdrawal,
+            _ => return None,
+        };
+        match (own, self.max) {
+            (Some(own), Some(max)) => Some(own.min(max)),
+            (own, max) => own.or(max),
+        }
+    }
+}
+
+/// Withdrawals of every client over the last 24 hours, to cap what a client withdraws
+/// in any 24 hours, see [`crate::Engine::set_daily_withdrawal_limit`]
+#[derive(Debug, Clone, Default)]
+pub struct WithdrawalWindow {
+    // When and how much, in the order they were applied
+    withdrawals: HashMap<ClientId, VecDeque<(i64, Decimal)>>,
+}
+
+impl WithdrawalWindow {
+    /// Length of the window in seconds
+    pub const PERIOD: i64 = 86_400;
+
+    pub fn new() -> Self {
+        WithdrawalWindow::default()
+    }
+
+    /// What `client` withdrew in the 24 hours up to `now`, in seconds since the Unix epoch.
+    /// Forgets the withdrawals before them.
+    pub fn withdrawn(&mut self, client: ClientId, now: i64) -> Decimal {
+        let Some(withdrawals) = self.withdrawals.get_mut(&client) else {
+            return Decimal::ZERO;
+        };
+        withdrawals.retain(|(at, _)| at.saturating_add(Self::PERIOD) > now);
+        let withdrawn = withdrawals.iter().fold(Decimal::ZERO, |sum, (_, amount)| {
+            sum.saturating_add(*amount)
+        });
+        if withdrawals.is_empty() {
+            self.withdrawals.remove(&client);
+        }
+        withdrawn
+    }
+
+    pub fn record(&mut self, client: ClientId, now: i64, amount: Decimal) {
+        self.withdrawals
+            .entry(client)
+            .or_default()
+            .push_back((now, amount));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.withdrawals.is_empty()
+    }
+}
+
+/// What is known about a client for compliance, see [`RiskTiers`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientAttributes {
+    /// Name of the tier whose limits apply, e.g. `high`
+    pub risk_tier: String,
+    pub kyc_level: Option<String>,
+}
+
+/// Limits of the clients of one risk tier
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TierLimits {
+    pub amounts: AmountLimits,
+    /// Cap of what a client withdraws in any 24 hours
+    pub daily_withdrawal: Option<Decimal>,
+}
+
+/// Attributes of clients and limits of risk tiers, see [`crate::Engine::set_risk_tiers`].
+/// A client gets the tighter of the engine's limits and those of its tier, clients without
+/// attributes or in a tier without limits only the engine's.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RiskTiers {
+    pub clients: HashMap<ClientId, ClientAttributes>,
+    pub tiers: HashMap<String, TierLimits>,
+}
+
+impl RiskTiers {
+    /// Limits of the tier of `client`
+    pub fn limits(&self, client: ClientId) -> Option<&TierLimits> {
+        let attributes = self.clients.get(&client)?;
+        self.tiers.get(&attributes.risk_tier)
+    }
+
+    /// Whether some tier caps daily withdrawals
+    pub fn has_daily_limits(&self) -> bool {
+        self.tiers
+            .values()
+            .any(|limits| limits.daily_withdrawal.is_some())
+    }
+}
+
+/// The lower of two limits, either one if there is only one
+pub(crate) fn tighter(a: Option<Decimal>, b: Option<Decimal>) -> Option<Decimal> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Reads client attributes from CSV with a `client,risk_tier` header and an optional
+/// `kyc_level` column, other columns are ignored. Fails on a row without a client id or tier.
+#[cfg(feature = "csv")]
+pub fn read_client_attributes<R: io::Read>(
+    reader: &mut csv::Reader<R>,
+) -> io::Result<HashMap<ClientId, ClientAttributes>> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let headers = reader.headers().map_err(io::Error::from)?.clone();
+    let index = |name: &str| headers.iter().position(|header| header.trim() == name);
+    let column = |name: &str| index(name).ok_or_else(|| invalid(format!("no {} column", name)));
+    let (client, risk_tier, kyc_level) =
+        (column("client")?, column("risk_tier")?, index("kyc_level"));
+    let mut clients = HashMap::new();
+    let mut record = csv::StringRecord::new();
+    while reader.read_record(&mut record).map_err(io::Error::from)? {
+        let line = record.position().map_or(0, |position| position.line());
+        let field = |index: usize| record.get(index).unwrap_or_default().trim();
+        let (Ok(id), tier) = (ClientId::from_str(field(client)), field(risk_tier)) else {
+            return Err(invalid(format!("line {}: not a client id", line)));
+        };
+        if tier.is_empty() {
+            return Err(invalid(format!("line {}: no risk tier", line)));
+        }
+        let attributes = ClientAttributes {
+            risk_tier: tier.to_string(),
+            kyc_level: kyc_level
+                .map(field)
+                .filter(|level| !level.is_empty())
+                .map(str::to_string),
+        };
+        clients.insert(id, attributes);
+    }
+    Ok(clients)
+}
+
+/// Reads the limits of risk tiers from CSV with a `tier` column and any of `max_amount`,
+/// `max_deposit`, `max_withdrawal` and `daily_withdrawal_limit`, an empty field for no limit:
+/// `high,500,,,1000`
+#[cfg(feature = "csv")]
+pub fn read_tier_limits<R: io::Read>(
+    reader: &mut csv::Reader<R>,
+) -> io::Result<HashMap<String, TierLimits>> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let headers = reader.headers().map_err(io::Error::from)?.clone();
+    let index = |name: &str| headers.iter().position(|header| header.trim() == name);
+    let tier = index("tier").ok_or_else(|| invalid("no tier column".to_string()))?;
+    let limits = [
+        "max_amount",
+        "max_deposit",
+        "max_withdrawal",
+        "daily_withdrawal_limit",
+    ]
+    .map(index);
+    let mut tiers = HashMap::new();
+    let mut record = csv::StringRecord::new();
+    while reader.read_record(&mut record).map_err(io::Error::from)? {
+        let line = record.position().map_or(0, |position| position.line());
+        let field = |index: usize| record.get(index).unwrap_or_default().trim();
+        let mut amounts = [None; 4];
+        for (amount, index) in amounts.iter_mut().zip(limits) {
+            let Some(value) = index.map(field).filter(|value| !value.is_empty()) else {
+                continue;
+            };
+            let limit = Decimal::from_str(value)
+                .map_err(|_| invalid(format!("line {}: {} isn't an amount", line, value)))?;
+            *amount = Some(limit);
+        }
+        let [max, deposit, withdrawal, daily_withdrawal] = amounts;
+        let limits = TierLimits {
+            amounts: AmountLimits {
+                max,
+                deposit,
+                withdrawal,
+            },
+            daily_withdrawal,
+        };
+        tiers.insert(field(tier).to_string(), limits);
+    }
+    Ok(tiers)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::limits::AmountLimits;
+    use crate::model::{Transaction, TransactionType};
+    use crate::{Engine, ErrorCode, Rejection};
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn amounts_over_the_limit_are_rejected() {
+        let limits = AmountLimits {
+            max: Some(Decimal::from(1000)),
+            withdrawal: Some(Decimal::from(200)),
+            ..AmountLimits::default()
+        };
+        assert_eq!(
+            limits.limit(TransactionType::Deposit),
+            Some(Decimal::from(1000))
+        );
+        assert_eq!(limits.limit(TransactionType::Dispute), None);
+
+        let mut engine = Engine::new();
+        engine.set_amount_limits(limits);
+        let transaction = |transaction_type, tx, amount| Transaction {
+            transaction_type,
+            client: 1,
+            tx,
+            amount: Some(Decimal::from(amount)),
+        };
+        assert!(engine
+            .apply(transaction(TransactionType::Deposit, 1, 1000))
+            .is_ok());
+        let rejection = engine
+            .apply(transaction(TransactionType::Withdrawal, 2, 201))
+            .unwrap_err();
+        assert_eq!(
+            rejection,
+            Rejection::OverLimit {
+                amount: Decimal::from(201),
+                limit: Decimal::from(200),
+            }
+        );
+        assert_eq!(rejection.code(), ErrorCode::AmountLimit);
+        assert_eq!(rejection.to_string(), "amount 201 is over the limit of 200");
+        assert_eq!(engine.accounts()[&1].available, Decimal::from(1000));
+        // The rejected tx id wasn't used up
+        assert!(engine
+            .apply(transaction(TransactionType::Withdrawal, 2, 200))
+            .is_ok());
+    }
+
+    #[test]
+    fn withdrawals_are_capped_over_any_24_hours() {
+        const HOUR: i64 = 3600;
+        let mut engine = Engine::new();
+        assert!(!engine.needs_time());
+        engine.set_daily_withdrawal_limit(Some(Decimal::from(100)));
+        assert!(engine.needs_time());
+        let mut apply = |now: i64, transaction_type, tx, amount: i64| {
+            engine.set_time(now);
+            engine.apply(Transaction {
+                transaction_type,
+                client: 1,
+                tx,
+                amount: Some(Decimal::from(amount)),
+            })
+        };
+        assert!(apply(0, TransactionType::Deposit, 1, 500).is_ok());
+        assert!(apply(0, TransactionType::Withdrawal, 2, 60).is_ok());
+        assert!(apply(10 * HOUR, TransactionType::Withdrawal, 3, 40).is_ok());
+        let rejection = apply(20 * HOUR, TransactionType::Withdrawal, 4, 1).unwrap_err();
+        assert_eq!(rejection.code(), ErrorCode::WithdrawalLimit);
+        assert_eq!(
+            rejection.to_string(),
+            "withdrawals of 101 in 24 hours would be over the limit of 100"
+        );
+        // The first withdrawal left the window, the second one hasn't
+        assert!(apply(24 * HOUR, TransactionType::Withdrawal, 4, 61).is_err());
+        assert!(apply(24 * HOUR, TransactionType::Withdrawal, 4, 60).is_ok());
+        // Deposits aren't capped
+        assert!(apply(24 * HOUR, TransactionType::Deposit, 5, 1000).is_ok());
+    }
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn risk_tiers_tighten_the_limits() {
+        use crate::engine::limits::{read_client_attributes, read_tier_limits, RiskTiers};
+
+        let clients = "client,risk_tier,kyc_level
+1,high,1
+2,low,";
+        let tiers = "tier,max_amount,max_withdrawal,daily_withdrawal_limit
+high,500,100,150
+low,5000,,";
+        let tiers = RiskTiers {
+            clients: read_client_attributes(&mut csv::Reader::from_reader(clients.as_bytes()))
+                .unwrap(),
+            tiers: read_tier_limits(&mut csv::Reader::from_reader(tiers.as_bytes())).unwrap(),
+        };
+        assert_eq!(tiers.clients[&1].kyc_level.as_deref(), Some("1"));
+        assert_eq!(tiers.clients[&2].kyc_level, None);
+
+        let mut engine = Engine::new();
+        engine.set_amount_limits(AmountLimits {
+            max: Some(Decimal::from(1000)),
+            ..AmountLimits::default()
+        });
+        engine.set_risk_tiers(tiers);
+        engine.set_time(0);
+        let mut apply = |client, transaction_type, tx, amount: i64| {
+            engine.apply(Transaction {
+                transaction_type,
+                client,
+                tx,
+                amount: Some(Decimal::from(amount)),
+            })
+        };
+        // The tier's limit is tighter than the engine's, or the other way round
+        assert!(apply(1, TransactionType::Deposit, 1, 501).is_err());
+        assert!(apply(1, TransactionType::Deposit, 1, 500).is_ok());
+        assert!(apply(2, TransactionType::Deposit, 2, 1001).is_err());
+        assert!(apply(3, TransactionType::Deposit, 3, 1000).is_ok());
+        assert!(apply(1, TransactionType::Withdrawal, 4, 101).is_err());
+        assert!(apply(1, TransactionType::Withdrawal, 4, 100).is_ok());
+        let rejection = apply(1, TransactionType::Withdrawal, 5, 60).unwrap_err();
+        assert_eq!(rejection.code(), ErrorCode::WithdrawalLimit);
+        assert!(apply(3, TransactionType::Withdrawal, 5, 1000).is_ok());
+
+        let invalid = "tier,max_amount\nhigh,lots";
+        let err = read_tier_limits(&mut csv::Reader::from_reader(invalid.as_bytes())).unwrap_err();
+        assert_eq!(err.to_string(), "line 2: lots isn't an amount");
+    }
+}
@@ -0,0 +1,501 @@
+//! An engine keeping balances in integer minor units, for the highest throughput.
+//!
+//! [`MinorUnitsEngine`] applies the rules of an [`crate::Engine`] in its default configuration
+//! with amounts as `i128` ten-thousandths instead of [`Decimal`]s, so every balance change is
+//! an integer addition instead of decimal arithmetic aligning scales. Amounts are converted
+//! as transactions come in and balances as the report goes out, so sources, sinks and
+//! output are those of the engine, and so are the results as long as:
+//! - amounts have at most four decimal places, as in the specification. Rows with more
+//!   are rejected as [`ErrorCode::BadAmount`] where the engine keeps every digit.
+//! - balances stay within ±7.9 × 10^24, beyond which a [`Decimal`] can't keep four decimal
+//!   places. Transactions taking them further are rejected as [`Rejection::ArithmeticOverflow`].
+//!
+//! Nothing else of the engine is there: no observers, validators, limits or dispute policies,
+//! disputes of unknown transactions are ignored and balances may go negative.
+use crate::engine::policy::{DisputeState, DisputedTx};
+use crate::engine::{status_after, AccountMap, BuildHasher, Rejection, TransactionIndex};
+use crate::io::source::TransactionSource;
+use crate::io::{ErrorCode, ParseMode, RowError};
+use crate::model::{Account, Activity, ClientId, Status, Transaction, TransactionType, TxId};
+use crate::report::ProcessReport;
+use rust_decimal::{Decimal, RoundingStrategy};
+use std::collections::{BTreeSet, HashMap};
+use tracing::debug;
+
+/// Decimal places of a minor unit
+pub const SCALE: u32 = 4;
+
+/// Largest balance in minor units, the largest [`Decimal`] with [`SCALE`] decimal places
+const MAX_UNITS: i128 = (1 << 96) - 1;
+
+/// `amount` in minor units, `None` if it has more than [`SCALE`] decimal places
+pub fn to_minor_units(amount: Decimal) -> Option<i128> {
+    let (mantissa, scale) = (amount.mantissa(), amount.scale());
+    if scale <= SCALE {
+        return Some(mantissa * 10i128.pow(SCALE - scale));
+    }
+    // Trailing zeros, e.g. `1.50000`
+    let excess = 10i128.pow(scale - SCALE);
+    (mantissa % excess == 0).then_some(mantissa / excess)
+}
+
+/// `amount` in minor units, rounded half away from zero as the CSV output rounds it
+pub fn rounded_minor_units(amount: Decimal) -> i128 {
+    let rounded = amount.round_dp_with_strategy(SCALE, RoundingStrategy::MidpointAwayFromZero);
+    to_minor_units(rounded).unwrap_or_else(|| unreachable!("rounded to {} places", SCALE))
+}
+
+/// `units` as a [`Decimal`] with [`SCALE`] decimal places.
+/// Panics beyond ±(2^96 - 1) units, which no balance of the engine reaches.
+pub fn from_minor_units(units: i128) -> Decimal {
+    Decimal::from_i128_with_scale(units, SCALE)
+}
+
+/// A [`Transaction`] with its amount in minor units
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MinorTransaction {
+    pub transaction_type: TransactionType,
+    pub client: ClientId,
+    pub tx: TxId,
+    pub amount: Option<i128>,
+}
+
+impl MinorTransaction {
+    /// `None` if the amount of `transaction` has more than [`SCALE`] decimal places
+    pub fn new(transaction: &Transaction) -> Option<Self> {
+        let amount = match transaction.amount {
+            Some(amount) => Some(to_minor_units(amount)?),
+            None => None,
+        };
+        Some(MinorTransaction {
+            transaction_type: transaction.transaction_type,
+            client: transaction.client,
+            tx: transaction.tx,
+            amount,
+        })
+    }
+}
+
+/// [`DisputedTx`] in minor units
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct MinorTx {
+    transaction_type: TransactionType,
+    client: ClientId,
+    amount: i128,
+    held: i128,
+    state: DisputeState,
+}
+
+/// [`Account`] in minor units
+#[derive(Debug, Clone, Default)]
+struct MinorAccount {
+    available: i128,
+    held: i128,
+    status: Status,
+    disputed: BTreeSet<TxId>,
+    deposits: u64,
+    withdrawals: u64,
+    deposited: i128,
+    withdrawn: i128,
+}
+
+impl MinorAccount {
+    /// Moves the balances to `available` and `held` unless one of them or their total
+    /// is out of range
+    fn set(&mut self, available: i128, held: i128) -> Result<(), Rejection> {
+        let total = available + held;
+        if [available, held, total]
+            .iter()
+            .any(|units| units.abs() > MAX_UNITS)
+        {
+            return Err(Rejection::ArithmeticOverflow);
+        }
+        self.available = available;
+        self.held = held;
+        Ok(())
+    }
+
+    fn into_account(self, client: ClientId) -> Account {
+        let mut account = Account::new(client);
+        account.available = from_minor_units(self.available);
+        account.held = from_minor_units(self.held);
+        account.status = self.status;
+        for tx in self.disputed {
+            account.set_disputed(tx, true);
+        }
+        account.set_activity(Activity {
+            deposits: self.deposits,
+            withdrawals: self.withdrawals,
+            deposited: from_minor_units(self.deposited),
+            withdrawn: from_minor_units(self.withdrawn),
+        });
+        account
+    }
+}
+
+/// Applies transactions like a default [`crate::Engine`] with balances in minor units
+#[derive(Debug, Default)]
+pub struct MinorUnitsEngine {
+    accounts: HashMap<ClientId, MinorAccount, BuildHasher>,
+    transactions: TransactionIndex<MinorTx>,
+}
+
+impl MinorUnitsEngine {
+    pub fn new() -> Self {
+        MinorUnitsEngine::default()
+    }
+
+    /// Update the client's account with `transaction`, see [`crate::Engine::apply`]
+    pub fn apply(&mut self, transaction: MinorTransaction) -> Result<(), Rejection> {
+        let MinorTransaction {
+            transaction_type,
+            client,
+            tx,
+            ..
+        } = transaction;
+        let amount = transaction.amount.unwrap_or(0);
+        if amount < 0
+            && matches!(
+                transaction_type,
+                TransactionType::Deposit | TransactionType::Withdrawal
+            )
+        {
+            return Err(Rejection::NegativeAmount(from_minor_units(amount)));
+        }
+        match transaction_type {
+            TransactionType::ChargebackReversal => return self.reverse_chargeback(client, tx),
+            transaction_type if transaction_type.changes_status() => {
+                let current = self
+                    .accounts
+                    .get(&client)
+                    .map_or(Status::Active, |account| account.status);
+                let status = status_after(client, current, transaction_type)?;
+                self.accounts.entry(client).or_default().status = status;
+                return Ok(());
+            }
+            TransactionType::Deposit | TransactionType::Withdrawal
+                if self.transactions.contains(tx) =>
+            {
+                return Err(Rejection::DuplicateTx(tx));
+            }
+            _ => {}
+        }
+
+        let account = self.accounts.entry(client).or_default();
+        if let TransactionType::Deposit | TransactionType::Withdrawal = transaction_type {
+            let available = match transaction_type {
+                TransactionType::Deposit => account.available + amount,
+                _ => account.available - amount,
+            };
+            account.set(available, account.held)?;
+            let (count, volume) = match transaction_type {
+                TransactionType::Deposit => (&mut account.deposits, &mut account.deposited),
+                _ => (&mut account.withdrawals, &mut account.withdrawn),
+            };
+            *count += 1;
+            *volume = (*volume + amount).min(MAX_UNITS);
+            self.transactions.insert(
+                tx,
+                MinorTx {
+                    transaction_type,
+                    client,
+                    amount,
+                    held: 0,
+                    state: DisputeState::Processed,
+                },
+            );
+            return Ok(());
+        }
+
+        let Some(record) = self.transactions.get_mut(tx) else {
+            debug!(client, tx, "{} of unknown tx ignored", transaction_type);
+            return Ok(());
+        };
+        if record.client != client {
+            debug!(
+                client,
+                tx, "{} of another client's tx ignored", transaction_type
+            );
+            return Ok(());
+        }
+        if matches!(
+            record.state,
+            DisputeState::ChargedBack | DisputeState::Reversed
+        ) {
+            return Err(Rejection::ChargedBack(tx));
+        }
+        // The StandardDisputePolicy
+        let (moved, state) = match (transaction_type, record.state) {
+            (TransactionType::Dispute, DisputeState::Disputed) => return Ok(()),
+            (TransactionType::Dispute, _) => {
+                let moved = transaction
+                    .amount
+                    .map_or(record.amount, |amount| amount.min(record.amount).max(0));
+                (moved, DisputeState::Disputed)
+            }
+            (TransactionType::Resolve, DisputeState::Disputed) => {
+                (record.held, DisputeState::Resolved)
+            }
+            (TransactionType::Chargeback, DisputeState::Disputed) => {
+                (record.held, DisputeState::ChargedBack)
+            }
+            _ => {
+                debug!(client, tx, "{} ignored by dispute policy", transaction_type);
+                return Ok(());
+            }
+        };
+        let (available, held) = match transaction_type {
+            TransactionType::Dispute => (account.available - moved, account.held + moved),
+            TransactionType::Resolve => (account.available + moved, account.held - moved),
+            _ => (account.available, account.held - moved),
+        };
+        account.set(available, held)?;
+        if transaction_type == TransactionType::Chargeback && !account.status.locked() {
+            account.status = Status::Frozen;
+        }
+        if state == DisputeState::Disputed {
+            account.disputed.insert(tx);
+        } else {
+            account.disputed.remove(&tx);
+        }
+        record.held = match transaction_type {
+            TransactionType::Dispute => record.held + moved,
+            TransactionType::Chargeback => moved,
+            _ => (record.held - moved).max(0),
+        };
+        record.state = state;
+        Ok(())
+    }
+
+    /// Gives the client back what the chargeback of `tx` took, strictly as the engine does
+    fn reverse_chargeback(&mut self, client: ClientId, tx: TxId) -> Result<(), Rejection> {
+        let record = self
+            .transactions
+            .get_mut(tx)
+            .filter(|record| record.client == client && record.state == DisputeState::ChargedBack)
+            .ok_or(Rejection::NotChargedBack(tx))?;
+        let account = self
+            .accounts
+            .get_mut(&client)
+            .ok_or(Rejection::NotChargedBack(tx))?;
+        account.set(account.available + record.held, account.held)?;
+        record.held = 0;
+        record.state = DisputeState::Reversed;
+        Ok(())
+    }
+
+    /// Same as [`crate::Engine::process_iter`]
+    pub fn process_iter<E: std::fmt::Display>(
+        mut self,
+        transactions: impl Iterator<Item = Result<Transaction, E>>,
+    ) -> ProcessReport {
+        let items = transactions.enumerate().map(|(index, item)| {
+            let line = index as u64 + 1;
+            let item = item.map_err(|err| RowError {
+                line,
+                record: String::new(),
+                code: ErrorCode::MalformedRow,
+                message: err.to_string(),
+            });
+            (line, item)
+        });
+        let errors = self
+            .process_lines(items, ParseMode::Collecting)
+            .unwrap_or_else(|_| unreachable!("only strict mode fails"));
+        ProcessReport {
+            errors,
+            ..self.into()
+        }
+    }
+
+    /// Same as [`crate::Engine::process_source_with`]
+    pub fn process_source_with(
+        mut self,
+        mut source: impl TransactionSource,
+        mode: ParseMode,
+    ) -> Result<ProcessReport, RowError> {
+        let mut index = 0;
+        let items = std::iter::from_fn(|| {
+            let item = source.next_transaction()?;
+            index += 1;
+            Some((source.line().unwrap_or(index), item))
+        });
+        let errors = self.process_lines(items, mode)?;
+        Ok(ProcessReport {
+            errors,
+            ..self.into()
+        })
+    }
+
+    fn process_lines(
+        &mut self,
+        items: impl Iterator<Item = (u64, Result<Transaction, RowError>)>,
+        mode: ParseMode,
+    ) -> Result<Vec<RowError>, RowError> {
+        let mut errors = vec![];
+        for (line, item) in items {
+            let error = match item {
+                Ok(transaction) => {
+                    let applied = match MinorTransaction::new(&transaction) {
+                        Some(minor) => self
+                            .apply(minor)
+                            .map_err(|rejection| (rejection.code(), rejection.to_string())),
+                        None => Err((
+                            ErrorCode::BadAmount,
+                            format!("amount with more than {} decimal places", SCALE),
+                        )),
+                    };
+                    match applied {
+                        Ok(()) => continue,
+                        Err((code, message)) => RowError {
+                            line,
+                            record: transaction.to_string(),
+                            code,
+                            message,
+                        },
+                    }
+                }
+                Err(error) => error,
+            };
+            mode.reject(error, &mut errors)?;
+        }
+        Ok(errors)
+    }
+
+    /// The accounts as [`crate::Engine::accounts`] has them
+    pub fn accounts(&self) -> AccountMap {
+        self.accounts
+            .iter()
+            .map(|(client, account)| (*client, account.clone().into_account(*client)))
+            .collect()
+    }
+}
+
+/// A report of everything `engine` applied, in [`Decimal`]s, without errors
+impl From<MinorUnitsEngine> for ProcessReport {
+    fn from(engine: MinorUnitsEngine) -> Self {
+        let accounts = engine
+            .accounts
+            .into_iter()
+            .map(|(client, account)| (client, account.into_account(client)))
+            .collect();
+        let transactions = engine
+            .transactions
+            .into_iter()
+            .map(|(tx, record)| {
+                let record = DisputedTx {
+                    transaction_type: record.transaction_type,
+                    client: record.client,
+                    amount: from_minor_units(record.amount),
+                    held: from_minor_units(record.held),
+                    state: record.state,
+                };
+                (tx, record)
+            })
+            .collect();
+        ProcessReport {
+            accounts,
+            transactions,
+            ..ProcessReport::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::generate::{Generator, GeneratorConfig};
+
+    /// Both engines' reports, which have to be the same
+    fn compare(transactions: Vec<Transaction>) {
+        let items = || transactions.iter().map(|t| Ok::<_, String>(*t));
+        let expected = Engine::new().process_iter(items());
+        let report = MinorUnitsEngine::new().process_iter(items());
+        assert_eq!(report.accounts, expected.accounts);
+        assert_eq!(report.transactions, expected.transactions);
+        assert_eq!(report.errors, expected.errors);
+        assert_eq!(report.state_digest(), expected.state_digest());
+    }
+
+    #[test]
+    fn gives_the_results_of_the_decimal_engine() {
+        let config = GeneratorConfig {
+            clients: 50,
+            rows: 20_000,
+            dispute_rate: 0.05,
+            chargeback_rate: 0.3,
+            seed: 7,
+        };
+        compare(Generator::new(config).collect());
+
+        let transaction = |transaction_type, client, tx, amount: Option<&str>| Transaction {
+            transaction_type,
+            client,
+            tx,
+            amount: amount.map(|amount| amount.parse().unwrap()),
+        };
+        use TransactionType::*;
+        compare(vec![
+            transaction(Deposit, 1, 1, Some("10.5")),
+            transaction(Deposit, 1, 1, Some("1")),
+            transaction(Dispute, 1, 1, Some("4.25")),
+            transaction(Dispute, 2, 1, None),
+            transaction(Withdrawal, 1, 2, Some("20.0001")),
+            transaction(Resolve, 1, 1, None),
+            transaction(Dispute, 1, 1, None),
+            transaction(Chargeback, 1, 1, None),
+            transaction(Dispute, 1, 1, None),
+            transaction(ChargebackReversal, 1, 1, None),
+            transaction(ChargebackReversal, 1, 1, None),
+            transaction(Unlock, 1, 0, None),
+            transaction(Unlock, 1, 0, None),
+            transaction(Dispute, 3, 9, None),
+            transaction(Deposit, 4, 3, Some("7922816251426433759354395")),
+            transaction(Withdrawal, 2, 4, Some("0")),
+        ]);
+    }
+
+    #[test]
+    fn rejects_balances_a_decimal_cant_hold_to_minor_units() {
+        let mut engine = MinorUnitsEngine::new();
+        let deposit = |tx, amount: &str| MinorTransaction {
+            transaction_type: TransactionType::Deposit,
+            client: 1,
+            tx,
+            amount: to_minor_units(amount.parse().unwrap()),
+        };
+        assert_eq!(
+            engine.apply(deposit(1, "7922816251426433759354395")),
+            Ok(())
+        );
+        assert_eq!(
+            engine.apply(deposit(2, "1")),
+            Err(Rejection::ArithmeticOverflow)
+        );
+        assert_eq!(
+            engine.accounts()[&1].available.to_string(),
+            "7922816251426433759354395.0000"
+        );
+    }
+
+    #[test]
+    fn rejects_amounts_finer_than_minor_units() {
+        assert_eq!(to_minor_units("1.50000".parse().unwrap()), Some(15_000));
+        assert_eq!(to_minor_units("-0.0001".parse().unwrap()), Some(-1));
+        assert_eq!(to_minor_units("0.00001".parse().unwrap()), None);
+        assert_eq!(from_minor_units(15_000).to_string(), "1.5000");
+
+        let report = MinorUnitsEngine::new().process_iter(
+            [Ok::<_, String>(Transaction {
+                transaction_type: TransactionType::Deposit,
+                client: 1,
+                tx: 1,
+                amount: Some("0.00001".parse().unwrap()),
+            })]
+            .into_iter(),
+        );
+        assert_eq!(report.errors[0].code, ErrorCode::BadAmount);
+        assert!(report.accounts.is_empty());
+    }
+}
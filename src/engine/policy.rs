@@ -0,0 +1,213 @@
+//! What Dispute, Resolve and Chargeback transactions do.
+//!
+//! The engine looks up the referenced transaction and checks it belongs to the client,
+//! a [`DisputePolicy`] then decides how much is moved, what it costs
+//! and where the referenced transaction ends up in the dispute lifecycle.
+use crate::model::{ClientId, Transaction, TransactionType};
+use rust_decimal::Decimal;
+
+/// Where a deposit or withdrawal stands in the dispute lifecycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisputeState {
+    Processed,
+    Disputed,
+    /// Was under dispute until a Resolve, can be disputed again
+    /// unless [`crate::Engine::set_allow_redisputes`] forbids it
+    Resolved,
+    /// The engine rejects anything referencing the transaction afterwards
+    /// but a ChargebackReversal
+    ChargedBack,
+    /// Final, the chargeback was reversed
+    Reversed,
+}
+
+impl DisputeState {
+    /// As in JSON states, e.g. `charged_back`
+    pub fn name(self) -> &'static str {
+        match self {
+            DisputeState::Processed => "processed",
+            DisputeState::Disputed => "disputed",
+            DisputeState::Resolved => "resolved",
+            DisputeState::ChargedBack => "charged_back",
+            DisputeState::Reversed => "reversed",
+        }
+    }
+}
+
+/// What the engine keeps of a deposit or withdrawal to settle disputes referencing it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisputedTx {
+    /// Deposit or Withdrawal
+    pub transaction_type: TransactionType,
+    pub client: ClientId,
+    pub amount: Decimal,
+    /// Part of `amount` held by the open dispute, or taken by the chargeback once charged back,
+    /// zero otherwise
+    pub held: Decimal,
+    pub state: DisputeState,
+}
+
+/// Effect of a Dispute, Resolve or Chargeback on the client's account
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisputeAction {
+    /// Amount moved the way the transaction type moves it:
+    /// held by a Dispute, released by a Resolve, removed by a Chargeback
+    pub amount: Decimal,
+    /// Taken from the available funds on top, e.g. a dispute fee
+    pub fee: Decimal,
+    /// State of the referenced transaction afterwards
+    pub state: DisputeState,
+}
+
+/// Dispute semantics, plugged into the engine with [`crate::Engine::set_dispute_policy`]
+pub trait DisputePolicy {
+    /// Decide what `transaction` does to the client's own `referenced` transaction,
+    /// `None` ignores it. Never called for a charged back transaction or a ChargebackReversal,
+    /// which the engine settles itself.
+    fn decide(
+        &mut self,
+        transaction: &Transaction,
+        referenced: &DisputedTx,
+    ) -> Option<DisputeAction>;
+}
+
+/// The engine's default rules: a dispute holds the amount it names, clamped to the referenced
+/// transaction's, or all of it without one. A transaction already under dispute can't be
+/// disputed again, only a disputed transaction can be resolved or charged back,
+/// settling the held part, and nothing costs a fee. A resolved transaction can be
+/// disputed again like one that never was.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StandardDisputePolicy;
+
+impl DisputePolicy for StandardDisputePolicy {
+    fn decide(
+        &mut self,
+        transaction: &Transaction,
+        referenced: &DisputedTx,
+    ) -> Option<DisputeAction> {
+        let (amount, state) = match (transaction.transaction_type, referenced.state) {
+            (TransactionType::Dispute, DisputeState::Disputed) => return None,
+            (TransactionType::Dispute, _) => {
+                let amount = transaction.amount.map_or(referenced.amount, |amount| {
+                    amount.min(referenced.amount).max(Decimal::ZERO)
+                });
+                (amount, DisputeState::Disputed)
+            }
+            (TransactionType::Resolve, DisputeState::Disputed) => {
+                (referenced.held, DisputeState::Resolved)
+            }
+            (TransactionType::Chargeback, DisputeState::Disputed) => {
+                (referenced.held, DisputeState::ChargedBack)
+            }
+            _ => return None,
+        };
+        Some(DisputeAction {
+            amount,
+            fee: Decimal::ZERO,
+            state,
+        })
+    }
+}
+
+#[cfg(all(test, feature = "csv"))]
+mod tests {
+    use crate::engine::policy::{
+        DisputeAction, DisputePolicy, DisputeState, DisputedTx, StandardDisputePolicy,
+    };
+    use crate::io::csv::{process_transactions_with, process_transactions_with_engine};
+    use crate::model::{Transaction, TransactionType};
+    use crate::{Engine, ParseOptions};
+    use rust_decimal::Decimal;
+
+    /// Only deposits can be disputed, and opening a dispute costs 1
+    struct DepositsWithFee;
+
+    impl DisputePolicy for DepositsWithFee {
+        fn decide(
+            &mut self,
+            transaction: &Transaction,
+            referenced: &DisputedTx,
+        ) -> Option<DisputeAction> {
+            if referenced.transaction_type != TransactionType::Deposit {
+                return None;
+            }
+            let action = StandardDisputePolicy.decide(transaction, referenced)?;
+            Some(match transaction.transaction_type {
+                TransactionType::Dispute => DisputeAction {
+                    fee: Decimal::new(1, 0),
+                    ..action
+                },
+                _ => action,
+            })
+        }
+    }
+
+    #[test]
+    fn custom_policy_decides_dispute_effects() {
+        let data = "type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,4
+dispute,1,2,
+dispute,1,1,
+resolve,1,1,";
+        let mut engine = Engine::new();
+        engine.set_dispute_policy(DepositsWithFee);
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let report =
+            process_transactions_with_engine(&mut reader, &ParseOptions::default(), engine)
+                .unwrap();
+        let account = &report.accounts[&1];
+        assert_eq!(account.available, Decimal::new(5, 0));
+        assert_eq!(account.held, Decimal::ZERO);
+    }
+
+    #[test]
+    fn standard_policy_only_settles_disputed_transactions() {
+        let referenced = DisputedTx {
+            transaction_type: TransactionType::Deposit,
+            client: 1,
+            amount: Decimal::new(1, 0),
+            held: Decimal::ZERO,
+            state: DisputeState::Processed,
+        };
+        let resolve = Transaction {
+            transaction_type: TransactionType::Resolve,
+            client: 1,
+            tx: 1,
+            amount: None,
+        };
+        assert_eq!(StandardDisputePolicy.decide(&resolve, &referenced), None);
+        let disputed = DisputedTx {
+            held: Decimal::new(1, 0),
+            state: DisputeState::Disputed,
+            ..referenced
+        };
+        let action = StandardDisputePolicy.decide(&resolve, &disputed).unwrap();
+        assert_eq!(action.state, DisputeState::Resolved);
+        let dispute = Transaction {
+            transaction_type: TransactionType::Dispute,
+            ..resolve
+        };
+        assert_eq!(StandardDisputePolicy.decide(&dispute, &disputed), None);
+    }
+
+    #[test]
+    fn partial_disputes_settle_the_held_part() {
+        let data = "type,client,tx,amount
+deposit,1,1,10
+dispute,1,1,4
+chargeback,1,1,
+deposit,2,2,10
+dispute,2,2,25
+resolve,2,2,1";
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let report = process_transactions_with(&mut reader, &ParseOptions::default()).unwrap();
+        let account = &report.accounts[&1];
+        assert_eq!(account.available, Decimal::new(6, 0));
+        assert_eq!(account.held, Decimal::ZERO);
+        assert!(account.locked());
+        let account = &report.accounts[&2];
+        assert_eq!(account.available, Decimal::new(10, 0));
+        assert_eq!(account.held, Decimal::ZERO);
+    }
+}
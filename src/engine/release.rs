@@ -0,0 +1,293 @@
+//! Releasing held funds of disputes left open for too long.
+//!
+//! Transactions carry no time of their own, so [`HoldRelease`] is told when each update
+//! happened and what time it is now, e.g. from a timestamp column of the input or the
+//! system clock. Expired disputes come back as synthetic resolves to apply like any other
+//! transaction, so observers and outputs see them as ordinary resolves.
+use crate::model::{AccountUpdate, ClientId, Transaction, TransactionType, TxId};
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+
+/// Open disputes and when they were opened, in seconds since the Unix epoch
+#[derive(Debug, Clone)]
+pub struct HoldRelease {
+    after: i64,
+    opened: HashMap<TxId, (i64, ClientId)>,
+    // Ordered by opening time, oldest first
+    queue: BTreeSet<(i64, TxId)>,
+}
+
+impl HoldRelease {
+    /// Releases disputes still open `after` they were opened, to the second
+    pub fn new(after: Duration) -> Self {
+        HoldRelease {
+            after: i64::try_from(after.as_secs()).unwrap_or(i64::MAX),
+            opened: HashMap::new(),
+            queue: BTreeSet::new(),
+        }
+    }
+
+    /// Notes that `update` was applied at `now`: a dispute starts the clock of the
+    /// transaction it references, a resolve or chargeback stops it
+    pub fn record(&mut self, now: i64, update: &AccountUpdate) {
+        match update.transaction_type {
+            TransactionType::Dispute => {
+                self.stop(update.tx);
+                self.opened.insert(update.tx, (now, update.client));
+                self.queue.insert((now, update.tx));
+            }
+            TransactionType::Resolve | TransactionType::Chargeback => self.stop(update.tx),
+            TransactionType::Deposit
+            | TransactionType::Withdrawal
+            | TransactionType::ChargebackReversal
+            | TransactionType::Unlock
+            | TransactionType::Review
+            | TransactionType::Reinstate
+            | TransactionType::Close => {}
+        }
+    }
+
+    /// Resolves of the disputes opened at least the release period before `now`,
+    /// oldest first. They are no longer tracked afterwards, whether or not they get applied.
+    pub fn expired(&mut self, now: i64) -> Vec<Transaction> {
+        let mut resolves = vec![];
+        while let Some(&(opened, tx)) = self.queue.first() {
+            if opened.saturating_add(self.after) > now {
+                break;
+            }
+            self.queue.pop_first();
+            if let Some((_, client)) = self.opened.remove(&tx) {
+                resolves.push(Transaction {
+                    transaction_type: TransactionType::Resolve,
+                    client,
+                    tx,
+                    amount: None,
+                });
+            }
+        }
+        resolves
+    }
+
+    /// Disputes whose clock is running
+    pub fn open(&self) -> usize {
+        self.opened.len()
+    }
+
+    fn stop(&mut self, tx: TxId) {
+        if let Some((opened, _)) = self.opened.remove(&tx) {
+            self.queue.remove(&(opened, tx));
+        }
+    }
+}
+
+/// Seconds since the Unix epoch of an ISO 8601 date or timestamp, `2024-03-01`,
+/// `2024-03-01T12:30:00Z`, `2024-03-01 12:30:00.250+02:00`, ... Timestamps without
+/// an offset are taken as UTC and fractions of a second are dropped.
+pub fn parse_timestamp(timestamp: &str) -> Option<i64> {
+    let timestamp = timestamp.trim();
+    let date = timestamp.get(..10)?;
+    let bytes = date.as_bytes();
+    if bytes[4] != b'-' || bytes[7] != b'-' {
+        return None;
+    }
+    let (year, month, day) = (
+        digits(&date[..4])?,
+        digits(&date[5..7])?,
+        digits(&date[8..10])?,
+    );
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return None;
+    }
+    let mut seconds = days_from_civil(year, month, day) * 86_400;
+    let rest = &timestamp[10..];
+    if rest.is_empty() {
+        return Some(seconds);
+    }
+    let rest = rest.strip_prefix(['T', 't', ' '])?;
+    let (hour, minute) = (digits(rest.get(..2)?)?, digits(rest.get(3..5)?)?);
+    if rest.as_bytes()[2] != b':' || hour > 23 || minute > 59 {
+        return None;
+    }
+    seconds += hour * 3600 + minute * 60;
+    let mut rest = &rest[5..];
+    if let Some(after) = rest.strip_prefix(':') {
+        let second = digits(after.get(..2)?)?;
+        if second > 60 {
+            return None;
+        }
+        seconds += second;
+        rest = &after[2..];
+        if let Some(fraction) = rest.strip_prefix('.') {
+            let end = fraction
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(fraction.len());
+            if end == 0 {
+                return None;
+            }
+            rest = &fraction[end..];
+        }
+    }
+    let offset = match rest {
+        "" | "Z" | "z" => 0,
+        _ => {
+            let sign = match rest.as_bytes()[0] {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            let offset = &rest[1..];
+            let (hours, minutes) = match offset.len() {
+                5 if offset.as_bytes()[2] == b':' => (offset.get(..2)?, offset.get(3..)?),
+                4 => (offset.get(..2)?, offset.get(2..)?),
+                2 => (offset, "00"),
+                _ => return None,
+            };
+            sign * (digits(hours)? * 3600 + digits(minutes)? * 60)
+        }
+    };
+    Some(seconds - offset)
+}
+
+/// `seconds` since the Unix epoch as an ISO 8601 UTC timestamp, `2024-03-01T12:30:00Z`
+pub fn format_timestamp(seconds: i64) -> String {
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86_400));
+    let time = seconds.rem_euclid(86_400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+fn digits(s: &str) -> Option<i64> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+pub(crate) fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date
+pub(crate) fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Year, month and day of the date `days` after 1970-01-01, the inverse of [`days_from_civil`]
+pub(crate) fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::release::{format_timestamp, parse_timestamp, HoldRelease};
+    use crate::model::{Transaction, TransactionType, TxId};
+    use crate::Engine;
+    use rust_decimal::Decimal;
+    use std::time::Duration;
+
+    #[test]
+    fn timestamps_are_parsed_as_utc_seconds() {
+        assert_eq!(parse_timestamp("1970-01-01"), Some(0));
+        assert_eq!(parse_timestamp("2024-03-01"), Some(1_709_251_200));
+        assert_eq!(parse_timestamp("2024-03-01T12:30:00Z"), Some(1_709_296_200));
+        assert_eq!(
+            parse_timestamp(" 2024-03-01 14:30:00.250+02:00"),
+            Some(1_709_296_200)
+        );
+        assert_eq!(
+            parse_timestamp("2024-03-01T12:30-0100"),
+            Some(1_709_299_800)
+        );
+        assert_eq!(parse_timestamp("2024-02-29"), Some(1_709_164_800));
+        assert_eq!(parse_timestamp("2023-02-29"), None);
+        assert_eq!(parse_timestamp("2024-03-01T25:00"), None);
+        assert_eq!(parse_timestamp("2024-03-01T12:30:00 UTC"), None);
+        assert_eq!(parse_timestamp("01/03/2024"), None);
+        assert_eq!(parse_timestamp("yesterday"), None);
+        assert_eq!(parse_timestamp("2024-03-01T12:30+é1"), None);
+        for timestamp in [
+            "1969-12-31T23:59:59Z",
+            "2000-02-29T00:00:00Z",
+            "2024-03-01T12:30:00Z",
+        ] {
+            assert_eq!(
+                format_timestamp(parse_timestamp(timestamp).unwrap()),
+                timestamp
+            );
+        }
+    }
+
+    #[test]
+    fn disputes_open_too_long_are_resolved() {
+        const DAY: i64 = 86_400;
+        let mut engine = Engine::new();
+        let mut release = HoldRelease::new(Duration::from_secs(30 * DAY as u64));
+        let apply = |engine: &mut Engine,
+                     release: &mut HoldRelease,
+                     now: i64,
+                     transaction_type: TransactionType,
+                     tx: TxId| {
+            let transaction = Transaction {
+                transaction_type,
+                client: 1,
+                tx,
+                amount: Some(Decimal::TEN).filter(|_| transaction_type == TransactionType::Deposit),
+            };
+            let _ = engine.apply_each(transaction, |update| release.record(now, &update));
+        };
+        for (day, transaction_type, tx) in [
+            (0, TransactionType::Deposit, 3),
+            (0, TransactionType::Deposit, 4),
+            (1, TransactionType::Dispute, 3),
+            (2, TransactionType::Dispute, 4),
+            (5, TransactionType::Resolve, 4),
+            (6, TransactionType::Dispute, 4),
+        ] {
+            apply(&mut engine, &mut release, day * DAY, transaction_type, tx);
+        }
+        assert_eq!(release.open(), 2);
+        assert!(release.expired(30 * DAY).is_empty());
+        let expired = release.expired(36 * DAY);
+        assert_eq!(
+            expired.iter().map(|resolve| resolve.tx).collect::<Vec<_>>(),
+            [3, 4]
+        );
+        for resolve in expired {
+            engine.apply(resolve).unwrap();
+        }
+        assert_eq!(engine.accounts()[&1].held, Decimal::ZERO);
+        assert_eq!(engine.accounts()[&1].available, Decimal::from(20));
+        assert_eq!(release.open(), 0);
+    }
+}
@@ -0,0 +1,269 @@
+//! Standing deposits and withdrawals, e.g. a monthly fee, expanded into transactions.
+//!
+//! Like [`crate::HoldRelease`], a [`Scheduler`] is told what time it is, e.g. the timestamp of
+//! each input row, and returns the transactions that fell due since, to apply before the row.
+//! They get tx ids counting down from the largest [`TxId`], so they don't collide with the
+//! ids of an input numbered from one.
+use crate::engine::release::{civil_from_days, days_from_civil, days_in_month};
+use crate::engine::AccountMap;
+use crate::model::{ClientId, Transaction, TransactionType, TxId};
+use rust_decimal::Decimal;
+#[cfg(feature = "csv")]
+use std::io;
+use std::str::FromStr;
+
+/// How often a [`Schedule`] falls due
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Every {
+    Day,
+    Week,
+    /// On the day of the month of the start, or the last day of shorter months
+    Month,
+}
+
+impl FromStr for Every {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "day" => Ok(Every::Day),
+            "week" => Ok(Every::Week),
+            "month" => Ok(Every::Month),
+            _ => Err(format!("{} isn't day, week or month", s)),
+        }
+    }
+}
+
+/// A deposit or withdrawal repeating from `start`, in seconds since the Unix epoch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    /// Deposit or Withdrawal
+    pub transaction_type: TransactionType,
+    /// `None` for every client that has an account when it falls due
+    pub client: Option<ClientId>,
+    pub amount: Decimal,
+    pub every: Every,
+    /// First time it falls due
+    pub start: i64,
+    /// Last time it may fall due, if it ends
+    pub end: Option<i64>,
+}
+
+impl Schedule {
+    /// When it falls due for the `n`th time, counting from zero at `start`
+    pub fn occurrence(&self, n: u64) -> Option<i64> {
+        const DAY: i64 = 86_400;
+        let n = i64::try_from(n).ok()?;
+        let at = match self.every {
+            Every::Day => self.start.checked_add(n.checked_mul(DAY)?)?,
+            Every::Week => self.start.checked_add(n.checked_mul(7 * DAY)?)?,
+            Every::Month => {
+                let (year, month, day) = civil_from_days(self.start.div_euclid(DAY));
+                let months = (month - 1).checked_add(n)?;
+                let year = year.checked_add(months.div_euclid(12))?;
+                let month = months.rem_euclid(12) + 1;
+                let day = day.min(days_in_month(year, month));
+                days_from_civil(year, month, day).checked_mul(DAY)? + self.start.rem_euclid(DAY)
+            }
+        };
+        Some(at).filter(|at| self.end.is_none_or(|end| *at <= end))
+    }
+}
+
+/// Expands schedules into transactions as time passes
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    // With how many times each fell due
+    schedules: Vec<(Schedule, u64)>,
+    next_tx: TxId,
+}
+
+impl Scheduler {
+    pub fn new(schedules: Vec<Schedule>) -> Self {
+        Scheduler {
+            schedules: schedules
+                .into_iter()
+                .map(|schedule| (schedule, 0))
+                .collect(),
+            next_tx: TxId::MAX,
+        }
+    }
+
+    /// The transactions that fell due at or before `now` and weren't returned yet, with when
+    /// they fell due, ordered by time then by schedule. A schedule for every client gives
+    /// one transaction per client in `accounts`, ordered by client id.
+    pub fn due(&mut self, now: i64, accounts: &AccountMap) -> Vec<(i64, Transaction)> {
+        let mut due = vec![];
+        for (index, (schedule, count)) in self.schedules.iter_mut().enumerate() {
+            while let Some(at) = schedule.occurrence(*count).filter(|at| *at <= now) {
+                *count += 1;
+                due.push((at, index));
+            }
+        }
+        due.sort_unstable();
+        let mut transactions = vec![];
+        for (at, index) in due {
+            let schedule = &self.schedules[index].0;
+            let clients = match schedule.client {
+                Some(client) => vec![client],
+                None => crate::engine::sorted_accounts(accounts)
+                    .iter()
+                    .map(|account| account.client)
+                    .collect(),
+            };
+            for client in clients {
+                let transaction = Transaction {
+                    transaction_type: schedule.transaction_type,
+                    client,
+                    tx: self.next_tx,
+                    amount: Some(schedule.amount),
+                };
+                self.next_tx = self.next_tx.saturating_sub(1);
+                transactions.push((at, transaction));
+            }
+        }
+        transactions
+    }
+}
+
+/// Reads schedules from CSV with a `type,client,amount,every,start` header and an optional
+/// `end` column: `withdrawal,*,2.50,month,2024-01-31`. A client of `*` stands for every client,
+/// `every` is `day`, `week` or `month` and `start` and `end` are ISO 8601 dates or timestamps.
+/// Fails on the first row that isn't a positive deposit or withdrawal schedule.
+#[cfg(feature = "csv")]
+pub fn read_schedules<R: io::Read>(reader: &mut csv::Reader<R>) -> io::Result<Vec<Schedule>> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let headers = reader.headers().map_err(io::Error::from)?.clone();
+    let index = |name: &str| headers.iter().position(|header| header.trim() == name);
+    let column = |name: &str| index(name).ok_or_else(|| invalid(format!("no {} column", name)));
+    let (kind, client, amount, every, start, end) = (
+        column("type")?,
+        column("client")?,
+        column("amount")?,
+        column("every")?,
+        column("start")?,
+        index("end"),
+    );
+    let mut schedules = vec![];
+    let mut record = csv::StringRecord::new();
+    while reader.read_record(&mut record).map_err(io::Error::from)? {
+        let line = record.position().map_or(0, |position| position.line());
+        let field = |index: usize| record.get(index).unwrap_or_default().trim();
+        let transaction_type = TransactionType::from_str(field(kind))
+            .ok()
+            .filter(|kind| matches!(kind, TransactionType::Deposit | TransactionType::Withdrawal));
+        let client = match field(client) {
+            "*" => Ok(None),
+            client => ClientId::from_str(client).map(Some),
+        };
+        let amount = Decimal::from_str(field(amount))
+            .ok()
+            .filter(|amount| *amount > Decimal::ZERO);
+        let end = match end.map(field).filter(|end| !end.is_empty()) {
+            Some(end) => crate::parse_timestamp(end).ok_or(()).map(Some),
+            None => Ok(None),
+        };
+        let parsed = (
+            transaction_type,
+            client,
+            amount,
+            Every::from_str(field(every)),
+            crate::parse_timestamp(field(start)),
+            end,
+        );
+        let (Some(transaction_type), Ok(client), Some(amount), Ok(every), Some(start), Ok(end)) =
+            parsed
+        else {
+            return Err(invalid(format!("line {}: not a schedule", line)));
+        };
+        schedules.push(Schedule {
+            transaction_type,
+            client,
+            amount,
+            every,
+            start,
+            end,
+        });
+    }
+    Ok(schedules)
+}
+
+#[cfg(all(test, feature = "csv"))]
+mod tests {
+    use crate::engine::schedule::{read_schedules, Scheduler};
+    use crate::model::{Transaction, TransactionType, TxId};
+    use crate::{parse_timestamp, Engine};
+    use rust_decimal::Decimal;
+
+    fn at(timestamp: &str) -> i64 {
+        parse_timestamp(timestamp).unwrap()
+    }
+
+    #[test]
+    fn monthly_schedules_keep_their_day() {
+        let data = "type,client,amount,every,start,end
+withdrawal,1,2.5,month,2024-01-31T09:00:00Z,2024-05-01";
+        let schedules = read_schedules(&mut csv::Reader::from_reader(data.as_bytes())).unwrap();
+        let occurrences: Vec<_> = (0..5).map(|n| schedules[0].occurrence(n)).collect();
+        assert_eq!(
+            occurrences,
+            [
+                Some(at("2024-01-31T09:00:00Z")),
+                Some(at("2024-02-29T09:00:00Z")),
+                Some(at("2024-03-31T09:00:00Z")),
+                Some(at("2024-04-30T09:00:00Z")),
+                None,
+            ]
+        );
+
+        let invalid = "type,client,amount,every,start
+dispute,1,1,month,2024-01-01";
+        let err = read_schedules(&mut csv::Reader::from_reader(invalid.as_bytes())).unwrap_err();
+        assert_eq!(err.to_string(), "line 2: not a schedule");
+    }
+
+    #[test]
+    fn due_transactions_are_expanded_for_every_client() {
+        let data = "type,client,amount,every,start
+withdrawal,*,1,month,2024-01-01
+deposit,2,5,week,2024-01-10";
+        let schedules = read_schedules(&mut csv::Reader::from_reader(data.as_bytes())).unwrap();
+        let mut scheduler = Scheduler::new(schedules);
+        let mut engine = Engine::new();
+        for (client, tx) in [(1, 1), (3, 2)] {
+            let deposit = Transaction {
+                transaction_type: TransactionType::Deposit,
+                client,
+                tx,
+                amount: Some(Decimal::TEN),
+            };
+            engine.apply(deposit).unwrap();
+        }
+        assert!(scheduler
+            .due(at("2023-12-31"), engine.accounts())
+            .is_empty());
+        let due = scheduler.due(at("2024-02-01"), engine.accounts());
+        let rows: Vec<_> = due
+            .iter()
+            .map(|(_, transaction)| (transaction.client, transaction.tx))
+            .collect();
+        let max = TxId::MAX;
+        assert_eq!(
+            rows,
+            [
+                (1, max),
+                (3, max - 1),
+                (2, max - 2),
+                (2, max - 3),
+                (2, max - 4),
+                (2, max - 5),
+                (1, max - 6),
+                (3, max - 7),
+            ]
+        );
+        assert_eq!(due[2].0, at("2024-01-10"));
+        assert!(scheduler
+            .due(at("2024-02-01"), engine.accounts())
+            .is_empty());
+    }
+}
@@ -0,0 +1,117 @@
+//! A concurrent copy of the balances, for a server answering balance queries
+//! while the engine applies transactions.
+//!
+//! [`SharedAccounts`] is an [`EngineObserver`] keeping a [`DashMap`] up to date with every
+//! update. Its clones share the map, so a query thread reads the balances through one clone
+//! while the engine writes through another, locking only the shard of the map an account is in
+//! instead of the whole engine.
+use crate::engine::{AccountMap, BuildHasher, EngineObserver};
+use crate::model::{Account, AccountUpdate, ClientId};
+use dashmap::DashMap;
+use std::sync::Arc;
+
+/// Balances and status of every account, as of the last update the engine applied to it.
+/// Disputed transactions and activity are only those of [`SharedAccounts::with_accounts`].
+#[derive(Debug, Clone, Default)]
+pub struct SharedAccounts {
+    accounts: Arc<DashMap<ClientId, Account, BuildHasher>>,
+}
+
+impl SharedAccounts {
+    pub fn new() -> Self {
+        SharedAccounts::default()
+    }
+
+    /// Starting from `accounts`, e.g. those of an engine restored from a state
+    pub fn with_accounts(accounts: &AccountMap) -> Self {
+        let shared = SharedAccounts::new();
+        for (client, account) in accounts {
+            shared.accounts.insert(*client, account.clone());
+        }
+        shared
+    }
+
+    pub fn get(&self, client: ClientId) -> Option<Account> {
+        self.accounts.get(&client).map(|account| account.clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
+
+    /// Copy of all accounts, each as of the moment it was copied
+    pub fn snapshot(&self) -> AccountMap {
+        self.accounts
+            .iter()
+            .map(|account| (*account.key(), account.value().clone()))
+            .collect()
+    }
+}
+
+impl EngineObserver for SharedAccounts {
+    fn on_applied(&mut self, update: &AccountUpdate) {
+        let mut account = self
+            .accounts
+            .entry(update.client)
+            .or_insert_with(|| Account::new(update.client));
+        account.available = update.available;
+        account.held = update.held;
+        account.status = update.status;
+    }
+}
+
+#[cfg(all(test, feature = "csv"))]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::io::csv::process_transactions_with_engine;
+    use crate::ParseOptions;
+    use std::thread;
+
+    #[test]
+    fn follows_the_engine_from_another_thread() {
+        let data = "\
+type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+dispute,1,1,
+withdrawal,2,3,1.5
+chargeback,1,1,
+";
+        let mut engine = Engine::new();
+        let shared = SharedAccounts::new();
+        engine.add_observer(shared.clone());
+        let reader = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                // Until the withdrawal shows, without waiting for the engine to finish
+                while shared
+                    .get(2)
+                    .is_none_or(|account| account.available != "3.5".parse().unwrap())
+                {
+                    thread::yield_now();
+                }
+            })
+        };
+        let report = process_transactions_with_engine(
+            &mut csv::Reader::from_reader(data.as_bytes()),
+            &ParseOptions::default(),
+            engine,
+        )
+        .unwrap();
+        reader.join().unwrap();
+        assert_eq!(shared.len(), 2);
+        for (client, account) in &report.accounts {
+            let copy = shared.get(*client).unwrap();
+            assert_eq!(
+                (copy.available, copy.held, copy.status),
+                (account.available, account.held, account.status)
+            );
+        }
+        assert_eq!(shared.snapshot().len(), 2);
+    }
+}
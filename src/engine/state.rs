@@ -0,0 +1,888 @@
+//! Saving what an engine knows and picking up from it later.
+//!
+//! An [`EngineState`] holds the accounts and the deposits and withdrawals disputes can still
+//! reference. It is written in a compact binary format, little endian with decimals in their
+//! 16 byte [`Decimal::serialize`] form and client and tx ids as u64, after a magic and the
+//! version of the format.
+//! Disputes kept by [`crate::UnknownReference::Defer`] are not part of it.
+//!
+//! [`EngineState::write_json`] writes the same state as indented JSON instead, to be reviewed
+//! or, in an emergency, patched by hand and read back with [`EngineState::read_json`].
+use crate::dedup::content_hash;
+use crate::engine::policy::{DisputeState, DisputedTx};
+use crate::engine::{AccountMap, Engine, TransactionIndex};
+use crate::model::{Account, Activity, ClientId, Status, TransactionType, TxId};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 7] = b"TPSTATE";
+
+/// Version of the format, the byte after the magic
+const VERSION: u8 = b'1';
+
+/// Accounts and referenceable transactions of an engine, see [`Engine::into_state`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EngineState {
+    pub accounts: AccountMap,
+    pub transactions: TransactionIndex,
+    /// Text ids of the clients by number, see [`crate::IdFormat::Text`].
+    /// Empty for numeric ids, and in the states of [`Engine::into_state`].
+    pub client_names: BTreeMap<ClientId, String>,
+    /// Text ids of the transactions by number, as [`EngineState::client_names`]
+    pub tx_names: BTreeMap<TxId, String>,
+}
+
+/// Why [`EngineState::merge`] refused a state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeError {
+    /// The smallest tx id both states know, they come from overlapping inputs
+    Overlap(TxId),
+    /// A client whose balances or activity would overflow once summed
+    Overflow(ClientId),
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeError::Overlap(tx) => write!(f, "tx {} is in an earlier input as well", tx),
+            MergeError::Overflow(client) => {
+                write!(f, "the balances of client {} overflow once merged", client)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MergeError {}
+
+impl EngineState {
+    /// Writes the state, accounts ordered by client id and transactions by tx id
+    /// so the same state always gives the same bytes
+    pub fn write_to(&self, output: impl Write) -> io::Result<()> {
+        write_state(
+            &self.accounts,
+            &self.transactions,
+            [&self.client_names, &self.tx_names],
+            output,
+        )
+    }
+
+    /// Reads a state written by [`EngineState::write_to`]. Which transactions each account
+    /// has under dispute is rebuilt from the transactions. Fails with
+    /// [`io::ErrorKind::InvalidData`] on a state no engine leaves behind, see
+    /// [`EngineState::read_json`].
+    pub fn read_from(mut input: impl Read) -> io::Result<Self> {
+        let [magic @ .., version]: [u8; 8] = read(&mut input)?;
+        if &magic != MAGIC {
+            return Err(invalid("not an engine state file"));
+        }
+        if version != VERSION {
+            return Err(invalid(&format!(
+                "an engine state of unknown format version {}",
+                char::from(version)
+            )));
+        }
+        let mut state = EngineState::default();
+        for _ in 0..read_u64(&mut input)? {
+            let mut account = Account::new(read_client(&mut input)?);
+            account.available = read_decimal(&mut input)?;
+            account.held = read_decimal(&mut input)?;
+            account.status = read_status(&mut input)?;
+            account.set_activity(Activity {
+                deposits: read_u64(&mut input)?,
+                withdrawals: read_u64(&mut input)?,
+                deposited: read_decimal(&mut input)?,
+                withdrawn: read_decimal(&mut input)?,
+            });
+            state.accounts.insert(account.client, account);
+        }
+        for _ in 0..read_u64(&mut input)? {
+            let tx = read_u64(&mut input)?;
+            let transaction_type = match read_flag(&mut input)? {
+                false => TransactionType::Deposit,
+                true => TransactionType::Withdrawal,
+            };
+            let client = read_client(&mut input)?;
+            let amount = read_decimal(&mut input)?;
+            let held = read_decimal(&mut input)?;
+            let state_code: [u8; 1] = read(&mut input)?;
+            let dispute_state = match state_code[0] {
+                0 => DisputeState::Processed,
+                1 => DisputeState::Disputed,
+                2 => DisputeState::ChargedBack,
+                3 => DisputeState::Resolved,
+                4 => DisputeState::Reversed,
+                _ => return Err(invalid("unknown dispute state")),
+            };
+            if dispute_state == DisputeState::Disputed {
+                state
+                    .accounts
+                    .get_mut(&client)
+                    .ok_or_else(|| no_account(tx, client))?
+                    .set_disputed(tx, true);
+            }
+            state.transactions.insert(
+                tx,
+                DisputedTx {
+                    transaction_type,
+                    client,
+                    amount,
+                    held,
+                    state: dispute_state,
+                },
+            );
+        }
+        state.client_names = read_names(&mut input)?;
+        state.tx_names = read_names(&mut input)?;
+        state.check()?;
+        Ok(state)
+    }
+
+    /// Writes the state as JSON, accounts ordered by client id and transactions by tx id:
+    /// `{"accounts":[{"client":1,"available":"7.5","held":"2.5","locked":false,"status":"active",
+    /// "activity":{..}}],
+    /// "transactions":[{"tx":1,"type":"deposit","client":1,"amount":"10","held":"2.5",
+    /// "state":"disputed"}]}`. Amounts are strings so they keep their precision.
+    pub fn write_json(&self, mut output: impl Write) -> io::Result<()> {
+        let mut transactions: Vec<_> = self.transactions.iter().collect();
+        transactions.sort_unstable_by_key(|(tx, _)| *tx);
+        let json = JsonState {
+            accounts: crate::engine::sorted_accounts(&self.accounts)
+                .into_iter()
+                .map(|account| JsonAccount {
+                    client: account.client,
+                    available: account.available,
+                    held: account.held,
+                    locked: account.locked(),
+                    status: Some(account.status),
+                    activity: *account.activity(),
+                })
+                .collect(),
+            transactions: transactions
+                .into_iter()
+                .map(|(tx, record)| JsonTransaction {
+                    tx,
+                    transaction_type: match record.transaction_type {
+                        TransactionType::Withdrawal => JsonTransactionType::Withdrawal,
+                        _ => JsonTransactionType::Deposit,
+                    },
+                    client: record.client,
+                    amount: record.amount,
+                    held: record.held,
+                    state: match record.state {
+                        DisputeState::Processed => JsonDisputeState::Processed,
+                        DisputeState::Disputed => JsonDisputeState::Disputed,
+                        DisputeState::ChargedBack => JsonDisputeState::ChargedBack,
+                        DisputeState::Resolved => JsonDisputeState::Resolved,
+                        DisputeState::Reversed => JsonDisputeState::Reversed,
+                    },
+                })
+                .collect(),
+            client_names: self.client_names.clone(),
+            tx_names: self.tx_names.clone(),
+        };
+        serde_json::to_writer_pretty(&mut output, &json)?;
+        output.write_all(b"\n")?;
+        output.flush()
+    }
+
+    /// Reads a state written by [`EngineState::write_json`]. Like [`EngineState::read_from`],
+    /// which transactions each account has under dispute is rebuilt from the transactions in
+    /// the `disputed` state. A hand-patched state is checked like one the engine wrote:
+    /// it fails with [`io::ErrorKind::InvalidData`] naming the offending field on a client or
+    /// tx id that appears twice, negative held funds or amounts, available plus held
+    /// overflowing, a transaction holding more than its amount, or one disputed by a client
+    /// without an account.
+    pub fn read_json(input: impl Read) -> io::Result<Self> {
+        let json: JsonState = serde_json::from_reader(input)?;
+        let mut state = EngineState {
+            client_names: json.client_names,
+            tx_names: json.tx_names,
+            ..EngineState::default()
+        };
+        for saved in json.accounts {
+            let mut account = Account::new(saved.client);
+            account.available = saved.available;
+            account.held = saved.held;
+            account.status = saved.status.unwrap_or(match saved.locked {
+                true => Status::Frozen,
+                false => Status::Active,
+            });
+            account.set_activity(saved.activity);
+            if state.accounts.insert(saved.client, account).is_some() {
+                return Err(invalid(&format!("client {} appears twice", saved.client)));
+            }
+        }
+        for saved in json.transactions {
+            let dispute_state = match saved.state {
+                JsonDisputeState::Processed => DisputeState::Processed,
+                JsonDisputeState::Disputed => DisputeState::Disputed,
+                JsonDisputeState::ChargedBack => DisputeState::ChargedBack,
+                JsonDisputeState::Resolved => DisputeState::Resolved,
+                JsonDisputeState::Reversed => DisputeState::Reversed,
+            };
+            if dispute_state == DisputeState::Disputed {
+                state
+                    .accounts
+                    .get_mut(&saved.client)
+                    .ok_or_else(|| no_account(saved.tx, saved.client))?
+                    .set_disputed(saved.tx, true);
+            }
+            let record = DisputedTx {
+                transaction_type: match saved.transaction_type {
+                    JsonTransactionType::Deposit => TransactionType::Deposit,
+                    JsonTransactionType::Withdrawal => TransactionType::Withdrawal,
+                },
+                client: saved.client,
+                amount: saved.amount,
+                held: saved.held,
+                state: dispute_state,
+            };
+            if state.transactions.insert(saved.tx, record).is_some() {
+                return Err(invalid(&format!("tx {} appears twice", saved.tx)));
+            }
+        }
+        state.check()?;
+        Ok(state)
+    }
+
+    /// Fails on balances and transactions no engine leaves behind, see
+    /// [`EngineState::read_json`]
+    fn check(&self) -> io::Result<()> {
+        for account in crate::engine::sorted_accounts(&self.accounts) {
+            let (client, held) = (account.client, account.held);
+            if held.is_sign_negative() && !held.is_zero() {
+                return Err(invalid(&format!(
+                    "client {}: held {} is negative",
+                    client, held
+                )));
+            }
+            if account.available.checked_add(held).is_none() {
+                return Err(invalid(&format!(
+                    "client {}: available {} plus held {} overflows",
+                    client, account.available, held
+                )));
+            }
+        }
+        let mut transactions: Vec<_> = self.transactions.iter().collect();
+        transactions.sort_unstable_by_key(|(tx, _)| *tx);
+        for (tx, record) in transactions {
+            if record.amount.is_sign_negative() && !record.amount.is_zero() {
+                return Err(invalid(&format!(
+                    "tx {}: amount {} is negative",
+                    tx, record.amount
+                )));
+            }
+            if record.held.is_sign_negative() && !record.held.is_zero()
+                || record.held > record.amount
+            {
+                return Err(invalid(&format!(
+                    "tx {}: held {} isn't within its amount {}",
+                    tx, record.held, record.amount
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the accounts and transactions of `other`, e.g. the state of another shard or
+    /// region. Balances and activity of the same client are summed, and an account takes the
+    /// status of the other state unless it is locked in its own: a chargeback anywhere freezes
+    /// the client. Fails leaving `self` untouched when both states know a tx id, since they
+    /// then come from overlapping inputs, or when a client's sums would overflow.
+    pub fn merge(&mut self, other: EngineState) -> Result<(), MergeError> {
+        let overlap = other
+            .transactions
+            .iter()
+            .map(|(tx, _)| tx)
+            .filter(|tx| self.transactions.contains(*tx))
+            .min();
+        if let Some(tx) = overlap {
+            return Err(MergeError::Overlap(tx));
+        }
+        let mut merged = Vec::new();
+        for theirs in crate::engine::sorted_accounts(&other.accounts) {
+            let Some(ours) = self.accounts.get(&theirs.client) else {
+                continue;
+            };
+            let account = merged_account(ours, theirs).ok_or(MergeError::Overflow(ours.client))?;
+            merged.push(account);
+        }
+        self.transactions.extend(other.transactions);
+        self.client_names.extend(other.client_names);
+        self.tx_names.extend(other.tx_names);
+        for (client, theirs) in other.accounts {
+            self.accounts.entry(client).or_insert(theirs);
+        }
+        for account in merged {
+            self.accounts.insert(account.client, account);
+        }
+        Ok(())
+    }
+}
+
+/// The format of [`EngineState::write_to`], from the parts of a state
+fn write_state(
+    accounts: &AccountMap,
+    transactions: &TransactionIndex,
+    names: [&BTreeMap<u64, String>; 2],
+    mut output: impl Write,
+) -> io::Result<()> {
+    output.write_all(MAGIC)?;
+    output.write_all(&[VERSION])?;
+    output.write_all(&(accounts.len() as u64).to_le_bytes())?;
+    for account in crate::engine::sorted_accounts(accounts) {
+        write_id(&mut output, account.client)?;
+        output.write_all(&account.available.serialize())?;
+        output.write_all(&account.held.serialize())?;
+        output.write_all(&[status_code(account.status)])?;
+        let activity = account.activity();
+        output.write_all(&activity.deposits.to_le_bytes())?;
+        output.write_all(&activity.withdrawals.to_le_bytes())?;
+        output.write_all(&activity.deposited.serialize())?;
+        output.write_all(&activity.withdrawn.serialize())?;
+    }
+    let mut transactions: Vec<_> = transactions.iter().collect();
+    transactions.sort_unstable_by_key(|(tx, _)| *tx);
+    output.write_all(&(transactions.len() as u64).to_le_bytes())?;
+    for (tx, record) in transactions {
+        write_id(&mut output, tx)?;
+        output.write_all(&[match record.transaction_type {
+            TransactionType::Withdrawal => 1,
+            _ => 0,
+        }])?;
+        write_id(&mut output, record.client)?;
+        output.write_all(&record.amount.serialize())?;
+        output.write_all(&record.held.serialize())?;
+        output.write_all(&[match record.state {
+            DisputeState::Processed => 0,
+            DisputeState::Disputed => 1,
+            DisputeState::ChargedBack => 2,
+            DisputeState::Resolved => 3,
+            DisputeState::Reversed => 4,
+        }])?;
+    }
+    // Client names, then tx names
+    for names in names {
+        output.write_all(&(names.len() as u64).to_le_bytes())?;
+        for (id, name) in names {
+            write_id(&mut output, *id)?;
+            output.write_all(&(name.len() as u64).to_le_bytes())?;
+            output.write_all(name.as_bytes())?;
+        }
+    }
+    output.flush()
+}
+
+impl Engine {
+    /// Replaces the accounts and transactions of the engine with `state`,
+    /// keeping its configuration
+    pub fn restore(&mut self, state: EngineState) {
+        self.accounts = state.accounts;
+        self.transactions = state.transactions;
+        self.eviction.restart(&self.transactions);
+        if let Some(undo) = &mut self.undo {
+            undo.restart();
+        }
+    }
+
+    /// What the engine holds besides its accounts and transactions, which an [`EngineState`]
+    /// doesn't keep: disputes deferred or parked for later, the order and evicted tx ids of an
+    /// [`crate::Eviction`], or the withdrawals counting towards a daily limit. `None` if
+    /// restoring a state of the engine continues exactly where it is.
+    pub fn unsaved_state(&self) -> Option<&'static str> {
+        if !self.waiting.is_empty() {
+            Some("disputes waiting for another row of their transaction")
+        } else if self.eviction.evicts() {
+            Some("the order of the transactions kept for eviction")
+        } else if !self.withdrawals.is_empty() {
+            Some("the withdrawals of the last 24 hours")
+        } else {
+            None
+        }
+    }
+
+    /// Writes the state as [`EngineState::write_to`] does, without taking it out of the engine
+    pub fn write_state(&self, output: impl Write) -> io::Result<()> {
+        let none = BTreeMap::new();
+        self.write_state_with_names([&none, &none], output)
+    }
+
+    /// Same as [`Engine::write_state`] with the text ids of the clients and transactions,
+    /// see [`EngineState::client_names`] and [`EngineState::tx_names`]
+    pub(crate) fn write_state_with_names(
+        &self,
+        names: [&BTreeMap<u64, String>; 2],
+        output: impl Write,
+    ) -> io::Result<()> {
+        write_state(&self.accounts, &self.transactions, names, output)
+    }
+
+    /// A copy of the accounts and transactions, e.g. to save elsewhere. [`Engine::begin`]
+    /// rolls back without copying them.
+    pub fn state(&self) -> EngineState {
+        EngineState {
+            accounts: self.accounts.clone(),
+            transactions: self.transactions.clone(),
+            client_names: BTreeMap::new(),
+            tx_names: BTreeMap::new(),
+        }
+    }
+
+    pub fn into_state(self) -> EngineState {
+        EngineState {
+            accounts: self.accounts,
+            transactions: self.transactions,
+            client_names: BTreeMap::new(),
+            tx_names: BTreeMap::new(),
+        }
+    }
+
+    /// Hash of the balances and statuses of all accounts, see [`digest`]
+    pub fn state_digest(&self) -> u128 {
+        digest(&self.accounts)
+    }
+}
+
+/// Hash of the balances and statuses of `accounts`, the same for the same accounts
+/// on every run and machine, so two runs can be compared without comparing their output.
+/// Amounts are compared by value, `1.5` and `1.50` hash the same.
+pub fn digest(accounts: &AccountMap) -> u128 {
+    let mut bytes = Vec::with_capacity(accounts.len() * 41);
+    for account in crate::engine::sorted_accounts(accounts) {
+        bytes.extend_from_slice(&id_bytes(account.client));
+        bytes.extend_from_slice(&account.available.normalize().serialize());
+        bytes.extend_from_slice(&account.held.normalize().serialize());
+        bytes.push(status_code(account.status));
+    }
+    content_hash(&bytes)
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonState {
+    accounts: Vec<JsonAccount>,
+    transactions: Vec<JsonTransaction>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    client_names: BTreeMap<ClientId, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    tx_names: BTreeMap<TxId, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonAccount {
+    client: ClientId,
+    available: Decimal,
+    held: Decimal,
+    // Implied by the status, which states written before it existed lack
+    #[serde(default)]
+    locked: bool,
+    #[serde(default)]
+    status: Option<Status>,
+    #[serde(default)]
+    activity: Activity,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonTransaction {
+    tx: TxId,
+    #[serde(rename = "type")]
+    transaction_type: JsonTransactionType,
+    client: ClientId,
+    amount: Decimal,
+    #[serde(default)]
+    held: Decimal,
+    state: JsonDisputeState,
+}
+
+/// Only deposits and withdrawals can be referenced
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum JsonTransactionType {
+    Deposit,
+    Withdrawal,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum JsonDisputeState {
+    Processed,
+    Disputed,
+    ChargedBack,
+    Resolved,
+    Reversed,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn no_account(tx: TxId, client: ClientId) -> io::Error {
+    invalid(&format!(
+        "tx {}: disputed by client {}, which has no account",
+        tx, client
+    ))
+}
+
+/// `ours` with the balances and activity of `theirs` added, `None` if the balances, their
+/// total or the counts overflow
+fn merged_account(ours: &Account, theirs: &Account) -> Option<Account> {
+    let mut account = ours.clone();
+    account.available = ours.available.checked_add(theirs.available)?;
+    account.held = ours.held.checked_add(theirs.held)?;
+    account.available.checked_add(account.held)?;
+    if !ours.locked() {
+        account.status = theirs.status;
+    }
+    for tx in theirs.disputed() {
+        account.set_disputed(tx, true);
+    }
+    let (activity, other_activity) = (ours.activity(), theirs.activity());
+    account.set_activity(Activity {
+        deposits: activity.deposits.checked_add(other_activity.deposits)?,
+        withdrawals: activity
+            .withdrawals
+            .checked_add(other_activity.withdrawals)?,
+        // Saturating, as the engine sums them
+        deposited: activity.deposited.saturating_add(other_activity.deposited),
+        withdrawn: activity.withdrawn.saturating_add(other_activity.withdrawn),
+    });
+    Some(account)
+}
+
+fn read<const N: usize>(input: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    read(input).map(u64::from_le_bytes)
+}
+
+fn id_bytes(id: u64) -> [u8; 8] {
+    id.to_le_bytes()
+}
+
+fn write_id(output: &mut impl Write, id: u64) -> io::Result<()> {
+    output.write_all(&id_bytes(id))
+}
+
+fn read_client(input: &mut impl Read) -> io::Result<ClientId> {
+    read_u64(input)
+}
+
+/// Text ids by number, see [`EngineState::client_names`]
+fn read_names(input: &mut impl Read) -> io::Result<BTreeMap<u64, String>> {
+    let mut names = BTreeMap::new();
+    for _ in 0..read_u64(input)? {
+        let id = read_u64(input)?;
+        let mut name = vec![0; read_u64(input)? as usize];
+        input.read_exact(&mut name)?;
+        let name = String::from_utf8(name).map_err(|_| invalid("text id isn't UTF-8"))?;
+        names.insert(id, name);
+    }
+    Ok(names)
+}
+
+fn read_flag(input: &mut impl Read) -> io::Result<bool> {
+    match read::<1>(input)? {
+        [0] => Ok(false),
+        [1] => Ok(true),
+        _ => Err(invalid("invalid flag")),
+    }
+}
+
+// Active and frozen were written as the locked flag before there were statuses
+fn status_code(status: Status) -> u8 {
+    match status {
+        Status::Active => 0,
+        Status::Frozen => 1,
+        Status::Closed => 2,
+        Status::UnderReview => 3,
+    }
+}
+
+fn read_status(input: &mut impl Read) -> io::Result<Status> {
+    match read::<1>(input)? {
+        [0] => Ok(Status::Active),
+        [1] => Ok(Status::Frozen),
+        [2] => Ok(Status::Closed),
+        [3] => Ok(Status::UnderReview),
+        _ => Err(invalid("invalid account status")),
+    }
+}
+
+fn read_decimal(input: &mut impl Read) -> io::Result<Decimal> {
+    read(input).map(Decimal::deserialize)
+}
+
+#[cfg(all(test, feature = "csv"))]
+mod tests {
+    use crate::engine::state::{EngineState, MergeError};
+    use crate::io::csv::{process_transactions_with, ParseOptions};
+    use crate::model::{Activity, Status, Transaction, TransactionType};
+    use crate::{Engine, Rejection};
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn state_survives_a_round_trip() {
+        let data = "type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,2.5
+dispute,1,1,4
+deposit,2,3,1
+dispute,2,3,
+chargeback,2,3,";
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let report = process_transactions_with(&mut reader, &ParseOptions::default()).unwrap();
+        let state = report.into_state();
+        let mut bytes = vec![];
+        state.write_to(&mut bytes).unwrap();
+        let restored = EngineState::read_from(&bytes[..]).unwrap();
+        assert_eq!(restored, state);
+        assert_eq!(restored.accounts[&1].disputed().collect::<Vec<_>>(), [1]);
+
+        // A restored engine still knows which transactions disputes can reference
+        let mut engine = Engine::new();
+        engine.restore(restored);
+        let resolve = Transaction {
+            transaction_type: TransactionType::Resolve,
+            client: 1,
+            tx: 1,
+            amount: None,
+        };
+        assert!(engine.apply(resolve).unwrap().is_some());
+        assert_eq!(engine.accounts()[&1].available, Decimal::new(75, 1));
+        let dispute = Transaction {
+            transaction_type: TransactionType::Dispute,
+            client: 2,
+            tx: 3,
+            amount: None,
+        };
+        assert_eq!(engine.apply(dispute), Err(Rejection::ChargedBack(3)));
+    }
+
+    #[test]
+    fn engines_roll_back_to_a_copy_of_their_state() {
+        let row = |transaction_type, tx, amount| Transaction {
+            transaction_type,
+            client: 1,
+            tx,
+            amount,
+        };
+        let mut engine = Engine::new();
+        engine
+            .apply(row(TransactionType::Deposit, 1, Some(Decimal::TEN)))
+            .unwrap();
+        let before = engine.state();
+        engine
+            .apply(row(TransactionType::Dispute, 1, None))
+            .unwrap();
+        engine
+            .apply(row(TransactionType::Deposit, 2, Some(Decimal::ONE)))
+            .unwrap();
+
+        engine.restore(before.clone());
+        assert_eq!(engine.state(), before);
+        assert_eq!(engine.accounts()[&1].available, Decimal::TEN);
+        // The dispute and the second deposit are forgotten
+        assert_eq!(
+            engine.apply(row(TransactionType::Resolve, 1, None)),
+            Ok(None)
+        );
+        engine
+            .apply(row(TransactionType::Deposit, 2, Some(Decimal::ONE)))
+            .unwrap();
+    }
+
+    #[test]
+    fn state_survives_a_json_round_trip() {
+        let data = "type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,2.5
+dispute,1,1,
+deposit,2,3,1
+dispute,2,3,
+chargeback,2,3,";
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let report = process_transactions_with(&mut reader, &ParseOptions::default()).unwrap();
+        let state = report.into_state();
+        let mut json = vec![];
+        state.write_json(&mut json).unwrap();
+        let restored = EngineState::read_json(&json[..]).unwrap();
+        assert_eq!(restored, state);
+        assert_eq!(restored.accounts[&1].disputed().collect::<Vec<_>>(), [1]);
+
+        // Hand-patched: client 2 unlocked and tx 1 no longer disputed
+        let json = String::from_utf8(json).unwrap();
+        let patched = json
+            .replace("\"status\": \"frozen\"", "\"status\": \"active\"")
+            .replace("\"state\": \"disputed\"", "\"state\": \"processed\"");
+        let restored = EngineState::read_json(patched.as_bytes()).unwrap();
+        assert!(!restored.accounts[&2].locked());
+        assert_eq!(restored.accounts[&1].disputed().count(), 0);
+
+        // Written before there were statuses, locked means frozen
+        let old = json.replace(",\n      \"status\": \"frozen\"", "");
+        let restored = EngineState::read_json(old.as_bytes()).unwrap();
+        assert_eq!(restored.accounts[&2].status, Status::Frozen);
+
+        let twice = r#"{"accounts":[
+            {"client":1,"available":"1","held":"0","locked":false},
+            {"client":1,"available":"2","held":"0","locked":false}],"transactions":[]}"#;
+        let err = EngineState::read_json(twice.as_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), "client 1 appears twice");
+        let dispute = r#"{"accounts":[],"transactions":[
+            {"tx":1,"type":"dispute","client":1,"amount":"1","state":"processed"}]}"#;
+        assert!(EngineState::read_json(dispute.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn patched_json_states_are_checked() {
+        let read = |accounts: &str, transactions: &str| {
+            let json = format!(r#"{{"accounts":[{accounts}],"transactions":[{transactions}]}}"#);
+            let err = EngineState::read_json(json.as_bytes()).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+            err.to_string()
+        };
+        let account = |available: &str, held: &str| {
+            format!(r#"{{"client":1,"available":"{available}","held":"{held}"}}"#)
+        };
+        let deposit = |amount: &str, held: &str, state: &str| {
+            format!(
+                r#"{{"tx":4,"type":"deposit","client":1,"amount":"{amount}","held":"{held}","state":"{state}"}}"#
+            )
+        };
+        assert_eq!(
+            read(&account("1", "-1"), ""),
+            "client 1: held -1 is negative"
+        );
+        let max = Decimal::MAX.to_string();
+        assert_eq!(
+            read(&account(&max, &max), ""),
+            format!("client 1: available {max} plus held {max} overflows")
+        );
+        assert_eq!(
+            read(&account("0", "0"), &deposit("-5", "0", "processed")),
+            "tx 4: amount -5 is negative"
+        );
+        assert_eq!(
+            read(&account("0", "12"), &deposit("10", "12", "disputed")),
+            "tx 4: held 12 isn't within its amount 10"
+        );
+        assert_eq!(
+            read("", &deposit("10", "10", "disputed")),
+            "tx 4: disputed by client 1, which has no account"
+        );
+    }
+
+    #[test]
+    fn digests_compare_balances() {
+        let data = "type,client,tx,amount
+deposit,2,1,1.50
+deposit,1,2,3
+withdrawal,2,3,0.5";
+        let process = |data: &str| {
+            let mut reader = csv::Reader::from_reader(data.as_bytes());
+            process_transactions_with(&mut reader, &ParseOptions::default()).unwrap()
+        };
+        let report = process(data);
+        let digest = crate::engine::state::digest(&report.accounts);
+        let mut engine = Engine::new();
+        engine.restore(report.into_state());
+        assert_eq!(engine.state_digest(), digest);
+
+        // The same balances reached differently hash the same, other balances don't
+        let same = "type,client,tx,amount
+deposit,1,7,3.000
+deposit,2,8,1";
+        assert_eq!(
+            crate::engine::state::digest(&process(same).accounts),
+            digest
+        );
+        let other = "type,client,tx,amount
+deposit,1,7,3
+deposit,2,8,1.0001";
+        assert_ne!(
+            crate::engine::state::digest(&process(other).accounts),
+            digest
+        );
+    }
+
+    #[test]
+    fn states_of_shards_merge() {
+        let process = |data: &str| {
+            let mut reader = csv::Reader::from_reader(data.as_bytes());
+            let options = ParseOptions::default();
+            process_transactions_with(&mut reader, &options)
+                .unwrap()
+                .into_state()
+        };
+        let mut merged = process(
+            "type,client,tx,amount
+deposit,1,1,10
+deposit,2,2,5
+dispute,2,2,",
+        );
+        let other = process(
+            "type,client,tx,amount
+deposit,1,3,1.5
+deposit,3,4,2
+dispute,1,3,
+chargeback,1,3,",
+        );
+        merged.merge(other.clone()).unwrap();
+        let account = &merged.accounts[&1];
+        assert_eq!(account.available, Decimal::new(10, 0));
+        assert!(account.locked());
+        assert_eq!(account.activity().deposits, 2);
+        assert_eq!(merged.accounts[&2].disputed().collect::<Vec<_>>(), [2]);
+        assert_eq!(merged.accounts.len(), 3);
+        assert_eq!(merged.transactions.len(), 4);
+
+        // Overlapping inputs
+        let before = merged.clone();
+        assert_eq!(merged.merge(other), Err(MergeError::Overlap(3)));
+        assert_eq!(merged, before);
+
+        // Counts that no longer fit
+        let mut busy = EngineState::default();
+        let mut account = merged.accounts[&3].clone();
+        account.set_activity(Activity {
+            deposits: u64::MAX,
+            ..*account.activity()
+        });
+        busy.accounts.insert(3, account);
+        assert_eq!(merged.merge(busy), Err(MergeError::Overflow(3)));
+        assert_eq!(merged, before);
+    }
+
+    #[test]
+    fn other_files_are_refused() {
+        assert!(EngineState::read_from(&b"type,client,tx,amount"[..]).is_err());
+        let mut bytes = vec![];
+        EngineState::default().write_to(&mut bytes).unwrap();
+        assert!(bytes.starts_with(b"TPSTATE1"));
+        bytes[7] = b'2';
+        let error = EngineState::read_from(&bytes[..]).unwrap_err();
+        assert!(error.to_string().contains("format version 2"));
+    }
+
+    #[test]
+    fn client_names_are_saved() {
+        let mut state = EngineState::default();
+        state.client_names.insert(7, "acct-7".to_string());
+        state.tx_names.insert(8, "tx-8".to_string());
+        let mut bytes = vec![];
+        state.write_to(&mut bytes).unwrap();
+        assert_eq!(EngineState::read_from(&bytes[..]).unwrap(), state);
+        let mut json = vec![];
+        state.write_json(&mut json).unwrap();
+        assert_eq!(EngineState::read_json(&json[..]).unwrap(), state);
+    }
+}
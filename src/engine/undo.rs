@@ -0,0 +1,133 @@
+//! Taking back the rows applied since a point, e.g. a watched file that fails halfway in
+//! strict mode, without copying the whole state up front.
+//!
+//! Between [`Engine::begin`] and [`Engine::commit`] the engine keeps the account and the
+//! transaction record each row touches as they were before, and holds back what it tells
+//! its observers. [`Engine::rollback`] puts the old ones back and drops what was held back,
+//! so webhooks and alerts never hear of rows that didn't stay applied.
+use crate::engine::policy::DisputedTx;
+use crate::engine::{AccountMap, BuildHasher, Engine, EngineObserver, Rejection, TransactionIndex};
+use crate::model::{Account, AccountUpdate, ClientId, Transaction, TxId, UnknownTransaction};
+use std::collections::HashMap;
+
+/// What observers are told, see [`EngineObserver`]
+#[derive(Debug)]
+pub(crate) enum Notice {
+    /// The account is that of a chargeback which locked it
+    Applied(AccountUpdate, Option<Account>),
+    Rejected(Transaction, Rejection),
+    UnknownType(UnknownTransaction),
+}
+
+impl Notice {
+    pub(crate) fn send(&self, observers: &mut [Box<dyn EngineObserver>]) {
+        for observer in observers {
+            match self {
+                Notice::Applied(update, locked) => {
+                    observer.on_applied(update);
+                    if let Some(account) = locked {
+                        observer.on_account_locked(account, update.tx);
+                    }
+                }
+                Notice::Rejected(transaction, rejection) => {
+                    observer.on_rejected(transaction, rejection)
+                }
+                Notice::UnknownType(transaction) => observer.on_unknown_type(transaction),
+            }
+        }
+    }
+}
+
+/// Accounts and transaction records as they were before the first row changing them,
+/// `None` for those that didn't exist, and the notices held back since
+#[derive(Debug, Default)]
+pub(crate) struct UndoLog {
+    accounts: HashMap<ClientId, Option<Account>, BuildHasher>,
+    transactions: HashMap<TxId, Option<DisputedTx>, BuildHasher>,
+    notices: Vec<Notice>,
+}
+
+impl UndoLog {
+    /// Keeps the account of `client` and the record of `tx`, unless kept already
+    pub(crate) fn record(
+        &mut self,
+        client: ClientId,
+        tx: TxId,
+        accounts: &AccountMap,
+        transactions: &TransactionIndex,
+    ) {
+        self.accounts
+            .entry(client)
+            .or_insert_with(|| accounts.get(&client).cloned());
+        self.transactions
+            .entry(tx)
+            .or_insert_with(|| transactions.get(tx).copied());
+    }
+
+    /// Forgets what was kept, e.g. once a state replaced it, holding on to the notices
+    pub(crate) fn restart(&mut self) {
+        self.accounts.clear();
+        self.transactions.clear();
+    }
+
+    /// Puts back what was kept
+    fn undo(self, accounts: &mut AccountMap, transactions: &mut TransactionIndex) {
+        for (client, account) in self.accounts {
+            match account {
+                Some(account) => accounts.insert(client, account),
+                None => accounts.remove(&client),
+            };
+        }
+        for (tx, record) in self.transactions {
+            match record {
+                Some(record) => transactions.insert(tx, record),
+                None => transactions.remove(tx),
+            };
+        }
+    }
+}
+
+impl Engine {
+    /// Starts keeping what the rows applied from now on change, to keep them with
+    /// [`Engine::commit`] or take them back with [`Engine::rollback`]. Observers only hear of
+    /// the rows once they are committed. Beginning again before either keeps the first start.
+    pub fn begin(&mut self) {
+        if self.undo.is_none() {
+            self.undo = Some(UndoLog::default());
+        }
+    }
+
+    /// Keeps the rows applied since [`Engine::begin`] and tells the observers about them
+    pub fn commit(&mut self) {
+        if let Some(undo) = self.undo.take() {
+            for notice in &undo.notices {
+                notice.send(&mut self.observers);
+            }
+        }
+    }
+
+    /// Takes back the rows applied since [`Engine::begin`], observers never hear of them.
+    /// A state restored in between stays, only the rows applied since are taken back.
+    /// Fails if the engine holds what a rollback can't take back, see
+    /// [`Engine::unsaved_state`], leaving the rows applied and still to commit or roll back.
+    pub fn rollback(&mut self) -> Result<(), &'static str> {
+        if let Some(unsaved) = self.unsaved_state() {
+            return Err(unsaved);
+        }
+        if let Some(undo) = self.undo.take() {
+            undo.undo(&mut self.accounts, &mut self.transactions);
+        }
+        Ok(())
+    }
+
+    /// Tells the observers, or holds it back until [`Engine::commit`]
+    pub(crate) fn notify(&mut self, notice: impl FnOnce() -> Notice) {
+        if self.observers.is_empty() {
+            return;
+        }
+        match &mut self.undo {
+            Some(undo) => undo.notices.push(notice()),
+            None => notice().send(&mut self.observers),
+        }
+    }
+}
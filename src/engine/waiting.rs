@@ -0,0 +1,167 @@
+//! Dispute, Resolve and Chargeback rows that can't apply yet, waiting for another row of the
+//! transaction they reference: those of tx ids not seen yet with
+//! [`crate::UnknownReference::Defer`], and those the dispute policy ignored with
+//! [`crate::Engine::set_retry_out_of_order`].
+//!
+//! Both wait in one queue, which an [`crate::Eviction`] bounds like the transactions kept:
+//! the rows waiting longest are dropped once there are more than it keeps or they waited
+//! longer than its window, and count as [lost](crate::Engine::lost_to_eviction).
+use crate::engine::BuildHasher;
+use crate::model::{Transaction, TxId};
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+
+/// Rows of one tx id, in the order they arrived
+#[derive(Debug, Clone)]
+struct Rows {
+    // When the first of them started waiting, if the time was known
+    since: Option<i64>,
+    rows: Vec<Transaction>,
+}
+
+/// Waiting rows by the tx id they reference
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Waiting {
+    by_tx: HashMap<TxId, Rows, BuildHasher>,
+    // Tx ids in the order they started waiting, with the time they did. Entries whose tx
+    // no longer waits since that time are stale and skipped.
+    order: VecDeque<(Option<i64>, TxId)>,
+    len: usize,
+}
+
+impl Waiting {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether rows wait for `tx`
+    pub(crate) fn contains(&self, tx: TxId) -> bool {
+        self.by_tx.contains_key(&tx)
+    }
+
+    /// Tx ids with rows waiting, in no particular order
+    pub(crate) fn txs(&self) -> Vec<TxId> {
+        self.by_tx.keys().copied().collect()
+    }
+
+    /// Adds `transaction` behind the other rows of its tx id, as of `now`
+    pub(crate) fn push(&mut self, transaction: Transaction, now: Option<i64>) {
+        let tx = transaction.tx;
+        let waiting = self.by_tx.entry(tx).or_insert_with(|| {
+            self.order.push_back((now, tx));
+            Rows {
+                since: now,
+                rows: vec![],
+            }
+        });
+        waiting.rows.push(transaction);
+        self.len += 1;
+        // Stale entries are dropped once they make up most of the order
+        if self.order.len() > 2 * self.by_tx.len() + 64 {
+            let by_tx = &self.by_tx;
+            self.order
+                .retain(|(since, tx)| by_tx.get(tx).is_some_and(|rows| rows.since == *since));
+        }
+    }
+
+    /// Takes the rows waiting for `tx` to retry them. The tx keeps its place while they are
+    /// retried, rows pushed again wait since the time they first did, see [`Waiting::done`].
+    pub(crate) fn take(&mut self, tx: TxId) -> Vec<Transaction> {
+        let Some(waiting) = self.by_tx.get_mut(&tx) else {
+            return vec![];
+        };
+        self.len -= waiting.rows.len();
+        mem::take(&mut waiting.rows)
+    }
+
+    /// Forgets `tx` after its rows were retried, unless some of them wait again
+    pub(crate) fn done(&mut self, tx: TxId) {
+        if self
+            .by_tx
+            .get(&tx)
+            .is_some_and(|waiting| waiting.rows.is_empty())
+        {
+            self.by_tx.remove(&tx);
+        }
+    }
+
+    /// Takes the rows of `tx` for good
+    pub(crate) fn remove(&mut self, tx: TxId) -> Vec<Transaction> {
+        let rows = self.take(tx);
+        self.by_tx.remove(&tx);
+        rows
+    }
+
+    /// Drops the rows of the tx ids waiting longest while `expired` says so of the number of
+    /// rows waiting and the time the oldest started, returning how many were dropped and
+    /// calling `dropped` with their tx ids.
+    /// Tx ids waiting since before the time was known count as waiting since `now`.
+    pub(crate) fn drop_oldest(
+        &mut self,
+        now: Option<i64>,
+        mut expired: impl FnMut(usize, Option<i64>) -> bool,
+        mut dropped_tx: impl FnMut(TxId),
+    ) -> usize {
+        let mut dropped = 0;
+        while let Some(&(since, tx)) = self.order.front() {
+            let Some(waiting) = self.by_tx.get_mut(&tx).filter(|rows| rows.since == since) else {
+                self.order.pop_front();
+                continue;
+            };
+            if since.is_none() && now.is_some() {
+                waiting.since = now;
+                self.order[0].0 = now;
+                continue;
+            }
+            if !expired(self.len, since) {
+                break;
+            }
+            self.order.pop_front();
+            dropped += self.remove(tx).len();
+            dropped_tx(tx);
+        }
+        dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::TransactionType;
+
+    fn dispute(tx: TxId) -> Transaction {
+        Transaction {
+            transaction_type: TransactionType::Dispute,
+            client: 1,
+            tx,
+            amount: None,
+        }
+    }
+
+    #[test]
+    fn rows_retried_keep_their_place() {
+        let mut waiting = Waiting::default();
+        waiting.push(dispute(1), Some(10));
+        waiting.push(dispute(2), Some(20));
+        waiting.push(dispute(1), Some(30));
+        assert_eq!(waiting.take(1).len(), 2);
+        waiting.push(dispute(1), Some(40));
+        waiting.done(1);
+        assert_eq!(waiting.len, 2);
+
+        // Tx 1 still waits since 10
+        let mut dropped = vec![];
+        assert_eq!(
+            waiting.drop_oldest(Some(40), |_, since| since < Some(15), |tx| dropped.push(tx)),
+            1
+        );
+        assert_eq!(waiting.txs(), [2]);
+        assert!(!waiting.contains(1));
+        assert_eq!(
+            waiting.drop_oldest(Some(40), |len, _| len > 0, |tx| dropped.push(tx)),
+            1
+        );
+        assert_eq!(dropped, [1, 2]);
+        assert!(waiting.is_empty());
+    }
+}
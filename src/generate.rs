@@ -0,0 +1,320 @@
+//! Synthetic transaction data for benchmarks and stress tests.
+//!
+//! The [`Generator`] is seeded, so the same configuration always produces the same rows.
+//! [`simulate`] replays them through an engine, for load tests and for comparing
+//! the results of two versions of the engine by their [`Simulation::digest`].
+use crate::engine::Engine;
+use crate::model::{ClientId, Transaction, TransactionType, TxId};
+use crate::report::ProcessReport;
+use rand::Rng;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use rust_decimal::Decimal;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+#[cfg(feature = "csv")]
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Number of recent deposits kept around as dispute candidates.
+/// Disputes in real feeds tend to follow their deposit closely.
+const DISPUTE_WINDOW: usize = 10_000;
+
+/// Largest generated deposit in ten-thousandths, i.e. 1000.0000
+const MAX_DEPOSIT: i64 = 10_000_000;
+
+/// Shape of the generated data
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratorConfig {
+    /// Number of distinct clients, ids run from 1 to `clients`
+    pub clients: ClientId,
+    /// Number of rows to generate
+    pub rows: u64,
+    /// Probability of a row opening a dispute, the same rate is used to settle open disputes.
+    /// Rates outside `0.0..=1.0` are clamped to it, NaN counts as 0.
+    pub dispute_rate: f64,
+    /// Share of settled disputes that end in a chargeback rather than a resolve, clamped
+    /// like the dispute rate
+    pub chargeback_rate: f64,
+    pub seed: u64,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        GeneratorConfig {
+            clients: 1000,
+            rows: 10_000,
+            dispute_rate: 0.01,
+            chargeback_rate: 0.1,
+            seed: 0,
+        }
+    }
+}
+
+/// Iterator over a reproducible stream of internally consistent transactions.
+///
+/// Withdrawals stay within the balance the client has deposited so far,
+/// disputes only reference recent deposits of the disputing client
+/// and are later either resolved or charged back.
+pub struct Generator {
+    config: GeneratorConfig,
+    rng: ChaCha8Rng,
+    emitted: u64,
+    next_tx: TxId,
+    // Indexed by client id
+    balances: Vec<Decimal>,
+    // (client, tx) of recent undisputed deposits
+    recent_deposits: VecDeque<(ClientId, TxId)>,
+    // (client, tx) of disputes that have not been settled yet
+    open_disputes: Vec<(ClientId, TxId)>,
+}
+
+impl Generator {
+    pub fn new(config: GeneratorConfig) -> Self {
+        let clients = config.clients.max(1);
+        Generator {
+            rng: ChaCha8Rng::seed_from_u64(config.seed),
+            emitted: 0,
+            next_tx: 1,
+            balances: vec![Decimal::ZERO; clients as usize + 1],
+            recent_deposits: VecDeque::with_capacity(DISPUTE_WINDOW),
+            open_disputes: vec![],
+            config: GeneratorConfig { clients, ..config },
+        }
+    }
+
+    /// Writes all remaining rows as CSV, including the header
+    #[cfg(feature = "csv")]
+    pub fn write_csv<W: io::Write>(self, writer: W) -> Result<(), csv::Error> {
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record(["type", "client", "tx", "amount"])?;
+        for transaction in self {
+            let amount = transaction
+                .amount
+                .map(|a| a.to_string())
+                .unwrap_or_default();
+            writer.write_record([
+                transaction.transaction_type.to_string(),
+                transaction.client.to_string(),
+                transaction.tx.to_string(),
+                amount,
+            ])?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn deposit(&mut self, client: ClientId) -> Transaction {
+        let amount = Decimal::new(self.rng.gen_range(1..=MAX_DEPOSIT), 4);
+        let tx = self.take_tx();
+        self.balances[client as usize] += amount;
+        if self.recent_deposits.len() == DISPUTE_WINDOW {
+            self.recent_deposits.pop_front();
+        }
+        self.recent_deposits.push_back((client, tx));
+        Transaction {
+            transaction_type: TransactionType::Deposit,
+            client,
+            tx,
+            amount: Some(amount),
+        }
+    }
+
+    fn withdrawal(&mut self, client: ClientId) -> Transaction {
+        let balance = self.balances[client as usize];
+        let max = (balance * Decimal::new(10_000, 0))
+            .trunc()
+            .try_into()
+            .unwrap_or(MAX_DEPOSIT);
+        let amount = Decimal::new(self.rng.gen_range(1..=max), 4);
+        self.balances[client as usize] -= amount;
+        Transaction {
+            transaction_type: TransactionType::Withdrawal,
+            client,
+            tx: self.take_tx(),
+            amount: Some(amount),
+        }
+    }
+
+    fn take_tx(&mut self) -> TxId {
+        let tx = self.next_tx;
+        self.next_tx = self.next_tx.wrapping_add(1);
+        tx
+    }
+}
+
+/// `rate` as a probability `gen_bool` takes, which panics on NaN
+fn probability(rate: f64) -> f64 {
+    match rate.is_nan() {
+        true => 0.0,
+        false => rate.clamp(0.0, 1.0),
+    }
+}
+
+impl Iterator for Generator {
+    type Item = Transaction;
+
+    fn next(&mut self) -> Option<Transaction> {
+        if self.emitted >= self.config.rows {
+            return None;
+        }
+        self.emitted += 1;
+
+        let rate = probability(self.config.dispute_rate);
+        if !self.open_disputes.is_empty() && self.rng.gen_bool(rate) {
+            let index = self.rng.gen_range(0..self.open_disputes.len());
+            let (client, tx) = self.open_disputes.swap_remove(index);
+            let chargeback_rate = probability(self.config.chargeback_rate);
+            let transaction_type = if self.rng.gen_bool(chargeback_rate) {
+                TransactionType::Chargeback
+            } else {
+                TransactionType::Resolve
+            };
+            return Some(Transaction {
+                transaction_type,
+                client,
+                tx,
+                amount: None,
+            });
+        }
+        if !self.recent_deposits.is_empty() && self.rng.gen_bool(rate) {
+            let index = self.rng.gen_range(0..self.recent_deposits.len());
+            let (client, tx) = self.recent_deposits.swap_remove_back(index)?;
+            self.open_disputes.push((client, tx));
+            return Some(Transaction {
+                transaction_type: TransactionType::Dispute,
+                client,
+                tx,
+                amount: None,
+            });
+        }
+
+        let client = self.rng.gen_range(1..=self.config.clients);
+        // Withdraw only what the client has, otherwise deposit
+        if self.balances[client as usize] >= Decimal::new(1, 4) && self.rng.gen_bool(0.4) {
+            Some(self.withdrawal(client))
+        } else {
+            Some(self.deposit(client))
+        }
+    }
+}
+
+/// What replaying generated transactions produced
+#[derive(Debug)]
+pub struct Simulation {
+    pub report: ProcessReport,
+    /// Transactions replayed
+    pub transactions: u64,
+    /// See [`crate::Engine::state_digest`]
+    pub digest: u128,
+    /// Time spent generating and applying the transactions
+    pub elapsed: Duration,
+}
+
+/// Replays the transactions of `config` through `engine`, collecting rejections like
+/// [`crate::ParseMode::Collecting`]. The same configuration and engine settings always give
+/// the same report and digest.
+pub fn simulate(config: GeneratorConfig, engine: Engine) -> Simulation {
+    let transactions = config.rows;
+    let start = Instant::now();
+    let report = engine.process_iter(Generator::new(config).map(Ok::<_, Infallible>));
+    Simulation {
+        elapsed: start.elapsed(),
+        digest: report.state_digest(),
+        transactions,
+        report,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generate::{simulate, Generator, GeneratorConfig};
+    use crate::model::{ClientId, Transaction, TransactionType, TxId};
+    use std::collections::HashMap;
+
+    fn config() -> GeneratorConfig {
+        GeneratorConfig {
+            clients: 10,
+            rows: 5_000,
+            dispute_rate: 0.05,
+            chargeback_rate: 0.1,
+            seed: 42,
+        }
+    }
+
+    #[test]
+    fn same_seed_same_rows() {
+        let first: Vec<Transaction> = Generator::new(config()).collect();
+        let second: Vec<Transaction> = Generator::new(config()).collect();
+        assert_eq!(first.len(), 5_000);
+        assert_eq!(first, second);
+        let other: Vec<Transaction> = Generator::new(GeneratorConfig {
+            seed: 7,
+            ..config()
+        })
+        .collect();
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn rates_out_of_range_are_clamped() {
+        let rows = |dispute_rate, chargeback_rate| {
+            Generator::new(GeneratorConfig {
+                dispute_rate,
+                chargeback_rate,
+                ..config()
+            })
+            .filter(|transaction| transaction.amount.is_none())
+            .count()
+        };
+        assert_eq!(rows(f64::NAN, f64::NAN), 0);
+        assert_eq!(rows(-1.0, 0.5), 0);
+        assert!(rows(f64::INFINITY, f64::NAN) > 0);
+    }
+
+    #[test]
+    fn disputes_reference_own_deposits() {
+        let mut deposits: HashMap<TxId, ClientId> = HashMap::new();
+        let mut disputes = 0;
+        for transaction in Generator::new(config()) {
+            match transaction.transaction_type {
+                TransactionType::Deposit => {
+                    deposits.insert(transaction.tx, transaction.client);
+                }
+                TransactionType::Withdrawal => {}
+                _ => {
+                    disputes += 1;
+                    assert_eq!(deposits.get(&transaction.tx), Some(&transaction.client));
+                }
+            }
+        }
+        assert!(disputes > 0);
+    }
+
+    #[test]
+    fn simulations_are_reproducible() {
+        let first = simulate(config(), crate::Engine::new());
+        assert_eq!(first.transactions, 5_000);
+        assert!(first.report.errors.is_empty(), "{:?}", first.report.errors);
+        assert_eq!(
+            simulate(config(), crate::Engine::new()).digest,
+            first.digest
+        );
+
+        // Only chargebacks lock accounts
+        let locked = |chargeback_rate| {
+            let config = GeneratorConfig {
+                chargeback_rate,
+                ..config()
+            };
+            let report = simulate(config, crate::Engine::new()).report;
+            report
+                .accounts
+                .values()
+                .filter(|account| account.locked())
+                .count()
+        };
+        assert_eq!(locked(0.0), 0);
+        assert!(locked(1.0) > 0);
+    }
+}
@@ -0,0 +1,224 @@
+//! How the ids of the input are written, and the names behind text ids.
+//!
+//! [`ClientId`](crate::ClientId)s are `u64` whatever the input holds. An [`IdFormat`] says how
+//! wide the numbers of an input may be, rows with wider ones being malformed, or that the ids
+//! are text. Text ids are mapped to numbers by hashing, so the same name gets the same number
+//! in every run, shard and thread without anything shared, and the names are kept in
+//! [`IdNames`] to be written out again. Those of tx ids only matter while the engine keeps
+//! the transaction, [`crate::Engine::set_tx_names`] drops the others.
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// How ids are written in the input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdFormat {
+    /// Numbers up to [`u16::MAX`], the client ids of the specification
+    U16,
+    /// Numbers up to [`u32::MAX`]
+    U32,
+    /// Numbers up to [`u64::MAX`]
+    U64,
+    /// Any text, e.g. `acct-00042`, read as its [`text_id`] and remembered in the names
+    Text(IdNames),
+}
+
+impl IdFormat {
+    /// The largest number an id of this format can be
+    pub fn max(&self) -> u64 {
+        match self {
+            IdFormat::U16 => u16::MAX.into(),
+            IdFormat::U32 => u32::MAX.into(),
+            IdFormat::U64 | IdFormat::Text(_) => u64::MAX,
+        }
+    }
+
+    /// Bits of the numbers of this format
+    pub fn bits(&self) -> u32 {
+        u64::BITS - self.max().leading_zeros()
+    }
+
+    /// The names of text ids, `None` for numbers
+    pub fn names(&self) -> Option<&IdNames> {
+        match self {
+            IdFormat::Text(names) => Some(names),
+            _ => None,
+        }
+    }
+
+    /// Checks a number read from the input fits the format. Text ids are numbers already.
+    pub(crate) fn check(&self, what: &str, id: u64) -> Result<(), String> {
+        match id > self.max() {
+            true => Err(format!(
+                "{} id {} is too large, at most {}",
+                what,
+                id,
+                self.max()
+            )),
+            false => Ok(()),
+        }
+    }
+}
+
+/// The number a text id is read as: the first 8 bytes of its SHA-256, little endian
+pub fn text_id(name: &str) -> u64 {
+    let digest = Sha256::digest(name.as_bytes());
+    u64::from_le_bytes(digest[..8].try_into().expect("SHA-256 has 32 bytes"))
+}
+
+// Locks the names are split over by number, so threads reading text ids seldom wait
+// for each other
+const SHARDS: usize = 64;
+
+/// Text ids seen so far by number, shared by the clones of an [`IdFormat::Text`]
+#[derive(Clone)]
+pub struct IdNames(Arc<[Mutex<BTreeMap<u64, String>>; SHARDS]>);
+
+impl Default for IdNames {
+    fn default() -> Self {
+        IdNames(Arc::new(std::array::from_fn(|_| Mutex::default())))
+    }
+}
+
+impl IdNames {
+    pub fn new() -> Self {
+        IdNames::default()
+    }
+
+    /// The names sharing a lock with `id`
+    fn shard(&self, id: u64) -> MutexGuard<'_, BTreeMap<u64, String>> {
+        self.0[(id % SHARDS as u64) as usize]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn shards(&self) -> impl Iterator<Item = MutexGuard<'_, BTreeMap<u64, String>>> {
+        self.0
+            .iter()
+            .map(|shard| shard.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// The number of the text id `name`, remembering the name. Fails if another name
+    /// seen before hashes to the same number, which 64 bits make unlikely for any real input.
+    /// Names forgotten since aren't checked.
+    pub fn id(&self, name: &str) -> Result<u64, String> {
+        let id = text_id(name);
+        let mut names = self.shard(id);
+        match names.get(&id) {
+            Some(seen) if seen != name => Err(format!("ids {} and {} collide", seen, name)),
+            Some(_) => Ok(id),
+            None => {
+                names.insert(id, name.to_string());
+                Ok(id)
+            }
+        }
+    }
+
+    /// The text id read as `id`, if one was
+    pub fn name(&self, id: u64) -> Option<String> {
+        self.shard(id).get(&id).cloned()
+    }
+
+    /// Forgets the name of `id`, e.g. of a transaction the engine no longer keeps
+    pub fn forget(&self, id: u64) {
+        self.shard(id).remove(&id);
+    }
+
+    /// Keeps only the names of the ids `keep` says so of
+    pub fn retain(&self, mut keep: impl FnMut(u64) -> bool) {
+        for mut names in self.shards() {
+            names.retain(|id, _| keep(*id));
+        }
+    }
+
+    /// All names remembered by number
+    pub fn to_map(&self) -> BTreeMap<u64, String> {
+        self.shards()
+            .flat_map(|names| names.clone().into_iter())
+            .collect()
+    }
+
+    /// Remembers names read before, e.g. from a saved state
+    pub fn extend(&self, names: impl IntoIterator<Item = (u64, String)>) {
+        for (id, name) in names {
+            self.shard(id).insert(id, name);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards().map(|names| names.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Names read before, e.g. [`crate::EngineState::client_names`]
+impl From<BTreeMap<u64, String>> for IdNames {
+    fn from(names: BTreeMap<u64, String>) -> Self {
+        let known = IdNames::new();
+        known.extend(names);
+        known
+    }
+}
+
+/// Holds customer ids, only their number is logged
+impl fmt::Debug for IdNames {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "IdNames({} names)", self.len())
+    }
+}
+
+/// The same names, not just equal ones
+impl PartialEq for IdNames {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for IdNames {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_bound_the_numbers() {
+        assert_eq!(IdFormat::U16.bits(), 16);
+        assert_eq!(IdFormat::U32.bits(), 32);
+        assert_eq!(IdFormat::Text(IdNames::new()).bits(), 64);
+        assert!(IdFormat::U16.check("client", 65535).is_ok());
+        assert_eq!(
+            IdFormat::U16.check("client", 65536),
+            Err("client id 65536 is too large, at most 65535".to_string())
+        );
+        assert!(IdFormat::U64.check("client", u64::MAX).is_ok());
+    }
+
+    #[test]
+    fn text_ids_keep_their_names() {
+        let names = IdNames::new();
+        let id = names.id("acct-42").unwrap();
+        assert_eq!(id, text_id("acct-42"));
+        assert_eq!(names.id("acct-42"), Ok(id));
+        assert_ne!(names.id("acct-43").unwrap(), id);
+        assert_eq!(names.name(id).as_deref(), Some("acct-42"));
+        assert_eq!(names.name(7), None);
+
+        let clone = names.clone();
+        clone.id("acct-44").unwrap();
+        assert_eq!(names.len(), 3);
+        assert_eq!(clone, names);
+        assert_ne!(IdNames::new(), names);
+
+        names.extend([(text_id("old"), "old".to_string())]);
+        assert_eq!(names.to_map()[&text_id("old")], "old");
+
+        names.forget(id);
+        assert_eq!(names.name(id), None);
+        names.retain(|id| id == text_id("old"));
+        assert_eq!(names.to_map().into_values().collect::<Vec<_>>(), ["old"]);
+    }
+}
@@ -0,0 +1,1097 @@
+//! Reading transactions from CSV.
+use crate::engine::state::EngineState;
+use crate::engine::{AccountMap, Engine};
+use crate::ids::{IdFormat, IdNames};
+pub use crate::io::{ErrorCode, ParseMode, RowError};
+use crate::model::{
+    AccountUpdate, ClientId, Metadata, Transaction, TransactionType, TxId, UnknownTransaction,
+};
+use crate::pseudonym::Pseudonymizer;
+use crate::report::ProcessReport;
+use csv::{ByteRecord, Reader};
+use rust_decimal::Decimal;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::str::FromStr;
+use tracing::warn;
+
+/// What happens to rows of a type that is none of the [`TransactionType`]s
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownTypes {
+    /// Treat them as malformed rows, handled by the [`ParseMode`]
+    #[default]
+    Reject,
+    /// Skip them without an error whatever the [`ParseMode`], counting them by type
+    /// in [`ProcessReport::unknown_types`] and passing them to
+    /// [`crate::EngineObserver::on_unknown_type`]
+    Skip,
+}
+
+/// How amounts are written in the input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AmountFormat {
+    /// Plain decimals such as `1234.56`
+    #[default]
+    Plain,
+    /// `.` as decimal separator, e.g. `1,234.56` or `$1,234.56`
+    DecimalPoint,
+    /// `,` as decimal separator, e.g. `1.234,56` or `1 234,56 €`
+    DecimalComma,
+}
+
+impl AmountFormat {
+    /// Rewrite `amount` as a plain decimal.
+    /// Currency symbols and codes around the number are dropped, and so are grouping separators
+    /// (the other one of `.` and `,`, spaces and `'`) wherever they are.
+    pub(crate) fn normalize(self, amount: &str) -> Cow<'_, str> {
+        let (decimal, grouping) = match self {
+            AmountFormat::Plain => return Cow::Borrowed(amount),
+            AmountFormat::DecimalPoint => ('.', ','),
+            AmountFormat::DecimalComma => (',', '.'),
+        };
+        let is_symbol = |c: char| {
+            !(c.is_ascii_digit() || c == '-' || c == '+' || c == decimal || c == grouping)
+        };
+        let amount = amount.trim_matches(is_symbol);
+        // The sign may come before the currency, e.g. `-$5`
+        let (negative, amount) = match amount.strip_prefix('-') {
+            Some(amount) => (true, amount),
+            None => (false, amount.strip_prefix('+').unwrap_or(amount)),
+        };
+        let amount = amount.trim_start_matches(is_symbol);
+        let mut normalized = String::with_capacity(amount.len() + 1);
+        if negative {
+            normalized.push('-');
+        }
+        for c in amount.chars() {
+            match c {
+                c if c == decimal => normalized.push('.'),
+                c if c == grouping => {}
+                ' ' | '\'' | '\u{a0}' | '\u{202f}' => {}
+                c => normalized.push(c),
+            }
+        }
+        Cow::Owned(normalized)
+    }
+}
+
+/// Options for reading transactions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseOptions {
+    pub mode: ParseMode,
+    /// Trim whitespace around headers and fields, e.g. ` deposit, 1, 1, 1.0`
+    pub trim: bool,
+    /// Accept transaction types in any case, e.g. `Deposit`
+    pub case_insensitive: bool,
+    /// Other names of transaction types, e.g. `withdraw` -> [`TransactionType::Withdrawal`].
+    /// Matched in any case as well with `case_insensitive`.
+    /// Defaults to a few names seen in real exports, see [`default_type_aliases`].
+    pub type_aliases: HashMap<String, TransactionType>,
+    /// Input header names to read as one of the [`COLUMNS`], e.g. `customer_id` -> `client`
+    pub column_aliases: HashMap<String, String>,
+    /// Localized amounts to accept, plain decimals only by default
+    pub amount_format: AmountFormat,
+    pub unknown_types: UnknownTypes,
+    /// Reject deposits and withdrawals without an amount, and resolves and chargebacks
+    /// with one. Otherwise they are applied with a warning, a missing amount counting as zero.
+    /// Negative amounts are rejected either way.
+    pub strict_amounts: bool,
+    /// Replace client ids with their pseudonyms as rows are parsed, and the records of
+    /// rejected rows with [`crate::pseudonym::REDACTED`]
+    pub pseudonyms: Option<Pseudonymizer>,
+    /// How client ids are written, rows with wider ones are malformed. [`IdFormat::U16`]
+    /// as in the specification by default.
+    pub client_ids: IdFormat,
+    /// How tx ids are written, [`IdFormat::U32`] as in the specification by default.
+    /// [`IdFormat::Text`] takes UUIDs and any other string.
+    pub tx_ids: IdFormat,
+}
+
+/// Columns a transactions file is expected to have
+pub const COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            mode: ParseMode::Lenient,
+            trim: true,
+            case_insensitive: true,
+            type_aliases: default_type_aliases(),
+            column_aliases: HashMap::new(),
+            amount_format: AmountFormat::Plain,
+            unknown_types: UnknownTypes::Reject,
+            strict_amounts: false,
+            pseudonyms: None,
+            client_ids: IdFormat::U16,
+            tx_ids: IdFormat::U32,
+        }
+    }
+}
+
+/// `withdraw`, `charge_back` and `charge-back`
+pub fn default_type_aliases() -> HashMap<String, TransactionType> {
+    [
+        ("withdraw", TransactionType::Withdrawal),
+        ("charge_back", TransactionType::Chargeback),
+        ("charge-back", TransactionType::Chargeback),
+    ]
+    .into_iter()
+    .map(|(alias, transaction_type)| (alias.to_string(), transaction_type))
+    .collect()
+}
+
+impl ParseOptions {
+    /// The transaction type called `name`, by its own name or one of the aliases
+    fn transaction_type(&self, name: &[u8]) -> Option<TransactionType> {
+        TransactionType::from_bytes(name, self.case_insensitive).or_else(|| {
+            self.type_aliases
+                .iter()
+                .find(|(alias, _)| match self.case_insensitive {
+                    true => name.eq_ignore_ascii_case(alias.as_bytes()),
+                    false => name == alias.as_bytes(),
+                })
+                .map(|(_, transaction_type)| *transaction_type)
+        })
+    }
+
+    /// Replace aliased headers with the expected column names
+    fn rename_headers(&self, headers: &ByteRecord) -> ByteRecord {
+        headers
+            .iter()
+            .map(|header| {
+                std::str::from_utf8(header)
+                    .ok()
+                    .and_then(|header| self.column_aliases.get(header))
+                    .map_or(header, |column| column.as_bytes())
+            })
+            .collect()
+    }
+
+    /// The names of the text client and tx ids read so far, e.g. to save with a checkpoint
+    pub fn text_id_names(&self) -> [BTreeMap<u64, String>; 2] {
+        [&self.client_ids, &self.tx_ids]
+            .map(|ids| ids.names().map(IdNames::to_map).unwrap_or_default())
+    }
+
+    /// Remembers the names of the text ids in `state`, e.g. a saved state read with these
+    /// options, so they are given the same ids again
+    pub fn learn_names(&self, state: &mut EngineState) {
+        let names = [&mut state.client_names, &mut state.tx_names];
+        for (ids, names) in [&self.client_ids, &self.tx_ids].into_iter().zip(names) {
+            if let Some(known) = ids.names() {
+                known.extend(std::mem::take(names));
+            }
+        }
+    }
+
+    /// Checks the amount of `transaction` is present only where it belongs and not negative,
+    /// see [`ParseOptions::strict_amounts`]. `line_offset` is added to the line of the record.
+    pub(crate) fn check_amount(
+        &self,
+        transaction: &Transaction,
+        record: &ByteRecord,
+        line_offset: u64,
+    ) -> Result<(), RowError> {
+        let Some(problem) = transaction.amount_problem() else {
+            return Ok(());
+        };
+        let mut error = self.redact(RowError::invalid(record, ErrorCode::BadAmount, problem));
+        error.line += line_offset;
+        if self.strict_amounts || transaction.negative_amount().is_some() {
+            return Err(error);
+        }
+        warn!(line = error.line, record = %error.record, "{}", error.message);
+        Ok(())
+    }
+
+    /// Checks the ids of a parsed row fit [`ParseOptions::client_ids`] and
+    /// [`ParseOptions::tx_ids`]
+    fn check_ids(&self, client: ClientId, tx: TxId, record: &ByteRecord) -> Result<(), RowError> {
+        self.client_ids
+            .check("client", client)
+            .and_then(|()| self.tx_ids.check("tx", tx))
+            .map_err(|message| RowError::invalid(record, ErrorCode::MalformedRow, message))
+    }
+
+    /// Handles a rejected row according to the mode, see [`ParseOptions::pseudonyms`]
+    pub(crate) fn reject(
+        &self,
+        error: RowError,
+        errors: &mut Vec<RowError>,
+    ) -> Result<(), RowError> {
+        self.mode.reject(self.redact(error), errors)
+    }
+
+    /// `error` without the record it was read from when pseudonymizing
+    pub(crate) fn redact(&self, error: RowError) -> RowError {
+        match &self.pseudonyms {
+            Some(pseudonyms) => pseudonyms.redact(error),
+            None => error,
+        }
+    }
+
+    /// Whether records have to be rewritten before they can be deserialized
+    fn normalizes(&self) -> bool {
+        self.trim
+            || self.case_insensitive
+            || !self.type_aliases.is_empty()
+            || self.amount_format != AmountFormat::Plain
+            || self.client_ids.names().is_some()
+            || self.tx_ids.names().is_some()
+    }
+
+    /// Copy `record` into `normalized`, trimmed, with the transaction type by its own name,
+    /// text ids by their number and a plain amount
+    fn normalize(
+        &self,
+        record: &ByteRecord,
+        [type_column, client_column, tx_column, amount_column]: [usize; 4],
+        normalized: &mut ByteRecord,
+    ) -> Result<(), String> {
+        normalized.clear();
+        normalized.set_position(record.position().cloned());
+        for (i, field) in record.iter().enumerate() {
+            let field = if self.trim { field.trim_ascii() } else { field };
+            if i == type_column {
+                // Unknown types are left for deserializing to fail on
+                match self.transaction_type(field) {
+                    Some(transaction_type) => {
+                        normalized.push_field(transaction_type.name().as_bytes())
+                    }
+                    None => normalized.push_field(field),
+                }
+            } else if (i == client_column || i == tx_column) && !field.is_empty() {
+                let ids = match i == client_column {
+                    true => &self.client_ids,
+                    false => &self.tx_ids,
+                };
+                match ids.names() {
+                    Some(names) => {
+                        let id = names.id(&String::from_utf8_lossy(field))?;
+                        normalized.push_field(id.to_string().as_bytes())
+                    }
+                    None => normalized.push_field(field),
+                }
+            } else if i == amount_column && self.amount_format != AmountFormat::Plain {
+                match std::str::from_utf8(field) {
+                    Ok(amount) => {
+                        normalized.push_field(self.amount_format.normalize(amount).as_bytes())
+                    }
+                    Err(_) => normalized.push_field(field),
+                }
+            } else {
+                normalized.push_field(field);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RowError {
+    pub(crate) fn new(error: &csv::Error, record: &ByteRecord) -> Self {
+        let line = error
+            .position()
+            .or_else(|| record.position())
+            .map_or(0, |p| p.line());
+        // Deserialize errors otherwise repeat the position in the message
+        let message = match error.kind() {
+            csv::ErrorKind::Deserialize { err, .. } => err.to_string(),
+            _ => error.to_string(),
+        };
+        RowError {
+            line,
+            record: raw_record(record),
+            code: match error.is_io_error() {
+                true => ErrorCode::Io,
+                false => ErrorCode::MalformedRow,
+            },
+            message,
+        }
+    }
+
+    /// A row that parsed but failed validation
+    pub(crate) fn invalid(record: &ByteRecord, code: ErrorCode, message: String) -> Self {
+        RowError {
+            line: record.position().map_or(0, |p| p.line()),
+            record: raw_record(record),
+            code,
+            message,
+        }
+    }
+}
+
+/// Re-encode a record as a CSV line, so quoted fields read the same as in the input
+pub(crate) fn raw_record(record: &ByteRecord) -> String {
+    let mut writer = csv::WriterBuilder::new()
+        .flexible(true)
+        .terminator(csv::Terminator::Any(b'\n'))
+        .from_writer(vec![]);
+    // Writing to a Vec can't fail
+    let _ = writer.write_byte_record(record);
+    let line = writer.into_inner().unwrap_or_default();
+    String::from_utf8_lossy(&line)
+        .trim_end_matches('\n')
+        .to_string()
+}
+
+/// Turns CSV records into transactions according to the parse options
+#[derive(Debug, Clone)]
+pub(crate) struct RowParser<'a> {
+    options: &'a ParseOptions,
+    headers: Option<ByteRecord>,
+    // Columns of the type, client, tx and amount, rewritten before deserializing
+    normalized_columns: [usize; 4],
+    // Position of each of the COLUMNS, None if one is missing
+    columns: Option<[usize; 4]>,
+    // Reused buffer for trimmed and lowercased records
+    normalized: ByteRecord,
+    // Pseudonyms of the clients seen so far, with ParseOptions::pseudonyms
+    pseudonyms: HashMap<ClientId, ClientId>,
+}
+
+impl<'a> RowParser<'a> {
+    /// `headers` as read from the input, `None` to deserialize records by position
+    pub(crate) fn new(options: &'a ParseOptions, headers: Option<&ByteRecord>) -> Self {
+        let headers = headers.map(|headers| {
+            let mut headers = headers.clone();
+            // Readers that weren't built with decode_input may still have a BOM
+            if let Some(first) = headers.get(0) {
+                if let Some(first) = first.strip_prefix(b"\xEF\xBB\xBF") {
+                    headers = std::iter::once(first)
+                        .chain(headers.iter().skip(1))
+                        .collect();
+                }
+            }
+            if options.trim {
+                headers.trim();
+            }
+            options.rename_headers(&headers)
+        });
+        let position = |column: &str, default: usize| {
+            headers
+                .as_ref()
+                .and_then(|headers| headers.iter().position(|h| h == column.as_bytes()))
+                .unwrap_or(default)
+        };
+        let normalized_columns = [
+            position("type", 0),
+            position("client", 1),
+            position("tx", 2),
+            position("amount", 3),
+        ];
+        let columns = match &headers {
+            Some(headers) => {
+                let position = |column: &str| headers.iter().position(|h| h == column.as_bytes());
+                match COLUMNS.map(position) {
+                    [Some(t), Some(client), Some(tx), Some(amount)] => {
+                        Some([t, client, tx, amount])
+                    }
+                    _ => None,
+                }
+            }
+            None => Some([0, 1, 2, 3]),
+        };
+        RowParser {
+            options,
+            headers,
+            normalized_columns,
+            columns,
+            normalized: ByteRecord::new(),
+            pseudonyms: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn headers(&self) -> Option<&ByteRecord> {
+        self.headers.as_ref()
+    }
+
+    pub(crate) fn parse(&mut self, record: &ByteRecord) -> Result<Transaction, RowError> {
+        let mut transaction = self.deserialize(record)?;
+        self.options
+            .check_ids(transaction.client, transaction.tx, record)?;
+        transaction.client = self.pseudonym(transaction.client);
+        Ok(transaction)
+    }
+
+    /// `client`, or its pseudonym with [`ParseOptions::pseudonyms`]
+    fn pseudonym(&mut self, client: ClientId) -> ClientId {
+        match &self.options.pseudonyms {
+            Some(pseudonyms) => *self
+                .pseudonyms
+                .entry(client)
+                .or_insert_with(|| pseudonyms.pseudonym(client)),
+            None => client,
+        }
+    }
+
+    fn deserialize(&mut self, record: &ByteRecord) -> Result<Transaction, RowError> {
+        if let Some(transaction) = self.parse_fast(record) {
+            return Ok(transaction);
+        }
+        // Anything the fast path can't handle goes through serde,
+        // which also produces the error messages
+        let parsed = if self.options.normalizes() {
+            self.options
+                .normalize(record, self.normalized_columns, &mut self.normalized)
+                .map_err(|message| RowError::invalid(record, ErrorCode::MalformedRow, message))?;
+            self.normalized.deserialize(self.headers.as_ref())
+        } else {
+            record.deserialize(self.headers.as_ref())
+        };
+        parsed.map_err(|err| RowError::new(&err, record))
+    }
+
+    /// Reads a row that failed to [`RowParser::parse`] as an [`UnknownTransaction`]
+    /// if its type is the only problem and [`UnknownTypes::Skip`] is set
+    pub(crate) fn parse_unknown(&mut self, record: &ByteRecord) -> Option<UnknownTransaction> {
+        if self.options.unknown_types != UnknownTypes::Skip {
+            return None;
+        }
+        let mut parsed: UnknownTransaction = if self.options.normalizes() {
+            self.options
+                .normalize(record, self.normalized_columns, &mut self.normalized)
+                .ok()?;
+            self.normalized.deserialize(self.headers.as_ref())
+        } else {
+            record.deserialize(self.headers.as_ref())
+        }
+        .ok()?;
+        self.options
+            .check_ids(parsed.client, parsed.tx, record)
+            .ok()?;
+        match self
+            .options
+            .transaction_type(parsed.transaction_type.as_bytes())
+        {
+            Some(_) => None,
+            None => {
+                parsed.client = self.pseudonym(parsed.client);
+                Some(parsed)
+            }
+        }
+    }
+}
+
+impl RowParser<'_> {
+    /// Fields of the columns other than the [`COLUMNS`], by header name.
+    /// Empty without headers.
+    pub(crate) fn metadata(&self, record: &ByteRecord) -> Metadata {
+        let Some(headers) = &self.headers else {
+            return Metadata::new();
+        };
+        headers
+            .iter()
+            .zip(record.iter())
+            .filter(|(header, _)| !COLUMNS.iter().any(|column| column.as_bytes() == *header))
+            .map(|(header, field)| {
+                let field = if self.options.trim {
+                    field.trim_ascii()
+                } else {
+                    field
+                };
+                (
+                    String::from_utf8_lossy(header).into_owned(),
+                    String::from_utf8_lossy(field).into_owned(),
+                )
+            })
+            .collect()
+    }
+
+    /// Reads the fields straight from the record without allocating.
+    /// Only accepts what the serde path would accept as well.
+    fn parse_fast(&self, record: &ByteRecord) -> Option<Transaction> {
+        let [type_column, client_column, tx_column, amount_column] = self.columns?;
+        let field = |column: usize| {
+            let field = record.get(column)?;
+            let field = if self.options.trim {
+                field.trim_ascii()
+            } else {
+                field
+            };
+            std::str::from_utf8(field).ok()
+        };
+        let transaction_type = self
+            .options
+            .transaction_type(field(type_column)?.as_bytes())?;
+        let amount = match field(amount_column)? {
+            "" => None,
+            amount => Some(Decimal::from_str(&self.options.amount_format.normalize(amount)).ok()?),
+        };
+        let id = |column: usize, ids: &IdFormat| match (field(column)?, ids.names()) {
+            ("", _) => None,
+            (id, Some(names)) => names.id(id).ok(),
+            (id, None) => id.parse().ok(),
+        };
+        Some(Transaction {
+            transaction_type,
+            client: id(client_column, &self.options.client_ids)?,
+            tx: id(tx_column, &self.options.tx_ids)?,
+            amount,
+        })
+    }
+}
+
+/// Accepts a reader object.
+/// The function reads file line by line - creates a transaction per line
+/// stores relevant value in an accounts map
+/// Malformed rows are skipped
+pub fn process_transactions<R: io::Read>(reader: &mut Reader<R>) -> AccountMap {
+    process_transactions_with(reader, &ParseOptions::default())
+        .map(|report| report.accounts)
+        .unwrap_or_default()
+}
+
+/// Same as [`process_transactions`] with rows read according to `options`.
+/// Only [`ParseMode::Strict`] returns an error.
+pub fn process_transactions_with<R: io::Read>(
+    reader: &mut Reader<R>,
+    options: &ParseOptions,
+) -> Result<ProcessReport, RowError> {
+    process_transactions_with_updates(reader, options, |_| {})
+}
+
+/// Same as [`process_transactions_with`], calling `on_update` with the new balances
+/// every time a row changes an account, in input order
+pub fn process_transactions_with_updates<R: io::Read, F: FnMut(AccountUpdate)>(
+    reader: &mut Reader<R>,
+    options: &ParseOptions,
+    on_update: F,
+) -> Result<ProcessReport, RowError> {
+    process_records(reader, options, Engine::new(), on_update)
+}
+
+/// Same as [`process_transactions_with`], applying the rows with `engine`,
+/// e.g. one with [`crate::EngineObserver`]s registered
+pub fn process_transactions_with_engine<R: io::Read>(
+    reader: &mut Reader<R>,
+    options: &ParseOptions,
+    engine: Engine,
+) -> Result<ProcessReport, RowError> {
+    process_records(reader, options, engine, |_| {})
+}
+
+pub(crate) fn process_records<R: io::Read, F: FnMut(AccountUpdate)>(
+    reader: &mut Reader<R>,
+    options: &ParseOptions,
+    mut engine: Engine,
+    mut on_update: F,
+) -> Result<ProcessReport, RowError> {
+    let mut errors: Vec<RowError> = vec![];
+
+    // Reading records ourselves instead of using reader.deserialize()
+    // keeps the line of a failing row around
+    let mut record = ByteRecord::new();
+    let headers = match reader.has_headers() {
+        true => match reader.byte_headers() {
+            Ok(headers) => Some(headers.clone()),
+            // Without headers none of the rows can be read
+            Err(err) => {
+                options.reject(RowError::new(&err, &record), &mut errors)?;
+                return Ok(ProcessReport {
+                    errors,
+                    ..engine.into()
+                });
+            }
+        },
+        false => None,
+    };
+    let mut parser = RowParser::new(options, headers.as_ref());
+    let mut unknown_types = BTreeMap::new();
+    loop {
+        match reader.read_byte_record(&mut record) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) => {
+                options.reject(RowError::new(&err, &record), &mut errors)?;
+                // The underlying reader failed, there is nothing more to read
+                if err.is_io_error() {
+                    break;
+                }
+                continue;
+            }
+        }
+        let transaction = match parser.parse(&record) {
+            Ok(transaction) => transaction,
+            Err(error) => {
+                match parser.parse_unknown(&record) {
+                    Some(unknown) => skip_unknown(unknown, &mut engine, &mut unknown_types),
+                    None => options.reject(error, &mut errors)?,
+                }
+                continue;
+            }
+        };
+        if let Err(error) = options.check_amount(&transaction, &record, 0) {
+            options.reject(error, &mut errors)?;
+            continue;
+        }
+        if let Err(rejection) = engine.apply_each(transaction, &mut on_update) {
+            options.reject(
+                RowError::invalid(&record, rejection.code(), rejection.to_string()),
+                &mut errors,
+            )?;
+        }
+    }
+    Ok(ProcessReport {
+        errors,
+        unknown_types,
+        ..engine.into()
+    })
+}
+
+/// Counts `unknown` and hands it to the observers of `engine`
+pub(crate) fn skip_unknown(
+    unknown: UnknownTransaction,
+    engine: &mut Engine,
+    unknown_types: &mut BTreeMap<String, u64>,
+) {
+    engine.skip_unknown(&unknown);
+    *unknown_types.entry(unknown.transaction_type).or_default() += 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::{Engine, EngineObserver};
+    use crate::ids::{text_id, IdFormat, IdNames};
+    use crate::io::csv::{
+        process_transactions, process_transactions_with, process_transactions_with_engine,
+        process_transactions_with_updates, AmountFormat, ParseMode, ParseOptions, RowError,
+        UnknownTypes,
+    };
+    use crate::io::{decode_input, ErrorCode};
+    use crate::model::{AccountUpdate, Status, TransactionType, UnknownTransaction};
+    use crate::report::{write_accounts_with, Column, OutputOptions};
+    use rust_decimal::prelude::Zero;
+    use rust_decimal::Decimal;
+    use std::cell::RefCell;
+    use std::collections::{BTreeMap, HashMap};
+    use std::rc::Rc;
+
+    #[test]
+    fn updates_follow_balance_changes() {
+        let data = "type,client,tx,amount
+deposit,1,1,2.0
+dispute,2,1,
+dispute,1,1,
+chargeback,1,1,";
+        let mut updates = vec![];
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        process_transactions_with_updates(&mut reader, &ParseOptions::default(), |update| {
+            updates.push(update)
+        })
+        .unwrap();
+        // The other client's dispute changes nothing
+        assert_eq!(updates.len(), 3);
+        assert_eq!(updates[1].transaction_type, TransactionType::Dispute);
+        assert_eq!(updates[1].held, Decimal::new(2, 0));
+        assert_eq!(
+            updates[2],
+            AccountUpdate {
+                client: 1,
+                tx: 1,
+                transaction_type: TransactionType::Chargeback,
+                amount: Decimal::new(2, 0),
+                fee: Decimal::ZERO,
+                available: Decimal::zero(),
+                held: Decimal::zero(),
+                status: Status::Frozen,
+            }
+        );
+    }
+
+    fn options(mode: ParseMode) -> ParseOptions {
+        ParseOptions {
+            mode,
+            ..ParseOptions::default()
+        }
+    }
+
+    const MALFORMED: &str = "type,client,tx,amount
+deposit,1,1,1.0
+teleport,1,2,1.0
+deposit,1,3,abc
+deposit,1,4,2.0";
+
+    #[test]
+    fn lenient_mode_skips_malformed_rows() {
+        let mut reader = csv::Reader::from_reader(MALFORMED.as_bytes());
+        let report = process_transactions_with(&mut reader, &options(ParseMode::Lenient)).unwrap();
+        assert_eq!(
+            report.accounts.get(&1).unwrap().available,
+            Decimal::new(3, 0)
+        );
+        assert!(report.errors.is_empty());
+    }
+
+    #[test]
+    fn collecting_mode_reports_line_numbers() {
+        let mut reader = csv::Reader::from_reader(MALFORMED.as_bytes());
+        let report =
+            process_transactions_with(&mut reader, &options(ParseMode::Collecting)).unwrap();
+        assert_eq!(
+            report.accounts.get(&1).unwrap().available,
+            Decimal::new(3, 0)
+        );
+        let lines: Vec<u64> = report.errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![3, 4]);
+    }
+
+    #[test]
+    fn strict_mode_aborts_on_first_malformed_row() {
+        let mut reader = csv::Reader::from_reader(MALFORMED.as_bytes());
+        let error =
+            process_transactions_with(&mut reader, &options(ParseMode::Strict)).unwrap_err();
+        assert_eq!(
+            error,
+            RowError {
+                line: 3,
+                record: "teleport,1,2,1.0".to_string(),
+                code: ErrorCode::MalformedRow,
+                message: "Invalid transaction type".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn row_error_keeps_raw_record() {
+        let data = b"type,client,tx,amount\ndeposit,\"1,5\",1,1.0\ndeposit,1,2,\xff\n";
+        let mut reader = csv::Reader::from_reader(&data[..]);
+        let report =
+            process_transactions_with(&mut reader, &options(ParseMode::Collecting)).unwrap();
+        let records: Vec<&str> = report.errors.iter().map(|e| e.record.as_str()).collect();
+        assert_eq!(
+            records,
+            vec!["deposit,\"1,5\",1,1.0", "deposit,1,2,\u{fffd}"]
+        );
+        assert_eq!(report.errors[1].line, 3);
+    }
+
+    #[test]
+    fn duplicate_tx_id_is_reported() {
+        let data = "type,client,tx,amount
+deposit,1,1,1.0
+withdrawal,1,1,1.0";
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let report =
+            process_transactions_with(&mut reader, &options(ParseMode::Collecting)).unwrap();
+        assert_eq!(
+            report.errors,
+            vec![RowError {
+                line: 3,
+                record: "withdrawal,1,1,1.0".to_string(),
+                code: ErrorCode::DuplicateTx,
+                message: "duplicate tx id 1".to_string(),
+            }]
+        );
+    }
+
+    const PADDED: &str = "type, client, tx, amount
+ Deposit, 1, 1, 1.0
+DEPOSIT ,1 ,2 , 2.0";
+
+    #[test]
+    fn padded_and_capitalized_fields_are_accepted() {
+        let mut reader = csv::Reader::from_reader(PADDED.as_bytes());
+        let report = process_transactions_with(&mut reader, &options(ParseMode::Strict)).unwrap();
+        assert_eq!(
+            report.accounts.get(&1).unwrap().available,
+            Decimal::new(3, 0)
+        );
+    }
+
+    #[test]
+    fn transaction_type_aliases_are_accepted() {
+        let data = "type,client,tx,amount
+DEPOSIT,1,1,5.0
+Withdraw,1,2,1.0
+payout,0x1,3,1.0
+deposit,1,4,1.0
+dispute,1,4,
+Charge_Back,1,4,";
+        let mut options = options(ParseMode::Strict);
+        options
+            .type_aliases
+            .insert("payout".to_string(), TransactionType::Withdrawal);
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let report = process_transactions_with(&mut reader, &options).unwrap();
+        let account = &report.accounts[&1];
+        assert_eq!(account.available, Decimal::new(3, 0));
+        assert!(account.locked());
+
+        // Only the exact aliases without case folding
+        options.case_insensitive = false;
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let error = process_transactions_with(&mut reader, &options).unwrap_err();
+        assert_eq!(error.line, 2);
+    }
+
+    #[test]
+    fn unknown_types_can_be_skipped_and_counted() {
+        #[derive(Default)]
+        struct Unknowns(Rc<RefCell<Vec<UnknownTransaction>>>);
+
+        impl EngineObserver for Unknowns {
+            fn on_unknown_type(&mut self, transaction: &UnknownTransaction) {
+                self.0.borrow_mut().push(transaction.clone());
+            }
+        }
+
+        let data = "type,client,tx,amount
+deposit,1,1,5.0
+refund,1,2,1.0
+transfer,1,3,
+refund,0x1,4,2.0";
+        // Malformed rows by default
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let error =
+            process_transactions_with(&mut reader, &options(ParseMode::Strict)).unwrap_err();
+        assert_eq!(error.line, 3);
+
+        let options = ParseOptions {
+            unknown_types: UnknownTypes::Skip,
+            ..options(ParseMode::Strict)
+        };
+        let unknowns = Unknowns::default();
+        let seen = unknowns.0.clone();
+        let mut engine = Engine::new();
+        engine.add_observer(unknowns);
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let report = process_transactions_with_engine(&mut reader, &options, engine).unwrap();
+        assert_eq!(report.accounts[&1].available, Decimal::new(5, 0));
+        assert_eq!(
+            report.unknown_types,
+            BTreeMap::from([("refund".to_string(), 2), ("transfer".to_string(), 1)])
+        );
+        let seen = seen.borrow();
+        assert_eq!(seen.len(), 3);
+        assert_eq!(
+            seen[2],
+            UnknownTransaction {
+                transaction_type: "refund".to_string(),
+                client: 1,
+                tx: 4,
+                amount: Some(Decimal::new(2, 0)),
+            }
+        );
+    }
+
+    #[test]
+    fn trimming_and_case_folding_can_be_disabled() {
+        let options = ParseOptions {
+            mode: ParseMode::Collecting,
+            trim: false,
+            case_insensitive: false,
+            ..ParseOptions::default()
+        };
+        let mut reader = csv::Reader::from_reader(PADDED.as_bytes());
+        let report = process_transactions_with(&mut reader, &options).unwrap();
+        assert!(report.accounts.is_empty());
+        assert_eq!(report.errors.len(), 2);
+    }
+
+    #[test]
+    fn aliased_headers_are_mapped_to_columns() {
+        let data = "txn_type,customer_id,transaction_id,value
+deposit,1,1,1.0";
+        let options = ParseOptions {
+            mode: ParseMode::Strict,
+            column_aliases: HashMap::from([
+                ("txn_type".to_string(), "type".to_string()),
+                ("customer_id".to_string(), "client".to_string()),
+                ("transaction_id".to_string(), "tx".to_string()),
+                ("value".to_string(), "amount".to_string()),
+            ]),
+            ..ParseOptions::default()
+        };
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let report = process_transactions_with(&mut reader, &options).unwrap();
+        assert_eq!(
+            report.accounts.get(&1).unwrap().available,
+            Decimal::new(1, 0)
+        );
+    }
+
+    #[test]
+    fn misplaced_amounts_can_be_rejected() {
+        let data = "type,client,tx,amount
+deposit,1,1,
+deposit,1,2,2.0
+dispute,1,2,
+resolve,1,2,1.0";
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let report =
+            process_transactions_with(&mut reader, &options(ParseMode::Collecting)).unwrap();
+        assert!(report.errors.is_empty());
+        assert_eq!(report.accounts[&1].held, Decimal::zero());
+
+        let options = ParseOptions {
+            strict_amounts: true,
+            ..options(ParseMode::Collecting)
+        };
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let report = process_transactions_with(&mut reader, &options).unwrap();
+        let messages: Vec<&str> = report
+            .errors
+            .iter()
+            .map(|error| error.message.as_str())
+            .collect();
+        assert_eq!(
+            messages,
+            ["deposit without an amount", "resolve with an amount"]
+        );
+        assert_eq!(report.accounts[&1].held, Decimal::new(2, 0));
+    }
+
+    #[test]
+    fn localized_amounts_are_normalized() {
+        let data = "type,client,tx,amount
+deposit,1,1,\"1.234,56 €\"
+deposit,0x1,2,EUR 1 000
+withdrawal,1,3,\"0,5\"";
+        let options = ParseOptions {
+            amount_format: AmountFormat::DecimalComma,
+            ..options(ParseMode::Strict)
+        };
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let report = process_transactions_with(&mut reader, &options).unwrap();
+        assert_eq!(report.accounts[&1].available, Decimal::new(223406, 2));
+
+        // Plain decimals only by default
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        assert!(
+            process_transactions_with(&mut reader, &ParseOptions::default())
+                .unwrap()
+                .accounts
+                .is_empty()
+        );
+
+        assert_eq!(AmountFormat::DecimalPoint.normalize("-$1,234.5"), "-1234.5");
+        assert_eq!(AmountFormat::DecimalComma.normalize("1'234,5"), "1234.5");
+    }
+
+    #[test]
+    fn client_ids_are_bounded_or_read_as_text() {
+        let data = "type,client,tx,amount
+deposit,65535,1,1
+deposit,65536,2,1
+deposit, 65536 ,3,\"1,0\"";
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let report =
+            process_transactions_with(&mut reader, &options(ParseMode::Collecting)).unwrap();
+        assert_eq!(report.accounts.len(), 1);
+        assert_eq!(report.errors.len(), 2);
+        assert_eq!(
+            report.errors[0].message,
+            "client id 65536 is too large, at most 65535"
+        );
+        let options = ParseOptions {
+            client_ids: IdFormat::U32,
+            amount_format: AmountFormat::DecimalComma,
+            ..options(ParseMode::Strict)
+        };
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let report = process_transactions_with(&mut reader, &options).unwrap();
+        assert_eq!(report.accounts[&65536].available, Decimal::new(2, 0));
+
+        let data = "type,client,tx,amount
+deposit,acct-1,1,1
+deposit, acct-1 ,2,\"1,0\"
+withdrawal,\"acct,2\",3,1";
+        let names = IdNames::new();
+        let options = ParseOptions {
+            client_ids: IdFormat::Text(names.clone()),
+            ..options
+        };
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let report = process_transactions_with(&mut reader, &options).unwrap();
+        assert_eq!(
+            report.accounts[&text_id("acct-1")].available,
+            Decimal::new(2, 0)
+        );
+        assert_eq!(names.len(), 2);
+        let mut output = vec![];
+        let output_options = OutputOptions {
+            columns: Some(vec![Column::Client]),
+            client_names: Some(names),
+            ..OutputOptions::default()
+        };
+        write_accounts_with(&report.accounts, &mut output, &output_options).unwrap();
+        let mut written: Vec<&str> = std::str::from_utf8(&output).unwrap().lines().collect();
+        written.sort_unstable();
+        assert_eq!(written, ["\"acct,2\"", "acct-1", "client"]);
+    }
+
+    #[test]
+    fn tx_ids_can_be_uuids() {
+        let data = "type,client,tx,amount
+deposit,1,4294967296,1
+deposit,1,6f1c2a9e-8d0b-4c55-9b1e-2f4a7c3d9e01,5
+dispute,1, 6f1c2a9e-8d0b-4c55-9b1e-2f4a7c3d9e01 ,";
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let report =
+            process_transactions_with(&mut reader, &options(ParseMode::Collecting)).unwrap();
+        assert_eq!(
+            report.errors[0].message,
+            "tx id 4294967296 is too large, at most 4294967295"
+        );
+
+        let names = IdNames::new();
+        let options = ParseOptions {
+            tx_ids: IdFormat::Text(names.clone()),
+            ..options(ParseMode::Strict)
+        };
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let report = process_transactions_with(&mut reader, &options).unwrap();
+        assert_eq!(report.accounts[&1].held, Decimal::new(5, 0));
+        let mut output = vec![];
+        let output_options = OutputOptions {
+            columns: Some(vec![Column::Disputed]),
+            tx_names: Some(names),
+            ..OutputOptions::default()
+        };
+        write_accounts_with(&report.accounts, &mut output, &output_options).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            "disputed\n6f1c2a9e-8d0b-4c55-9b1e-2f4a7c3d9e01\n"
+        );
+    }
+
+    #[test]
+    fn utf8_bom_is_stripped() {
+        let data = "\u{feff}type,client,tx,amount\ndeposit,1,1,1.0";
+        let accounts = process_transactions(&mut csv::Reader::from_reader(data.as_bytes()));
+        assert_eq!(accounts.get(&1).unwrap().available, Decimal::new(1, 0));
+    }
+
+    #[test]
+    fn utf16_input_is_transcoded() {
+        let text = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+        let mut data = vec![0xFF, 0xFE];
+        data.extend(text.encode_utf16().flat_map(|unit| unit.to_le_bytes()));
+        let input = decode_input(&data[..], None).unwrap();
+        let accounts = process_transactions(&mut csv::Reader::from_reader(input));
+        assert_eq!(accounts.get(&1).unwrap().available, Decimal::new(1, 0));
+    }
+
+    #[test]
+    fn latin1_input_is_transcoded() {
+        let mut input = decode_input(&b"caf\xe9"[..], Some("latin1")).unwrap();
+        let mut text = String::new();
+        std::io::Read::read_to_string(&mut input, &mut text).unwrap();
+        assert_eq!(text, "caf\u{e9}");
+        assert!(decode_input(&b""[..], Some("klingon")).is_err());
+    }
+
+    #[test]
+    fn exotic_values_fall_back_to_serde() {
+        let data = "type,client,tx,amount
+deposit,0x1,1,1e2
+deposit,1,2,";
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let report = process_transactions_with(&mut reader, &options(ParseMode::Strict)).unwrap();
+        assert_eq!(
+            report.accounts.get(&1).unwrap().available,
+            Decimal::new(100, 0)
+        );
+    }
+}
@@ -0,0 +1,396 @@
+//! Reading transactions files: decoding the input and parsing it as CSV.
+#[cfg(feature = "fs")]
+use crate::engine::Engine;
+#[cfg(feature = "fs")]
+use crate::io::csv::{process_records, ParseOptions};
+#[cfg(feature = "fs")]
+use crate::model::AccountUpdate;
+#[cfg(feature = "fs")]
+use crate::parallel::process_parallel;
+#[cfg(feature = "fs")]
+use crate::report::ProcessReport;
+use encoding_rs::Encoding;
+use encoding_rs_io::DecodeReaderBytesBuilder;
+#[cfg(feature = "fs")]
+use memmap2::Mmap;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::fmt;
+#[cfg(feature = "fs")]
+use std::fs::File;
+use std::io;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+#[cfg(feature = "fs")]
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tracing::info;
+
+#[cfg(feature = "csv")]
+pub mod csv;
+#[cfg(feature = "object-store")]
+pub mod object;
+#[cfg(feature = "fs")]
+pub mod sort;
+pub mod source;
+
+/// How rows that fail to parse are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParseMode {
+    /// Skip malformed rows
+    #[default]
+    Lenient,
+    /// Skip malformed rows but collect their errors in the report
+    Collecting,
+    /// Abort on the first malformed row
+    Strict,
+}
+
+impl ParseMode {
+    /// Handle a malformed row, only [`ParseMode::Strict`] hands the error back
+    pub(crate) fn reject(
+        self,
+        error: RowError,
+        errors: &mut Vec<RowError>,
+    ) -> Result<(), RowError> {
+        if self != ParseMode::Strict {
+            info!(
+                line = error.line,
+                record = %error.record,
+                code = %error.code,
+                "skipped row: {}",
+                error.message
+            );
+        }
+        match self {
+            ParseMode::Lenient => Ok(()),
+            ParseMode::Collecting => {
+                errors.push(error);
+                Ok(())
+            }
+            ParseMode::Strict => Err(error),
+        }
+    }
+}
+
+/// How transactions are sent one at a time between processes, e.g. replayed to a socket
+/// that applies them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireFormat {
+    /// `type,client,tx,amount` lines without a header, e.g. `deposit,1,1,1.5`
+    #[default]
+    Csv,
+    /// One JSON object per line, e.g. `{"type":"deposit","client":1,"tx":1,"amount":"1.5"}`
+    Json,
+}
+
+/// A row that could not be parsed or was rejected
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RowError {
+    /// Line of the row in the input, starting at 1 for the header
+    pub line: u64,
+    /// The row as read from the input, invalid UTF-8 replaced
+    pub record: String,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+/// Why a row was skipped, stable for automation to branch on unlike [`RowError::message`].
+/// Serialized as e.g. `DUP_TX_ID`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// The input couldn't be read
+    #[serde(rename = "IO_ERROR")]
+    Io,
+    /// Not a transaction: wrong number of fields, unknown type, a field that isn't a number
+    MalformedRow,
+    /// A required column is missing from the header
+    MissingColumn,
+    /// A negative amount, or an amount missing or present where it doesn't belong
+    BadAmount,
+    /// A deposit or withdrawal reused an already seen tx id
+    #[serde(rename = "DUP_TX_ID")]
+    DuplicateTx,
+    /// A Dispute, Resolve or Chargeback of a tx id that hasn't been seen
+    #[serde(rename = "UNKNOWN_REF_TX")]
+    UnknownRefTx,
+    /// A Dispute, Resolve or Chargeback of another client's transaction
+    ClientMismatch,
+    AlreadyDisputed,
+    /// A Resolve or Chargeback of a transaction that isn't under dispute
+    NotDisputed,
+    ChargedBack,
+    /// A ChargebackReversal of a transaction that wasn't charged back
+    NotChargedBack,
+    /// An unlock of an account that isn't locked
+    NotLocked,
+    /// An administrative row that doesn't apply to the status of the account,
+    /// e.g. an unlock of a closed account
+    WrongStatus,
+    /// A withdrawal or dispute hold of more than the available funds
+    InsufficientFunds,
+    /// A [`crate::TransactionValidator`] refused the transaction
+    Vetoed,
+    /// A balance would overflow
+    Overflow,
+    /// A deposit or withdrawal over its [`crate::AmountLimits`]
+    AmountLimit,
+    /// A withdrawal over the limit of a client's withdrawals in 24 hours
+    WithdrawalLimit,
+    /// A deposit or withdrawal of a locked account, if those are rejected
+    LockedAccount,
+}
+
+impl ErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::Io => "IO_ERROR",
+            ErrorCode::MalformedRow => "MALFORMED_ROW",
+            ErrorCode::MissingColumn => "MISSING_COLUMN",
+            ErrorCode::BadAmount => "BAD_AMOUNT",
+            ErrorCode::DuplicateTx => "DUP_TX_ID",
+            ErrorCode::UnknownRefTx => "UNKNOWN_REF_TX",
+            ErrorCode::ClientMismatch => "CLIENT_MISMATCH",
+            ErrorCode::AlreadyDisputed => "ALREADY_DISPUTED",
+            ErrorCode::NotDisputed => "NOT_DISPUTED",
+            ErrorCode::ChargedBack => "CHARGED_BACK",
+            ErrorCode::NotChargedBack => "NOT_CHARGED_BACK",
+            ErrorCode::NotLocked => "NOT_LOCKED",
+            ErrorCode::WrongStatus => "WRONG_STATUS",
+            ErrorCode::InsufficientFunds => "INSUFFICIENT_FUNDS",
+            ErrorCode::Vetoed => "VETOED",
+            ErrorCode::Overflow => "OVERFLOW",
+            ErrorCode::AmountLimit => "AMOUNT_LIMIT",
+            ErrorCode::WithdrawalLimit => "WITHDRAWAL_LIMIT",
+            ErrorCode::LockedAccount => "LOCKED_ACCOUNT",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Display for RowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {} ({}): {}", self.line, self.record, self.message)
+    }
+}
+
+impl std::error::Error for RowError {}
+
+/// How a transactions file is read, independent of how its rows are parsed
+#[cfg(feature = "fs")]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InputOptions {
+    /// Encoding of input without a byte order mark, see [`decode_input`]
+    pub encoding: Option<String>,
+    /// Memory-map the file instead of reading it through a buffer.
+    /// The file must not be modified while it is processed. Objects in a store are
+    /// streamed regardless.
+    pub mmap: bool,
+    /// Parse on all cores, see [`crate::parallel`].
+    /// Reads the whole file into memory unless combined with `mmap`.
+    pub parallel: bool,
+}
+
+/// Opens `path` for reading as UTF-8, see [`decode_input`].
+/// With the `object-store` feature `path` may be the URL of an object, see [`object`].
+#[cfg(feature = "fs")]
+pub fn open(path: &Path, encoding: Option<&str>) -> io::Result<impl Read> {
+    decode_input(io::BufReader::new(open_raw(path)?), encoding)
+}
+
+/// The file or object at `path` as it is stored, without decoding it
+#[cfg(feature = "fs")]
+pub fn open_raw(path: &Path) -> io::Result<Box<dyn Read + Send>> {
+    #[cfg(feature = "object-store")]
+    if let Some(url) = object::object_url(path) {
+        return Ok(Box::new(object::open_object(&url)?));
+    }
+    Ok(Box::new(File::open(path)?))
+}
+
+/// Processes the transactions file at `path` with `engine`, calling `on_update` for every account change.
+/// Failing to read the file is an io::Error, a malformed row in strict mode a RowError.
+/// With the `object-store` feature `path` may be the URL of an object, see [`object`].
+#[cfg(feature = "fs")]
+pub fn process_file(
+    path: &Path,
+    input: &InputOptions,
+    options: &ParseOptions,
+    engine: Engine,
+    on_update: impl FnMut(AccountUpdate),
+) -> io::Result<Result<ProcessReport, RowError>> {
+    let encoding = input.encoding.as_deref();
+    #[cfg(feature = "object-store")]
+    if let Some(url) = object::object_url(path) {
+        let object = object::open_object(&url)?;
+        return process_read(object, input, options, engine, on_update);
+    }
+    let file = File::open(path)?;
+    if input.mmap {
+        // SAFETY: the mapping is only read from. Changing the file while it is mapped
+        // is undefined behavior, which the InputOptions::mmap documentation warns about.
+        let map = unsafe { Mmap::map(&file) }?;
+        if input.parallel {
+            let decoded = decode_bytes(&map, encoding)?;
+            Ok(process_parallel(&decoded, options, engine, on_update))
+        } else {
+            let decoded = decode_input(&map[..], encoding)?;
+            Ok(process_records(
+                &mut ::csv::Reader::from_reader(decoded),
+                options,
+                engine,
+                on_update,
+            ))
+        }
+    } else {
+        process_read(file, input, options, engine, on_update)
+    }
+}
+
+/// [`process_file`] for input that can't be memory-mapped
+#[cfg(feature = "fs")]
+fn process_read(
+    read: impl Read,
+    input: &InputOptions,
+    options: &ParseOptions,
+    engine: Engine,
+    on_update: impl FnMut(AccountUpdate),
+) -> io::Result<Result<ProcessReport, RowError>> {
+    let encoding = input.encoding.as_deref();
+    if input.parallel {
+        let mut bytes = vec![];
+        io::BufReader::new(read).read_to_end(&mut bytes)?;
+        let decoded = decode_bytes(&bytes, encoding)?;
+        Ok(process_parallel(&decoded, options, engine, on_update))
+    } else {
+        let decoded = decode_input(io::BufReader::new(read), encoding)?;
+        Ok(process_records(
+            &mut ::csv::Reader::from_reader(decoded),
+            options,
+            engine,
+            on_update,
+        ))
+    }
+}
+
+/// Wraps `input` so it reads as UTF-8.
+/// A byte order mark is stripped and UTF-16 input with a BOM is transcoded.
+/// Input without a BOM is transcoded from `encoding` when given, e.g. `latin1` or `utf-16le`,
+/// and passed through untouched otherwise.
+pub fn decode_input<R: io::Read>(
+    input: R,
+    encoding: Option<&str>,
+) -> Result<impl io::Read, io::Error> {
+    let encoding = match encoding {
+        Some(label) => Some(Encoding::for_label(label.as_bytes()).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown encoding {}", label),
+            )
+        })?),
+        None => None,
+    };
+    Ok(DecodeReaderBytesBuilder::new()
+        .encoding(encoding)
+        .bom_override(true)
+        .strip_bom(true)
+        .utf8_passthru(true)
+        .build(input))
+}
+
+/// Like [`decode_input`] for input that is already in memory.
+/// Only copies the input when it has to be transcoded.
+pub fn decode_bytes<'a>(
+    input: &'a [u8],
+    encoding: Option<&str>,
+) -> Result<Cow<'a, [u8]>, io::Error> {
+    let utf16 = input.starts_with(b"\xFF\xFE") || input.starts_with(b"\xFE\xFF");
+    if encoding.is_none() && !utf16 {
+        return Ok(Cow::Borrowed(
+            input.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(input),
+        ));
+    }
+    let mut decoded = vec![];
+    decode_input(input, encoding)?.read_to_end(&mut decoded)?;
+    Ok(Cow::Owned(decoded))
+}
+
+/// Reads `inner` like `tail -f`: at the end of the input it waits `poll` and reads again,
+/// so rows appended later are read as well and the end of the input is never reported
+#[derive(Debug)]
+pub struct Follow<R> {
+    inner: R,
+    poll: Duration,
+}
+
+impl<R: Read> Follow<R> {
+    pub fn new(inner: R, poll: Duration) -> Self {
+        Follow { inner, poll }
+    }
+}
+
+impl<R: Read> Read for Follow<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            match self.inner.read(buf)? {
+                0 => thread::sleep(self.poll),
+                read => return Ok(read),
+            }
+        }
+    }
+}
+
+/// Seeks the input itself, e.g. to continue a run where it stopped
+impl<R: Seek> Seek for Follow<R> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::io::Follow;
+    use std::io::Read;
+    use std::time::Duration;
+
+    /// Hands out its chunks one read at a time, with an end of input between them
+    struct Appended(Vec<&'static [u8]>);
+
+    impl Read for Appended {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let chunk = self.0.first_mut().map_or(&b""[..], |chunk| *chunk);
+            if chunk.is_empty() {
+                if !self.0.is_empty() {
+                    self.0.remove(0);
+                }
+                return Ok(0);
+            }
+            let read = chunk.len().min(buf.len());
+            buf[..read].copy_from_slice(&chunk[..read]);
+            self.0[0] = &chunk[read..];
+            Ok(read)
+        }
+    }
+
+    #[test]
+    fn follow_reads_past_the_end_of_the_input() {
+        let inner = Appended(vec![b"deposit,1,1,1.0\n", b"deposit,1,2,2.0\n"]);
+        let mut follow = Follow::new(inner, Duration::from_millis(1));
+        let mut buf = [0; 32];
+        let mut read = vec![];
+        while read.len() < 32 {
+            let n = follow.read(&mut buf).unwrap();
+            read.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(read, b"deposit,1,1,1.0\ndeposit,1,2,2.0\n");
+    }
+}
@@ -0,0 +1,114 @@
+//! Input paths that are object store URLs, `s3://bucket/key.csv`, `gs://`, `az://`,
+//! `abfss://` and the others the `object_store` crate recognises.
+//!
+//! The object is streamed through the parser as it downloads, nothing is written to disk.
+//! Credentials and settings come from the environment under the names `object_store`
+//! gives them, e.g. `AWS_ACCESS_KEY_ID`, `AWS_REGION`, `GOOGLE_SERVICE_ACCOUNT` or
+//! `AZURE_STORAGE_ACCOUNT_NAME`, so the usual instance and workload credentials apply.
+use bytes::{Buf, Bytes};
+use futures::stream::{BoxStream, StreamExt};
+use object_store::{parse_url_opts, ObjectStoreScheme};
+use std::io::{self, Read};
+use std::path::Path;
+use tokio::runtime::{self, Runtime};
+use url::Url;
+
+/// The URL of `path` if it names an object in a store, `None` for a local path
+pub fn object_url(path: &Path) -> Option<Url> {
+    let path = path.to_str()?;
+    if !path.contains("://") {
+        return None;
+    }
+    let url = Url::parse(path).ok()?;
+    ObjectStoreScheme::parse(&url).is_ok().then_some(url)
+}
+
+/// Starts downloading the object at `url`, configured by the environment
+pub fn open_object(url: &Url) -> io::Result<ObjectReader> {
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let options = std::env::vars().map(|(key, value)| (key.to_ascii_lowercase(), value));
+    let (store, path) = parse_url_opts(url, options).map_err(io::Error::other)?;
+    let result = runtime.block_on(store.get(&path)).map_err(object_error)?;
+    Ok(ObjectReader {
+        stream: result.into_stream(),
+        chunk: Bytes::new(),
+        runtime,
+    })
+}
+
+fn object_error(err: object_store::Error) -> io::Error {
+    let kind = match err {
+        object_store::Error::NotFound { .. } => io::ErrorKind::NotFound,
+        object_store::Error::PermissionDenied { .. }
+        | object_store::Error::Unauthenticated { .. } => io::ErrorKind::PermissionDenied,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, err)
+}
+
+/// An object as it downloads, read on the calling thread
+pub struct ObjectReader {
+    stream: BoxStream<'static, object_store::Result<Bytes>>,
+    // Downloaded but not read yet
+    chunk: Bytes,
+    runtime: Runtime,
+}
+
+impl Read for ObjectReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while !self.chunk.has_remaining() {
+            match self.runtime.block_on(self.stream.next()) {
+                Some(chunk) => self.chunk = chunk.map_err(object_error)?,
+                None => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.remaining());
+        self.chunk.copy_to_slice(&mut buf[..len]);
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn recognises_object_urls_only() {
+        for url in [
+            "s3://bucket/day.csv",
+            "gs://bucket/day.csv",
+            "az://container/day.csv",
+        ] {
+            assert!(object_url(Path::new(url)).is_some(), "{}", url);
+        }
+        for path in [
+            "day.csv",
+            "/data/day.csv",
+            "C:\\data\\day.csv",
+            "ftp://host/day.csv",
+        ] {
+            assert!(object_url(Path::new(path)).is_none(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn streams_the_object() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let content = "type,client,tx,amount\n".repeat(10_000);
+        file.write_all(content.as_bytes()).unwrap();
+        let url = Url::from_file_path(file.path()).unwrap();
+        let mut read = String::new();
+        open_object(&url)
+            .unwrap()
+            .read_to_string(&mut read)
+            .unwrap();
+        assert_eq!(read, content);
+
+        let missing = Url::from_file_path(file.path().with_extension("missing")).unwrap();
+        let err = open_object(&missing).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,159 @@
+//! Sorting a transactions file by the timestamp of its rows, for feeds that arrive unordered
+//! but carry reliable timestamps.
+//!
+//! Rows are sorted in chunks of at most `buffer_rows`, each written to a temporary file,
+//! and the chunks merged into the output, so the input can be larger than memory.
+use crate::engine::release::parse_timestamp;
+use csv::{ByteRecord, ReaderBuilder, WriterBuilder};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use tracing::info;
+
+/// Copies the CSV `input` to `output` ordered by the ISO 8601 `time_column` of its rows,
+/// see [`parse_timestamp`], keeping at most `buffer_rows` rows in memory.
+///
+/// The sort is stable: rows with the same time keep their order. Rows without a time
+/// go first, in their order, so they aren't applied after transactions that came later.
+/// Rows are copied as they are, malformed ones included, for the parser to report.
+/// Returns the number of temporary files used, none if the input fit in `buffer_rows`.
+pub fn sort_by_time(
+    input: impl Read,
+    output: impl Write,
+    time_column: &str,
+    buffer_rows: usize,
+) -> csv::Result<usize> {
+    let mut reader = ReaderBuilder::new().flexible(true).from_reader(input);
+    let headers = reader.byte_headers()?.clone();
+    let column = headers
+        .iter()
+        .position(|name| name.trim_ascii() == time_column.as_bytes())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no `{}` column to sort by", time_column),
+            )
+        })?;
+    let mut writer = WriterBuilder::new().flexible(true).from_writer(output);
+    writer.write_byte_record(&headers)?;
+
+    let buffer_rows = buffer_rows.max(1);
+    let mut chunk = Vec::new();
+    let mut runs = Vec::new();
+    let mut record = ByteRecord::new();
+    while reader.read_byte_record(&mut record)? {
+        chunk.push((time_of(&record, column), record.clone()));
+        if chunk.len() == buffer_rows {
+            runs.push(spill(&mut chunk)?);
+        }
+    }
+    if runs.is_empty() {
+        chunk.sort_by_key(|(time, _)| *time);
+        for (_, record) in &chunk {
+            writer.write_byte_record(record)?;
+        }
+    } else {
+        if !chunk.is_empty() {
+            runs.push(spill(&mut chunk)?);
+        }
+        merge(&mut runs, column, &mut writer)?;
+    }
+    writer.flush()?;
+    info!(runs = runs.len(), "sorted the input by `{}`", time_column);
+    Ok(runs.len())
+}
+
+fn time_of(record: &ByteRecord, column: usize) -> Option<i64> {
+    let field = std::str::from_utf8(record.get(column)?).ok()?;
+    parse_timestamp(field.trim())
+}
+
+/// Sorts `chunk` into a temporary file, emptying it
+fn spill(chunk: &mut Vec<(Option<i64>, ByteRecord)>) -> csv::Result<File> {
+    chunk.sort_by_key(|(time, _)| *time);
+    let mut writer = WriterBuilder::new()
+        .flexible(true)
+        .has_headers(false)
+        .from_writer(tempfile::tempfile()?);
+    for (_, record) in chunk.drain(..) {
+        writer.write_byte_record(&record)?;
+    }
+    let mut file = writer.into_inner().map_err(|err| err.into_error())?;
+    file.rewind()?;
+    Ok(file)
+}
+
+/// Merges the sorted `runs` into `writer`, taking the earlier run first on equal times
+fn merge(
+    runs: &mut [File],
+    column: usize,
+    writer: &mut csv::Writer<impl Write>,
+) -> csv::Result<()> {
+    let count = runs.len();
+    let mut readers: Vec<_> = runs
+        .iter_mut()
+        .map(|file| {
+            ReaderBuilder::new()
+                .flexible(true)
+                .has_headers(false)
+                .from_reader(io::BufReader::new(file))
+        })
+        .collect();
+    let mut heads = vec![ByteRecord::new(); count];
+    let mut heap = BinaryHeap::with_capacity(count);
+    for (run, reader) in readers.iter_mut().enumerate() {
+        if reader.read_byte_record(&mut heads[run])? {
+            heap.push(Reverse((time_of(&heads[run], column), run)));
+        }
+    }
+    while let Some(Reverse((_, run))) = heap.pop() {
+        writer.write_byte_record(&heads[run])?;
+        if readers[run].read_byte_record(&mut heads[run])? {
+            heap.push(Reverse((time_of(&heads[run], column), run)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUT: &str = "\
+type,client,tx,amount,timestamp
+deposit,1,3,3.0,2024-01-03
+deposit,1,1,1.0,2024-01-01
+withdrawal,1,4,1.0,
+deposit,1,2,2.0,2024-01-02T00:00:00Z
+dispute,1,1,,2024-01-02
+";
+
+    const SORTED: &str = "\
+type,client,tx,amount,timestamp
+withdrawal,1,4,1.0,
+deposit,1,1,1.0,2024-01-01
+deposit,1,2,2.0,2024-01-02T00:00:00Z
+dispute,1,1,,2024-01-02
+deposit,1,3,3.0,2024-01-03
+";
+
+    fn sort(buffer_rows: usize) -> (String, usize) {
+        let mut output = vec![];
+        let runs = sort_by_time(INPUT.as_bytes(), &mut output, "timestamp", buffer_rows).unwrap();
+        (String::from_utf8(output).unwrap(), runs)
+    }
+
+    #[test]
+    fn sorts_in_memory_and_through_temporary_files_alike() {
+        assert_eq!(sort(100), (SORTED.to_string(), 0));
+        assert_eq!(sort(2), (SORTED.to_string(), 3));
+        assert_eq!(sort(1), (SORTED.to_string(), 5));
+    }
+
+    #[test]
+    fn needs_the_time_column() {
+        let err = sort_by_time(INPUT.as_bytes(), vec![], "date", 10).unwrap_err();
+        assert_eq!(err.to_string(), "no `date` column to sort by");
+    }
+}
@@ -0,0 +1,460 @@
+//! Where transactions come from, independent of how they are applied.
+//!
+//! A [`TransactionSource`] hands out transactions one at a time, [`Engine::process_source`]
+//! applies all of them. [`CsvSource`] and [`JsonLinesSource`] cover the formats this crate reads,
+//! other formats only need to implement the trait.
+//!
+//! [`Engine::process_source`]: crate::Engine::process_source
+use crate::ids::{IdFormat, IdNames};
+#[cfg(feature = "csv")]
+use crate::io::csv::{ParseOptions, RowParser};
+use crate::io::{ErrorCode, RowError};
+use crate::model::{Metadata, Transaction};
+use crate::pseudonym::Pseudonymizer;
+#[cfg(feature = "csv")]
+use csv::{ByteRecord, Position, Reader};
+#[cfg(feature = "csv")]
+use std::collections::BTreeMap;
+use std::io;
+use std::io::BufRead;
+
+/// An entry of a source that isn't a transaction, with its line in the input
+pub type SourceError = RowError;
+
+/// Hands out transactions until the input is exhausted
+pub trait TransactionSource {
+    /// The next transaction, `None` at the end of the input
+    fn next_transaction(&mut self) -> Option<Result<Transaction, SourceError>>;
+
+    /// Line of the last transaction handed out, `None` if the source has no lines
+    fn line(&self) -> Option<u64> {
+        None
+    }
+
+    /// Extra fields of the last transaction handed out, `None` if the source doesn't keep them
+    fn metadata(&self) -> Option<&Metadata> {
+        None
+    }
+}
+
+impl<S: TransactionSource + ?Sized> TransactionSource for Box<S> {
+    fn next_transaction(&mut self) -> Option<Result<Transaction, SourceError>> {
+        (**self).next_transaction()
+    }
+
+    fn line(&self) -> Option<u64> {
+        (**self).line()
+    }
+
+    fn metadata(&self) -> Option<&Metadata> {
+        (**self).metadata()
+    }
+}
+
+/// Transactions read as CSV rows according to [`ParseOptions`], ignoring its mode.
+/// Rows of an unknown type are skipped with [`crate::UnknownTypes::Skip`] and counted.
+/// Columns beyond the [`crate::COLUMNS`] are ignored unless [`CsvSource::keep_metadata`] is set.
+#[cfg(feature = "csv")]
+pub struct CsvSource<'a, R> {
+    reader: Reader<R>,
+    options: &'a ParseOptions,
+    parser: RowParser<'a>,
+    record: ByteRecord,
+    unknown_types: BTreeMap<String, u64>,
+    metadata: Option<Metadata>,
+    done: bool,
+}
+
+#[cfg(feature = "csv")]
+impl<'a, R: io::Read> CsvSource<'a, R> {
+    /// Reads the headers of `reader` right away, failing if they can't be read
+    pub fn new(mut reader: Reader<R>, options: &'a ParseOptions) -> Result<Self, SourceError> {
+        let headers = match reader.has_headers() {
+            true => Some(
+                reader
+                    .byte_headers()
+                    .map_err(|err| RowError::new(&err, &ByteRecord::new()))?
+                    .clone(),
+            ),
+            false => None,
+        };
+        Ok(CsvSource {
+            parser: RowParser::new(options, headers.as_ref()),
+            reader,
+            options,
+            record: ByteRecord::new(),
+            unknown_types: BTreeMap::new(),
+            metadata: None,
+            done: false,
+        })
+    }
+
+    /// Where the row after the last one handed out starts, to continue from with
+    /// [`CsvSource::seek`]
+    pub fn position(&self) -> &Position {
+        self.reader.position()
+    }
+
+    /// Keep the fields of the other columns of every row, see [`TransactionSource::metadata`]
+    pub fn keep_metadata(mut self) -> Self {
+        self.metadata = Some(Metadata::new());
+        self
+    }
+
+    /// Number of rows skipped per unknown transaction type so far
+    pub fn unknown_types(&self) -> &BTreeMap<String, u64> {
+        &self.unknown_types
+    }
+
+    /// Whether the headers have `column`, after [`ParseOptions::column_aliases`].
+    /// Inputs without headers have none.
+    pub fn has_column(&self, column: &str) -> bool {
+        self.parser
+            .headers()
+            .is_some_and(|headers| headers.iter().any(|header| header == column.as_bytes()))
+    }
+}
+
+#[cfg(feature = "csv")]
+impl<R: io::Read + io::Seek> CsvSource<'_, R> {
+    /// Continues with the row at `position`, a [`CsvSource::position`] of the same input,
+    /// e.g. taken by an earlier run that was interrupted. Rows keep their lines.
+    pub fn seek(&mut self, position: Position) -> Result<(), SourceError> {
+        self.reader
+            .seek(position)
+            .map_err(|err| RowError::new(&err, &ByteRecord::new()))
+    }
+}
+
+#[cfg(feature = "csv")]
+impl<R: io::Read> TransactionSource for CsvSource<'_, R> {
+    fn next_transaction(&mut self) -> Option<Result<Transaction, SourceError>> {
+        while !self.done {
+            match self.reader.read_byte_record(&mut self.record) {
+                Ok(true) => {}
+                Ok(false) => self.done = true,
+                Err(err) => {
+                    // The underlying reader failed, there is nothing more to read
+                    self.done = err.is_io_error();
+                    return Some(Err(self.options.redact(RowError::new(&err, &self.record))));
+                }
+            }
+            if self.done {
+                break;
+            }
+            let transaction = match self.parser.parse(&self.record) {
+                Ok(transaction) => transaction,
+                Err(error) => match self.parser.parse_unknown(&self.record) {
+                    Some(unknown) => {
+                        *self
+                            .unknown_types
+                            .entry(unknown.transaction_type)
+                            .or_default() += 1;
+                        continue;
+                    }
+                    None => return Some(Err(self.options.redact(error))),
+                },
+            };
+            if let Some(metadata) = &mut self.metadata {
+                *metadata = self.parser.metadata(&self.record);
+            }
+            return Some(
+                self.options
+                    .check_amount(&transaction, &self.record, 0)
+                    .map(|()| transaction),
+            );
+        }
+        None
+    }
+
+    fn line(&self) -> Option<u64> {
+        self.record.position().map(|position| position.line())
+    }
+
+    fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
+}
+
+/// Transactions read as JSON Lines, one object per line with the fields of the CSV columns,
+/// e.g. `{"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}`.
+/// Amounts are strings so no precision is lost to floating point. Blank lines are skipped.
+pub struct JsonLinesSource<R> {
+    input: R,
+    buffer: String,
+    line: u64,
+    pseudonyms: Option<Pseudonymizer>,
+    client_ids: IdFormat,
+    tx_ids: IdFormat,
+    done: bool,
+}
+
+impl<R: BufRead> JsonLinesSource<R> {
+    pub fn new(input: R) -> Self {
+        JsonLinesSource {
+            input,
+            buffer: String::new(),
+            line: 0,
+            done: false,
+            pseudonyms: None,
+            client_ids: IdFormat::U16,
+            tx_ids: IdFormat::U32,
+        }
+    }
+
+    /// Read client ids as `format`, as [`crate::ParseOptions::client_ids`]. Text ids may be
+    /// JSON strings or numbers.
+    pub fn client_ids(mut self, format: IdFormat) -> Self {
+        self.client_ids = format;
+        self
+    }
+
+    /// Read tx ids as `format`, as [`crate::ParseOptions::tx_ids`]
+    pub fn tx_ids(mut self, format: IdFormat) -> Self {
+        self.tx_ids = format;
+        self
+    }
+
+    /// Replace client ids with their pseudonyms, as [`crate::ParseOptions::pseudonyms`]
+    pub fn pseudonymize(mut self, pseudonyms: Pseudonymizer) -> Self {
+        self.pseudonyms = Some(pseudonyms);
+        self
+    }
+}
+
+impl<R: BufRead> TransactionSource for JsonLinesSource<R> {
+    fn next_transaction(&mut self) -> Option<Result<Transaction, SourceError>> {
+        while !self.done {
+            self.buffer.clear();
+            self.line += 1;
+            match self.input.read_line(&mut self.buffer) {
+                Ok(0) => self.done = true,
+                Ok(_) => {}
+                // The line was read, it just isn't UTF-8
+                Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                    return Some(Err(RowError {
+                        line: self.line,
+                        record: String::new(),
+                        code: ErrorCode::MalformedRow,
+                        message: err.to_string(),
+                    }))
+                }
+                Err(err) => {
+                    // The underlying reader failed, there is nothing more to read
+                    self.done = true;
+                    return Some(Err(RowError {
+                        line: self.line,
+                        record: String::new(),
+                        code: ErrorCode::Io,
+                        message: err.to_string(),
+                    }));
+                }
+            }
+            if self.done {
+                break;
+            }
+            let line = self.buffer.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let parsed = match (self.client_ids.names(), self.tx_ids.names()) {
+                (None, None) => serde_json::from_str(line).map_err(|err| err.to_string()),
+                names => with_text_ids(line, names),
+            };
+            let parsed = parsed
+                .and_then(|transaction: Transaction| {
+                    self.client_ids.check("client", transaction.client)?;
+                    self.tx_ids.check("tx", transaction.tx)?;
+                    Ok(transaction)
+                })
+                .map_err(|message| RowError {
+                    line: self.line,
+                    record: line.to_string(),
+                    code: ErrorCode::MalformedRow,
+                    message,
+                });
+            return Some(match &self.pseudonyms {
+                Some(pseudonyms) => parsed
+                    .map(|transaction: Transaction| Transaction {
+                        client: pseudonyms.pseudonym(transaction.client),
+                        ..transaction
+                    })
+                    .map_err(|error| pseudonyms.redact(error)),
+                None => parsed,
+            });
+        }
+        None
+    }
+
+    fn line(&self) -> Option<u64> {
+        Some(self.line)
+    }
+}
+
+/// Reads a JSON line whose client or tx ids are text, with the names of either,
+/// replaced by their number before deserializing
+fn with_text_ids(
+    line: &str,
+    (client_names, tx_names): (Option<&IdNames>, Option<&IdNames>),
+) -> Result<Transaction, String> {
+    let mut object: serde_json::Value =
+        serde_json::from_str(line).map_err(|err| err.to_string())?;
+    for (field, names) in [("client", client_names), ("tx", tx_names)] {
+        let (Some(id), Some(names)) = (object.get_mut(field), names) else {
+            continue;
+        };
+        let name = match &*id {
+            serde_json::Value::String(name) => name.clone(),
+            other => other.to_string(),
+        };
+        *id = names.id(&name)?.into();
+    }
+    serde_json::from_value(object).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ids::{IdFormat, IdNames};
+    use crate::io::source::{JsonLinesSource, TransactionSource};
+    use crate::io::ErrorCode;
+    use crate::model::TransactionType;
+    use rust_decimal::Decimal;
+    use std::io::{self, BufReader, Read};
+
+    #[test]
+    fn json_lines_are_read_with_their_line() {
+        let data = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}
+
+{"type": "withdrawal", "client": 1, "tx": 2, "amount": "0.5"}
+{"type": "teleport", "client": 1, "tx": 3}
+{"type": "dispute", "client": 1, "tx": 1}"#;
+        let mut source = JsonLinesSource::new(data.as_bytes());
+        let deposit = source.next_transaction().unwrap().unwrap();
+        assert_eq!(deposit.amount, Some(Decimal::new(15, 1)));
+        let withdrawal = source.next_transaction().unwrap().unwrap();
+        assert_eq!(withdrawal.amount, Some(Decimal::new(5, 1)));
+        assert_eq!(source.line(), Some(3));
+        let error = source.next_transaction().unwrap().unwrap_err();
+        assert_eq!(error.line, 4);
+        let dispute = source.next_transaction().unwrap().unwrap();
+        assert_eq!(dispute.transaction_type, TransactionType::Dispute);
+        assert_eq!(dispute.amount, None);
+        assert!(source.next_transaction().is_none());
+    }
+
+    #[test]
+    fn json_lines_end_at_a_failing_reader() {
+        /// Fails every read after its lines
+        struct Failing(&'static [u8]);
+
+        impl Read for Failing {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                match self.0.is_empty() {
+                    true => Err(io::Error::other("disk on fire")),
+                    false => self.0.read(buf),
+                }
+            }
+        }
+
+        let data = b"{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": \"1\"}\n\xff\n";
+        let mut source = JsonLinesSource::new(BufReader::new(Failing(data)));
+        assert!(source.next_transaction().unwrap().is_ok());
+        let invalid = source.next_transaction().unwrap().unwrap_err();
+        assert_eq!((invalid.line, invalid.code), (2, ErrorCode::MalformedRow));
+        let failed = source.next_transaction().unwrap().unwrap_err();
+        assert_eq!((failed.line, failed.code), (3, ErrorCode::Io));
+        assert!(source.next_transaction().is_none());
+    }
+
+    #[test]
+    fn json_lines_can_have_text_client_ids() {
+        let data = r#"{"type": "deposit", "client": "acct-1", "tx": 1, "amount": "1.5"}
+{"type": "deposit", "client": 7, "tx": 2, "amount": "1"}"#;
+        let names = IdNames::new();
+        let mut source =
+            JsonLinesSource::new(data.as_bytes()).client_ids(IdFormat::Text(names.clone()));
+        let deposit = source.next_transaction().unwrap().unwrap();
+        assert_eq!(names.name(deposit.client).as_deref(), Some("acct-1"));
+        let deposit = source.next_transaction().unwrap().unwrap();
+        assert_eq!(names.name(deposit.client).as_deref(), Some("7"));
+
+        let wide = r#"{"type": "deposit", "client": 70000, "tx": 1, "amount": "1"}"#;
+        let mut source = JsonLinesSource::new(wide.as_bytes());
+        let error = source.next_transaction().unwrap().unwrap_err();
+        assert_eq!(error.message, "client id 70000 is too large, at most 65535");
+        let mut source = JsonLinesSource::new(wide.as_bytes()).client_ids(IdFormat::U32);
+        assert_eq!(source.next_transaction().unwrap().unwrap().client, 70000);
+
+        let uuid = r#"{"type": "deposit", "client": 1, "tx": "0b7e-41", "amount": "1"}"#;
+        let mut source =
+            JsonLinesSource::new(uuid.as_bytes()).tx_ids(IdFormat::Text(names.clone()));
+        let deposit = source.next_transaction().unwrap().unwrap();
+        assert_eq!(names.name(deposit.tx).as_deref(), Some("0b7e-41"));
+    }
+
+    #[test]
+    fn one_engine_applies_several_sources() {
+        let mut engine = crate::Engine::new();
+        let first = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "2"}"#;
+        let second = r#"{"type": "dispute", "client": 1, "tx": 1}
+{"type": "deposit", "client": 1, "tx": 1, "amount": "2"}"#;
+        let mode = crate::ParseMode::Collecting;
+        let errors = engine.apply_source(JsonLinesSource::new(first.as_bytes()), mode);
+        assert_eq!(errors, Ok(vec![]));
+        let errors = engine
+            .apply_source(JsonLinesSource::new(second.as_bytes()), mode)
+            .unwrap();
+        assert_eq!(errors[0].message, "duplicate tx id 1");
+        assert_eq!(engine.accounts()[&1].held, Decimal::new(2, 0));
+    }
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn sources_drive_the_engine() {
+        use crate::io::csv::ParseOptions;
+        use crate::io::source::CsvSource;
+        use crate::Engine;
+
+        let data = "type,client,tx,amount
+deposit,1,1,2.0
+deposit,1,x,1.0
+deposit,1,1,1.0
+withdrawal,1,2,0.5";
+        let options = ParseOptions::default();
+        let reader = csv::Reader::from_reader(data.as_bytes());
+        let source = CsvSource::new(reader, &options).unwrap();
+        let report = Engine::new().process_source(source);
+        assert_eq!(report.accounts[&1].available, Decimal::new(15, 1));
+        let lines: Vec<u64> = report.errors.iter().map(|error| error.line).collect();
+        assert_eq!(lines, [3, 4]);
+        assert_eq!(report.errors[1].message, "duplicate tx id 1");
+    }
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn csv_source_keeps_other_columns_as_metadata() {
+        use crate::io::csv::ParseOptions;
+        use crate::io::source::CsvSource;
+
+        let data = "type,client,merchant,tx,amount,reference
+deposit,1, Shop ,1,2.0,abc
+withdrawal,1,,2,1.0,";
+        let options = ParseOptions::default();
+        let reader = csv::Reader::from_reader(data.as_bytes());
+        let mut source = CsvSource::new(reader, &options).unwrap().keep_metadata();
+        source.next_transaction().unwrap().unwrap();
+        let metadata = source.metadata().unwrap();
+        assert_eq!(metadata["merchant"], "Shop");
+        assert_eq!(metadata["reference"], "abc");
+        assert_eq!(metadata.len(), 2);
+        assert!(source.has_column("merchant"));
+        assert!(!source.has_column("timestamp"));
+        source.next_transaction().unwrap().unwrap();
+        assert_eq!(source.metadata().unwrap()["merchant"], "");
+
+        let reader = csv::Reader::from_reader(data.as_bytes());
+        let mut source = CsvSource::new(reader, &options).unwrap();
+        source.next_transaction().unwrap().unwrap();
+        assert_eq!(source.metadata(), None);
+    }
+}
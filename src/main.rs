@@ -1,12 +1,2799 @@
-use std::env;
+use std::fmt::Display;
+use std::fs::File;
+use std::io;
+use std::io::{IsTerminal, Seek, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+#[cfg(feature = "encryption")]
+use std::sync::OnceLock;
+use std::time::Duration;
+use std::{fs, iter, process};
 
-use transaction_parser::{process_transactions, write_stdout};
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use rust_decimal::Decimal;
+use tracing::level_filters::LevelFilter;
+use transaction_parser::checkpoint::{resume_checkpoint, write_checkpoint, ResumeToken};
+use transaction_parser::dedup::{read_hash, SeenContent};
+use transaction_parser::diagnostics::{Diagnostics, Phase};
+use transaction_parser::diff::diff_accounts;
+#[cfg(feature = "encryption")]
+use transaction_parser::encryption::{
+    is_encrypted, DecryptionKey, EncryptedWriter, EncryptionRecipients,
+};
+use transaction_parser::generate::{Generator, GeneratorConfig};
+use transaction_parser::io::sort::sort_by_time;
+use transaction_parser::io::{open, open_raw};
+use transaction_parser::prelude::*;
+use transaction_parser::replay::{Pace, ReplaySink};
+#[cfg(feature = "arrow")]
+use transaction_parser::report::arrow::{write_accounts_ipc, UpdateLogWriter};
+use transaction_parser::report::audit::{last_record, verify_audit_log, AuditLog};
+#[cfg(feature = "kafka")]
+use transaction_parser::report::kafka::KafkaSink;
+#[cfg(feature = "redis")]
+use transaction_parser::report::redis::RedisSink;
+use transaction_parser::service;
+use transaction_parser::service::feed::{Feed, FeedHandler, FeedLoop};
+#[cfg(unix)]
+use transaction_parser::service::listen::ConnectionLimit;
+use transaction_parser::service::watch::Watcher;
+use transaction_parser::sharded::process_sharded;
+use transaction_parser::stats::file_stats;
+use transaction_parser::validate::validate_transactions;
+use transaction_parser::verify::{net_deposits, trial_balance};
+use transaction_parser::webhook::{Webhook, WebhookClient, WebhookUrl};
+#[cfg(all(unix, feature = "dashmap"))]
+use transaction_parser::SharedAccounts;
+use transaction_parser::{
+    default_type_aliases, read_accounts, read_client_attributes, read_schedules, read_tier_limits,
+    AmountLimits, BalanceAlert, EngineState, Eviction, IdFormat, IdNames, InvariantCheck, Ledger,
+    LedgerWriter, MinorUnitsEngine, NegativeBalanceBehavior, Period, PeriodReport, Pseudonymizer,
+    RedisCommandWriter, RiskTiers, RowHandler, RowLoop, RowOrigin, TxId, Violation, WireFormat,
+    COLUMNS,
+};
+
+/// Computes account balances from a CSV of transactions
+#[derive(Parser)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    process: ProcessArgs,
+
+    /// Log more to stderr: skipped rows and locked accounts, then ignored disputes with -vv
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Log less to stderr, -qq turns logging off
+    #[arg(short, long, global = true, action = ArgAction::Count, conflicts_with = "verbose")]
+    quiet: u8,
+
+    /// Log one JSON object per line instead of text, including every row skipped with
+    /// --mode lenient or collecting
+    #[arg(long, global = true)]
+    log_json: bool,
+
+    /// File with the age identity, `AGE-SECRET-KEY-1...` as written by age-keygen, to read
+    /// encrypted files with. It's only loaded once one is read, runs that just encrypt don't
+    /// need it. TRANSACTION_PARSER_KEY may hold the key itself instead.
+    #[cfg(feature = "encryption")]
+    #[arg(long, value_name = "PATH", global = true)]
+    key_file: Option<PathBuf>,
+
+    /// Encrypt the accounts written to stdout, the --snapshot and --save-state files and the
+    /// --updates, --events, --ledger and --audit-log files to this age public key, `age1...`,
+    /// so balances don't land on disk in plaintext. Repeat it to encrypt to several keys.
+    #[cfg(feature = "encryption")]
+    #[arg(long, value_name = "RECIPIENT", global = true)]
+    recipient: Vec<EncryptionRecipients>,
+
+    /// Encrypt like --recipient to the public keys of a file, one per line
+    #[cfg(feature = "encryption")]
+    #[arg(long, value_name = "PATH", global = true)]
+    recipients_file: Option<PathBuf>,
+}
+
+/// Set by --log-json
+static LOG_JSON: AtomicBool = AtomicBool::new(false);
+
+/// Set from --recipient and --recipients-file, what is written is encrypted to them
+#[cfg(feature = "encryption")]
+static RECIPIENTS: OnceLock<Option<EncryptionRecipients>> = OnceLock::new();
+
+/// Set from --key-file, read once an encrypted file is
+#[cfg(feature = "encryption")]
+static KEY_FILE: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+impl Cli {
+    fn init_logging(&self) {
+        let level = match self.verbose as i8 - self.quiet as i8 {
+            i8::MIN..=-2 => LevelFilter::OFF,
+            -1 => LevelFilter::ERROR,
+            0 => LevelFilter::WARN,
+            1 => LevelFilter::INFO,
+            2 => LevelFilter::DEBUG,
+            _ => LevelFilter::TRACE,
+        };
+        let logger = tracing_subscriber::fmt()
+            .with_max_level(level)
+            .with_writer(io::stderr)
+            .with_ansi(io::stderr().is_terminal());
+        LOG_JSON.store(self.log_json, Ordering::Relaxed);
+        if self.log_json {
+            logger.json().init();
+        } else {
+            logger.without_time().with_target(false).init();
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    fn init_encryption(&self) {
+        let mut recipients = self.recipient.clone();
+        if let Some(path) = &self.recipients_file {
+            let content = fs::read_to_string(path).unwrap_or_else(|err| exit_with(path, err));
+            recipients.push(content.parse().unwrap_or_else(|err| exit_with(path, err)));
+        }
+        let recipients = (!recipients.is_empty()).then(|| recipients.into_iter().collect());
+        let _ = RECIPIENTS.set(recipients);
+        let _ = KEY_FILE.set(self.key_file.clone());
+    }
+}
+
+/// The recipients of --recipient and --recipients-file, if any
+#[cfg(feature = "encryption")]
+fn recipients() -> Option<&'static EncryptionRecipients> {
+    RECIPIENTS.get().and_then(Option::as_ref)
+}
+
+/// The key of --key-file or TRANSACTION_PARSER_KEY, loaded the first time the encrypted file
+/// `path` needs it
+#[cfg(feature = "encryption")]
+fn decryption_key(path: &Path) -> &'static DecryptionKey {
+    static KEY: OnceLock<DecryptionKey> = OnceLock::new();
+    KEY.get_or_init(|| match KEY_FILE.get().and_then(Option::as_ref) {
+        Some(key_file) => {
+            let content =
+                fs::read_to_string(key_file).unwrap_or_else(|err| exit_with(key_file, err));
+            content
+                .parse()
+                .unwrap_or_else(|err| exit_with(key_file, err))
+        }
+        None => {
+            let Ok(key) = std::env::var(DecryptionKey::ENV) else {
+                let hint = format!("encrypted, pass --key-file or set {}", DecryptionKey::ENV);
+                exit_with(path, hint);
+            };
+            key.parse().unwrap_or_else(|err| {
+                eprintln!("{}: {}", DecryptionKey::ENV, err);
+                process::exit(2);
+            })
+        }
+    })
+}
+
+/// Runs `write` on `output`, encrypted to the --recipient keys if given
+fn write_encrypted<E: From<io::Error>>(
+    mut output: impl Write,
+    write: impl FnOnce(&mut dyn Write) -> Result<(), E>,
+) -> Result<(), E> {
+    #[cfg(feature = "encryption")]
+    if let Some(recipients) = recipients() {
+        let mut encrypted = recipients.encrypt(output)?;
+        write(&mut encrypted)?;
+        return Ok(encrypted.finish()?.flush()?);
+    }
+    write(&mut output)?;
+    Ok(output.flush()?)
+}
+
+/// The content of `path`, decrypted with the key if it is encrypted
+fn read_file(path: &Path) -> Vec<u8> {
+    let content = fs::read(path).unwrap_or_else(|err| exit_with(path, err));
+    #[cfg(feature = "encryption")]
+    if is_encrypted(&content) {
+        let mut decrypted = vec![];
+        decryption_key(path)
+            .decrypt(&content[..])
+            .and_then(|mut plain| io::copy(&mut plain, &mut decrypted))
+            .unwrap_or_else(|err| exit_with(path, err));
+        return decrypted;
+    }
+    content
+}
+
+#[derive(Args)]
+struct ProcessArgs {
+    /// Transactions CSV to process
+    input: Option<PathBuf>,
+
+    #[command(flatten)]
+    rules: RuleArgs,
+
+    #[command(flatten)]
+    alerts: AlertArgs,
+
+    #[command(flatten)]
+    format: FormatArgs,
+
+    #[command(flatten)]
+    output: OutputArgs,
+
+    /// Memory-map the input instead of reading it through a buffer, faster on very large files.
+    /// The file must not be modified while it is processed.
+    #[arg(long)]
+    mmap: bool,
+
+    /// Parse the input on all cores. Reads the whole file into memory unless combined with --mmap.
+    #[arg(long)]
+    parallel: bool,
+
+    /// Keep reading the input as rows are appended, like `tail -f`, until interrupted.
+    /// The accounts are written to --snapshot instead of stdout.
+    #[arg(
+        long,
+        group = "live",
+        requires = "snapshot",
+        conflicts_with_all = ["mmap", "parallel"]
+    )]
+    follow: bool,
+
+    /// Serve on this Unix socket instead of reading a file, until interrupted. Every connection
+    /// sends one record per line without a header, as CSV or JSON (--listen-format).
+    /// The accounts are written to --snapshot instead of stdout. In strict mode a bad record
+    /// closes the connection it came on, not the server.
+    #[cfg(unix)]
+    #[arg(
+        long,
+        value_name = "SOCKET",
+        group = "live",
+        requires = "snapshot",
+        conflicts_with_all = ["input", "mmap", "parallel"]
+    )]
+    listen: Option<PathBuf>,
+
+    /// Answer balance queries on this Unix socket while serving --listen, without waiting for
+    /// the transactions being applied: every connection sends client ids one per line and gets
+    /// each account back as a JSON line, `null` if there is none
+    #[cfg(all(unix, feature = "dashmap"))]
+    #[arg(long, value_name = "SOCKET", requires = "listen")]
+    query_socket: Option<PathBuf>,
+
+    /// Format of the records sent to --listen
+    #[cfg(unix)]
+    #[arg(long, value_enum, default_value_t = ListenFormat::Csv)]
+    listen_format: ListenFormat,
+
+    /// Clients --listen and --query-socket each serve at once, a connection over it is
+    /// closed right away
+    #[cfg(unix)]
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 64,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    max_connections: u64,
+
+    /// With --follow or --listen, rewrite the accounts to this file whenever they changed,
+    /// at most once per --refresh
+    #[arg(long, value_name = "PATH", requires = "live")]
+    snapshot: Option<PathBuf>,
+
+    /// Seconds between two snapshots with --follow or --listen, at least 1. Also how long
+    /// the feed is waited for before the snapshot is written.
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    refresh: u64,
+
+    /// With --follow, save where the input was read up to and the accounts and transactions
+    /// after it to this file every --checkpoint-interval, replacing it atomically. A run started
+    /// with an existing checkpoint resumes from it, so an interrupted backfill applies every row
+    /// exactly once. The input must be UTF-8 and only ever be appended to. Rows after the last
+    /// checkpoint may show up twice in --updates and the other outputs. Only the accounts and
+    /// transactions are saved, so disputes held back by --unknown-refs defer or
+    /// --retry-out-of-order and the eviction of --keep-transactions can't be checkpointed.
+    #[arg(
+        long,
+        value_name = "PATH",
+        requires = "follow",
+        conflicts_with_all = ["encoding", "retry_out_of_order", "keep_transactions"]
+    )]
+    checkpoint: Option<PathBuf>,
+
+    /// Seconds between two --checkpoint saves
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 60,
+        requires = "checkpoint"
+    )]
+    checkpoint_interval: u64,
+
+    /// Write the new balances of every account change to this file while processing,
+    /// e.g. a named pipe read by a dashboard
+    #[arg(long, value_name = "PATH")]
+    updates: Option<PathBuf>,
+
+    /// Format of --updates
+    #[arg(long, value_enum, default_value_t = UpdatesFormat::Csv, requires = "updates")]
+    updates_format: UpdatesFormat,
+
+    /// Publish every account change to this Kafka topic while processing, one JSON object
+    /// like those of --updates-format json per message, keyed by client
+    #[cfg(feature = "kafka")]
+    #[arg(
+        long,
+        value_name = "TOPIC",
+        requires = "kafka_brokers",
+        conflicts_with_all = ["shards", "minor_units"]
+    )]
+    kafka_topic: Option<String>,
+
+    /// Bootstrap servers of the --kafka-topic cluster, `host:port[,host:port...]`
+    #[cfg(feature = "kafka")]
+    #[arg(long, value_name = "HOSTS", requires = "kafka_topic")]
+    kafka_brokers: Option<String>,
+
+    /// Keep a hash of the balances of every client on this Redis server live while
+    /// processing, `client:<id>` with the fields of --updates-format redis,
+    /// e.g. `redis://cache:6379/0`
+    #[cfg(feature = "redis")]
+    #[arg(long, value_name = "URL", conflicts_with_all = ["shards", "minor_units"])]
+    redis_url: Option<String>,
+
+    /// Write every change as an account event (Deposited, FundsHeld, Locked, ...) to this file,
+    /// one JSON object per line
+    #[arg(long, value_name = "PATH")]
+    events: Option<PathBuf>,
+
+    /// Keep the input columns beyond type, client, tx and amount, e.g. a description,
+    /// and add them to every JSON --events and --updates object as `metadata`
+    #[arg(long, conflicts_with_all = ["mmap", "parallel", "live"])]
+    metadata: bool,
+
+    /// Add the file and line each JSON --events and --updates object stems from
+    /// as `source`. Like with --metadata the file is then read row by row.
+    #[arg(long, conflicts_with_all = ["mmap", "parallel"])]
+    provenance: bool,
+
+    /// Write deposit and withdrawal volumes and dispute counts per period and client
+    /// to this CSV file, dated by --time-column. The file is read row by row.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["mmap", "parallel", "live"])]
+    periods: Option<PathBuf>,
+
+    /// Write every update as double-entry bookings against system accounts
+    /// (cash, fees) to this CSV file. Exits with an error after processing
+    /// if the ledger doesn't balance to zero or disagrees with the accounts.
+    #[arg(long, value_name = "PATH", conflicts_with = "initial_state")]
+    ledger: Option<PathBuf>,
+
+    /// Append every update to this tamper-evident log, each record with the hash of the one
+    /// before, continuing the chain of an existing log. Prints the last record to stderr as
+    /// `audit: <seq>:<hash>`, to keep elsewhere. `verify-audit` checks the log.
+    #[arg(long, value_name = "PATH")]
+    audit_log: Option<PathBuf>,
+
+    /// Length of the --periods
+    #[arg(long, value_enum, default_value_t = PeriodLength::Month, requires = "periods")]
+    period: PeriodLength,
+
+    /// Resolve disputes still open this long after they were opened, e.g. `30d`, `12h`,
+    /// `90m` or `45s`, as of the --time-column of each row. The file is read row by row.
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration,
+        conflicts_with_all = ["mmap", "parallel", "live"]
+    )]
+    release_holds_after: Option<Duration>,
+
+    /// Apply the standing deposits and withdrawals of this CSV file as they fall due,
+    /// `type,client,amount,every,start[,end]` with `*` for every client, as of the
+    /// --time-column of each row. The file is read row by row.
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["mmap", "parallel", "live", "pseudonymize"]
+    )]
+    schedule: Option<PathBuf>,
+
+    /// Reject withdrawals that would take what a client withdrew in the last 24 hours,
+    /// as of the --time-column of each row, over this amount as WITHDRAWAL_LIMIT.
+    /// The file is read row by row.
+    #[arg(
+        long,
+        value_name = "AMOUNT",
+        conflicts_with_all = ["mmap", "parallel", "live"]
+    )]
+    daily_withdrawal_limit: Option<Decimal>,
+
+    /// Keep deposits and withdrawals for disputes to reference only this long, e.g. `120d`,
+    /// as of the --time-column of each row, to bound memory. Disputes of older transactions
+    /// are ignored and counted at the end. The file is read row by row.
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration,
+        conflicts_with_all = ["mmap", "parallel", "live", "keep_transactions"]
+    )]
+    dispute_window: Option<Duration>,
+
+    /// Input column with the ISO 8601 date or timestamp of each row, for --periods,
+    /// --release-holds-after, --schedule, --daily-withdrawal-limit, --dispute-window
+    /// and --sort-by-time. Those but the sort need it, rows with a missing or bad time
+    /// being malformed.
+    #[arg(long, value_name = "NAME", default_value = "timestamp")]
+    time_column: String,
+
+    /// Sort the input by --time-column before applying it, for feeds that arrive unordered.
+    /// Rows without a time go first. Lines reported for skipped rows are those of the sorted
+    /// input. The file is read row by row.
+    #[arg(long, conflicts_with_all = ["mmap", "parallel", "live"])]
+    sort_by_time: bool,
+
+    /// Rows --sort-by-time keeps in memory, larger inputs are sorted through temporary files
+    #[arg(
+        long,
+        value_name = "ROWS",
+        default_value_t = 1_000_000,
+        requires = "sort_by_time"
+    )]
+    sort_buffer_rows: usize,
+
+    /// Apply the rows on this many threads, each owning the accounts of a shard of clients.
+    /// Every client's rows are still applied in order, but no updates, events, ledger or
+    /// alerts are written.
+    #[arg(
+        long,
+        value_name = "THREADS",
+        conflicts_with_all = [
+            "mmap", "parallel", "live", "updates", "events", "ledger", "audit_log", "metadata",
+            "provenance",
+            "periods", "release_holds_after", "schedule", "daily_withdrawal_limit",
+            "dispute_window", "tier_limits", "sort_by_time", "lock_webhook", "alert_below"
+        ]
+    )]
+    shards: Option<usize>,
+
+    /// Keep balances as integer ten-thousandths instead of decimals, for the highest
+    /// throughput. The results are the same for amounts of up to four decimal places, rows
+    /// with more are skipped as BAD_AMOUNT. Only the default rules are supported, and no
+    /// updates, events, ledger or alerts are written.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "mmap", "parallel", "live", "updates", "events", "ledger", "audit_log", "metadata",
+            "provenance",
+            "periods", "release_holds_after", "schedule", "daily_withdrawal_limit",
+            "dispute_window", "sort_by_time", "shards", "lock_webhook", "alert_below",
+            "unknown_refs", "report_repeated_disputes", "no_redisputes", "unlock_on_reversal",
+            "reject_locked", "retry_out_of_order", "negative_balances", "check_invariants", "initial_state",
+            "max_amount", "max_deposit", "max_withdrawal", "client_attributes", "tier_limits",
+            "keep_transactions"
+        ]
+    )]
+    minor_units: bool,
+
+    /// Print a hash of the final balances to stderr, `digest: <32 hex digits>`,
+    /// the same for the same balances on every run and machine
+    #[arg(long)]
+    digest: bool,
+
+    /// Save the accounts and the transactions disputes can reference to this file
+    /// after processing, for `query`. Written as JSON if the path ends in `.json`.
+    #[arg(long, value_name = "PATH", conflicts_with = "live")]
+    save_state: Option<PathBuf>,
+
+    /// Skip the input without applying it if a file with the same content was processed
+    /// before, as recorded in --seen-file, e.g. a daily file uploaded twice. The accounts
+    /// are written as if the input had no rows.
+    #[arg(long, requires = "seen_file", conflicts_with = "live")]
+    skip_repeated: bool,
+
+    /// File keeping the content hashes of the inputs --skip-repeated processed, created if
+    /// missing and updated once an input is processed
+    #[arg(long, value_name = "PATH", requires = "skip_repeated")]
+    seen_file: Option<PathBuf>,
+
+    /// Also write the accounts to this Arrow IPC file, with their activity, for analytics
+    /// tools such as pyarrow, polars or DuckDB
+    #[cfg(feature = "arrow")]
+    #[arg(long, value_name = "PATH", conflicts_with = "live")]
+    arrow: Option<PathBuf>,
+
+    /// Write every update to this Arrow IPC file, the columns of --updates
+    #[cfg(feature = "arrow")]
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["shards", "minor_units"])]
+    arrow_updates: Option<PathBuf>,
+
+    /// Write the client behind each --pseudonymize pseudonym of the accounts to this CSV file,
+    /// `pseudonym,client`, to keep apart from the outputs it unlocks
+    #[arg(
+        long,
+        value_name = "PATH",
+        requires = "pseudonymize",
+        conflicts_with = "live"
+    )]
+    pseudonym_map: Option<PathBuf>,
+
+    /// Print where the run spent its time and memory to stderr at the end: rows per second,
+    /// time spent parsing, applying and writing, and the peak memory use. The file is read
+    /// row by row unless --mmap, --parallel or --shards say otherwise, which only tell the time
+    /// spent parsing and applying together.
+    #[arg(long, conflicts_with = "live")]
+    diagnostics: bool,
+}
+
+/// How rows are checked and applied
+#[derive(Args)]
+struct RuleArgs {
+    /// How rows that fail to parse are handled
+    #[arg(long, value_enum, default_value_t = Mode::Lenient)]
+    mode: Mode,
+
+    /// What happens to disputes, resolves and chargebacks of a tx id that hasn't been seen yet
+    #[arg(long, value_enum, default_value_t = UnknownRefs::Ignore)]
+    unknown_refs: UnknownRefs,
+
+    /// Treat disputes of a transaction that is already under dispute as malformed rows
+    /// instead of ignoring them
+    #[arg(long)]
+    report_repeated_disputes: bool,
+
+    /// Ignore disputes of a transaction whose earlier dispute was resolved
+    /// instead of holding its funds again
+    #[arg(long)]
+    no_redisputes: bool,
+
+    /// Unlock the account when the chargeback that locked it is reversed
+    #[arg(long)]
+    unlock_on_reversal: bool,
+
+    /// Skip deposits and withdrawals of locked accounts as LOCKED_ACCOUNT instead of
+    /// applying them
+    #[arg(long)]
+    reject_locked: bool,
+
+    /// Keep only the most recent N deposits and withdrawals for disputes to reference,
+    /// besides those under dispute, to bound memory. Disputes of older transactions are
+    /// ignored and counted at the end.
+    #[arg(long, value_name = "N")]
+    keep_transactions: Option<usize>,
+
+    /// Keep resolves and chargebacks that don't apply yet because they are ahead of their
+    /// dispute, and retry them after every later row of the same transaction
+    #[arg(long)]
+    retry_out_of_order: bool,
+
+    /// What happens to withdrawals and dispute holds of more than the available funds
+    #[arg(long, value_enum, default_value_t = NegativeBalances::Allow)]
+    negative_balances: NegativeBalances,
+
+    /// Reject deposits and withdrawals without an amount and resolves and chargebacks
+    /// with one, instead of only logging a warning
+    #[arg(long)]
+    strict_amounts: bool,
+
+    /// Debugging: check the engine's invariants after every applied transaction and exit
+    /// with the transaction and the account before and after it on the first violation
+    #[arg(long)]
+    check_invariants: bool,
+
+    /// Start from the accounts of this accounts file or --save-state file, e.g. yesterday's
+    /// closing balances, instead of empty ones. Only a state file lets disputes reference
+    /// earlier transactions.
+    #[arg(long, value_name = "PATH")]
+    initial_state: Option<PathBuf>,
+
+    /// Reject deposits and withdrawals over this amount as AMOUNT_LIMIT
+    /// before they touch any balance
+    #[arg(long, value_name = "AMOUNT")]
+    max_amount: Option<Decimal>,
+
+    /// Reject deposits over this amount, see --max-amount
+    #[arg(long, value_name = "AMOUNT")]
+    max_deposit: Option<Decimal>,
+
+    /// Reject withdrawals over this amount, see --max-amount
+    #[arg(long, value_name = "AMOUNT")]
+    max_withdrawal: Option<Decimal>,
+
+    /// CSV with the risk tier and KYC level of clients, `client,risk_tier[,kyc_level]`,
+    /// whose --tier-limits apply on top of the others
+    #[arg(long, value_name = "PATH", requires = "tier_limits")]
+    client_attributes: Option<PathBuf>,
+
+    /// CSV with the limits of each risk tier, a `tier` column and any of `max_amount`,
+    /// `max_deposit`, `max_withdrawal` and `daily_withdrawal_limit`. The tighter limit wins.
+    #[arg(long, value_name = "PATH", requires = "client_attributes")]
+    tier_limits: Option<PathBuf>,
+
+    /// Replace client ids with pseudonyms in all outputs and logs, keyed with the content of
+    /// this file, at least 16 bytes of secret. The same key gives the same pseudonyms on every
+    /// run, so accounts and --initial-state files only carry over between runs with the same
+    /// key. The records of skipped rows are redacted.
+    #[arg(long, value_name = "KEYFILE", conflicts_with = "client_attributes")]
+    pseudonymize: Option<PathBuf>,
+}
+
+/// Who is told about accounts needing attention while processing
+#[derive(Args)]
+struct AlertArgs {
+    /// POST a JSON object with the client, the chargeback's tx and the balances to this
+    /// http:// URL, or https:// with the tls feature, whenever an account gets locked
+    #[arg(long, value_name = "URL")]
+    lock_webhook: Option<WebhookUrl>,
+
+    /// Warn when an account's available funds drop below this amount, e.g. 0 to catch
+    /// negative balances
+    #[arg(long, value_name = "AMOUNT", allow_hyphen_values = true)]
+    alert_below: Option<Decimal>,
+
+    /// POST the --alert-below alerts to this URL instead of logging them
+    #[arg(long, value_name = "URL", requires = "alert_below")]
+    alert_webhook: Option<WebhookUrl>,
+}
+
+/// Columns of the accounts written to stdout
+#[derive(Args)]
+struct OutputArgs {
+    /// Header names: v1 ends in `locked,balance`, v2 in `total,locked` as in the specification
+    #[arg(long, value_enum, default_value_t = Schema::V1)]
+    schema: Schema,
+
+    /// Decimal places of every amount written, at most 28. Amounts too large for that many
+    /// places keep the ones that fit.
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(..=28))]
+    scale: u32,
+
+    /// Add deposits, withdrawals, deposited and withdrawn columns counting each client's activity
+    #[arg(long)]
+    extended: bool,
+
+    /// Add a `disputed` column with the transactions each account has under dispute
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    disputed: Option<Disputed>,
+
+    /// Add a `status` column: active, frozen by a chargeback, closed or under_review
+    #[arg(long = "status")]
+    status_column: bool,
+
+    /// Write only these columns, in this order, e.g. `--columns client,total`
+    #[arg(long = "columns", value_name = "COLUMN", value_delimiter = ',')]
+    select_columns: Option<Vec<Column>>,
+
+    /// Leave out these columns, e.g. `--omit-columns locked`
+    #[arg(long, value_name = "COLUMN", value_delimiter = ',')]
+    omit_columns: Vec<Column>,
+
+    /// Only write locked accounts
+    #[arg(long)]
+    only_locked: bool,
+
+    /// Leave out accounts without available or held funds
+    #[arg(long)]
+    skip_zero_balances: bool,
+
+    /// Only write these clients, e.g. `--clients 100-200,7`
+    #[arg(long, value_name = "RANGE", value_delimiter = ',', value_parser = parse_clients)]
+    clients: Vec<RangeInclusive<ClientId>>,
+
+    /// Bytes of output buffered before they are written, fewer writes for millions of accounts
+    #[arg(long, value_name = "BYTES", default_value_t = 64 * 1024)]
+    output_buffer: usize,
+}
+
+impl RuleArgs {
+    /// The --mode, where --log-json has lenient mode collect the skipped rows to report them
+    fn parse_mode(&self) -> ParseMode {
+        match self.mode {
+            Mode::Lenient if LOG_JSON.load(Ordering::Relaxed) => ParseMode::Collecting,
+            mode => mode.into(),
+        }
+    }
+
+    fn parse_options(&self, format: &FormatArgs) -> ParseOptions {
+        let options = format.parse_options(self.parse_mode());
+        if self.pseudonymize.is_some() && options.client_ids.names().is_some() {
+            eprintln!("--pseudonymize needs numeric --client-ids");
+            process::exit(2);
+        }
+        let bits = options.client_ids.bits();
+        ParseOptions {
+            strict_amounts: self.strict_amounts,
+            pseudonyms: self
+                .pseudonymize
+                .as_deref()
+                .map(|path| read_pseudonym_key(path).within(bits)),
+            ..options
+        }
+    }
+
+    /// The engine for rows read with `options`, which learn the names of text ids
+    /// in the --initial-state
+    fn engine(&self, options: &ParseOptions) -> Engine {
+        let mut engine = Engine::new();
+        if let Some(path) = &self.initial_state {
+            let mut state = load_state(path);
+            options.learn_names(&mut state);
+            engine.restore(state);
+        }
+        engine.set_unknown_reference(self.unknown_refs.into());
+        engine.set_report_repeated_disputes(self.report_repeated_disputes);
+        engine.set_allow_redisputes(!self.no_redisputes);
+        engine.set_unlock_on_reversal(self.unlock_on_reversal);
+        engine.set_reject_locked(self.reject_locked);
+        engine.set_negative_balance_behavior(self.negative_balances.into());
+        engine.set_retry_out_of_order(self.retry_out_of_order);
+        if let Some(keep) = self.keep_transactions {
+            engine.set_eviction(Eviction::KeepLast(keep));
+        }
+        if let Some(names) = options.tx_ids.names() {
+            engine.set_tx_names(names.clone());
+        }
+        engine.set_amount_limits(AmountLimits {
+            max: self.max_amount,
+            deposit: self.max_deposit,
+            withdrawal: self.max_withdrawal,
+        });
+        if let (Some(clients), Some(tiers)) = (&self.client_attributes, &self.tier_limits) {
+            let read = |path: &Path| {
+                File::open(path)
+                    .map(csv::Reader::from_reader)
+                    .unwrap_or_else(|err| exit_with(path, err))
+            };
+            let clients = read_client_attributes(&mut read(clients))
+                .unwrap_or_else(|err| exit_with(clients, err));
+            let tiers =
+                read_tier_limits(&mut read(tiers)).unwrap_or_else(|err| exit_with(tiers, err));
+            engine.set_risk_tiers(RiskTiers { clients, tiers });
+        }
+        if self.check_invariants {
+            let check = InvariantCheck::new(|violation: &Violation| {
+                eprintln!("invariant violated: {}", violation);
+                process::exit(1);
+            });
+            engine.add_observer(check.with_accounts(engine.accounts()));
+        }
+        engine
+    }
+}
+
+impl AlertArgs {
+    /// Adds the observers sending the alerts to `engine`.
+    /// The returned webhooks have to be finished once `engine` is gone.
+    fn install(&self, engine: &mut Engine) -> Vec<Webhook> {
+        let mut webhooks = vec![];
+        if let Some(url) = &self.lock_webhook {
+            let webhook = Webhook::spawn(url.clone());
+            engine.add_observer(LockWebhook(webhook.sender()));
+            webhooks.push(webhook);
+        }
+        if let Some(limit) = self.alert_below {
+            let webhook = self.alert_webhook.clone().map(Webhook::spawn);
+            let sender = webhook.as_ref().map(Webhook::sender);
+            engine.add_observer(BalanceAlert::new(limit, move |update| match &sender {
+                Some(sender) => {
+                    let body = serde_json::json!({
+                        "event": "balance_below",
+                        "client": update.client,
+                        "tx": update.tx,
+                        "type": update.transaction_type,
+                        "available": update.available,
+                        "held": update.held,
+                        "limit": limit,
+                    });
+                    let _ = sender.send(body.to_string());
+                }
+                None => tracing::warn!(
+                    client = update.client,
+                    tx = update.tx,
+                    "available {} dropped below {}",
+                    update.available,
+                    limit
+                ),
+            }));
+            webhooks.extend(webhook);
+        }
+        webhooks
+    }
+}
+
+impl OutputArgs {
+    /// The output options, writing back the names of text ids
+    fn output_options(&self, client_ids: &IdFormat, tx_ids: &IdFormat) -> OutputOptions {
+        OutputOptions {
+            scale: Some(self.scale),
+            schema: self.schema.into(),
+            extended: self.extended,
+            disputed: self.disputed.map(Into::into),
+            status: self.status_column,
+            columns: self.select_columns.clone(),
+            omit: self.omit_columns.clone(),
+            only_locked: self.only_locked,
+            skip_zero_balances: self.skip_zero_balances,
+            clients: self.clients.clone(),
+            buffer_size: self.output_buffer,
+            client_names: client_ids.names().cloned(),
+            tx_names: tx_ids.names().cloned(),
+        }
+    }
+}
+
+/// How the input is read, shared by processing and validation
+#[derive(Args)]
+struct FormatArgs {
+    /// Keep whitespace around fields instead of trimming it
+    #[arg(long)]
+    no_trim: bool,
+
+    /// Only accept lowercase transaction types
+    #[arg(long)]
+    case_sensitive: bool,
+
+    /// Encoding of input without a byte order mark, e.g. `latin1` or `utf-16le`
+    #[arg(long)]
+    encoding: Option<String>,
+
+    /// Read the input header HEADER as column FIELD, e.g. `--column client=customer_id`
+    #[arg(long = "column", value_name = "FIELD=HEADER", value_parser = parse_column)]
+    columns: Vec<(String, String)>,
+
+    /// Read transaction type NAME as TYPE, e.g. `--type-alias payout=withdrawal`.
+    /// `withdraw` and `charge_back` are accepted already.
+    #[arg(long, value_name = "NAME=TYPE", value_parser = parse_type_alias)]
+    type_alias: Vec<(String, TransactionType)>,
+
+    /// What happens to rows of an unknown transaction type
+    #[arg(long, value_enum, default_value_t = UnknownTypesArg::Reject)]
+    unknown_types: UnknownTypesArg,
+
+    /// How amounts are written, localized formats also drop currency symbols and codes
+    #[arg(long, value_enum, default_value_t = Amounts::Plain)]
+    amount_format: Amounts,
+
+    /// How client ids are written, rows with wider ones are malformed
+    #[arg(long, value_enum, default_value_t = Ids::U16)]
+    client_ids: Ids,
+
+    /// How tx ids are written, `text` for UUIDs and other strings
+    #[arg(long, value_enum, default_value_t = Ids::U32)]
+    tx_ids: Ids,
+}
+
+impl FormatArgs {
+    fn parse_options(&self, mode: ParseMode) -> ParseOptions {
+        ParseOptions {
+            mode,
+            trim: !self.no_trim,
+            case_insensitive: !self.case_sensitive,
+            column_aliases: self
+                .columns
+                .iter()
+                .map(|(field, header)| (header.clone(), field.clone()))
+                .collect(),
+            amount_format: self.amount_format.into(),
+            unknown_types: self.unknown_types.into(),
+            type_aliases: default_type_aliases()
+                .into_iter()
+                .chain(self.type_alias.iter().cloned())
+                .collect(),
+            client_ids: self.client_ids.into(),
+            tx_ids: self.tx_ids.into(),
+            ..ParseOptions::default()
+        }
+    }
+}
+
+/// Parses a client id or an inclusive `FIRST-LAST` range of them
+fn parse_clients(s: &str) -> Result<RangeInclusive<ClientId>, String> {
+    let parse = |client: &str| {
+        client
+            .trim()
+            .parse::<ClientId>()
+            .map_err(|err| format!("invalid client `{}`: {}", client, err))
+    };
+    match s.split_once('-') {
+        Some((first, last)) => match (parse(first)?, parse(last)?) {
+            (first, last) if first > last => Err(format!(
+                "range `{}` ends before it starts, write `{}-{}`",
+                s, last, first
+            )),
+            (first, last) => Ok(first..=last),
+        },
+        None => parse(s).map(|client| client..=client),
+    }
+}
+
+/// Parses a duration in days, hours, minutes or seconds, e.g. `30d`
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let unit = match s.chars().last() {
+        Some('d') => 86_400,
+        Some('h') => 3600,
+        Some('m') => 60,
+        Some('s') => 1,
+        _ => return Err("expected a number of days, hours, minutes or seconds, e.g. 30d".into()),
+    };
+    let count: u64 = s[..s.len() - 1].parse().map_err(|err| format!("{}", err))?;
+    count
+        .checked_mul(unit)
+        .map(Duration::from_secs)
+        .ok_or_else(|| "too long".to_string())
+}
+
+/// Parses a `NAME=TYPE` transaction type alias
+fn parse_type_alias(s: &str) -> Result<(String, TransactionType), String> {
+    let (name, transaction_type) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=TYPE, got `{}`", s))?;
+    let transaction_type = transaction_type
+        .to_ascii_lowercase()
+        .parse()
+        .map_err(|_| format!("unknown transaction type `{}`", transaction_type))?;
+    Ok((name.to_string(), transaction_type))
+}
+
+/// Parses a `FIELD=HEADER` column mapping
+fn parse_column(s: &str) -> Result<(String, String), String> {
+    let (field, header) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected FIELD=HEADER, got `{}`", s))?;
+    if !COLUMNS.contains(&field) {
+        return Err(format!(
+            "unknown field `{}`, expected one of {}",
+            field,
+            COLUMNS.join(", ")
+        ));
+    }
+    Ok((field.to_string(), header.to_string()))
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ListenFormat {
+    /// `type,client,tx,amount`, e.g. `deposit,1,1,1.5`
+    Csv,
+    /// One object per line, e.g. `{"type":"deposit","client":1,"tx":1,"amount":"1.5"}`
+    Json,
+}
+
+impl From<ListenFormat> for WireFormat {
+    fn from(format: ListenFormat) -> Self {
+        match format {
+            ListenFormat::Csv => WireFormat::Csv,
+            ListenFormat::Json => WireFormat::Json,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum UpdatesFormat {
+    /// `client,tx,type,amount,available,held,locked,status` with a header
+    Csv,
+    /// One object per line with the same fields, a message per update for e.g. `kcat -P`
+    Json,
+    /// `HSET client:<id>` commands upserting the balances, for `redis-cli --pipe`
+    Redis,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum PeriodLength {
+    Day,
+    Month,
+}
+
+impl From<PeriodLength> for Period {
+    fn from(length: PeriodLength) -> Self {
+        match length {
+            PeriodLength::Day => Period::Day,
+            PeriodLength::Month => Period::Month,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Mode {
+    /// Skip malformed rows
+    Lenient,
+    /// Skip malformed rows and report them on stderr
+    Collecting,
+    /// Abort on the first malformed row
+    Strict,
+}
+
+impl From<Mode> for ParseMode {
+    fn from(mode: Mode) -> Self {
+        match mode {
+            Mode::Lenient => ParseMode::Lenient,
+            Mode::Collecting => ParseMode::Collecting,
+            Mode::Strict => ParseMode::Strict,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Amounts {
+    /// Plain decimals, e.g. 1234.56
+    Plain,
+    /// `.` as decimal separator, e.g. 1,234.56
+    DecimalPoint,
+    /// `,` as decimal separator, e.g. 1.234,56
+    DecimalComma,
+}
+
+impl From<Amounts> for AmountFormat {
+    fn from(amounts: Amounts) -> Self {
+        match amounts {
+            Amounts::Plain => AmountFormat::Plain,
+            Amounts::DecimalPoint => AmountFormat::DecimalPoint,
+            Amounts::DecimalComma => AmountFormat::DecimalComma,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Ids {
+    /// Numbers up to 65535, client ids of the specification
+    U16,
+    /// Numbers up to 4294967295, tx ids of the specification
+    U32,
+    /// Numbers up to 18446744073709551615
+    U64,
+    /// Any text, written back as read in the accounts output
+    Text,
+}
+
+impl From<Ids> for IdFormat {
+    fn from(ids: Ids) -> Self {
+        match ids {
+            Ids::U16 => IdFormat::U16,
+            Ids::U32 => IdFormat::U32,
+            Ids::U64 => IdFormat::U64,
+            Ids::Text => IdFormat::Text(IdNames::new()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum UnknownTypesArg {
+    /// Treat them as malformed rows, see --mode
+    Reject,
+    /// Skip them and report how many there were of each type on stderr
+    Skip,
+}
+
+impl From<UnknownTypesArg> for UnknownTypes {
+    fn from(unknown_types: UnknownTypesArg) -> Self {
+        match unknown_types {
+            UnknownTypesArg::Reject => UnknownTypes::Reject,
+            UnknownTypesArg::Skip => UnknownTypes::Skip,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Schema {
+    V1,
+    V2,
+}
+
+impl From<Schema> for OutputSchema {
+    fn from(schema: Schema) -> Self {
+        match schema {
+            Schema::V1 => OutputSchema::V1,
+            Schema::V2 => OutputSchema::V2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Disputed {
+    /// How many transactions are under dispute
+    Count,
+    /// Their tx ids, separated by `;`
+    List,
+}
+
+impl From<Disputed> for DisputedColumn {
+    fn from(disputed: Disputed) -> Self {
+        match disputed {
+            Disputed::Count => DisputedColumn::Count,
+            Disputed::List => DisputedColumn::List,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum UnknownRefs {
+    /// Ignore them
+    Ignore,
+    /// Treat them like malformed rows, see --mode
+    Reject,
+    /// Apply them once the referenced transaction arrives, for unordered input
+    Defer,
+}
+
+impl From<UnknownRefs> for UnknownReference {
+    fn from(unknown_refs: UnknownRefs) -> Self {
+        match unknown_refs {
+            UnknownRefs::Ignore => UnknownReference::Ignore,
+            UnknownRefs::Reject => UnknownReference::Reject,
+            UnknownRefs::Defer => UnknownReference::Defer,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum NegativeBalances {
+    /// Treat them like malformed rows, see --mode
+    Reject,
+    /// Withdraw or hold only the available funds
+    Clamp,
+    /// Let the available funds go negative
+    Allow,
+}
+
+impl From<NegativeBalances> for NegativeBalanceBehavior {
+    fn from(negative_balances: NegativeBalances) -> Self {
+        match negative_balances {
+            NegativeBalances::Reject => NegativeBalanceBehavior::Reject,
+            NegativeBalances::Clamp => NegativeBalanceBehavior::ClampToZero,
+            NegativeBalances::Allow => NegativeBalanceBehavior::Allow,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Write a synthetic transactions CSV for benchmarks and stress tests
+    Generate(GenerateArgs),
+    /// Replay generated transactions through the engine and print the time taken
+    /// and a digest of the balances, for load tests and comparing versions
+    Simulate(SimulateArgs),
+    /// Send the rows of a transactions file on to a socket, webhook or pipe at a steady rate
+    /// or as far apart as their timestamps, to load-test the systems that consume them
+    Replay(ReplayArgs),
+    /// Check a transactions file without computing balances, listing every problem found.
+    /// Exits with status 1 if there are any.
+    Validate(ValidateArgs),
+    /// Count the rows of a transactions file per type, its clients, tx ids and amounts,
+    /// without computing balances
+    Stats(StatsArgs),
+    /// Process every CSV dropped into a directory against the same accounts, moving each
+    /// to an archive directory once applied. Runs until interrupted.
+    Watch(Box<WatchArgs>),
+    /// Print one account of a state saved with --save-state, with the transactions it has
+    /// under dispute, without processing anything
+    Query(QueryArgs),
+    /// Check an accounts file written by this tool adds up: every total is available plus held,
+    /// no held funds are negative and, with --input, the totals sum to the net deposits.
+    /// Exits with status 1 and lists the discrepancies if not.
+    Verify(VerifyArgs),
+    /// Compare two accounts files or --save-state files, printing every added, removed
+    /// or changed account as a JSON object per line. Exits with status 1 if they differ.
+    Diff(DiffArgs),
+    /// Combine the accounts of several accounts files or --save-state files, e.g. of shards
+    /// or regions, and print them. Balances are summed and an account locked in any input
+    /// is locked.
+    Merge(MergeArgs),
+    /// Check an --audit-log is intact: every record follows from the one before, none was
+    /// changed, inserted or removed. Prints the last record as `<seq>:<hash>`, exits with
+    /// status 1 at the first record that doesn't.
+    VerifyAudit(VerifyAuditArgs),
+}
+
+#[derive(Args)]
+struct VerifyAuditArgs {
+    /// Log written with --audit-log
+    log: PathBuf,
+}
+
+#[derive(Args)]
+struct MergeArgs {
+    #[arg(required = true, num_args = 2..)]
+    inputs: Vec<PathBuf>,
+
+    /// Save the merged accounts and transactions to this file, see --save-state
+    #[arg(long, value_name = "PATH")]
+    save_state: Option<PathBuf>,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Args)]
+struct DiffArgs {
+    before: PathBuf,
+    after: PathBuf,
+}
+
+#[derive(Args)]
+struct VerifyArgs {
+    /// Accounts CSV to check
+    accounts: PathBuf,
+
+    /// Transactions CSV the accounts were computed from, whose deposits less withdrawals and
+    /// chargebacks the totals have to add up to. Summed from the rows without the engine,
+    /// so accounts computed with limits or --negative-balances reject don't add up.
+    #[arg(long, value_name = "PATH")]
+    input: Option<PathBuf>,
+
+    #[command(flatten)]
+    format: FormatArgs,
+}
+
+#[derive(Args)]
+struct QueryArgs {
+    /// State file written by --save-state
+    #[arg(long, value_name = "PATH")]
+    state: PathBuf,
+
+    /// Client id, or the text id of a state saved with --client-ids text
+    #[arg(long)]
+    client: String,
+}
+
+#[derive(Args)]
+struct ValidateArgs {
+    /// Transactions CSV to check
+    input: PathBuf,
+
+    #[command(flatten)]
+    format: FormatArgs,
+}
+
+#[derive(Args)]
+struct StatsArgs {
+    /// Transactions CSV to inspect
+    input: PathBuf,
+
+    #[command(flatten)]
+    format: FormatArgs,
+}
+
+#[derive(Args)]
+struct WatchArgs {
+    /// Directory new transactions CSVs are dropped into
+    dir: PathBuf,
+
+    /// Where processed files are moved to, created if missing
+    #[arg(long, value_name = "DIR")]
+    archive: PathBuf,
+
+    /// Rewrite the accounts to this file after every processed file
+    #[arg(long, value_name = "PATH")]
+    snapshot: PathBuf,
+
+    /// Save the accounts and the transactions disputes can reference to this file after
+    /// every processed file, to pick up where it stopped with --initial-state. Written as
+    /// JSON if the path ends in `.json`.
+    #[arg(long, value_name = "PATH")]
+    save_state: Option<PathBuf>,
+
+    /// Archive files with the same content as a file processed before without applying them,
+    /// e.g. a daily file uploaded twice
+    #[arg(long)]
+    skip_repeated: bool,
+
+    /// Keep the content hashes of processed files in this file, so --skip-repeated also
+    /// recognises files of earlier runs. Created if missing, updated after every file.
+    #[arg(long, value_name = "PATH", requires = "skip_repeated")]
+    seen_file: Option<PathBuf>,
+
+    /// Seconds between two scans of the directory.
+    /// A file is processed once its size didn't change between two scans.
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 5,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    interval: u64,
+
+    #[command(flatten)]
+    rules: RuleArgs,
+
+    #[command(flatten)]
+    alerts: AlertArgs,
+
+    #[command(flatten)]
+    format: FormatArgs,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Args)]
+struct GenerateArgs {
+    #[command(flatten)]
+    generator: GeneratorArgs,
+    /// Output file, defaults to stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+/// Shape of generated transactions
+#[derive(Args)]
+struct GeneratorArgs {
+    /// Number of distinct clients
+    #[arg(long, default_value_t = 1000)]
+    clients: ClientId,
+    /// Number of rows to generate
+    #[arg(long, default_value_t = 10_000)]
+    rows: u64,
+    /// Probability of a row opening (or settling) a dispute
+    #[arg(long, default_value_t = 0.01, value_parser = parse_rate)]
+    dispute_rate: f64,
+    /// Share of settled disputes charged back rather than resolved
+    #[arg(long, default_value_t = 0.1, value_parser = parse_rate)]
+    chargeback_rate: f64,
+    /// Seed for reproducible output
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+impl GeneratorArgs {
+    fn config(&self) -> GeneratorConfig {
+        GeneratorConfig {
+            clients: self.clients,
+            rows: self.rows,
+            dispute_rate: self.dispute_rate,
+            chargeback_rate: self.chargeback_rate,
+            seed: self.seed,
+        }
+    }
+}
+
+#[derive(Args)]
+struct SimulateArgs {
+    #[command(flatten)]
+    generator: GeneratorArgs,
+    /// Exit with status 1 if the digest of the resulting balances differs,
+    /// e.g. the one printed by the previous version
+    #[arg(long, value_name = "DIGEST", value_parser = parse_digest)]
+    expect_digest: Option<u128>,
+}
+
+fn parse_digest(digest: &str) -> Result<u128, String> {
+    u128::from_str_radix(digest, 16).map_err(|_| format!("`{}` is not a hex digest", digest))
+}
+
+#[derive(Args)]
+struct ReplayArgs {
+    /// Transactions CSV to replay
+    input: PathBuf,
+
+    /// Send this many rows per second
+    #[arg(long, value_name = "ROWS", value_parser = parse_positive, conflicts_with = "timestamps")]
+    rate: Option<f64>,
+
+    /// Send the rows as far apart as their --time-column says. Rows without a time go
+    /// right after the row before.
+    #[arg(long)]
+    timestamps: bool,
+
+    /// With --timestamps, replay this many times faster, e.g. 60 for an hour in a minute
+    #[arg(long, value_parser = parse_positive, default_value_t = 1.0, requires = "timestamps")]
+    speed: f64,
+
+    /// Input column with the ISO 8601 date or timestamp of each row, for --timestamps
+    #[arg(long, value_name = "NAME", default_value = "timestamp")]
+    time_column: String,
+
+    /// Format of the rows sent, as --listen reads them. --webhook always gets JSON.
+    #[arg(long, value_enum, default_value_t = ListenFormat::Csv)]
+    send_format: ListenFormat,
+
+    /// Write the rows to this file instead of stdout, e.g. a named pipe
+    #[arg(short, long, value_name = "PATH", group = "target")]
+    output: Option<PathBuf>,
+
+    /// Produce every row as a message to this Kafka topic, keyed by client. Rows the
+    /// brokers didn't acknowledge by the end fail the replay.
+    #[cfg(feature = "kafka")]
+    #[arg(
+        long,
+        value_name = "TOPIC",
+        group = "target",
+        requires = "kafka_brokers"
+    )]
+    kafka_topic: Option<String>,
+
+    /// Bootstrap servers of the --kafka-topic cluster, `host:port[,host:port...]`
+    #[cfg(feature = "kafka")]
+    #[arg(long, value_name = "HOSTS", requires = "kafka_topic")]
+    kafka_brokers: Option<String>,
+
+    /// Send the rows to this Unix socket, e.g. the --listen socket of another instance
+    #[cfg(unix)]
+    #[arg(long, value_name = "SOCKET", group = "target")]
+    socket: Option<PathBuf>,
+
+    /// POST every row as a JSON object to this http:// URL, or https:// with the tls
+    /// feature, one request at a time. Rows the endpoint doesn't take are counted as failed.
+    #[arg(long, value_name = "URL", group = "target")]
+    webhook: Option<WebhookUrl>,
+
+    #[command(flatten)]
+    format: FormatArgs,
+}
+
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(value) if (0.0..=1.0).contains(&value) => Ok(value),
+        _ => Err(format!("`{}` is not a rate from 0 to 1", s)),
+    }
+}
+
+fn parse_positive(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(value) if value.is_finite() && value > 0.0 => Ok(value),
+        _ => Err(format!("`{}` is not a positive number", s)),
+    }
+}
 
 fn main() {
-    // Since we are only accepting the first positional argument
-    // There is no need for a more advanced parser like clap
-    let args: Vec<String> = env::args().collect();
-    let mut reader = csv::Reader::from_path(args[1].clone()).unwrap();
-    let accounts = process_transactions(&mut reader);
-    write_stdout(&accounts);
+    let cli = Cli::parse();
+    cli.init_logging();
+    #[cfg(feature = "encryption")]
+    cli.init_encryption();
+    match cli.command {
+        Some(Command::Generate(args)) => generate(args),
+        Some(Command::Simulate(args)) => simulate(&args),
+        Some(Command::Replay(args)) => replay(&args),
+        Some(Command::Validate(args)) => validate(&args),
+        Some(Command::Stats(args)) => stats(&args),
+        Some(Command::Watch(args)) => watch(&args),
+        Some(Command::Query(args)) => query(&args),
+        Some(Command::Verify(args)) => verify(&args),
+        Some(Command::Diff(args)) => diff(&args),
+        Some(Command::Merge(args)) => merge(&args),
+        Some(Command::VerifyAudit(args)) => verify_audit(&args),
+        #[cfg(unix)]
+        None if cli.process.listen.is_some() => listen(&cli.process),
+        None => match &cli.process.input {
+            Some(input) if cli.process.follow => follow(input, &cli.process),
+            Some(input) => process(input, &cli.process),
+            None => {
+                eprintln!("usage: transaction_parser <transactions.csv>");
+                process::exit(2);
+            }
+        },
+    }
+}
+
+fn process(path: &Path, args: &ProcessArgs) {
+    let mut outputs = Outputs::create(args);
+    let input = InputOptions {
+        encoding: args.format.encoding.clone(),
+        mmap: args.mmap,
+        parallel: args.parallel,
+    };
+    let options = args.rules.parse_options(&args.format);
+    let mut diagnostics = Diagnostics::new(args.diagnostics);
+    let mut webhooks = vec![];
+    let seen = args
+        .seen_file
+        .as_deref()
+        .map(|seen_path| (seen_path, load_seen(seen_path), file_hash(path)));
+    let repeated = seen
+        .as_ref()
+        .is_some_and(|(_, seen, hash)| seen.contains_hash(*hash));
+    let report = if repeated {
+        ProcessReport::from(args.rules.engine(&options))
+    } else if args.minor_units {
+        diagnostics.time(Phase::Process, || process_minor_units(path, args, &options))
+    } else if let Some(shards) = args.shards {
+        diagnostics.time(Phase::Process, || {
+            process_shards(path, shards, args, &options)
+        })
+    } else if args.metadata
+        || args.provenance
+        || args.periods.is_some()
+        || args.release_holds_after.is_some()
+        || args.schedule.is_some()
+        || args.daily_withdrawal_limit.is_some()
+        || args.dispute_window.is_some()
+        || args.rules.tier_limits.is_some()
+        || args.sort_by_time
+        || (args.diagnostics && !args.mmap && !args.parallel)
+    {
+        let mut engine = args.rules.engine(&options);
+        webhooks = args.alerts.install(&mut engine);
+        process_rows(path, args, &options, engine, &mut outputs, &mut diagnostics)
+    } else {
+        let mut engine = args.rules.engine(&options);
+        webhooks = args.alerts.install(&mut engine);
+        let processing = diagnostics.start();
+        let processed = process_file(path, &input, &options, engine, |update| {
+            outputs.write(update, Origin::default())
+        });
+        diagnostics.stop(processing, Phase::Process);
+        match processed {
+            Ok(Ok(report)) => report,
+            Ok(Err(err)) => exit_with(path, err),
+            Err(err) => exit_with(path, err),
+        }
+    };
+    diagnostics.time(Phase::Write, || outputs.finish(&report.accounts));
+    webhooks.into_iter().for_each(Webhook::finish);
+    if args.digest {
+        eprintln!("digest: {:032x}", report.state_digest());
+    }
+    for error in &report.errors {
+        report_skipped(path, error);
+    }
+    if repeated {
+        report_repeated(path);
+    }
+    for (transaction_type, count) in &report.unknown_types {
+        if LOG_JSON.load(Ordering::Relaxed) {
+            let object = serde_json::json!({
+                "file": path,
+                "unknown_type": transaction_type,
+                "rows": count,
+            });
+            eprintln!("{}", object);
+        } else {
+            eprintln!(
+                "{}: skipped {} rows of unknown type `{}`",
+                path.display(),
+                count,
+                transaction_type
+            );
+        }
+    }
+    if report.lost_to_eviction > 0 {
+        if LOG_JSON.load(Ordering::Relaxed) {
+            let object = serde_json::json!({
+                "file": path,
+                "lost_to_eviction": report.lost_to_eviction,
+            });
+            eprintln!("{}", object);
+        } else {
+            eprintln!(
+                "{}: ignored {} disputes, resolves and chargebacks of evicted transactions or dropped while waiting",
+                path.display(),
+                report.lost_to_eviction
+            );
+        }
+    }
+    let writing = diagnostics.start();
+    let stdout = io::stdout().lock();
+    let written = write_encrypted(stdout, |output| {
+        let output_options = args
+            .output
+            .output_options(&options.client_ids, &options.tx_ids);
+        write_accounts_with(&report.accounts, output, &output_options)
+    });
+    #[cfg(feature = "arrow")]
+    if let Some(arrow_path) = &args.arrow {
+        let file = File::create(arrow_path).unwrap_or_else(|err| exit_with(arrow_path, err));
+        write_accounts_ipc(&report.accounts, io::BufWriter::new(file))
+            .unwrap_or_else(|err| exit_with(arrow_path, err));
+    }
+    if let (Some(map_path), Some(pseudonyms)) = (&args.pseudonym_map, &options.pseudonyms) {
+        write_pseudonym_map(map_path, &report.accounts, pseudonyms);
+    }
+    if let Some(state_path) = &args.save_state {
+        let mut state = report.into_state();
+        [state.client_names, state.tx_names] = options.text_id_names();
+        save_state(state_path, &state);
+    }
+    if let Some((seen_path, mut seen, hash)) = seen.filter(|_| !repeated) {
+        seen.insert_hash(hash);
+        save_seen(seen_path, &seen);
+    }
+    diagnostics.stop(writing, Phase::Write);
+    if args.diagnostics {
+        let json = LOG_JSON.load(Ordering::Relaxed);
+        let _ = diagnostics.write_report(path, json, io::stderr().lock());
+    }
+    if let Err(err) = written {
+        // The reader went away, e.g. `| head`, there is no one left to tell
+        if matches!(err.kind(), csv::ErrorKind::Io(err) if err.kind() == io::ErrorKind::BrokenPipe)
+        {
+            return;
+        }
+        eprintln!("error writing accounts: {}", err);
+        process::exit(1);
+    }
+}
+
+/// Applies the rows of `path` on `shards` threads, see [`process_sharded`]
+fn process_shards(
+    path: &Path,
+    shards: usize,
+    args: &ProcessArgs,
+    options: &ParseOptions,
+) -> ProcessReport {
+    let input =
+        open(path, args.format.encoding.as_deref()).unwrap_or_else(|err| exit_with(path, err));
+    let source = CsvSource::new(csv::Reader::from_reader(input), options)
+        .unwrap_or_else(|err| exit_with(path, err));
+    let engine = || args.rules.engine(options);
+    process_sharded(shards, engine, source, options.mode).unwrap_or_else(|err| exit_with(path, err))
+}
+
+/// Applies the rows of `path` in minor units, see [`MinorUnitsEngine`]
+fn process_minor_units(path: &Path, args: &ProcessArgs, options: &ParseOptions) -> ProcessReport {
+    let input =
+        open(path, args.format.encoding.as_deref()).unwrap_or_else(|err| exit_with(path, err));
+    let source = CsvSource::new(csv::Reader::from_reader(input), options)
+        .unwrap_or_else(|err| exit_with(path, err));
+    MinorUnitsEngine::new()
+        .process_source_with(source, options.mode)
+        .unwrap_or_else(|err| exit_with(path, err))
+}
+
+/// Applies the rows of `path` one by one, handing each update to `outputs`
+/// with the metadata, line and timestamp of its row
+fn process_rows(
+    path: &Path,
+    args: &ProcessArgs,
+    options: &ParseOptions,
+    mut engine: Engine,
+    outputs: &mut Outputs,
+    diagnostics: &mut Diagnostics,
+) -> ProcessReport {
+    let input =
+        open(path, args.format.encoding.as_deref()).unwrap_or_else(|err| exit_with(path, err));
+    let input: Box<dyn io::Read> = if args.sort_by_time {
+        let mut sorted = tempfile::tempfile().unwrap_or_else(|err| exit_with(path, err));
+        diagnostics
+            .time(Phase::Sort, || {
+                sort_by_time(input, &sorted, &args.time_column, args.sort_buffer_rows)
+            })
+            .unwrap_or_else(|err| exit_with(path, err));
+        sorted.rewind().unwrap_or_else(|err| exit_with(path, err));
+        Box::new(io::BufReader::new(sorted))
+    } else {
+        Box::new(input)
+    };
+    let mut source = CsvSource::new(csv::Reader::from_reader(input), options)
+        .unwrap_or_else(|err| exit_with(path, err));
+    engine.set_daily_withdrawal_limit(args.daily_withdrawal_limit);
+    if let Some(window) = args.dispute_window {
+        engine.set_eviction(Eviction::Window(window));
+    }
+    let mut rows = RowLoop::new(options.mode).time_column(&args.time_column);
+    if args.periods.is_some() {
+        rows = rows.needs_time("--periods");
+    }
+    if let Some(after) = args.release_holds_after {
+        rows = rows.release_holds_after(after);
+    }
+    if let Some(path) = &args.schedule {
+        let schedules = File::open(path)
+            .and_then(|file| read_schedules(&mut csv::Reader::from_reader(file)))
+            .unwrap_or_else(|err| exit_with(path, err));
+        rows = rows.schedules(schedules);
+    }
+    if args.metadata || rows.time_needed_for(&engine).is_some() {
+        source = source.keep_metadata();
+    }
+    if let Err(err) = rows.check_source(&engine, &source) {
+        exit_with(path, format!("{}, see --time-column", err));
+    }
+    let mut handler = RowOutputs {
+        path,
+        args,
+        outputs,
+    };
+    let errors = rows
+        .run_timed(&mut engine, &mut source, &mut handler, diagnostics)
+        .unwrap_or_else(|err| exit_with(path, err));
+    ProcessReport {
+        errors,
+        unknown_types: source.unknown_types().clone(),
+        ..engine.into()
+    }
+}
+
+/// Writes the updates of [`process_rows`] to the outputs, with the metadata and the file and
+/// line of their row if asked for
+struct RowOutputs<'a, 'b> {
+    path: &'a Path,
+    args: &'a ProcessArgs,
+    outputs: &'a mut Outputs<'b>,
+}
+
+impl RowHandler for RowOutputs<'_, '_> {
+    fn update(&mut self, update: AccountUpdate, origin: RowOrigin<'_>) {
+        let origin = Origin {
+            metadata: origin.metadata.filter(|_| self.args.metadata),
+            source: origin
+                .line
+                .filter(|_| self.args.provenance)
+                .map(|line| (self.path, line)),
+            timestamp: origin.timestamp,
+        };
+        self.outputs.write(update, origin)
+    }
+}
+
+/// Applies rows as they are appended to `path` and keeps the --snapshot file up to date.
+/// Rows are parsed on a separate thread so the snapshot is refreshed while waiting for more.
+fn follow(path: &Path, args: &ProcessArgs) {
+    const POLL: Duration = Duration::from_millis(200);
+    let options = args.rules.parse_options(&args.format);
+    let file = File::open(path).unwrap_or_else(|err| exit_with(path, err));
+    if args.checkpoint.is_some() && matches!(args.rules.unknown_refs, UnknownRefs::Defer) {
+        eprintln!("--checkpoint can't keep the disputes --unknown-refs defer holds back");
+        process::exit(2);
+    }
+    let Some(checkpoint) = &args.checkpoint else {
+        let receiver = service::feed::follow(file, POLL, args.format.encoding.as_deref(), &options)
+            .unwrap_or_else(|err| exit_with(path, err));
+        apply_feed(path, args, &options, receiver, None);
+        process::exit(1);
+    };
+    let resumed = checkpoint.exists().then(|| {
+        let input = File::open(path).unwrap_or_else(|err| exit_with(path, err));
+        resume_checkpoint(&read_file(checkpoint)[..], input)
+            .unwrap_or_else(|err| exit_with(checkpoint, err))
+    });
+    let position = resumed.as_ref().map(|(token, _)| token.position());
+    let receiver = service::feed::follow_from(file, POLL, &options, position);
+    apply_feed(
+        path,
+        args,
+        &options,
+        receiver,
+        resumed.map(|(_, state)| state),
+    );
+    // Reading the input failed
+    process::exit(1);
+}
+
+/// Applies the records sent to the --listen socket, every connection on its own thread,
+/// at most --max-connections at once
+#[cfg(unix)]
+fn listen(args: &ProcessArgs) {
+    let socket = args.listen.as_deref().expect("--listen is set");
+    let listener = service::listen::bind(socket).unwrap_or_else(|err| exit_with(socket, err));
+    let options = args.rules.parse_options(&args.format);
+    let receiver = service::listen::listen(
+        listener,
+        args.listen_format.into(),
+        &options,
+        connection_limit(args),
+    );
+    apply_feed(socket, args, &options, receiver, None);
+    process::exit(1);
+}
+
+/// The --max-connections of a socket
+#[cfg(unix)]
+fn connection_limit(args: &ProcessArgs) -> ConnectionLimit {
+    ConnectionLimit::new(usize::try_from(args.max_connections).unwrap_or(usize::MAX))
+}
+
+/// Writes what a feed applies to the outputs, the --snapshot and the --checkpoint, and
+/// reports its bad rows for `label`
+struct FeedOutputs<'a> {
+    label: &'a Path,
+    args: &'a ProcessArgs,
+    options: &'a ParseOptions,
+    outputs: Outputs<'a>,
+    snapshot: &'a Path,
+    output_options: OutputOptions,
+}
+
+impl FeedHandler for FeedOutputs<'_> {
+    fn update(&mut self, update: AccountUpdate, line: u64) {
+        let origin = Origin {
+            source: self.args.provenance.then_some((self.label, line)),
+            ..Origin::default()
+        };
+        self.outputs.write(update, origin);
+    }
+
+    fn skipped(&mut self, error: &RowError) {
+        report_skipped(self.label, error);
+    }
+
+    #[cfg(unix)]
+    fn dropped(&mut self, connection: u64, error: &RowError) {
+        report_dropped(self.label, connection, error);
+    }
+
+    fn refresh(&mut self, engine: &Engine) {
+        self.outputs.flush();
+        write_snapshot(self.snapshot, engine.accounts(), &self.output_options);
+    }
+
+    fn checkpoint(&mut self, engine: &Engine, position: &csv::Position) {
+        if let Some(checkpoint) = &self.args.checkpoint {
+            // The outputs hold everything before the checkpoint
+            self.outputs.flush();
+            save_checkpoint(checkpoint, self.label, position, engine, self.options);
+        }
+    }
+}
+
+/// Applies the transactions sent to `receiver` and keeps the --snapshot file up to date,
+/// until all senders are gone. Errors are handled by --mode and reported for `label`.
+/// Starts from `resumed`, the state of a --checkpoint, if given.
+fn apply_feed(
+    label: &Path,
+    args: &ProcessArgs,
+    options: &ParseOptions,
+    receiver: Receiver<Feed>,
+    resumed: Option<EngineState>,
+) {
+    let snapshot = args
+        .snapshot
+        .as_deref()
+        .expect("--follow and --listen require --snapshot");
+    let mut engine = args.rules.engine(options);
+    if let Some(mut state) = resumed {
+        options.learn_names(&mut state);
+        engine.restore(state);
+    }
+    let webhooks = args.alerts.install(&mut engine);
+    #[cfg(all(unix, feature = "dashmap"))]
+    if let Some(socket) = &args.query_socket {
+        let accounts = SharedAccounts::with_accounts(engine.accounts());
+        engine.add_observer(accounts.clone());
+        let listener = service::listen::bind(socket).unwrap_or_else(|err| exit_with(socket, err));
+        let client_ids = options.client_ids.clone();
+        service::listen::serve_queries(listener, accounts, client_ids, connection_limit(args));
+    }
+    let mut handler = FeedOutputs {
+        label,
+        args,
+        options,
+        outputs: Outputs::create(args),
+        snapshot,
+        output_options: args
+            .output
+            .output_options(&options.client_ids, &options.tx_ids),
+    };
+    let mut feed_loop = FeedLoop::new(args.rules.parse_mode(), Duration::from_secs(args.refresh));
+    if args.checkpoint.is_some() {
+        feed_loop = feed_loop.checkpoint_every(Duration::from_secs(args.checkpoint_interval));
+    }
+    let position = feed_loop
+        .run(&mut engine, receiver, &mut handler)
+        .unwrap_or_else(|error| exit_with(label, error));
+    handler.outputs.finish(engine.accounts());
+    write_snapshot(snapshot, engine.accounts(), &handler.output_options);
+    if let (Some(checkpoint), Some(position)) = (&args.checkpoint, &position) {
+        save_checkpoint(checkpoint, label, position, &engine, options);
+    }
+    if args.digest {
+        eprintln!("digest: {:032x}", engine.state_digest());
+    }
+    drop(engine);
+    webhooks.into_iter().for_each(Webhook::finish);
+}
+
+/// Applies the files dropped into the watched directory in name order, one engine for all
+fn watch(args: &WatchArgs) {
+    let interval = Duration::from_secs(args.interval);
+    let mut watcher = Watcher::new(&args.dir, &args.archive, interval)
+        .unwrap_or_else(|err| exit_with(&args.archive, err));
+    let options = args.rules.parse_options(&args.format);
+    let output_options = args
+        .output
+        .output_options(&options.client_ids, &options.tx_ids);
+    let mut engine = args.rules.engine(&options);
+    // Runs until interrupted, the webhooks are never finished
+    let _webhooks = args.alerts.install(&mut engine);
+    write_snapshot(&args.snapshot, engine.accounts(), &output_options);
+    let mut seen = args
+        .seen_file
+        .as_deref()
+        .map_or_else(SeenContent::new, load_seen);
+    let archive = |watcher: &mut Watcher, path: &Path| {
+        if let Err(err) = watcher.archive(path) {
+            exit_with(path, err);
+        }
+    };
+    loop {
+        let path = watcher
+            .next_file()
+            .unwrap_or_else(|err| exit_with(&args.dir, err));
+        // Only counted as seen once it is applied
+        let hash = match args
+            .skip_repeated
+            .then(|| open_raw(&path).and_then(read_hash))
+        {
+            Some(Ok(hash)) if seen.contains_hash(hash) => {
+                report_repeated(&path);
+                archive(&mut watcher, &path);
+                continue;
+            }
+            Some(Ok(hash)) => Some(hash),
+            Some(Err(err)) => {
+                eprintln!("{}: {}", path.display(), err);
+                continue;
+            }
+            None => None,
+        };
+        let input = match open(&path, args.format.encoding.as_deref()) {
+            Ok(input) => input,
+            Err(err) => {
+                eprintln!("{}: {}", path.display(), err);
+                continue;
+            }
+        };
+        // A file is applied whole or not at all in strict mode, alerts and webhooks only
+        // hear of its rows once it is
+        let strict = options.mode == ParseMode::Strict;
+        if strict {
+            engine.begin();
+        }
+        let applied = CsvSource::new(csv::Reader::from_reader(input), &options)
+            .and_then(|source| engine.apply_source(source, options.mode));
+        match applied {
+            Ok(errors) => {
+                engine.commit();
+                for error in &errors {
+                    report_skipped(&path, error);
+                }
+            }
+            Err(error) if strict && engine.rollback().is_ok() => {
+                eprintln!(
+                    "{}: {}, left in place until it changes",
+                    path.display(),
+                    error
+                );
+                watcher
+                    .skip(&path)
+                    .unwrap_or_else(|err| exit_with(&path, err));
+                continue;
+            }
+            // Left in place, the rows before the error can't be taken back
+            Err(error) => exit_with(&path, error),
+        }
+        if let Some(hash) = hash {
+            seen.insert_hash(hash);
+        }
+        archive(&mut watcher, &path);
+        write_snapshot(&args.snapshot, engine.accounts(), &output_options);
+        if let Some(state_path) = &args.save_state {
+            let mut state = engine.state();
+            [state.client_names, state.tx_names] = options.text_id_names();
+            save_state(state_path, &state);
+        }
+        if let Some(seen_path) = &args.seen_file {
+            save_seen(seen_path, &seen);
+        }
+    }
+}
+
+/// Replaces the file at `path` with the accounts, through a temporary file
+/// so readers never see a partial snapshot
+fn write_snapshot(path: &Path, accounts: &AccountMap, options: &OutputOptions) {
+    replace_file(path, |output| {
+        write_accounts_with(accounts, output, options)
+    });
+}
+
+/// Writes `path` through a temporary file renamed over it once on disk, so readers and
+/// a run interrupted halfway only ever see a complete file
+fn replace_file<E: From<io::Error> + Display>(
+    path: &Path,
+    write: impl FnOnce(&mut dyn Write) -> Result<(), E>,
+) {
+    let temporary = temporary_path(path);
+    let written = File::create(&temporary)
+        .map_err(E::from)
+        .and_then(|file| {
+            write_encrypted(io::BufWriter::new(&file), write)?;
+            Ok(file.sync_all()?)
+        })
+        .map_err(|err| err.to_string())
+        .and_then(|()| fs::rename(&temporary, path).map_err(|err| err.to_string()));
+    if let Err(err) = written {
+        exit_with(path, err);
+    }
+}
+
+/// Where [`replace_file`] writes `path` first
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    temporary.into()
+}
+
+/// A file written while processing, encrypted to the --recipient keys if given
+enum OutputFile {
+    Plain(io::BufWriter<File>),
+    #[cfg(feature = "encryption")]
+    Encrypted(EncryptedWriter<io::BufWriter<File>>),
+}
+
+impl OutputFile {
+    fn new(file: File) -> io::Result<Self> {
+        let output = io::BufWriter::new(file);
+        #[cfg(feature = "encryption")]
+        if let Some(recipients) = recipients() {
+            return Ok(OutputFile::Encrypted(recipients.encrypt(output)?));
+        }
+        Ok(OutputFile::Plain(output))
+    }
+
+    /// Writes what is left, an encrypted file is only complete after this
+    fn finish(self) -> io::Result<()> {
+        match self {
+            OutputFile::Plain(mut output) => output.flush(),
+            #[cfg(feature = "encryption")]
+            OutputFile::Encrypted(output) => output.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputFile::Plain(output) => output.write(buf),
+            #[cfg(feature = "encryption")]
+            OutputFile::Encrypted(output) => output.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputFile::Plain(output) => output.flush(),
+            #[cfg(feature = "encryption")]
+            OutputFile::Encrypted(output) => output.flush(),
+        }
+    }
+}
+
+/// Files written while processing, for --updates and --events.
+/// Only the first write error is kept, it is reported once processing is done.
+struct Outputs<'a> {
+    updates: Option<(&'a Path, UpdatesWriter)>,
+    events: Option<(&'a Path, OutputFile)>,
+    /// With the number of updates without a date
+    periods: Option<(&'a Path, PeriodReport, u64)>,
+    ledger: Option<(&'a Path, LedgerWriter<OutputFile>, Ledger)>,
+    /// With the temporary file renamed over the log at the end, if it's rewritten
+    audit: Option<(&'a Path, AuditLog<OutputFile>, Option<PathBuf>)>,
+    #[cfg(feature = "arrow")]
+    arrow_updates: Option<(&'a Path, UpdateLogWriter<OutputFile>)>,
+    /// With the brokers for messages
+    #[cfg(feature = "kafka")]
+    kafka: Option<(&'a Path, KafkaSink)>,
+    #[cfg(feature = "redis")]
+    redis: Option<(&'a Path, RedisSink)>,
+    error: Option<(&'a Path, String)>,
+}
+
+impl<'a> Outputs<'a> {
+    fn create(args: &'a ProcessArgs) -> Self {
+        let create = |path: &'a PathBuf| match File::create(path).and_then(OutputFile::new) {
+            Ok(output) => (path.as_path(), output),
+            Err(err) => exit_with(path, err),
+        };
+        Outputs {
+            updates: args
+                .updates
+                .as_ref()
+                .map(create)
+                .map(|(path, output)| (path, UpdatesWriter::new(output, args.updates_format))),
+            events: args.events.as_ref().map(create),
+            periods: args
+                .periods
+                .as_deref()
+                .map(|path| (path, PeriodReport::new(args.period.into()), 0)),
+            ledger: args.ledger.as_ref().map(create).map(|(path, output)| {
+                let writer = LedgerWriter::new(output).unwrap_or_else(|err| exit_with(path, err));
+                (path, writer, Ledger::new())
+            }),
+            audit: args.audit_log.as_deref().map(|path| {
+                let (log, temporary) =
+                    open_audit_log(path).unwrap_or_else(|err| exit_with(path, err));
+                (path, log, temporary)
+            }),
+            #[cfg(feature = "arrow")]
+            arrow_updates: args.arrow_updates.as_ref().map(create).map(|(path, file)| {
+                let writer = UpdateLogWriter::new(file).unwrap_or_else(|err| exit_with(path, err));
+                (path, writer)
+            }),
+            #[cfg(feature = "kafka")]
+            kafka: args
+                .kafka_topic
+                .as_ref()
+                .zip(args.kafka_brokers.as_deref())
+                .map(|(topic, brokers)| {
+                    let sink = KafkaSink::new(brokers, topic)
+                        .unwrap_or_else(|err| exit_with(Path::new(brokers), err));
+                    (Path::new(brokers), sink)
+                }),
+            #[cfg(feature = "redis")]
+            redis: args.redis_url.as_deref().map(|url| {
+                let sink =
+                    RedisSink::connect(url).unwrap_or_else(|err| exit_with(Path::new(url), err));
+                (Path::new(url), sink)
+            }),
+            error: None,
+        }
+    }
+
+    /// Writes `update`, JSON objects get the fields of `origin`
+    fn write(&mut self, update: AccountUpdate, origin: Origin) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Some((path, writer)) = &mut self.updates {
+            if let Err(err) = writer.write(&update, origin) {
+                self.error = Some((path, err.to_string()));
+            }
+        }
+        if let Some((_, report, undated)) = &mut self.periods {
+            let recorded = origin
+                .timestamp
+                .is_some_and(|timestamp| report.record(timestamp, &update));
+            *undated += u64::from(!recorded);
+        }
+        if let Some((path, writer, ledger)) = &mut self.ledger {
+            if let Err(err) = writer.write(&ledger.record(&update)) {
+                self.error = Some((path, err.to_string()));
+            }
+        }
+        if let Some((path, log, _)) = &mut self.audit {
+            if let Err(err) = log.append(&update) {
+                self.error = Some((path, err.to_string()));
+            }
+        }
+        #[cfg(feature = "arrow")]
+        if let Some((path, writer)) = &mut self.arrow_updates {
+            if let Err(err) = writer.write(&update) {
+                self.error = Some((path, err.to_string()));
+            }
+        }
+        #[cfg(feature = "kafka")]
+        if let Some((brokers, sink)) = &mut self.kafka {
+            if let Err(err) = sink.write_update(&update) {
+                self.error = Some((brokers, err.to_string()));
+            }
+        }
+        #[cfg(feature = "redis")]
+        if let Some((url, sink)) = &mut self.redis {
+            if let Err(err) = sink.write_update(&update) {
+                self.error = Some((url, err.to_string()));
+            }
+        }
+        if let Some((path, writer)) = &mut self.events {
+            for event in update.events() {
+                let written = write_json_line(&mut *writer, &event, origin);
+                if let Err(err) = written {
+                    self.error = Some((path, err.to_string()));
+                }
+            }
+        }
+    }
+
+    /// Flushes the files, exits if anything failed to be written
+    fn flush(&mut self) {
+        if let Some((path, err)) = &self.error {
+            exit_with(path, err);
+        }
+        if let Some((path, writer)) = &mut self.updates {
+            writer.flush().unwrap_or_else(|err| exit_with(path, err));
+        }
+        if let Some((path, writer)) = &mut self.events {
+            writer.flush().unwrap_or_else(|err| exit_with(path, err));
+        }
+        if let Some((path, writer, _)) = &mut self.ledger {
+            writer.flush().unwrap_or_else(|err| exit_with(path, err));
+        }
+        if let Some((path, log, _)) = &mut self.audit {
+            log.flush().unwrap_or_else(|err| exit_with(path, err));
+        }
+        #[cfg(feature = "kafka")]
+        if let Some((brokers, sink)) = &mut self.kafka {
+            sink.flush().unwrap_or_else(|err| exit_with(brokers, err));
+        }
+        #[cfg(feature = "redis")]
+        if let Some((url, sink)) = &mut self.redis {
+            sink.flush().unwrap_or_else(|err| exit_with(url, err));
+        }
+    }
+
+    /// Flushes and writes the files, exits if the --ledger disagrees with `accounts`
+    fn finish(mut self, accounts: &AccountMap) {
+        self.flush();
+        if let Some((path, writer)) = self.updates.take() {
+            writer.finish().unwrap_or_else(|err| exit_with(path, err));
+        }
+        if let Some((path, output)) = self.events.take() {
+            output.finish().unwrap_or_else(|err| exit_with(path, err));
+        }
+        if let Some((path, log, temporary)) = self.audit.take() {
+            let head = log.head();
+            let finished = log.into_inner().finish().and_then(|()| match temporary {
+                Some(temporary) => fs::rename(temporary, path),
+                None => Ok(()),
+            });
+            finished.unwrap_or_else(|err| exit_with(path, err));
+            eprintln!("audit: {}", head);
+        }
+        #[cfg(feature = "arrow")]
+        if let Some((path, writer)) = self.arrow_updates.take() {
+            let finished = writer
+                .finish()
+                .map_err(|err| err.to_string())
+                .and_then(|file| file.finish().map_err(|err| err.to_string()));
+            finished.unwrap_or_else(|err| exit_with(path, err));
+        }
+        if let Some((path, writer, ledger)) = self.ledger.take() {
+            let finished = writer.into_inner().and_then(OutputFile::finish);
+            finished.unwrap_or_else(|err| exit_with(path, err));
+            let discrepancies = ledger.discrepancies(accounts);
+            for discrepancy in &discrepancies {
+                eprintln!("{}: {}", path.display(), discrepancy);
+            }
+            if !discrepancies.is_empty() {
+                process::exit(1);
+            }
+        }
+        if let Some((path, report, undated)) = self.periods {
+            let file = File::create(path).unwrap_or_else(|err| exit_with(path, err));
+            report
+                .write_csv(io::BufWriter::new(file))
+                .unwrap_or_else(|err| exit_with(path, err));
+            if undated > 0 {
+                tracing::warn!(
+                    "{} updates without a date left out of {}",
+                    undated,
+                    path.display()
+                );
+            }
+        }
+    }
+}
+
+/// Opens the audit log at `path` to append to, starting it if it's new or empty.
+/// An encrypted file can't be appended to, so with --recipient the records so far are copied
+/// into a new encrypted log, written to a temporary file that is returned to be renamed over
+/// the log once it's finished.
+fn open_audit_log(path: &Path) -> io::Result<(AuditLog<OutputFile>, Option<PathBuf>)> {
+    #[cfg(feature = "encryption")]
+    if recipients().is_some() {
+        let records = match path.exists() {
+            true => read_file(path),
+            false => vec![],
+        };
+        let head = last_record(io::Cursor::new(&records))?;
+        let temporary = temporary_path(path);
+        let mut output = OutputFile::new(File::create(&temporary)?)?;
+        output.write_all(&records)?;
+        let log = match head {
+            Some(head) => AuditLog::resume(output, head),
+            None => AuditLog::new(output)?,
+        };
+        return Ok((log, Some(temporary)));
+    }
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)?;
+    #[cfg(feature = "encryption")]
+    {
+        use std::io::Read;
+        let mut start = vec![];
+        (&mut file).take(64).read_to_end(&mut start)?;
+        if is_encrypted(&start) {
+            let message = "encrypted, pass --recipient to add to it";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+    }
+    let head = last_record(&mut file)?;
+    let output = OutputFile::new(file)?;
+    let log = match head {
+        Some(head) => AuditLog::resume(output, head),
+        None => AuditLog::new(output)?,
+    };
+    Ok((log, None))
+}
+
+fn verify_audit(args: &VerifyAuditArgs) {
+    let path = &args.log;
+    let content = read_file(path);
+    let head = verify_audit_log(&content[..]).unwrap_or_else(|err| exit_with(path, err));
+    println!("{}", head);
+}
+
+/// What is known about the row behind an update, see --metadata and --provenance
+#[derive(Clone, Copy, Default)]
+struct Origin<'a> {
+    metadata: Option<&'a Metadata>,
+    /// File and line of the row
+    source: Option<(&'a Path, u64)>,
+    /// Date of the row, for --periods
+    timestamp: Option<&'a str>,
+}
+
+/// Writes `value` as one line of JSON, with a `metadata` field added if it's not empty
+/// and a `source` field if the origin's file is known
+fn write_json_line(
+    writer: &mut impl Write,
+    value: &impl serde::Serialize,
+    origin: Origin,
+) -> io::Result<()> {
+    let metadata = origin.metadata.filter(|metadata| !metadata.is_empty());
+    if metadata.is_none() && origin.source.is_none() {
+        serde_json::to_writer(&mut *writer, value)?;
+        return writer.write_all(b"\n");
+    }
+    let mut object = serde_json::to_value(value)?;
+    if let Some(object) = object.as_object_mut() {
+        if let Some(metadata) = metadata {
+            object.insert("metadata".to_string(), serde_json::to_value(metadata)?);
+        }
+        if let Some((file, line)) = origin.source {
+            let source = serde_json::json!({ "file": file, "line": line });
+            object.insert("source".to_string(), source);
+        }
+    }
+    serde_json::to_writer(&mut *writer, &object)?;
+    writer.write_all(b"\n")
+}
+
+enum UpdatesWriter {
+    Csv(Box<csv::Writer<OutputFile>>),
+    Json(OutputFile),
+    Redis(RedisCommandWriter<OutputFile>),
+}
+
+impl UpdatesWriter {
+    fn new(output: OutputFile, format: UpdatesFormat) -> Self {
+        match format {
+            UpdatesFormat::Csv => UpdatesWriter::Csv(Box::new(csv::Writer::from_writer(output))),
+            UpdatesFormat::Json => UpdatesWriter::Json(output),
+            UpdatesFormat::Redis => UpdatesWriter::Redis(RedisCommandWriter::new(output)),
+        }
+    }
+
+    /// The CSV and Redis formats have no room for `origin`
+    fn write(&mut self, update: &AccountUpdate, origin: Origin) -> io::Result<()> {
+        match self {
+            UpdatesWriter::Csv(writer) => Ok(writer.serialize(update)?),
+            UpdatesWriter::Json(writer) => write_json_line(writer, update, origin),
+            UpdatesWriter::Redis(sink) => sink.write_update(update),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            UpdatesWriter::Csv(writer) => writer.flush(),
+            UpdatesWriter::Json(writer) => writer.flush(),
+            UpdatesWriter::Redis(sink) => sink.flush(),
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            UpdatesWriter::Csv(writer) => writer.into_inner().map_err(|err| err.into_error())?,
+            UpdatesWriter::Json(output) => output,
+            UpdatesWriter::Redis(sink) => sink.into_inner(),
+        }
+        .finish()
+    }
+}
+
+/// Posts every account locked by a chargeback
+struct LockWebhook(mpsc::Sender<String>);
+
+impl EngineObserver for LockWebhook {
+    fn on_account_locked(&mut self, account: &Account, tx: TxId) {
+        let body = serde_json::json!({
+            "event": "account_locked",
+            "client": account.client,
+            "tx": tx,
+            "available": account.available,
+            "held": account.held,
+            "total": account.total(),
+        });
+        let _ = self.0.send(body.to_string());
+    }
+}
+
+/// Reports a row of `path` skipped with --mode collecting on stderr,
+/// as `{"file":..,"line":..,"record":..,"code":..,"message":..}` with --log-json
+fn report_skipped(path: &Path, error: &RowError) {
+    if LOG_JSON.load(Ordering::Relaxed) {
+        let object = serde_json::json!({
+            "file": path,
+            "line": error.line,
+            "record": error.record,
+            "code": error.code,
+            "message": error.message,
+        });
+        eprintln!("{}", object);
+    } else {
+        eprintln!("{}: skipped [{}] {}", path.display(), error.code, error);
+    }
+}
+
+/// The content hashes of --seen-file, none if it doesn't exist yet
+fn load_seen(path: &Path) -> SeenContent {
+    if !path.exists() {
+        return SeenContent::new();
+    }
+    SeenContent::read_from(&read_file(path)[..]).unwrap_or_else(|err| exit_with(path, err))
+}
+
+fn save_seen(path: &Path, seen: &SeenContent) {
+    replace_file(path, |output| seen.write_to(output));
+}
+
+/// The content hash of the file or object at `path`, for --skip-repeated
+fn file_hash(path: &Path) -> u128 {
+    open_raw(path)
+        .and_then(read_hash)
+        .unwrap_or_else(|err| exit_with(path, err))
+}
+
+/// Reports a --listen client dropped for `error` in strict mode on stderr
+#[cfg(unix)]
+fn report_dropped(socket: &Path, connection: u64, error: &RowError) {
+    if LOG_JSON.load(Ordering::Relaxed) {
+        let object = serde_json::json!({
+            "file": socket,
+            "connection": connection,
+            "dropped": error,
+        });
+        eprintln!("{}", object);
+    } else {
+        eprintln!(
+            "{}: dropped connection {}, {}",
+            socket.display(),
+            connection,
+            error
+        );
+    }
+}
+
+/// Reports a file skipped by --skip-repeated on stderr
+fn report_repeated(path: &Path) {
+    if LOG_JSON.load(Ordering::Relaxed) {
+        let object = serde_json::json!({ "file": path, "skipped": "repeated content" });
+        eprintln!("{}", object);
+    } else {
+        eprintln!(
+            "{}: skipped, same content as a file processed before",
+            path.display()
+        );
+    }
+}
+
+fn exit_with(path: &Path, err: impl Display) -> ! {
+    eprintln!("{}: {}", path.display(), err);
+    process::exit(1);
+}
+
+fn validate(args: &ValidateArgs) {
+    let path = &args.input;
+    let input =
+        open(path, args.format.encoding.as_deref()).unwrap_or_else(|err| exit_with(path, err));
+    let options = args.format.parse_options(ParseMode::Collecting);
+    let problems = validate_transactions(&mut csv::Reader::from_reader(input), &options);
+    for problem in &problems {
+        println!("{}: [{}] {}", path.display(), problem.code, problem);
+    }
+    if !problems.is_empty() {
+        eprintln!("{}: {} problems found", path.display(), problems.len());
+        process::exit(1);
+    }
+}
+
+fn stats(args: &StatsArgs) {
+    let path = &args.input;
+    let input =
+        open(path, args.format.encoding.as_deref()).unwrap_or_else(|err| exit_with(path, err));
+    let options = args.format.parse_options(ParseMode::Collecting);
+    let stats = file_stats(&mut csv::Reader::from_reader(input), &options);
+    println!("rows: {}", stats.rows);
+    println!("malformed rows: {}", stats.malformed);
+    println!("clients: {}", stats.clients.len());
+    match &stats.tx_ids {
+        Some(ids) => println!("tx ids: {}-{}", ids.start(), ids.end()),
+        None => println!("tx ids: none"),
+    }
+    for (transaction_type, of_type) in &stats.types {
+        print!("{}: {} rows", transaction_type, of_type.rows);
+        if let (Some(min), Some(max)) = (of_type.min, of_type.max) {
+            print!(", amounts {} to {}, sum {}", min, max, of_type.sum);
+        }
+        println!();
+    }
+    for (transaction_type, count) in &stats.unknown_types {
+        println!("unknown type `{}`: {} rows", transaction_type, count);
+    }
+}
+
+fn verify(args: &VerifyArgs) {
+    let path = &args.accounts;
+    let content = read_file(path);
+    let mut balance = trial_balance(&mut csv::Reader::from_reader(&content[..]))
+        .unwrap_or_else(|err| exit_with(path, err));
+    if let Some(input_path) = &args.input {
+        let options = args.format.parse_options(ParseMode::Lenient);
+        let input = open(input_path, args.format.encoding.as_deref())
+            .unwrap_or_else(|err| exit_with(input_path, err));
+        let mut source = CsvSource::new(csv::Reader::from_reader(input), &options)
+            .unwrap_or_else(|err| exit_with(input_path, err));
+        // Malformed rows are skipped, as they are by a lenient run
+        let rows = iter::from_fn(|| source.next_transaction()).filter_map(Result::ok);
+        balance.check_total(net_deposits(rows));
+    }
+    for discrepancy in &balance.discrepancies {
+        println!("{}: {}", path.display(), discrepancy);
+    }
+    if !balance.is_balanced() {
+        eprintln!(
+            "{}: {} discrepancies found",
+            path.display(),
+            balance.discrepancies.len()
+        );
+        process::exit(1);
+    }
+}
+
+fn diff(args: &DiffArgs) {
+    let (before, after) = (load_accounts(&args.before), load_accounts(&args.after));
+    let diffs = diff_accounts(&before, &after);
+    let mut stdout = io::stdout().lock();
+    for diff in &diffs {
+        if let Err(err) = write_json_line(&mut stdout, diff, Origin::default()) {
+            eprintln!("error writing differences: {}", err);
+            process::exit(1);
+        }
+    }
+    if !diffs.is_empty() {
+        process::exit(1);
+    }
+}
+
+fn merge(args: &MergeArgs) {
+    let mut merged = EngineState::default();
+    for path in &args.inputs {
+        if let Err(err) = merged.merge(load_state(path)) {
+            exit_with(path, err);
+        }
+    }
+    let [client_ids, tx_ids] = [&merged.client_names, &merged.tx_names]
+        .map(|names| IdFormat::Text(IdNames::from(names.clone())));
+    let written = write_encrypted(io::stdout().lock(), |output| {
+        let output_options = args.output.output_options(&client_ids, &tx_ids);
+        write_accounts_with(&merged.accounts, output, &output_options)
+    });
+    if let Err(err) = written {
+        eprintln!("error writing accounts: {}", err);
+        process::exit(1);
+    }
+    if let Some(path) = &args.save_state {
+        save_state(path, &merged);
+    }
+}
+
+/// Writes `state` as JSON if `path` ends in `.json`, in the binary format otherwise
+fn save_state(path: &Path, state: &EngineState) {
+    let json = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("json"));
+    replace_file(path, |output| match json {
+        true => state.write_json(output),
+        false => state.write_to(output),
+    });
+}
+
+/// The --pseudonymize key in `path`, without the line break an editor leaves
+fn read_pseudonym_key(path: &Path) -> Pseudonymizer {
+    let content = read_file(path);
+    let key = content.trim_ascii();
+    if key.len() < Pseudonymizer::MIN_KEY_LEN {
+        let hint = format!(
+            "a pseudonym key needs at least {} bytes",
+            Pseudonymizer::MIN_KEY_LEN
+        );
+        exit_with(path, hint);
+    }
+    Pseudonymizer::new(key)
+}
+
+/// Writes `pseudonym,client` for every account, ordered by pseudonym
+fn write_pseudonym_map(path: &Path, accounts: &AccountMap, pseudonyms: &Pseudonymizer) {
+    let mut clients: Vec<ClientId> = accounts.keys().copied().collect();
+    clients.sort_unstable();
+    let written = File::create(path)
+        .map_err(csv::Error::from)
+        .and_then(|file| {
+            write_encrypted(io::BufWriter::new(file), |output| {
+                let mut writer = csv::Writer::from_writer(output);
+                writer.write_record(["pseudonym", "client"])?;
+                for pseudonym in clients {
+                    writer.serialize((pseudonym, pseudonyms.reveal(pseudonym)))?;
+                }
+                Ok(writer.flush()?)
+            })
+        });
+    written.unwrap_or_else(|err| exit_with(path, err));
+}
+
+fn load_accounts(path: &Path) -> AccountMap {
+    load_state(path).accounts
+}
+
+/// A --save-state file, recognised by its magic or as JSON by its opening brace,
+/// or the accounts of an accounts CSV without any transactions
+fn load_state(path: &Path) -> EngineState {
+    let content = read_file(path);
+    let json = content.trim_ascii_start().starts_with(b"{");
+    let state = if content.starts_with(b"TPSTATE") {
+        EngineState::read_from(&content[..])
+    } else if json {
+        EngineState::read_json(&content[..])
+    } else {
+        read_accounts(&mut csv::Reader::from_reader(&content[..])).map(|accounts| EngineState {
+            accounts,
+            ..EngineState::default()
+        })
+    };
+    state.unwrap_or_else(|err| exit_with(path, err))
+}
+
+/// Writes the --checkpoint of `engine`, which applied the rows of `input` before `position`
+/// read with `options`
+fn save_checkpoint(
+    path: &Path,
+    input: &Path,
+    position: &csv::Position,
+    engine: &Engine,
+    options: &ParseOptions,
+) {
+    let token = File::open(input)
+        .and_then(|file| ResumeToken::from_position(file, position))
+        .unwrap_or_else(|err| exit_with(input, err));
+    let [client_names, tx_names] = options.text_id_names();
+    replace_file(path, |output| {
+        write_checkpoint(&token, engine, [&client_names, &tx_names], output)
+    });
+}
+
+fn query(args: &QueryArgs) {
+    let state = load_state(&args.state);
+    let client = state
+        .client_names
+        .iter()
+        .find(|(_, name)| **name == args.client)
+        .map(|(client, _)| *client)
+        .or_else(|| args.client.parse().ok());
+    let Some(client) = client.filter(|client| state.accounts.contains_key(client)) else {
+        eprintln!(
+            "{}: no account for client {}",
+            args.state.display(),
+            args.client
+        );
+        process::exit(1);
+    };
+    let options = OutputOptions {
+        disputed: Some(DisputedColumn::List),
+        status: true,
+        clients: vec![client..=client],
+        client_names: Some(IdNames::from(state.client_names.clone())),
+        tx_names: Some(IdNames::from(state.tx_names.clone())),
+        ..OutputOptions::default()
+    };
+    if let Err(err) = write_accounts_with(&state.accounts, io::stdout().lock(), &options) {
+        eprintln!("error writing account: {}", err);
+        process::exit(1);
+    }
+}
+
+fn simulate(args: &SimulateArgs) {
+    let simulation = transaction_parser::generate::simulate(args.generator.config(), Engine::new());
+    let seconds = simulation.elapsed.as_secs_f64();
+    println!("transactions: {}", simulation.transactions);
+    println!("rejected: {}", simulation.report.errors.len());
+    println!("accounts: {}", simulation.report.accounts.len());
+    println!("elapsed: {:.3}s", seconds);
+    println!(
+        "throughput: {:.0} transactions/s",
+        simulation.transactions as f64 / seconds
+    );
+    println!("digest: {:032x}", simulation.digest);
+    if let Some(expected) = args.expect_digest {
+        if expected != simulation.digest {
+            eprintln!("digest differs from the expected {:032x}", expected);
+            process::exit(1);
+        }
+    }
+}
+
+/// The sink of `args` with the name errors are reported under
+fn open_replay_sink(args: &ReplayArgs) -> (PathBuf, ReplaySink) {
+    let stream = |name: &Path, stream: io::Result<Box<dyn Write>>| {
+        let stream = stream.unwrap_or_else(|err| exit_with(name, err));
+        (
+            name.to_path_buf(),
+            ReplaySink::Stream(io::BufWriter::new(stream)),
+        )
+    };
+    if let Some(url) = &args.webhook {
+        let client = WebhookClient::new(url.clone());
+        return (PathBuf::from(url.to_string()), ReplaySink::Webhook(client));
+    }
+    #[cfg(feature = "kafka")]
+    if let (Some(topic), Some(brokers)) = (&args.kafka_topic, &args.kafka_brokers) {
+        let name = PathBuf::from(brokers);
+        let sink = KafkaSink::new(brokers, topic).unwrap_or_else(|err| exit_with(&name, err));
+        return (name, ReplaySink::Kafka(sink));
+    }
+    #[cfg(unix)]
+    if let Some(socket) = &args.socket {
+        let connected = std::os::unix::net::UnixStream::connect(socket);
+        return stream(socket, connected.map(|socket| Box::new(socket) as _));
+    }
+    match &args.output {
+        Some(path) => stream(path, File::create(path).map(|file| Box::new(file) as _)),
+        None => stream(Path::new("stdout"), Ok(Box::new(io::stdout()))),
+    }
+}
+
+/// Sends the rows of the input to the sink as the pacer says, printing how the replay went
+fn replay(args: &ReplayArgs) {
+    let path = &args.input;
+    let input =
+        open(path, args.format.encoding.as_deref()).unwrap_or_else(|err| exit_with(path, err));
+    let options = args.format.parse_options(ParseMode::Lenient);
+    let mut source = CsvSource::new(csv::Reader::from_reader(input), &options)
+        .unwrap_or_else(|err| exit_with(path, err));
+    if args.timestamps {
+        source = source.keep_metadata();
+    }
+    let pace = match args.rate {
+        Some(rate) => Pace::Rate(rate),
+        None if args.timestamps => Pace::Timestamps { speed: args.speed },
+        None => Pace::Unlimited,
+    };
+    let (target, mut sink) = open_replay_sink(args);
+    let summary = transaction_parser::replay::replay(
+        source,
+        &mut sink,
+        args.send_format.into(),
+        pace,
+        &args.time_column,
+        |error| report_skipped(path, error),
+    )
+    .unwrap_or_else(|err| exit_with(&target, err));
+    let seconds = summary.elapsed.as_secs_f64();
+    eprintln!(
+        "replayed {} rows in {:.3}s, {:.0} rows/s, {} skipped, {} failed, {:.3}s behind schedule",
+        summary.rows,
+        seconds,
+        summary.rows as f64 / seconds,
+        summary.skipped,
+        summary.failed,
+        summary.lag.as_secs_f64()
+    );
+}
+
+fn generate(args: GenerateArgs) {
+    let generator = Generator::new(args.generator.config());
+    let result = match args.output {
+        Some(path) => File::create(&path)
+            .map_err(csv::Error::from)
+            .and_then(|file| generator.write_csv(io::BufWriter::new(file))),
+        None => generator.write_csv(io::stdout().lock()),
+    };
+    if let Err(err) = result {
+        eprintln!("failed to write transactions: {}", err);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,907 @@
+//! Transactions as read from the input and the accounts they are applied to.
+use rust_decimal::prelude::Zero;
+use rust_decimal::Decimal;
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+
+/// Dispute, Resolve and Chargeback reference a deposit or withdrawal by the `tx` of their row,
+/// the engine looks it up when they are applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TransactionType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    /// The client won the representment of a chargeback, the funds come back
+    ChargebackReversal,
+    /// Administrative: reinstate the client's account frozen by a chargeback after manual
+    /// review, the tx id only identifies the row
+    Unlock,
+    /// Administrative: put the client's account under compliance review, as [`Unlock`]
+    ///
+    /// [`Unlock`]: TransactionType::Unlock
+    Review,
+    /// Administrative: end the compliance review of the client's account, as [`Unlock`]
+    ///
+    /// [`Unlock`]: TransactionType::Unlock
+    Reinstate,
+    /// Administrative: close the client's account for good, as [`Unlock`]
+    ///
+    /// [`Unlock`]: TransactionType::Unlock
+    Close,
+}
+
+/// Serialization for TransactionType
+/// We need this to let serde play well with parsing our enums
+impl FromStr for TransactionType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deposit" => Ok(TransactionType::Deposit),
+            "withdrawal" => Ok(TransactionType::Withdrawal),
+            "dispute" => Ok(TransactionType::Dispute),
+            "resolve" => Ok(TransactionType::Resolve),
+            "chargeback" => Ok(TransactionType::Chargeback),
+            "chargeback_reversal" => Ok(TransactionType::ChargebackReversal),
+            "unlock" => Ok(TransactionType::Unlock),
+            "review" => Ok(TransactionType::Review),
+            "reinstate" => Ok(TransactionType::Reinstate),
+            "close" => Ok(TransactionType::Close),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                "Invalid transaction type",
+            )),
+        }
+    }
+}
+
+impl TransactionType {
+    /// Name of the transaction type as it appears in the input
+    pub fn name(self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::ChargebackReversal => "chargeback_reversal",
+            TransactionType::Unlock => "unlock",
+            TransactionType::Review => "review",
+            TransactionType::Reinstate => "reinstate",
+            TransactionType::Close => "close",
+        }
+    }
+
+    /// Whether it is an administrative row changing the [`Status`] of an account,
+    /// see [`Status::after`]
+    pub fn changes_status(self) -> bool {
+        matches!(
+            self,
+            TransactionType::Unlock
+                | TransactionType::Review
+                | TransactionType::Reinstate
+                | TransactionType::Close
+        )
+    }
+
+    /// Byte-slice counterpart of [`FromStr`] that doesn't allocate
+    #[cfg(feature = "csv")]
+    pub(crate) fn from_bytes(name: &[u8], case_insensitive: bool) -> Option<Self> {
+        let types = [
+            (&b"deposit"[..], TransactionType::Deposit),
+            (b"withdrawal", TransactionType::Withdrawal),
+            (b"dispute", TransactionType::Dispute),
+            (b"resolve", TransactionType::Resolve),
+            (b"chargeback", TransactionType::Chargeback),
+            (b"chargeback_reversal", TransactionType::ChargebackReversal),
+            (b"unlock", TransactionType::Unlock),
+            (b"review", TransactionType::Review),
+            (b"reinstate", TransactionType::Reinstate),
+            (b"close", TransactionType::Close),
+        ];
+        types
+            .into_iter()
+            .find(|(expected, _)| match case_insensitive {
+                true => name.eq_ignore_ascii_case(expected),
+                false => name == *expected,
+            })
+            .map(|(_, transaction_type)| transaction_type)
+    }
+}
+
+/// serde + csv enum parsing code
+impl<'de> Deserialize<'de> for TransactionType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Serialized by name, the same as it appears in the input
+impl Serialize for TransactionType {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+/// Name of the transaction type as it appears in the input
+impl fmt::Display for TransactionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Identifies a client. Wide enough for any input, how wide the ids of an input may be
+/// is up to [`crate::ParseOptions::client_ids`], `u16` as in the specification by default.
+pub type ClientId = u64;
+
+/// Identifies a transaction. Wide enough for any input like [`ClientId`], how wide the
+/// tx ids of an input may be is up to [`crate::ParseOptions::tx_ids`], `u32` by default.
+pub type TxId = u64;
+
+/// Values of the input columns beyond a transaction's own, e.g. `description` or `merchant`,
+/// by column name. Kept next to transactions by sources that support it,
+/// see [`crate::TransactionSource::metadata`].
+pub type Metadata = BTreeMap<String, String>;
+
+/// Parsed data - Each row results in a transaction object.
+/// Serialized with the fields of the CSV columns, as [`crate::JsonLinesSource`] reads them.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct Transaction {
+    #[serde(rename = "type")]
+    pub transaction_type: TransactionType,
+    pub client: ClientId,
+    pub tx: TxId,
+    pub amount: Option<Decimal>,
+}
+
+impl Transaction {
+    /// Get account balance with a default value of Zero instead of None
+    pub(crate) fn amount(&self) -> Decimal {
+        match self.amount {
+            Some(amount) => amount,
+            None => Decimal::zero(),
+        }
+    }
+
+    /// The amount of a deposit or withdrawal if it is below zero, which is never applied
+    pub(crate) fn negative_amount(&self) -> Option<Decimal> {
+        match self.transaction_type {
+            TransactionType::Deposit | TransactionType::Withdrawal => self
+                .amount
+                .filter(|amount| amount.is_sign_negative() && !amount.is_zero()),
+            _ => None,
+        }
+    }
+
+    /// Deposits and withdrawals need an amount that isn't negative, resolves, chargebacks,
+    /// their reversals and administrative rows must not have one
+    #[cfg(feature = "csv")]
+    pub(crate) fn amount_problem(&self) -> Option<String> {
+        if let Some(amount) = self.negative_amount() {
+            return Some(format!("negative amount {}", amount));
+        }
+        match (self.transaction_type, self.amount) {
+            (TransactionType::Deposit | TransactionType::Withdrawal, None) => {
+                Some(format!("{} without an amount", self.transaction_type))
+            }
+            (
+                TransactionType::Resolve
+                | TransactionType::Chargeback
+                | TransactionType::ChargebackReversal
+                | TransactionType::Unlock
+                | TransactionType::Review
+                | TransactionType::Reinstate
+                | TransactionType::Close,
+                Some(_),
+            ) => Some(format!("{} with an amount", self.transaction_type)),
+            _ => None,
+        }
+    }
+}
+
+/// The transaction as a CSV row, `type,client,tx,amount`
+impl fmt::Display for Transaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{},", self.transaction_type, self.client, self.tx)?;
+        match self.amount {
+            Some(amount) => write!(f, "{}", amount),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A row whose type is none of the [`TransactionType`]s, kept as read
+/// with [`crate::UnknownTypes::Skip`]
+///
+/// Kept apart from [`Transaction`] rather than as a `TransactionType::Other(String)`: the
+/// engine passes transactions around by value as `Copy` types, which a type holding its name
+/// couldn't be, and a type the engine can't apply would have to be rejected by every match on
+/// it anyway. Unknown rows never reach [`crate::Engine::apply`], only observers and the report.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct UnknownTransaction {
+    #[serde(rename = "type")]
+    pub transaction_type: String,
+    pub client: ClientId,
+    pub tx: TxId,
+    pub amount: Option<Decimal>,
+}
+
+/// Account to hold data of an account
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    pub client: ClientId,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub status: Status,
+    // tx ids currently under dispute, kept by the engine
+    disputed: BTreeSet<TxId>,
+    activity: Activity,
+}
+
+/// Whether an account is in good standing, and why not. Every status but `Active` counts as
+/// locked, which doesn't keep transactions from being applied.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    #[default]
+    Active,
+    /// Locked by a chargeback, until it is reversed or the account is unlocked
+    Frozen,
+    /// Closed for good by a `close` row
+    Closed,
+    /// Locked pending a compliance review, from a `review` row until a `reinstate` one
+    UnderReview,
+}
+
+impl Status {
+    pub fn name(self) -> &'static str {
+        match self {
+            Status::Active => "active",
+            Status::Frozen => "frozen",
+            Status::Closed => "closed",
+            Status::UnderReview => "under_review",
+        }
+    }
+
+    pub fn locked(self) -> bool {
+        self != Status::Active
+    }
+
+    /// The status an administrative row of `transaction_type` leaves an account of this
+    /// status with: an unlock only unlocks a frozen account, a review only starts on an
+    /// open account and a reinstatement ends it, anything but a closed account can be
+    /// closed. `None` if the row doesn't apply to this status or changes none.
+    pub fn after(self, transaction_type: TransactionType) -> Option<Status> {
+        match (transaction_type, self) {
+            (TransactionType::Unlock, Status::Frozen) => Some(Status::Active),
+            (TransactionType::Review, Status::Active | Status::Frozen) => Some(Status::UnderReview),
+            (TransactionType::Reinstate, Status::UnderReview) => Some(Status::Active),
+            (TransactionType::Close, status) if status != Status::Closed => Some(Status::Closed),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Status {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            Status::Active,
+            Status::Frozen,
+            Status::Closed,
+            Status::UnderReview,
+        ]
+        .into_iter()
+        .find(|status| status.name() == s)
+        .ok_or_else(|| format!("unknown status `{}`", s))
+    }
+}
+
+/// Deposits and withdrawals applied to an account, disputes don't change them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Activity {
+    pub deposits: u64,
+    pub withdrawals: u64,
+    /// Sum of the deposits, saturating at [`Decimal::MAX`]
+    pub deposited: Decimal,
+    /// Sum of the withdrawals, saturating at [`Decimal::MAX`]
+    pub withdrawn: Decimal,
+}
+
+/// Serialization for Account
+impl Serialize for Account {
+    // Since we need to serialize the account
+    // With all fields and the total fiend which is computed
+    // We cant use the #[derive(Serialize)] macro
+    // We need to implement it ourself
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("Account", 6)?;
+        state.serialize_field("client", &self.client)?;
+        state.serialize_field("available", &self.available)?;
+        state.serialize_field("held", &self.held)?;
+        state.serialize_field("locked", &self.locked())?;
+        state.serialize_field("balance", &self.total())?;
+        state.serialize_field("status", &self.status)?;
+        state.end()
+    }
+}
+
+impl Account {
+    /// An empty, active account
+    pub fn new(client: ClientId) -> Self {
+        Account {
+            client,
+            available: Decimal::zero(),
+            held: Decimal::zero(),
+            status: Status::Active,
+            disputed: BTreeSet::new(),
+            activity: Activity::default(),
+        }
+    }
+
+    /// tx ids of the client's transactions currently under dispute, in ascending order.
+    /// Their amounts are what makes up `held`.
+    pub fn disputed(&self) -> impl Iterator<Item = TxId> + '_ {
+        self.disputed.iter().copied()
+    }
+
+    /// Whether the account is anything but [`Status::Active`]
+    pub fn locked(&self) -> bool {
+        self.status.locked()
+    }
+
+    /// Number and volume of the deposits and withdrawals applied so far
+    pub fn activity(&self) -> &Activity {
+        &self.activity
+    }
+
+    pub(crate) fn set_activity(&mut self, activity: Activity) {
+        self.activity = activity;
+    }
+
+    /// Record whether `tx` is under dispute
+    pub(crate) fn set_disputed(&mut self, tx: TxId, disputed: bool) {
+        if disputed {
+            self.disputed.insert(tx);
+        } else {
+            self.disputed.remove(&tx);
+        }
+    }
+
+    /// Return the value of held + available of the account
+    pub fn total(&self) -> Decimal {
+        self.available + self.held
+    }
+
+    /// Update accounts based on received transaction
+    /// Transactions whose resulting balances would overflow are skipped,
+    /// [`crate::Engine`] rejects them instead.
+    /// Dispute, Resolve and Chargeback need the referenced amount,
+    /// apply them with [`Account::apply_dispute`] instead.
+    pub fn update_transaction(&mut self, transaction: &Transaction) {
+        self.apply_transfer(transaction);
+    }
+
+    /// Apply a deposit or withdrawal and count it in the account's [`Activity`].
+    /// Returns false and leaves the account untouched if a balance would overflow
+    /// or `transaction` is of another type.
+    pub(crate) fn apply_transfer(&mut self, transaction: &Transaction) -> bool {
+        let (transaction_type, amount) = (transaction.transaction_type, transaction.amount());
+        if !matches!(
+            transaction_type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        ) || !self.apply(transaction_type, amount)
+        {
+            return false;
+        }
+        let activity = &mut self.activity;
+        let (count, volume) = match transaction_type {
+            TransactionType::Deposit => (&mut activity.deposits, &mut activity.deposited),
+            _ => (&mut activity.withdrawals, &mut activity.withdrawn),
+        };
+        *count += 1;
+        *volume = volume.saturating_add(amount);
+        true
+    }
+
+    /// Hold, release or charge back the referenced amount of `dispute`.
+    /// Returns false and leaves the account untouched if a balance would overflow.
+    pub fn apply_dispute(&mut self, dispute: &AppliedDispute) -> bool {
+        self.apply(dispute.transaction_type, dispute.amount)
+    }
+
+    /// Move `amount` as `transaction_type` does, Dispute family variants
+    /// use it as the amount of the referenced transaction.
+    /// Returns false and leaves the account untouched if a balance would overflow.
+    pub(crate) fn apply(&mut self, transaction_type: TransactionType, amount: Decimal) -> bool {
+        let (available, held) = match transaction_type {
+            TransactionType::Deposit => (self.available.checked_add(amount), Some(self.held)),
+            TransactionType::Withdrawal => (self.available.checked_sub(amount), Some(self.held)),
+            TransactionType::Dispute => (
+                self.available.checked_sub(amount),
+                self.held.checked_add(amount),
+            ),
+            TransactionType::Resolve => (
+                self.available.checked_add(amount),
+                self.held.checked_sub(amount),
+            ),
+            // The held funds leave the account, available ones stay
+            TransactionType::Chargeback => (Some(self.available), self.held.checked_sub(amount)),
+            TransactionType::ChargebackReversal => {
+                (self.available.checked_add(amount), Some(self.held))
+            }
+            TransactionType::Unlock
+            | TransactionType::Review
+            | TransactionType::Reinstate
+            | TransactionType::Close => (Some(self.available), Some(self.held)),
+        };
+        // The total has to stay representable as well
+        match (available, held) {
+            (Some(available), Some(held)) if available.checked_add(held).is_some() => {
+                self.available = available;
+                self.held = held;
+                // A chargeback doesn't override a closure or a review
+                if transaction_type == TransactionType::Chargeback && !self.locked() {
+                    self.status = Status::Frozen;
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A Dispute, Resolve, Chargeback or ChargebackReversal the engine applied to an account
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AppliedDispute {
+    pub transaction_type: TransactionType,
+    pub client: ClientId,
+    /// tx id of the referenced deposit or withdrawal
+    pub tx: TxId,
+    /// Amount of the referenced transaction that was moved
+    pub amount: Decimal,
+    /// What the dispute policy charged on top of the amount, taken from the available funds
+    pub fee: Decimal,
+}
+
+/// New balances of an account after a transaction changed them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccountUpdate {
+    pub client: ClientId,
+    /// tx id of the row that caused the update
+    pub tx: TxId,
+    pub transaction_type: TransactionType,
+    /// Amount moved, for the Dispute family the amount of the referenced transaction
+    pub amount: Decimal,
+    /// What the dispute policy charged on top of the amount, taken from the available funds.
+    /// Not serialized, the balances include it.
+    pub fee: Decimal,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub status: Status,
+}
+
+/// Serialized with the `locked` flag the status implies ahead of the status,
+/// `client,tx,type,amount,available,held,locked,status`
+impl Serialize for AccountUpdate {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("AccountUpdate", 8)?;
+        state.serialize_field("client", &self.client)?;
+        state.serialize_field("tx", &self.tx)?;
+        state.serialize_field("type", &self.transaction_type)?;
+        state.serialize_field("amount", &self.amount)?;
+        state.serialize_field("available", &self.available)?;
+        state.serialize_field("held", &self.held)?;
+        state.serialize_field("locked", &self.locked())?;
+        state.serialize_field("status", &self.status)?;
+        state.end()
+    }
+}
+
+impl AccountUpdate {
+    /// Whether the account is anything but [`Status::Active`] after the update
+    pub fn locked(&self) -> bool {
+        self.status.locked()
+    }
+
+    /// What the transaction did to the account as events, [`AccountEvent::Locked`] follows every
+    /// chargeback and [`AccountEvent::Unlocked`] a reversal that left the account unlocked.
+    /// An unlock or reinstatement is only [`AccountEvent::Unlocked`], a review or closure only
+    /// [`AccountEvent::StatusChanged`].
+    pub fn events(&self) -> impl Iterator<Item = AccountEvent> {
+        let (client, tx, amount) = (self.client, self.tx, self.amount);
+        let event = match self.transaction_type {
+            TransactionType::Deposit => Some(AccountEvent::Deposited { client, tx, amount }),
+            TransactionType::Withdrawal => Some(AccountEvent::Withdrew { client, tx, amount }),
+            TransactionType::Dispute => Some(AccountEvent::FundsHeld { client, tx, amount }),
+            TransactionType::Resolve => Some(AccountEvent::FundsReleased { client, tx, amount }),
+            TransactionType::Chargeback => Some(AccountEvent::ChargedBack { client, tx, amount }),
+            TransactionType::ChargebackReversal => {
+                Some(AccountEvent::ChargebackReversed { client, tx, amount })
+            }
+            TransactionType::Unlock | TransactionType::Reinstate => None,
+            TransactionType::Review | TransactionType::Close => {
+                let status = self.status;
+                Some(AccountEvent::StatusChanged { client, tx, status })
+            }
+        };
+        let locked = match self.transaction_type {
+            TransactionType::Chargeback => Some(AccountEvent::Locked { client, tx }),
+            TransactionType::ChargebackReversal
+            | TransactionType::Unlock
+            | TransactionType::Reinstate
+                if !self.locked() =>
+            {
+                Some(AccountEvent::Unlocked { client, tx })
+            }
+            _ => None,
+        };
+        event.into_iter().chain(locked)
+    }
+}
+
+/// A change to an account, replaying the events of a run in order rebuilds its balances.
+/// Serialized with the variant name in an `event` field, e.g.
+/// `{"event":"Deposited","client":1,"tx":1,"amount":"1.0"}`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "event")]
+pub enum AccountEvent {
+    /// `amount` was added to the available funds
+    Deposited {
+        client: ClientId,
+        tx: TxId,
+        amount: Decimal,
+    },
+    /// `amount` was taken from the available funds
+    Withdrew {
+        client: ClientId,
+        tx: TxId,
+        amount: Decimal,
+    },
+    /// `amount` of transaction `tx` moved from available to held
+    FundsHeld {
+        client: ClientId,
+        tx: TxId,
+        amount: Decimal,
+    },
+    /// `amount` of transaction `tx` moved from held back to available
+    FundsReleased {
+        client: ClientId,
+        tx: TxId,
+        amount: Decimal,
+    },
+    /// `amount` of transaction `tx` held by its dispute left the account
+    ChargedBack {
+        client: ClientId,
+        tx: TxId,
+        amount: Decimal,
+    },
+    /// The account was frozen by the chargeback of `tx`
+    Locked { client: ClientId, tx: TxId },
+    /// `amount` charged back from transaction `tx` was added back to the available funds
+    ChargebackReversed {
+        client: ClientId,
+        tx: TxId,
+        amount: Decimal,
+    },
+    /// The account was unlocked, by the reversal of the chargeback of `tx`
+    /// or by the unlock or reinstate row `tx`
+    Unlocked { client: ClientId, tx: TxId },
+    /// The review or close row `tx` left the account with `status`
+    StatusChanged {
+        client: ClientId,
+        tx: TxId,
+        status: Status,
+    },
+}
+
+#[cfg(feature = "arbitrary")]
+mod arbitrary_impls {
+    use crate::{ClientId, Transaction, TransactionType, TxId};
+    use arbitrary::{Arbitrary, Result, Unstructured};
+    use rust_decimal::Decimal;
+
+    /// Client ids are drawn from a small range so generated streams
+    /// contain several transactions per account
+    const MAX_CLIENT: ClientId = 8;
+    /// Transaction ids are drawn from a small range so disputes,
+    /// resolves and chargebacks regularly reference existing transactions
+    const MAX_TX: TxId = 64;
+
+    impl<'a> Arbitrary<'a> for TransactionType {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            Ok(match u.int_in_range(0..=9)? {
+                0 => TransactionType::Deposit,
+                1 => TransactionType::Withdrawal,
+                2 => TransactionType::Dispute,
+                3 => TransactionType::Resolve,
+                4 => TransactionType::Chargeback,
+                5 => TransactionType::ChargebackReversal,
+                6 => TransactionType::Unlock,
+                7 => TransactionType::Review,
+                8 => TransactionType::Reinstate,
+                _ => TransactionType::Close,
+            })
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for Transaction {
+        /// Deposits and withdrawals carry a non-negative amount of up to four decimal places,
+        /// the Dispute family carries none
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            let transaction_type = TransactionType::arbitrary(u)?;
+            let client = u.int_in_range(1..=MAX_CLIENT)?;
+            let tx = u.int_in_range(1..=MAX_TX)?;
+            let amount = match transaction_type {
+                TransactionType::Deposit | TransactionType::Withdrawal => Some(Decimal::new(
+                    u.int_in_range(0..=1_000_000_000)?,
+                    u.int_in_range(0..=4)?,
+                )),
+                _ => None,
+            };
+            Ok(Transaction {
+                transaction_type,
+                client,
+                tx,
+                amount,
+            })
+        }
+    }
+}
+
+#[cfg(all(test, feature = "csv"))]
+mod tests {
+    use crate::model::{
+        Account, AccountEvent, AccountUpdate, AppliedDispute, Status, Transaction, TransactionType,
+    };
+    use rust_decimal::prelude::Zero;
+    use rust_decimal::Decimal;
+
+    fn read_transaction(line: &str) -> Transaction {
+        let mut reader = csv::Reader::from_reader(line.as_bytes());
+        reader.deserialize().next().unwrap().unwrap()
+    }
+
+    #[test]
+    fn parse_deposit() {
+        let result = Transaction {
+            transaction_type: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some(Decimal::new(1, 0)),
+        };
+        let line = "type,client,tx,amount
+deposit,1,1,1.0";
+        let record: Transaction = read_transaction(line);
+        assert_eq!(result, record);
+    }
+
+    #[test]
+    fn parse_withdrawal() {
+        let result = Transaction {
+            transaction_type: TransactionType::Withdrawal,
+            client: 1,
+            tx: 1,
+            amount: Some(Decimal::new(1, 0)),
+        };
+        let line = "type,client,tx,amount
+withdrawal,1,1,1.0";
+        let record: Transaction = read_transaction(line);
+        assert_eq!(result, record);
+    }
+
+    #[test]
+    fn parse_chargeback() {
+        let result = Transaction {
+            transaction_type: TransactionType::Chargeback,
+            client: 1,
+            tx: 1,
+            amount: Some(Decimal::new(1, 0)),
+        };
+        let line = "type,client,tx,amount
+chargeback,1,1,1.0";
+        let record: Transaction = read_transaction(line);
+        assert_eq!(result, record);
+    }
+
+    #[test]
+    fn parse_dispute() {
+        let result = Transaction {
+            transaction_type: TransactionType::Dispute,
+            client: 1,
+            tx: 1,
+            amount: Some(Decimal::new(1, 0)),
+        };
+        let line = "type,client,tx,amount
+dispute,1,1,1.0";
+        let record: Transaction = read_transaction(line);
+        assert_eq!(result, record);
+    }
+
+    #[test]
+    fn parse_resolve() {
+        let result = Transaction {
+            transaction_type: TransactionType::Resolve,
+            client: 1,
+            tx: 1,
+            amount: Some(Decimal::new(1, 0)),
+        };
+        let line = "type,client,tx,amount
+resolve,1,1,1.0";
+        let record: Transaction = read_transaction(line);
+        assert_eq!(result, record);
+    }
+
+    #[test]
+    fn parse_transaction_with_no_amount() {
+        let result = Transaction {
+            transaction_type: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: None,
+        };
+        let line = "type,client,tx,amount
+deposit,1,1,";
+        let record: Transaction = read_transaction(line);
+        assert_eq!(result, record);
+    }
+
+    #[test]
+    fn deposits() {
+        let mut account = Account::new(1);
+        let transaction = Transaction {
+            transaction_type: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some(Decimal::new(1, 0)),
+        };
+        account.update_transaction(&transaction);
+        assert_eq!(account.available, Decimal::new(1, 0));
+        account.update_transaction(&transaction); // Add 1 again
+        assert_eq!(account.available, Decimal::new(2, 0));
+        assert_eq!(account.activity().deposits, 2);
+        assert_eq!(account.activity().deposited, Decimal::new(2, 0));
+    }
+
+    #[test]
+    fn withdrawal() {
+        let mut account = Account {
+            available: Decimal::new(1, 0),
+            ..Account::new(1)
+        };
+        let transaction = Transaction {
+            transaction_type: TransactionType::Withdrawal,
+            client: 1,
+            tx: 1,
+            amount: Some(Decimal::new(1, 0)),
+        };
+        account.update_transaction(&transaction);
+        assert_eq!(account.available, Decimal::zero());
+        assert_eq!(account.activity().withdrawals, 1);
+        assert_eq!(account.activity().withdrawn, Decimal::new(1, 0));
+    }
+
+    fn applied(transaction_type: TransactionType) -> AppliedDispute {
+        AppliedDispute {
+            transaction_type,
+            client: 1,
+            tx: 1,
+            amount: Decimal::new(1, 0),
+            fee: Decimal::ZERO,
+        }
+    }
+
+    #[test]
+    fn dispute() {
+        let mut account = Account {
+            available: Decimal::new(1, 0),
+            ..Account::new(1)
+        };
+        assert!(account.apply_dispute(&applied(TransactionType::Dispute)));
+        assert_eq!(account.available, Decimal::zero());
+        assert_eq!(account.held, Decimal::new(1, 0));
+    }
+
+    #[test]
+    fn resolve() {
+        let mut account = Account {
+            available: Decimal::new(1, 0),
+            held: Decimal::new(1, 0),
+            ..Account::new(1)
+        };
+        assert!(account.apply_dispute(&applied(TransactionType::Resolve)));
+        assert_eq!(account.available, Decimal::new(2, 0));
+        assert_eq!(account.held, Decimal::zero());
+    }
+
+    #[test]
+    fn chargeback() {
+        let mut account = Account {
+            available: Decimal::new(1, 0),
+            held: Decimal::new(1, 0),
+            ..Account::new(1)
+        };
+        assert!(account.apply_dispute(&applied(TransactionType::Chargeback)));
+        // Only the held funds are taken
+        assert_eq!(account.available, Decimal::new(1, 0));
+        assert_eq!(account.held, Decimal::zero());
+        assert_eq!(account.status, Status::Frozen);
+
+        let mut closed = Account {
+            status: Status::Closed,
+            ..Account::new(1)
+        };
+        assert!(closed.apply_dispute(&applied(TransactionType::Chargeback)));
+        assert_eq!(closed.status, Status::Closed);
+    }
+
+    #[test]
+    fn chargeback_update_is_followed_by_lock_event() {
+        let update = AccountUpdate {
+            client: 1,
+            tx: 2,
+            transaction_type: TransactionType::Chargeback,
+            amount: Decimal::new(1, 0),
+            fee: Decimal::ZERO,
+            available: Decimal::zero(),
+            held: Decimal::zero(),
+            status: Status::Frozen,
+        };
+        let events: Vec<AccountEvent> = update.events().collect();
+        assert_eq!(
+            events,
+            [
+                AccountEvent::ChargedBack {
+                    client: 1,
+                    tx: 2,
+                    amount: Decimal::new(1, 0)
+                },
+                AccountEvent::Locked { client: 1, tx: 2 }
+            ]
+        );
+        let json = serde_json::to_string(&events[1]).unwrap();
+        assert_eq!(json, r#"{"event":"Locked","client":1,"tx":2}"#);
+    }
+
+    #[test]
+    fn overflowing_transaction_is_skipped() {
+        let mut account = Account {
+            available: Decimal::MAX,
+            ..Account::new(1)
+        };
+        let transaction = Transaction {
+            transaction_type: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some(Decimal::new(1, 0)),
+        };
+        account.update_transaction(&transaction);
+        assert_eq!(account.available, Decimal::MAX);
+    }
+}
@@ -0,0 +1,212 @@
+//! Parses in-memory input on all cores.
+//!
+//! The input is split into line-aligned chunks which are parsed in parallel,
+//! the resulting transactions are then applied one chunk after the other, in input order.
+//! Only a bounded window of chunks is parsed ahead of the engine, so memory use
+//! doesn't grow with the input.
+//!
+//! Splitting on newlines means quoted fields must not contain line breaks,
+//! which transaction files don't have.
+use crate::engine::Engine;
+use crate::io::csv::{skip_unknown, ErrorCode, ParseOptions, RowError, RowParser};
+use crate::model::{AccountUpdate, Transaction};
+use crate::report::ProcessReport;
+use csv::ByteRecord;
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+
+/// Bytes of input per chunk, rounded up to the next line break
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// Chunks parsed ahead of the engine, per thread
+const CHUNKS_PER_THREAD: usize = 4;
+
+/// A parsed row, the raw record is kept for error reporting
+struct Row {
+    record: ByteRecord,
+    parsed: Result<Transaction, RowError>,
+}
+
+/// Rows of a chunk, with line numbers relative to the chunk
+struct Chunk {
+    rows: Vec<Row>,
+    lines: u64,
+}
+
+/// Same as [`crate::process_transactions_with`] for input that is already in memory,
+/// e.g. a memory-mapped file. The first line must be the header.
+pub fn process_transactions_parallel(
+    input: &[u8],
+    options: &ParseOptions,
+) -> Result<ProcessReport, RowError> {
+    process_transactions_parallel_with_updates(input, options, |_| {})
+}
+
+/// Same as [`crate::process_transactions_with_updates`] for input that is already in memory.
+/// Updates are reported in input order, from the thread applying the transactions.
+pub fn process_transactions_parallel_with_updates<F: FnMut(AccountUpdate)>(
+    input: &[u8],
+    options: &ParseOptions,
+    on_update: F,
+) -> Result<ProcessReport, RowError> {
+    process_parallel(input, options, Engine::new(), on_update)
+}
+
+pub(crate) fn process_parallel<F: FnMut(AccountUpdate)>(
+    input: &[u8],
+    options: &ParseOptions,
+    mut engine: Engine,
+    mut on_update: F,
+) -> Result<ProcessReport, RowError> {
+    let mut errors: Vec<RowError> = vec![];
+
+    let mut reader = csv::Reader::from_reader(input);
+    let mut parser = match reader.byte_headers() {
+        Ok(headers) => RowParser::new(options, Some(headers)),
+        Err(err) => {
+            options.reject(RowError::new(&err, &ByteRecord::new()), &mut errors)?;
+            return Ok(ProcessReport {
+                errors,
+                ..engine.into()
+            });
+        }
+    };
+    let body_start = reader.position().byte() as usize;
+    // Lines before the current chunk
+    let mut line_offset = reader.position().line() - 1;
+
+    let mut unknown_types = BTreeMap::new();
+
+    let chunks = split(&input[body_start..]);
+    let window = rayon::current_num_threads() * CHUNKS_PER_THREAD;
+    for batch in chunks.chunks(window) {
+        let parsed: Vec<Chunk> = batch
+            .par_iter()
+            .map(|chunk| parse_chunk(chunk, parser.clone()))
+            .collect();
+        for chunk in parsed {
+            for row in chunk.rows {
+                let transaction = match row.parsed {
+                    Ok(transaction) => transaction,
+                    Err(mut error) => {
+                        match parser.parse_unknown(&row.record) {
+                            Some(unknown) => skip_unknown(unknown, &mut engine, &mut unknown_types),
+                            None => {
+                                error.line += line_offset;
+                                options.reject(error, &mut errors)?;
+                            }
+                        }
+                        continue;
+                    }
+                };
+                if let Err(error) = options.check_amount(&transaction, &row.record, line_offset) {
+                    options.reject(error, &mut errors)?;
+                    continue;
+                }
+                if let Err(rejection) = engine.apply_each(transaction, &mut on_update) {
+                    let mut error =
+                        RowError::invalid(&row.record, rejection.code(), rejection.to_string());
+                    error.line += line_offset;
+                    options.reject(error, &mut errors)?;
+                }
+            }
+            line_offset += chunk.lines;
+        }
+    }
+    Ok(ProcessReport {
+        errors,
+        unknown_types,
+        ..engine.into()
+    })
+}
+
+/// Split `input` into chunks of about [`CHUNK_SIZE`] that end on a line break
+fn split(input: &[u8]) -> Vec<&[u8]> {
+    let mut chunks = vec![];
+    let mut start = 0;
+    while start < input.len() {
+        let end = (start + CHUNK_SIZE).min(input.len());
+        let end = input[end..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(input.len(), |newline| end + newline + 1);
+        chunks.push(&input[start..end]);
+        start = end;
+    }
+    chunks
+}
+
+fn parse_chunk(chunk: &[u8], mut parser: RowParser) -> Chunk {
+    // Field counts are checked against the header below,
+    // not against whichever row happens to start the chunk
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(chunk);
+    let fields = parser.headers().map_or(0, ByteRecord::len);
+    let mut rows = vec![];
+    let mut record = ByteRecord::new();
+    loop {
+        let parsed = match reader.read_byte_record(&mut record) {
+            Ok(false) => break,
+            Ok(true) if record.len() != fields => {
+                let message = format!(
+                    "found record with {} fields, but the header has {} fields",
+                    record.len(),
+                    fields
+                );
+                Err(RowError::invalid(&record, ErrorCode::MalformedRow, message))
+            }
+            Ok(true) => parser.parse(&record),
+            Err(err) => Err(RowError::new(&err, &record)),
+        };
+        rows.push(Row {
+            record: record.clone(),
+            parsed,
+        });
+    }
+    Chunk {
+        rows,
+        lines: chunk.iter().filter(|&&b| b == b'\n').count() as u64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::io::csv::{process_transactions_with, ParseMode, ParseOptions};
+    use crate::parallel::{process_transactions_parallel, split, CHUNK_SIZE};
+
+    #[test]
+    fn chunks_end_on_line_breaks() {
+        let line = b"deposit,1,1,1.0\n";
+        let input: Vec<u8> = line.repeat(CHUNK_SIZE / line.len() * 3);
+        let chunks = split(&input);
+        assert!(chunks.len() >= 3);
+        assert!(chunks.iter().all(|chunk| chunk.ends_with(b"\n")));
+        assert_eq!(chunks.concat(), input);
+    }
+
+    #[test]
+    fn matches_sequential_processing() {
+        let mut input = String::from("type,client,tx,amount\n");
+        for tx in 1..=200_000 {
+            let client = tx % 7;
+            match tx % 10 {
+                0 => input.push_str(&format!("dispute,{},{},\n", client, tx - 7)),
+                5 => input.push_str(&format!("resolve,{},{},\n", client, tx - 5)),
+                9 => input.push_str(&format!("teleport,{},{},1.0\n", client, tx)),
+                _ => input.push_str(&format!("deposit,{},{},{}.5\n", client, tx, tx)),
+            }
+        }
+        let options = ParseOptions {
+            mode: ParseMode::Collecting,
+            ..ParseOptions::default()
+        };
+        let mut reader = csv::Reader::from_reader(input.as_bytes());
+        let sequential = process_transactions_with(&mut reader, &options).unwrap();
+        let parallel = process_transactions_parallel(input.as_bytes(), &options).unwrap();
+        assert_eq!(parallel.accounts, sequential.accounts);
+        assert_eq!(parallel.errors, sequential.errors);
+        assert_eq!(parallel.errors.len(), 20_000);
+    }
+}
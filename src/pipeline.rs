@@ -0,0 +1,203 @@
+//! One entry point wiring a source, the engine and its plug-ins, and sinks together.
+//!
+//! [`rows`] applies a source row by row instead, with the rules going by the time of each
+//! row and every update handed out as it happens.
+use crate::engine::eviction::Eviction;
+use crate::engine::limits::AmountLimits;
+use crate::engine::policy::DisputePolicy;
+use crate::engine::{
+    Engine, EngineObserver, NegativeBalanceBehavior, TransactionValidator, UnknownReference,
+};
+use crate::ids::IdNames;
+use crate::io::source::TransactionSource;
+use crate::io::{ParseMode, RowError};
+use crate::report::sink::{write_to_sink, AccountSink};
+use crate::report::ProcessReport;
+use std::io;
+
+pub mod rows;
+
+/// Configures a processing run step by step, e.g.
+/// `EngineBuilder::new().source(csv).strict().sink(json).run()`.
+/// The engine settings are the same as the [`Engine`] setters.
+pub struct EngineBuilder<'a> {
+    engine: Engine,
+    mode: ParseMode,
+    source: Option<Box<dyn TransactionSource + 'a>>,
+    sinks: Vec<Box<dyn AccountSink + 'a>>,
+}
+
+impl Default for EngineBuilder<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> EngineBuilder<'a> {
+    /// A default engine in [`ParseMode::Lenient`], without source or sinks
+    pub fn new() -> Self {
+        EngineBuilder {
+            engine: Engine::new(),
+            mode: ParseMode::default(),
+            source: None,
+            sinks: vec![],
+        }
+    }
+
+    /// Where the transactions come from, nothing is applied without one
+    pub fn source(mut self, source: impl TransactionSource + 'a) -> Self {
+        self.source = Some(Box::new(source));
+        self
+    }
+
+    /// How source errors and rejected transactions are handled
+    pub fn mode(mut self, mode: ParseMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Abort on the first source error or rejected transaction
+    pub fn strict(self) -> Self {
+        self.mode(ParseMode::Strict)
+    }
+
+    /// Collect source errors and rejected transactions in [`ProcessReport::errors`]
+    pub fn collecting(self) -> Self {
+        self.mode(ParseMode::Collecting)
+    }
+
+    pub fn validator(mut self, validator: impl TransactionValidator + 'static) -> Self {
+        self.engine.add_validator(validator);
+        self
+    }
+
+    pub fn dispute_policy(mut self, policy: impl DisputePolicy + 'static) -> Self {
+        self.engine.set_dispute_policy(policy);
+        self
+    }
+
+    pub fn observer(mut self, observer: impl EngineObserver + 'static) -> Self {
+        self.engine.add_observer(observer);
+        self
+    }
+
+    pub fn unknown_reference(mut self, unknown_reference: UnknownReference) -> Self {
+        self.engine.set_unknown_reference(unknown_reference);
+        self
+    }
+
+    pub fn report_repeated_disputes(mut self, report: bool) -> Self {
+        self.engine.set_report_repeated_disputes(report);
+        self
+    }
+
+    pub fn allow_redisputes(mut self, allow: bool) -> Self {
+        self.engine.set_allow_redisputes(allow);
+        self
+    }
+
+    pub fn unlock_on_reversal(mut self, unlock: bool) -> Self {
+        self.engine.set_unlock_on_reversal(unlock);
+        self
+    }
+
+    pub fn reject_locked(mut self, reject: bool) -> Self {
+        self.engine.set_reject_locked(reject);
+        self
+    }
+
+    pub fn retry_out_of_order(mut self, retry: bool) -> Self {
+        self.engine.set_retry_out_of_order(retry);
+        self
+    }
+
+    pub fn negative_balance_behavior(mut self, behavior: NegativeBalanceBehavior) -> Self {
+        self.engine.set_negative_balance_behavior(behavior);
+        self
+    }
+
+    pub fn amount_limits(mut self, limits: AmountLimits) -> Self {
+        self.engine.set_amount_limits(limits);
+        self
+    }
+
+    pub fn eviction(mut self, eviction: Eviction) -> Self {
+        self.engine.set_eviction(eviction);
+        self
+    }
+
+    pub fn tx_names(mut self, names: IdNames) -> Self {
+        self.engine.set_tx_names(names);
+        self
+    }
+
+    /// Where the accounts go once all transactions are applied, in the order the sinks were added
+    pub fn sink(mut self, sink: impl AccountSink + 'a) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    /// The configured engine, for callers applying transactions themselves
+    pub fn build(self) -> Engine {
+        self.engine
+    }
+
+    /// Applies the transactions of the source and writes the accounts to every sink.
+    /// Failing to write to a sink is an io::Error, a rejected row in strict mode a RowError.
+    pub fn run(self) -> io::Result<Result<ProcessReport, RowError>> {
+        let report = match self.source {
+            Some(source) => match self.engine.process_source_with(source, self.mode) {
+                Ok(report) => report,
+                Err(error) => return Ok(Err(error)),
+            },
+            None => self.engine.into(),
+        };
+        for mut sink in self.sinks {
+            write_to_sink(&report.accounts, sink.as_mut())?;
+        }
+        Ok(Ok(report))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::io::source::JsonLinesSource;
+    use crate::pipeline::EngineBuilder;
+    use crate::report::sink::{JsonLinesSink, MemorySink};
+    use crate::UnknownReference;
+
+    const DATA: &str = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "2"}
+{"type": "dispute", "client": 1, "tx": 7}
+{"type": "deposit", "client": 2, "tx": 2, "amount": "1"}"#;
+
+    #[test]
+    fn builder_runs_source_to_sinks() {
+        let mut memory = MemorySink::default();
+        let mut json = vec![];
+        let report = EngineBuilder::new()
+            .source(JsonLinesSource::new(DATA.as_bytes()))
+            .collecting()
+            .unknown_reference(UnknownReference::Reject)
+            .sink(&mut memory)
+            .sink(JsonLinesSink::new(&mut json))
+            .run()
+            .unwrap()
+            .unwrap();
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].line, 2);
+        assert_eq!(memory.accounts.len(), 2);
+        assert_eq!(String::from_utf8(json).unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    fn strict_builder_stops_at_the_first_error() {
+        let error = EngineBuilder::new()
+            .source(JsonLinesSource::new(DATA.as_bytes()))
+            .strict()
+            .unknown_reference(UnknownReference::Reject)
+            .run()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(error.message, "unknown tx id 7");
+    }
+}
@@ -0,0 +1,318 @@
+//! Applying the rows of a source one by one, with the rules that go by the time of each row.
+//!
+//! [`RowLoop::run`] reads the time of every row from a column the source keeps as metadata,
+//! sets it on the engine, resolves the disputes open too long and applies the scheduled
+//! transactions due by then, and then the row itself. Every update goes to a [`RowHandler`]
+//! with where it comes from, e.g. to write an update log with the line of each row.
+use crate::diagnostics::{Diagnostics, Phase};
+use crate::engine::release::{format_timestamp, parse_timestamp, HoldRelease};
+use crate::engine::schedule::{Schedule, Scheduler};
+use crate::engine::Engine;
+#[cfg(feature = "csv")]
+use crate::io::source::CsvSource;
+use crate::io::source::TransactionSource;
+use crate::io::{ErrorCode, ParseMode, RowError};
+use crate::model::{AccountUpdate, Metadata};
+#[cfg(feature = "csv")]
+use std::io;
+use std::time::Duration;
+
+/// Where an update of a [`RowLoop`] comes from
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RowOrigin<'a> {
+    /// Line of the row, `None` for a released hold or a scheduled transaction
+    pub line: Option<u64>,
+    /// Columns of the row beyond the transaction, if the source keeps them
+    pub metadata: Option<&'a Metadata>,
+    /// Time of the row as written in its time column, or when the scheduled transaction
+    /// fell due
+    pub timestamp: Option<&'a str>,
+}
+
+/// What is done with the updates of a [`RowLoop`], nothing by default
+pub trait RowHandler {
+    /// An update of the row or released hold or scheduled transaction of `origin`
+    fn update(&mut self, _update: AccountUpdate, _origin: RowOrigin<'_>) {}
+}
+
+/// Applies the rows of a source in order, see the [module](self)
+#[derive(Debug, Clone)]
+pub struct RowLoop {
+    mode: ParseMode,
+    time_column: String,
+    // What the caller needs the time of each row for, named in errors
+    needs_time: Vec<String>,
+    release: Option<HoldRelease>,
+    scheduler: Option<Scheduler>,
+}
+
+impl RowLoop {
+    /// Handles bad rows by `mode`, reading the time of each row from a `timestamp` column
+    pub fn new(mode: ParseMode) -> Self {
+        RowLoop {
+            mode,
+            time_column: "timestamp".to_string(),
+            needs_time: vec![],
+            release: None,
+            scheduler: None,
+        }
+    }
+
+    /// Reads the ISO 8601 date or timestamp of each row from `column` instead
+    pub fn time_column(mut self, column: &str) -> Self {
+        self.time_column = column.to_string();
+        self
+    }
+
+    /// Requires the time of every row for `what`, e.g. a report by period the caller makes
+    /// of the updates
+    pub fn needs_time(mut self, what: &str) -> Self {
+        self.needs_time.push(what.to_string());
+        self
+    }
+
+    /// Resolves the disputes still open `after` they were opened, see [`HoldRelease`]
+    pub fn release_holds_after(mut self, after: Duration) -> Self {
+        self.release = Some(HoldRelease::new(after));
+        self
+    }
+
+    /// Applies the transactions of `schedules` as they fall due, see [`Scheduler`]
+    pub fn schedules(mut self, schedules: Vec<Schedule>) -> Self {
+        self.scheduler = Some(Scheduler::new(schedules));
+        self
+    }
+
+    /// What goes by the time of each row with `engine`, the first of them if several do:
+    /// the source has to keep the time column as metadata then, see
+    /// [`CsvSource::keep_metadata`](crate::CsvSource::keep_metadata)
+    pub fn time_needed_for(&self, engine: &Engine) -> Option<&str> {
+        let rules = [
+            (self.release.is_some(), "releasing holds"),
+            (self.scheduler.is_some(), "scheduling transactions"),
+            (engine.needs_time(), "the time-based rules of the engine"),
+        ];
+        self.needs_time.first().map(String::as_str).or_else(|| {
+            rules
+                .into_iter()
+                .find_map(|(needed, what)| needed.then_some(what))
+        })
+    }
+
+    /// Fails if something goes by the time of each row but `source` has no time column,
+    /// which it would silently skip otherwise
+    #[cfg(feature = "csv")]
+    pub fn check_source<R: io::Read>(
+        &self,
+        engine: &Engine,
+        source: &CsvSource<R>,
+    ) -> Result<(), String> {
+        match self.time_needed_for(engine) {
+            Some(what) if !source.has_column(&self.time_column) => Err(format!(
+                "{} needs the time of each row, but there is no column `{}`",
+                what, self.time_column
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Applies the rows of `source` to `engine` until it is exhausted, returning the rows
+    /// [`ParseMode::Collecting`] collects. Only [`ParseMode::Strict`] returns an error.
+    /// A row whose time is missing or isn't an ISO 8601 date or timestamp is malformed if
+    /// something goes by it.
+    pub fn run(
+        self,
+        engine: &mut Engine,
+        source: &mut impl TransactionSource,
+        handler: &mut impl RowHandler,
+    ) -> Result<Vec<RowError>, RowError> {
+        self.run_timed(engine, source, handler, &mut Diagnostics::new(false))
+    }
+
+    /// Same as [`RowLoop::run`], adding the time spent reading and applying the rows to
+    /// `diagnostics` with the number of rows
+    pub fn run_timed(
+        mut self,
+        engine: &mut Engine,
+        source: &mut impl TransactionSource,
+        handler: &mut impl RowHandler,
+        diagnostics: &mut Diagnostics,
+    ) -> Result<Vec<RowError>, RowError> {
+        let timed = self.time_needed_for(engine).is_some();
+        let mut errors = vec![];
+        let mut rows = 0;
+        while let Some(item) = diagnostics.time(Phase::Parse, || source.next_transaction()) {
+            rows += 1;
+            let applying = diagnostics.start();
+            let line = source.line().unwrap_or(0);
+            let metadata = source.metadata();
+            let applied = item.and_then(|transaction| {
+                let timestamp = metadata
+                    .and_then(|metadata| metadata.get(&self.time_column))
+                    .map(String::as_str);
+                let now = timestamp.and_then(parse_timestamp);
+                match now {
+                    Some(now) => engine.set_time(now),
+                    None if timed => {
+                        return Err(RowError {
+                            line,
+                            record: transaction.to_string(),
+                            code: ErrorCode::MalformedRow,
+                            message: format!(
+                                "{} `{}` isn't an ISO 8601 date or timestamp",
+                                self.time_column,
+                                timestamp.unwrap_or_default()
+                            ),
+                        })
+                    }
+                    None => {}
+                }
+                if let Some(now) = now {
+                    self.apply_due(engine, now, timestamp, handler);
+                }
+                let release = &mut self.release;
+                engine
+                    .apply_each(transaction, |update| {
+                        if let (Some(release), Some(now)) = (&mut *release, now) {
+                            release.record(now, &update);
+                        }
+                        let origin = RowOrigin {
+                            line: Some(line),
+                            metadata,
+                            timestamp,
+                        };
+                        handler.update(update, origin)
+                    })
+                    .map_err(|rejection| RowError {
+                        line,
+                        record: transaction.to_string(),
+                        code: rejection.code(),
+                        message: rejection.to_string(),
+                    })
+            });
+            diagnostics.stop(applying, Phase::Apply);
+            if let Err(error) = applied {
+                self.mode.reject(error, &mut errors)?;
+            }
+        }
+        diagnostics.set_rows(rows);
+        Ok(errors)
+    }
+
+    /// Resolves the disputes that expired and applies the scheduled transactions that fell
+    /// due by `now`, the time of the next row
+    fn apply_due(
+        &mut self,
+        engine: &mut Engine,
+        now: i64,
+        timestamp: Option<&str>,
+        handler: &mut impl RowHandler,
+    ) {
+        if let Some(release) = &mut self.release {
+            for resolve in release.expired(now) {
+                tracing::info!(
+                    client = resolve.client,
+                    tx = resolve.tx,
+                    "releasing the hold of a dispute open too long"
+                );
+                let _ = engine.apply_each(resolve, |update| {
+                    let origin = RowOrigin {
+                        timestamp,
+                        ..RowOrigin::default()
+                    };
+                    handler.update(update, origin)
+                });
+            }
+        }
+        if let Some(scheduler) = &mut self.scheduler {
+            for (at, scheduled) in scheduler.due(now, engine.accounts()) {
+                let at = format_timestamp(at);
+                let applied = engine.apply_each(scheduled, |update| {
+                    let origin = RowOrigin {
+                        timestamp: Some(&at),
+                        ..RowOrigin::default()
+                    };
+                    handler.update(update, origin)
+                });
+                if let Err(rejection) = applied {
+                    tracing::warn!("scheduled {} due {}: {}", scheduled, at, rejection);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "csv"))]
+mod tests {
+    use super::*;
+    use crate::io::csv::ParseOptions;
+    use crate::model::TransactionType;
+    use rust_decimal::Decimal;
+
+    #[derive(Default)]
+    struct Recorder(Vec<(TransactionType, Option<u64>, String)>);
+
+    impl RowHandler for Recorder {
+        fn update(&mut self, update: AccountUpdate, origin: RowOrigin<'_>) {
+            let timestamp = origin.timestamp.unwrap_or_default().to_string();
+            self.0
+                .push((update.transaction_type, origin.line, timestamp));
+        }
+    }
+
+    #[test]
+    fn holds_are_released_and_schedules_applied_before_each_row() {
+        let data = "type,client,tx,amount,timestamp
+deposit,1,1,10.0,2024-01-01
+dispute,1,1,,2024-01-01
+deposit,1,2,1.0,2024-01-05
+deposit,1,3,1.0,yesterday";
+        let time = |date| parse_timestamp(date).unwrap();
+        let fee = Schedule {
+            transaction_type: TransactionType::Withdrawal,
+            client: Some(1),
+            amount: Decimal::new(5, 1),
+            every: crate::engine::schedule::Every::Day,
+            start: time("2024-01-04"),
+            end: None,
+        };
+        let rows = RowLoop::new(ParseMode::Collecting)
+            .release_holds_after(Duration::from_secs(2 * 86_400))
+            .schedules(vec![fee]);
+        let options = ParseOptions::default();
+        let source = CsvSource::new(csv::Reader::from_reader(data.as_bytes()), &options).unwrap();
+        let mut engine = Engine::new();
+        assert_eq!(rows.check_source(&engine, &source), Ok(()));
+        assert_eq!(
+            rows.clone()
+                .time_column("date")
+                .check_source(&engine, &source),
+            Err(
+                "releasing holds needs the time of each row, but there is no column `date`"
+                    .to_string()
+            )
+        );
+
+        let mut source = source.keep_metadata();
+        let mut recorder = Recorder::default();
+        let errors = rows.run(&mut engine, &mut source, &mut recorder).unwrap();
+        let due = |date| format_timestamp(time(date));
+        assert_eq!(
+            recorder.0,
+            [
+                (TransactionType::Deposit, Some(2), "2024-01-01".to_string()),
+                (TransactionType::Dispute, Some(3), "2024-01-01".to_string()),
+                (TransactionType::Resolve, None, "2024-01-05".to_string()),
+                (TransactionType::Withdrawal, None, due("2024-01-04")),
+                (TransactionType::Withdrawal, None, due("2024-01-05")),
+                (TransactionType::Deposit, Some(4), "2024-01-05".to_string()),
+            ]
+        );
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            (errors[0].line, errors[0].code),
+            (5, ErrorCode::MalformedRow)
+        );
+        assert_eq!(engine.accounts()[&1].available, Decimal::from(10));
+    }
+}
@@ -0,0 +1,26 @@
+//! The types and functions most users of the crate need, `use transaction_parser::prelude::*;`
+pub use crate::engine::{
+    AccountMap, Engine, EngineObserver, Rejection, TransactionValidator, UnknownReference,
+};
+#[cfg(feature = "csv")]
+pub use crate::io::csv::{
+    process_transactions, process_transactions_with, process_transactions_with_engine,
+    process_transactions_with_updates, AmountFormat, ParseOptions, UnknownTypes,
+};
+#[cfg(feature = "csv")]
+pub use crate::io::source::CsvSource;
+pub use crate::io::source::{JsonLinesSource, SourceError, TransactionSource};
+#[cfg(feature = "fs")]
+pub use crate::io::{process_file, InputOptions};
+pub use crate::io::{ErrorCode, ParseMode, RowError};
+pub use crate::model::{
+    Account, AccountEvent, AccountUpdate, Activity, AppliedDispute, ClientId, Metadata,
+    Transaction, TransactionType, TxId, UnknownTransaction,
+};
+pub use crate::pipeline::EngineBuilder;
+#[cfg(feature = "csv")]
+pub use crate::report::sink::CsvSink;
+pub use crate::report::sink::{write_to_sink, AccountSink, JsonLinesSink, MemorySink};
+#[cfg(feature = "csv")]
+pub use crate::report::{write_accounts, write_accounts_with};
+pub use crate::report::{Column, DisputedColumn, OutputOptions, OutputSchema, ProcessReport};
@@ -0,0 +1,190 @@
+//! Client ids replaced with pseudonyms, so outputs and logs can be shared without
+//! identifying customers.
+//!
+//! A [`Pseudonymizer`] maps every [`ClientId`] to another one of the same width, the `u16`
+//! of the specification unless [`Pseudonymizer::within`] says otherwise, with a
+//! keyed permutation: a Feistel network whose rounds are HMAC-SHA256 under the key. Distinct
+//! clients always get distinct pseudonyms, the same key always gives the same ones, and
+//! without the key they can't be linked back. With it [`Pseudonymizer::reveal`] recovers the
+//! client, e.g. to write the mapping file of the `--pseudonym-map` option.
+//!
+//! Rows are pseudonymized as they are parsed, so the engine and everything after it only
+//! ever see pseudonyms. The raw records of rejected rows are replaced with [`REDACTED`].
+use crate::io::RowError;
+use crate::model::ClientId;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+
+/// The record of a rejected row once redacted, see [`Pseudonymizer::redact`]
+pub const REDACTED: &str = "<redacted>";
+
+/// Rounds of the Feistel network, four make it a pseudorandom permutation
+const ROUNDS: u8 = 4;
+
+/// Maps client ids to pseudonyms and back with a secret key
+#[derive(Clone)]
+pub struct Pseudonymizer {
+    key: Vec<u8>,
+    mac: Hmac<Sha256>,
+    // Bits of each half of a client id
+    half: u32,
+}
+
+impl Pseudonymizer {
+    /// Keys shorter than this are refused by the command line tool
+    pub const MIN_KEY_LEN: usize = 16;
+
+    pub fn new(key: &[u8]) -> Self {
+        Pseudonymizer {
+            key: key.to_vec(),
+            mac: Hmac::new_from_slice(key).expect("HMAC takes keys of any length"),
+            half: u16::BITS / 2,
+        }
+    }
+
+    /// Pseudonyms of `bits` wide client ids instead, e.g. [`crate::IdFormat::bits`] of the
+    /// input. Wider clients aren't pseudonymized correctly, the parser rejects them first.
+    pub fn within(mut self, bits: u32) -> Self {
+        assert!(
+            bits.is_multiple_of(2) && bits <= ClientId::BITS,
+            "{} bits can't be halved",
+            bits
+        );
+        self.half = bits / 2;
+        self
+    }
+
+    /// The pseudonym of `client`
+    pub fn pseudonym(&self, client: ClientId) -> ClientId {
+        let (mut left, mut right) = self.split(client);
+        for round in 0..ROUNDS {
+            (left, right) = (right, left ^ self.round(round, right));
+        }
+        self.join(left, right)
+    }
+
+    /// The client whose pseudonym is `pseudonym`
+    pub fn reveal(&self, pseudonym: ClientId) -> ClientId {
+        let (mut left, mut right) = self.split(pseudonym);
+        for round in (0..ROUNDS).rev() {
+            (left, right) = (right ^ self.round(round, left), left);
+        }
+        self.join(left, right)
+    }
+
+    /// `error` with its record replaced by [`REDACTED`], the record of a rejected row holds
+    /// the client id as it was in the input
+    pub fn redact(&self, mut error: RowError) -> RowError {
+        error.record = REDACTED.to_string();
+        error
+    }
+
+    fn round(&self, round: u8, half: u64) -> u64 {
+        let mut mac = self.mac.clone();
+        mac.update(&[round]);
+        mac.update(&half.to_le_bytes());
+        let digest = mac.finalize().into_bytes();
+        let bytes = digest[..8].try_into().expect("SHA-256 has 32 bytes");
+        u64::from_le_bytes(bytes) & self.half_mask()
+    }
+
+    fn half_mask(&self) -> u64 {
+        u64::MAX >> (u64::BITS - self.half)
+    }
+
+    fn split(&self, client: ClientId) -> (u64, u64) {
+        (
+            (client >> self.half) & self.half_mask(),
+            client & self.half_mask(),
+        )
+    }
+
+    fn join(&self, left: u64, right: u64) -> ClientId {
+        left << self.half | right
+    }
+}
+
+/// Kept out of logs
+impl fmt::Debug for Pseudonymizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Pseudonymizer")
+    }
+}
+
+impl PartialEq for Pseudonymizer {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key && self.half == other.half
+    }
+}
+
+impl Eq for Pseudonymizer {}
+
+#[cfg(all(test, feature = "csv"))]
+mod tests {
+    use super::*;
+    use crate::io::csv::{process_transactions_with, ParseOptions};
+    use crate::io::source::{JsonLinesSource, TransactionSource};
+    use crate::ParseMode;
+    use std::collections::HashSet;
+
+    #[test]
+    fn pseudonyms_are_a_permutation() {
+        let pseudonyms = Pseudonymizer::new(b"a key for these tests");
+        let clients = 0..=ClientId::from(u16::MAX);
+        let mut seen = HashSet::new();
+        let mut unchanged = 0;
+        for client in clients {
+            let pseudonym = pseudonyms.pseudonym(client);
+            assert!(seen.insert(pseudonym), "{} collides", client);
+            assert_eq!(pseudonyms.reveal(pseudonym), client);
+            unchanged += usize::from(pseudonym == client);
+        }
+        assert!(unchanged < 10);
+
+        let other = Pseudonymizer::new(b"another key for these tests");
+        assert_ne!(other.pseudonym(1), pseudonyms.pseudonym(1));
+        assert_eq!(
+            Pseudonymizer::new(b"a key for these tests").pseudonym(1),
+            pseudonyms.pseudonym(1)
+        );
+        assert!(seen.iter().all(|pseudonym| *pseudonym <= u16::MAX.into()));
+
+        let wide = Pseudonymizer::new(b"a key for these tests").within(64);
+        assert!(wide.pseudonym(1) > u16::MAX.into());
+        assert_eq!(ClientId::MAX, wide.reveal(wide.pseudonym(ClientId::MAX)));
+        assert_ne!(wide, pseudonyms);
+    }
+
+    #[test]
+    fn parsed_rows_only_carry_pseudonyms() {
+        let pseudonyms = Pseudonymizer::new(b"a key for these tests");
+        let options = ParseOptions {
+            mode: ParseMode::Collecting,
+            pseudonyms: Some(pseudonyms.clone()),
+            ..ParseOptions::default()
+        };
+        let input = "\
+type,client,tx,amount
+deposit,1,1,2.0
+deposit,2,2,1.0
+deposit,2,1,5.0
+deposit,3,x,1.0
+";
+        let report =
+            process_transactions_with(&mut csv::Reader::from_reader(input.as_bytes()), &options)
+                .unwrap();
+        let mut clients: Vec<_> = report.accounts.keys().copied().collect();
+        clients.sort_unstable();
+        let mut expected = vec![pseudonyms.pseudonym(1), pseudonyms.pseudonym(2)];
+        expected.sort_unstable();
+        assert_eq!(clients, expected);
+        assert_eq!(report.errors.len(), 2);
+        assert!(report.errors.iter().all(|error| error.record == REDACTED));
+
+        let json = r#"{"type": "deposit", "client": 7, "tx": 1, "amount": "1.5"}"#;
+        let mut source = JsonLinesSource::new(json.as_bytes()).pseudonymize(pseudonyms.clone());
+        let transaction = source.next_transaction().unwrap().unwrap();
+        assert_eq!(transaction.client, pseudonyms.pseudonym(7));
+    }
+}
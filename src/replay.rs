@@ -0,0 +1,335 @@
+//! Pacing a replay of historical transactions, for load tests of the systems downstream:
+//! at a steady rate, or as far apart as they originally happened.
+//!
+//! A [`Pacer`] keeps a schedule from the moment it is created, so a sink that is slow for a
+//! while is caught up with afterwards instead of shifting every later row. How far behind
+//! the schedule the replay is shows in [`Pacer::lag`]. [`replay`] sends the rows of a source
+//! to a [`ReplaySink`] at that pace.
+use crate::engine::release::parse_timestamp;
+use crate::io::source::TransactionSource;
+use crate::io::{RowError, WireFormat};
+use crate::model::Transaction;
+#[cfg(feature = "kafka")]
+use crate::report::kafka::KafkaSink;
+#[cfg(feature = "webhook")]
+use crate::webhook::WebhookClient;
+use std::io::{self, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// When the rows of a replay are sent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pace {
+    /// As fast as the sink takes them
+    Unlimited,
+    /// This many rows per second, evenly spaced. Must be positive.
+    Rate(f64),
+    /// As far apart as their timestamps, `speed` times faster. Rows without a timestamp,
+    /// or with one before the row ahead of them, go right after that row. `speed` must be
+    /// positive.
+    Timestamps { speed: f64 },
+}
+
+/// Tells how long to wait before sending each row of a replay
+#[derive(Debug, Clone)]
+pub struct Pacer {
+    pace: Pace,
+    start: Instant,
+    rows: u64,
+    // Timestamp of the first row that had one, in seconds since the Unix epoch
+    first: Option<i64>,
+    // When the last row was due, from the start
+    due: Duration,
+}
+
+impl Pacer {
+    /// Starts the schedule now
+    pub fn new(pace: Pace) -> Self {
+        Pacer {
+            pace,
+            start: Instant::now(),
+            rows: 0,
+            first: None,
+            due: Duration::ZERO,
+        }
+    }
+
+    /// When the next row is due, from the start of the replay. `timestamp` is the row's time
+    /// in seconds since the Unix epoch, as [`crate::parse_timestamp`] reads it. Rows due
+    /// later than a [`Duration`] holds, at rates or speeds near zero, are due at
+    /// [`Duration::MAX`].
+    pub fn due(&mut self, timestamp: Option<i64>) -> Duration {
+        let due = match self.pace {
+            Pace::Unlimited => Duration::ZERO,
+            Pace::Rate(rate) => seconds(self.rows as f64 / rate),
+            Pace::Timestamps { speed } => match timestamp {
+                Some(timestamp) => {
+                    let first = *self.first.get_or_insert(timestamp);
+                    let apart = timestamp.saturating_sub(first).max(0) as f64 / speed;
+                    seconds(apart).max(self.due)
+                }
+                None => self.due,
+            },
+        };
+        self.rows += 1;
+        self.due = due;
+        due
+    }
+
+    /// How long to wait before sending the next row, `None` if it is due already,
+    /// see [`Pacer::due`]
+    pub fn delay(&mut self, timestamp: Option<i64>) -> Option<Duration> {
+        let due = self.due(timestamp);
+        due.checked_sub(self.start.elapsed())
+            .filter(|delay| !delay.is_zero())
+    }
+
+    /// How far the replay is behind the schedule of the last row, zero with
+    /// [`Pace::Unlimited`]
+    pub fn lag(&self) -> Duration {
+        match self.pace {
+            Pace::Unlimited => Duration::ZERO,
+            _ => self.start.elapsed().saturating_sub(self.due),
+        }
+    }
+
+    /// Rows paced so far
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    /// Time since the start of the replay
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// `seconds` as a [`Duration`], the longest one if it doesn't hold them
+fn seconds(seconds: f64) -> Duration {
+    Duration::try_from_secs_f64(seconds).unwrap_or(Duration::MAX)
+}
+
+/// Where a replay sends rows
+pub enum ReplaySink {
+    /// A file, pipe or socket, one row per line
+    Stream(io::BufWriter<Box<dyn Write>>),
+    /// One POST per row, as JSON whatever the format
+    #[cfg(feature = "webhook")]
+    Webhook(WebhookClient),
+    /// One message per row, keyed by client
+    #[cfg(feature = "kafka")]
+    Kafka(KafkaSink),
+}
+
+impl ReplaySink {
+    pub fn stream(output: impl Write + 'static) -> Self {
+        ReplaySink::Stream(io::BufWriter::new(Box::new(output)))
+    }
+
+    /// Sends one row, `Ok(false)` if a webhook didn't take it
+    pub fn send(&mut self, transaction: &Transaction, format: WireFormat) -> io::Result<bool> {
+        match self {
+            ReplaySink::Stream(output) => {
+                match format {
+                    WireFormat::Csv => writeln!(output, "{}", transaction)?,
+                    WireFormat::Json => {
+                        serde_json::to_writer(&mut *output, transaction)?;
+                        writeln!(output)?;
+                    }
+                }
+                Ok(true)
+            }
+            #[cfg(feature = "webhook")]
+            ReplaySink::Webhook(client) => {
+                let body = serde_json::to_string(transaction)?;
+                match client.post(&body) {
+                    Ok(()) => Ok(true),
+                    Err(err) => {
+                        tracing::warn!(host = client.url().host(), "webhook failed: {}", err);
+                        Ok(false)
+                    }
+                }
+            }
+            #[cfg(feature = "kafka")]
+            ReplaySink::Kafka(sink) => {
+                let payload = match format {
+                    WireFormat::Csv => transaction.to_string().into_bytes(),
+                    WireFormat::Json => serde_json::to_vec(transaction)?,
+                };
+                sink.send(&transaction.client.to_string(), &payload)?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Hands what was written on, before a pause
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ReplaySink::Stream(output) => output.flush(),
+            #[cfg(feature = "webhook")]
+            ReplaySink::Webhook(_) => Ok(()),
+            // librdkafka sends in the background
+            #[cfg(feature = "kafka")]
+            ReplaySink::Kafka(_) => Ok(()),
+        }
+    }
+
+    /// Waits until everything sent was taken, at the end
+    pub fn finish(&mut self) -> io::Result<()> {
+        match self {
+            #[cfg(feature = "kafka")]
+            ReplaySink::Kafka(sink) => sink.flush(),
+            _ => self.flush(),
+        }
+    }
+}
+
+/// How a replay went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplaySummary {
+    /// Rows sent, or handed to a webhook that didn't take them
+    pub rows: u64,
+    /// Rows that couldn't be read
+    pub skipped: u64,
+    /// Rows a webhook didn't take
+    pub failed: u64,
+    pub elapsed: Duration,
+    /// How far behind the schedule of the last row the replay ended, see [`Pacer::lag`]
+    pub lag: Duration,
+}
+
+/// Sends the rows of `source` to `sink` in `format` at `pace`, as far apart as the
+/// `time_column` of their [metadata](TransactionSource::metadata) says with
+/// [`Pace::Timestamps`]. Rows that can't be read are handed to `skipped`.
+pub fn replay(
+    mut source: impl TransactionSource,
+    sink: &mut ReplaySink,
+    format: WireFormat,
+    pace: Pace,
+    time_column: &str,
+    mut skipped: impl FnMut(&RowError),
+) -> io::Result<ReplaySummary> {
+    let mut pacer = Pacer::new(pace);
+    let (mut skipped_rows, mut failed) = (0, 0);
+    while let Some(item) = source.next_transaction() {
+        let transaction = match item {
+            Ok(transaction) => transaction,
+            Err(error) => {
+                skipped(&error);
+                skipped_rows += 1;
+                continue;
+            }
+        };
+        let timestamp = source
+            .metadata()
+            .and_then(|metadata| metadata.get(time_column))
+            .and_then(|timestamp| parse_timestamp(timestamp));
+        if let Some(delay) = pacer.delay(timestamp) {
+            // Rows written so far reach the sink before the pause, not after it
+            sink.flush()?;
+            thread::sleep(delay);
+        }
+        failed += u64::from(!sink.send(&transaction, format)?);
+    }
+    sink.finish()?;
+    Ok(ReplaySummary {
+        rows: pacer.rows(),
+        skipped: skipped_rows,
+        failed,
+        elapsed: pacer.elapsed(),
+        lag: pacer.lag(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::source::JsonLinesSource;
+    use std::sync::{Arc, Mutex};
+
+    /// What a stream sink wrote, kept after the sink is gone
+    #[derive(Clone, Default)]
+    struct Written(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Written {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn replays_send_every_readable_row() {
+        let input = concat!(
+            r#"{"type":"deposit","client":1,"tx":1,"amount":"1.5"}"#,
+            "\n{\n",
+            r#"{"type":"withdrawal","client":1,"tx":2,"amount":"0.5"}"#,
+            "\n"
+        );
+        let written = Written::default();
+        let mut sink = ReplaySink::stream(written.clone());
+        let mut skipped = vec![];
+        let summary = replay(
+            JsonLinesSource::new(input.as_bytes()),
+            &mut sink,
+            WireFormat::Csv,
+            Pace::Rate(1000.0),
+            "timestamp",
+            |error| skipped.push(error.line),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(written.0.lock().unwrap().clone()).unwrap(),
+            "deposit,1,1,1.5\nwithdrawal,1,2,0.5\n"
+        );
+        assert_eq!((summary.rows, summary.skipped, summary.failed), (2, 1, 0));
+        assert_eq!(skipped, [2]);
+        assert!(summary.elapsed >= Duration::from_millis(1));
+    }
+
+    #[test]
+    fn rates_space_rows_evenly() {
+        let mut pacer = Pacer::new(Pace::Rate(4.0));
+        let due: Vec<_> = (0..5).map(|_| pacer.due(None)).collect();
+        assert_eq!(due, [0, 250, 500, 750, 1000].map(Duration::from_millis));
+        assert_eq!(pacer.rows(), 5);
+
+        let mut pacer = Pacer::new(Pace::Unlimited);
+        assert_eq!(pacer.delay(None), None);
+    }
+
+    #[test]
+    fn timestamps_keep_their_distance() {
+        let mut pacer = Pacer::new(Pace::Timestamps { speed: 60.0 });
+        let timestamps = [
+            Some(1_000),
+            Some(1_060),
+            None,
+            Some(1_030),
+            Some(1_180),
+            Some(1_180),
+        ];
+        let due: Vec<_> = timestamps
+            .into_iter()
+            .map(|timestamp| pacer.due(timestamp).as_secs())
+            .collect();
+        // Rows without a time or out of order go with the one before
+        assert_eq!(due, [0, 1, 1, 1, 3, 3]);
+    }
+
+    #[test]
+    fn paces_near_zero_saturate() {
+        let mut pacer = Pacer::new(Pace::Rate(1e-300));
+        assert_eq!(pacer.due(None), Duration::ZERO);
+        assert_eq!(pacer.due(None), Duration::MAX);
+        assert!(pacer.delay(None).is_some());
+
+        let mut pacer = Pacer::new(Pace::Timestamps { speed: 1e-300 });
+        assert_eq!(pacer.due(Some(0)), Duration::ZERO);
+        assert_eq!(pacer.due(Some(1)), Duration::MAX);
+        assert_eq!(pacer.due(None), Duration::MAX);
+    }
+}
@@ -0,0 +1,800 @@
+//! What processing a transactions file produces and how it is written out.
+#[cfg(feature = "csv")]
+use crate::engine::sorted_accounts;
+use crate::engine::state::EngineState;
+use crate::engine::{AccountMap, Engine, TransactionIndex};
+use crate::ids::IdNames;
+use crate::io::RowError;
+use crate::model::{Account, ClientId};
+#[cfg(feature = "csv")]
+use crate::model::{Activity, Status};
+#[cfg(feature = "csv")]
+use rust_decimal::{Decimal, RoundingStrategy};
+use std::collections::BTreeMap;
+#[cfg(feature = "csv")]
+use std::fmt::Write as _;
+#[cfg(feature = "csv")]
+use std::io;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+#[cfg(feature = "arrow")]
+pub mod arrow;
+pub mod audit;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod ledger;
+pub mod periods;
+#[cfg(feature = "polars")]
+pub mod polars;
+#[cfg(feature = "redis")]
+pub mod redis;
+pub mod sink;
+
+/// Result of processing a transactions file
+#[derive(Debug, Default)]
+pub struct ProcessReport {
+    pub accounts: AccountMap,
+    /// Rows skipped because they failed to parse or validate, only filled in [`crate::ParseMode::Collecting`]
+    pub errors: Vec<RowError>,
+    /// Number of rows skipped per unknown transaction type, only filled with [`crate::UnknownTypes::Skip`]
+    pub unknown_types: BTreeMap<String, u64>,
+    /// Deposits and withdrawals by tx id with their dispute state, see [`ProcessReport::into_state`]
+    pub transactions: TransactionIndex,
+    /// Disputes, resolves, chargebacks and reversals of transactions the engine had evicted,
+    /// and the rows it dropped while they waited, see [`crate::engine::eviction`]
+    pub lost_to_eviction: u64,
+}
+
+impl ProcessReport {
+    /// The accounts and transactions, to save them or continue processing from them
+    /// with [`Engine::restore`]
+    pub fn into_state(self) -> EngineState {
+        EngineState {
+            accounts: self.accounts,
+            transactions: self.transactions,
+            ..EngineState::default()
+        }
+    }
+
+    /// Hash of the accounts, see [`crate::engine::state::digest`]
+    pub fn state_digest(&self) -> u128 {
+        crate::engine::state::digest(&self.accounts)
+    }
+}
+
+/// A report of everything `engine` applied, without errors
+/// The end of a stream: rows waiting in the engine get a last try with [`Engine::retry_waiting`]
+impl From<Engine> for ProcessReport {
+    fn from(mut engine: Engine) -> Self {
+        engine.retry_waiting();
+        let lost_to_eviction = engine.lost_to_eviction();
+        let EngineState {
+            accounts,
+            transactions,
+            ..
+        } = engine.into_state();
+        ProcessReport {
+            accounts,
+            transactions,
+            lost_to_eviction,
+            ..ProcessReport::default()
+        }
+    }
+}
+
+/// Extra `disputed` output column listing the transactions under dispute, see [`Account::disputed`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisputedColumn {
+    /// How many transactions are under dispute
+    Count,
+    /// Their tx ids joined with `;`, e.g. `3;7`
+    List,
+}
+
+/// Header names of the accounts output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputSchema {
+    /// `client,available,held,locked,balance`, the names this crate has always written
+    #[default]
+    V1,
+    /// `client,available,held,total,locked`, the names and order of the specification
+    V2,
+}
+
+/// A column of the accounts output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    Client,
+    Available,
+    Held,
+    Locked,
+    /// `balance` in [`OutputSchema::V1`], `total` in [`OutputSchema::V2`]
+    Total,
+    Deposits,
+    Withdrawals,
+    Deposited,
+    Withdrawn,
+    Disputed,
+    /// The [`crate::Status`] of the account
+    Status,
+}
+
+impl Column {
+    const ALL: [Column; 11] = [
+        Column::Client,
+        Column::Available,
+        Column::Held,
+        Column::Locked,
+        Column::Total,
+        Column::Deposits,
+        Column::Withdrawals,
+        Column::Deposited,
+        Column::Withdrawn,
+        Column::Disputed,
+        Column::Status,
+    ];
+
+    /// Header of the column in `schema`
+    pub fn name(self, schema: OutputSchema) -> &'static str {
+        match self {
+            Column::Client => "client",
+            Column::Available => "available",
+            Column::Held => "held",
+            Column::Locked => "locked",
+            Column::Status => "status",
+            Column::Total => match schema {
+                OutputSchema::V1 => "balance",
+                OutputSchema::V2 => "total",
+            },
+            Column::Deposits => "deposits",
+            Column::Withdrawals => "withdrawals",
+            Column::Deposited => "deposited",
+            Column::Withdrawn => "withdrawn",
+            Column::Disputed => "disputed",
+        }
+    }
+
+    /// Appends the value of the column for `account` to `out`
+    #[cfg(feature = "csv")]
+    fn write_value(self, account: &Account, options: &OutputOptions, out: &mut String) {
+        let activity = account.activity();
+        // Writing to a String can't fail
+        let _ = match self {
+            Column::Client => match options
+                .client_names
+                .as_ref()
+                .and_then(|names| names.name(account.client))
+            {
+                Some(name) => out.write_str(&name),
+                None => write!(out, "{}", account.client),
+            },
+            Column::Available => options.write_amount(account.available, out),
+            Column::Held => options.write_amount(account.held, out),
+            Column::Locked => write!(out, "{}", account.locked()),
+            Column::Status => write!(out, "{}", account.status),
+            Column::Total => options.write_amount(account.total(), out),
+            Column::Deposits => write!(out, "{}", activity.deposits),
+            Column::Withdrawals => write!(out, "{}", activity.withdrawals),
+            Column::Deposited => options.write_amount(activity.deposited, out),
+            Column::Withdrawn => options.write_amount(activity.withdrawn, out),
+            Column::Disputed => match options.disputed.unwrap_or(DisputedColumn::List) {
+                DisputedColumn::Count => write!(out, "{}", account.disputed().count()),
+                DisputedColumn::List => account.disputed().enumerate().try_for_each(|(i, tx)| {
+                    let separator = if i == 0 { "" } else { ";" };
+                    match options.tx_names.as_ref().and_then(|names| names.name(tx)) {
+                        Some(name) => write!(out, "{}{}", separator, name),
+                        None => write!(out, "{}{}", separator, tx),
+                    }
+                }),
+            },
+        };
+    }
+}
+
+/// Accepts the header of the column in any schema, e.g. `balance` or `total`
+impl FromStr for Column {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Column::ALL
+            .into_iter()
+            .find(|column| column.name(OutputSchema::V1) == s || column.name(OutputSchema::V2) == s)
+            .ok_or_else(|| format!("unknown column `{}`", s))
+    }
+}
+
+/// Columns of the accounts output, the ones of [`OutputSchema::V1`] by default
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputOptions {
+    /// Write amounts with exactly this many decimal places, rounding half away from zero.
+    /// `None` writes them as computed, e.g. `1` next to `1.50`. A decimal has 28 places at
+    /// most, more are written as 28, and an amount too large for as many places next to its
+    /// integer digits, near [`Decimal::MAX`], keeps only the places that fit.
+    pub scale: Option<u32>,
+    pub schema: OutputSchema,
+    /// Add `deposits`, `withdrawals`, `deposited` and `withdrawn` columns, see [`Account::activity`]
+    pub extended: bool,
+    pub disputed: Option<DisputedColumn>,
+    /// Add a `status` column, see [`Account::status`]
+    pub status: bool,
+    /// Write exactly these columns in this order instead,
+    /// a selected `disputed` column lists the tx ids unless `disputed` says otherwise
+    pub columns: Option<Vec<Column>>,
+    /// Leave out these columns
+    pub omit: Vec<Column>,
+    /// Only write locked accounts
+    pub only_locked: bool,
+    /// Leave out accounts without available or held funds
+    pub skip_zero_balances: bool,
+    /// Only write accounts of clients in one of these ranges, all if empty
+    pub clients: Vec<RangeInclusive<ClientId>>,
+    /// Bytes of CSV buffered before they are written to the output, 64 KiB by default
+    pub buffer_size: usize,
+    /// Write the client column as the text ids the clients were read from,
+    /// see [`crate::IdFormat::Text`]. Clients without a name are written as numbers.
+    pub client_names: Option<IdNames>,
+    /// Write the tx ids of the `disputed` column as the text ids they were read from
+    pub tx_names: Option<IdNames>,
+}
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        OutputOptions {
+            scale: Some(4),
+            schema: OutputSchema::default(),
+            extended: false,
+            disputed: None,
+            status: false,
+            columns: None,
+            omit: vec![],
+            only_locked: false,
+            skip_zero_balances: false,
+            clients: vec![],
+            buffer_size: 64 * 1024,
+            client_names: None,
+            tx_names: None,
+        }
+    }
+}
+
+impl OutputOptions {
+    /// Whether `account` passes the filters
+    pub fn includes(&self, account: &Account) -> bool {
+        (!self.only_locked || account.locked())
+            && !(self.skip_zero_balances && account.available.is_zero() && account.held.is_zero())
+            && (self.clients.is_empty()
+                || self
+                    .clients
+                    .iter()
+                    .any(|clients| clients.contains(&account.client)))
+    }
+
+    #[cfg(feature = "csv")]
+    fn write_amount(&self, amount: Decimal, out: &mut String) -> std::fmt::Result {
+        match self.scale {
+            Some(scale) => {
+                let mut amount =
+                    amount.round_dp_with_strategy(scale, RoundingStrategy::MidpointAwayFromZero);
+                amount.rescale(scale);
+                write!(out, "{}", amount)
+            }
+            None => write!(out, "{}", amount),
+        }
+    }
+
+    /// The columns to write, in order
+    pub fn columns(&self) -> Vec<Column> {
+        let columns = match &self.columns {
+            Some(columns) => columns.clone(),
+            None => {
+                let mut columns = match self.schema {
+                    OutputSchema::V1 => vec![
+                        Column::Client,
+                        Column::Available,
+                        Column::Held,
+                        Column::Locked,
+                        Column::Total,
+                    ],
+                    OutputSchema::V2 => vec![
+                        Column::Client,
+                        Column::Available,
+                        Column::Held,
+                        Column::Total,
+                        Column::Locked,
+                    ],
+                };
+                if self.extended {
+                    columns.extend([
+                        Column::Deposits,
+                        Column::Withdrawals,
+                        Column::Deposited,
+                        Column::Withdrawn,
+                    ]);
+                }
+                if self.disputed.is_some() {
+                    columns.push(Column::Disputed);
+                }
+                if self.status {
+                    columns.push(Column::Status);
+                }
+                columns
+            }
+        };
+        columns
+            .into_iter()
+            .filter(|column| !self.omit.contains(column))
+            .collect()
+    }
+}
+
+/// Outputs accounts to stdout
+/// Accounts are written ordered by client id so the output is reproducible
+#[cfg(feature = "csv")]
+#[deprecated(note = "panics if stdout can't be written to, use `write_accounts` instead")]
+pub fn write_stdout(accounts: &AccountMap) {
+    write_accounts(accounts, io::stdout()).unwrap();
+}
+
+/// Writes `accounts` as CSV to `output`, ordered by client id so the output is reproducible
+#[cfg(feature = "csv")]
+pub fn write_accounts<W: io::Write>(accounts: &AccountMap, output: W) -> csv::Result<()> {
+    write_accounts_with(accounts, output, &OutputOptions::default())
+}
+
+/// Same as [`write_accounts`] with the columns and filters of `options`
+#[cfg(feature = "csv")]
+pub fn write_accounts_with<W: io::Write>(
+    accounts: &AccountMap,
+    output: W,
+    options: &OutputOptions,
+) -> csv::Result<()> {
+    let mut writer = AccountWriter::new(output, options);
+    writer.write_header(options.schema)?;
+    for account in sorted_accounts(accounts) {
+        if !options.includes(account) {
+            continue;
+        }
+        writer.write(account, options)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes accounts as CSV rows through a buffer of [`OutputOptions::buffer_size`] bytes,
+/// formatting every row into the same record instead of allocating each field.
+/// Nothing is written to the output before the buffer is full or flushed.
+#[cfg(feature = "csv")]
+pub(crate) struct AccountWriter<W: io::Write> {
+    writer: csv::Writer<W>,
+    columns: Vec<Column>,
+    record: csv::ByteRecord,
+    field: String,
+}
+
+#[cfg(feature = "csv")]
+impl<W: io::Write> AccountWriter<W> {
+    pub(crate) fn new(output: W, options: &OutputOptions) -> Self {
+        AccountWriter {
+            writer: csv::WriterBuilder::new()
+                .buffer_capacity(options.buffer_size.max(1))
+                .from_writer(output),
+            columns: options.columns(),
+            record: csv::ByteRecord::new(),
+            field: String::new(),
+        }
+    }
+
+    pub(crate) fn write_header(&mut self, schema: OutputSchema) -> csv::Result<()> {
+        self.writer
+            .write_record(self.columns.iter().map(|column| column.name(schema)))
+    }
+
+    pub(crate) fn write(&mut self, account: &Account, options: &OutputOptions) -> csv::Result<()> {
+        self.record.clear();
+        for column in &self.columns {
+            self.field.clear();
+            column.write_value(account, options, &mut self.field);
+            self.record.push_field(self.field.as_bytes());
+        }
+        self.writer.write_byte_record(&self.record)
+    }
+
+    /// Writes out the buffer, failing if the output can't take it
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Flushes the buffer and hands the output back
+    #[cfg(test)]
+    pub(crate) fn into_inner(self) -> io::Result<W> {
+        self.writer.into_inner().map_err(|err| err.into_error())
+    }
+}
+
+/// Reads back accounts written by [`write_accounts_with`] in any [`OutputSchema`], e.g.
+/// yesterday's output to start today's run from. The client, available, held and status
+/// or locked columns are required, a locked account without a status is taken as
+/// [`Status::Frozen`]. A total has to be available plus held, give or take the rounding
+/// of its last decimal place, so a truncated or edited file isn't taken for balances, and
+/// available plus held has to fit a `Decimal`.
+/// The activity columns of [`OutputOptions::extended`] are read back when present,
+/// the disputed column is ignored: the accounts have nothing under dispute.
+#[cfg(feature = "csv")]
+pub fn read_accounts<R: io::Read>(reader: &mut csv::Reader<R>) -> io::Result<AccountMap> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let headers = reader.headers().map_err(io::Error::from)?.clone();
+    let index = |column: Column| {
+        headers
+            .iter()
+            .position(|header| Column::from_str(header.trim()) == Ok(column))
+            .ok_or_else(|| invalid(format!("no {} column", column.name(OutputSchema::V2))))
+    };
+    let (client, available, held) = (
+        index(Column::Client)?,
+        index(Column::Available)?,
+        index(Column::Held)?,
+    );
+    // The status says more than the locked flag
+    let status = match index(Column::Status) {
+        Ok(status) => Ok(Ok(status)),
+        Err(_) => index(Column::Locked).map(Err),
+    }?;
+    let total = index(Column::Total).ok();
+    let counts = [Column::Deposits, Column::Withdrawals].map(|column| index(column).ok());
+    let volumes = [Column::Deposited, Column::Withdrawn].map(|column| index(column).ok());
+    let mut accounts = AccountMap::default();
+    let mut record = csv::StringRecord::new();
+    while reader.read_record(&mut record).map_err(io::Error::from)? {
+        let line = record.position().map_or(0, |position| position.line());
+        let field = |index: usize| record.get(index).unwrap_or_default().trim();
+        let parsed = (
+            ClientId::from_str(field(client)),
+            Decimal::from_str(field(available)),
+            Decimal::from_str(field(held)),
+            match status {
+                Ok(status) => Status::from_str(field(status)).ok(),
+                Err(locked) => bool::from_str(field(locked))
+                    .ok()
+                    .map(|locked| match locked {
+                        true => Status::Frozen,
+                        false => Status::Active,
+                    }),
+            },
+        );
+        let (Ok(client), Ok(available), Ok(held), Some(status)) = parsed else {
+            return Err(invalid(format!("line {}: not an account", line)));
+        };
+        // Balances no total can be written for aren't an account either
+        let sum = available.checked_add(held).ok_or_else(|| {
+            invalid(format!(
+                "line {}: available {} plus held {} overflows",
+                line, available, held
+            ))
+        })?;
+        if let Some(total) = total {
+            let total = Decimal::from_str(field(total))
+                .map_err(|_| invalid(format!("line {}: not an account", line)))?;
+            let off = sum.checked_sub(total).map(|difference| difference.abs());
+            if off.is_none_or(|off| off > Decimal::new(1, total.scale())) {
+                return Err(invalid(format!(
+                    "line {}: total {} isn't available plus held",
+                    line, total
+                )));
+            }
+        }
+        let count = |index: Option<usize>| index.map_or(Ok(0), |index| field(index).parse());
+        let volume = |index: Option<usize>| {
+            index.map_or(Ok(Decimal::ZERO), |index| Decimal::from_str(field(index)))
+        };
+        let activity = match (count(counts[0]), count(counts[1])) {
+            (Ok(deposits), Ok(withdrawals)) => match (volume(volumes[0]), volume(volumes[1])) {
+                (Ok(deposited), Ok(withdrawn)) => Some(Activity {
+                    deposits,
+                    withdrawals,
+                    deposited,
+                    withdrawn,
+                }),
+                _ => None,
+            },
+            _ => None,
+        }
+        .ok_or_else(|| invalid(format!("line {}: not an account", line)))?;
+        let mut account = Account::new(client);
+        account.available = available;
+        account.held = held;
+        account.status = status;
+        account.set_activity(activity);
+        accounts.insert(client, account);
+    }
+    Ok(accounts)
+}
+
+#[cfg(all(test, feature = "csv"))]
+mod tests {
+    use crate::io::csv::process_transactions;
+    use crate::model::Status;
+    use crate::report::{
+        read_accounts, write_accounts_with, Column, DisputedColumn, OutputOptions, OutputSchema,
+    };
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn disputed_column_lists_transactions_under_dispute() {
+        let data = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,2,2.0
+deposit,1,3,3.0
+dispute,1,3,
+dispute,1,1,
+dispute,1,2,
+resolve,1,2,
+deposit,2,4,1.0";
+        let accounts = process_transactions(&mut csv::Reader::from_reader(data.as_bytes()));
+        assert_eq!(accounts[&1].disputed().collect::<Vec<_>>(), [1, 3]);
+
+        let write = |disputed| {
+            let options = OutputOptions {
+                disputed,
+                ..OutputOptions::default()
+            };
+            let mut output = vec![];
+            write_accounts_with(&accounts, &mut output, &options).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(
+            write(Some(DisputedColumn::List)),
+            "client,available,held,locked,balance,disputed
+1,2.0000,4.0000,false,6.0000,1;3
+2,1.0000,0.0000,false,1.0000,
+"
+        );
+        assert!(write(Some(DisputedColumn::Count))
+            .ends_with(",6.0000,2\n2,1.0000,0.0000,false,1.0000,0\n"));
+        assert!(write(None).starts_with("client,available,held,locked,balance\n"));
+    }
+
+    #[test]
+    fn extended_columns_count_deposits_and_withdrawals() {
+        let data = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,2,2.5
+withdrawal,1,3,0.5
+dispute,1,1,";
+        let accounts = process_transactions(&mut csv::Reader::from_reader(data.as_bytes()));
+        let options = OutputOptions {
+            extended: true,
+            ..OutputOptions::default()
+        };
+        let mut output = vec![];
+        write_accounts_with(&accounts, &mut output, &options).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,locked,balance,deposits,withdrawals,deposited,withdrawn
+1,2.0000,1.0000,false,3.0000,2,1,3.5000,0.5000
+"
+        );
+    }
+
+    #[test]
+    fn schema_and_column_selection() {
+        let data = "type,client,tx,amount
+deposit,1,1,1.0";
+        let accounts = process_transactions(&mut csv::Reader::from_reader(data.as_bytes()));
+        let write = |options: OutputOptions| {
+            let mut output = vec![];
+            write_accounts_with(&accounts, &mut output, &options).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(
+            write(OutputOptions {
+                schema: OutputSchema::V2,
+                ..OutputOptions::default()
+            }),
+            "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n"
+        );
+        assert_eq!(
+            write(OutputOptions {
+                schema: OutputSchema::V2,
+                columns: Some(vec![Column::Total, Column::Client, Column::Deposits]),
+                omit: vec![Column::Client],
+                ..OutputOptions::default()
+            }),
+            "total,deposits\n1.0000,1\n"
+        );
+        assert_eq!("balance".parse(), Ok(Column::Total));
+        assert!("teleports".parse::<Column>().is_err());
+    }
+
+    #[test]
+    fn amounts_are_written_with_a_fixed_scale() {
+        let data = "type,client,tx,amount
+deposit,1,1,1
+deposit,1,2,0.125";
+        let accounts = process_transactions(&mut csv::Reader::from_reader(data.as_bytes()));
+        let write = |scale| {
+            let options = OutputOptions {
+                scale,
+                columns: Some(vec![Column::Available, Column::Held]),
+                ..OutputOptions::default()
+            };
+            let mut output = vec![];
+            write_accounts_with(&accounts, &mut output, &options).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(write(Some(2)), "available,held\n1.13,0.00\n");
+        assert_eq!(write(None), "available,held\n1.125,0\n");
+        assert_eq!(
+            write(Some(40)),
+            format!("available,held\n1.{:0<28},0.{:0<28}\n", "125", "")
+        );
+
+        let options = OutputOptions {
+            scale: Some(4),
+            ..OutputOptions::default()
+        };
+        let mut written = String::new();
+        options.write_amount(Decimal::MAX, &mut written).unwrap();
+        assert_eq!(written, Decimal::MAX.to_string());
+    }
+
+    #[test]
+    fn accounts_can_be_filtered() {
+        let data = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,1.0
+withdrawal,2,3,1.0
+deposit,3,4,1.0
+dispute,3,4,
+chargeback,3,4,
+deposit,4,5,1.0
+deposit,150,6,1.0";
+        let accounts = process_transactions(&mut csv::Reader::from_reader(data.as_bytes()));
+        let clients = |options: OutputOptions| -> Vec<String> {
+            let options = OutputOptions {
+                columns: Some(vec![Column::Client]),
+                ..options
+            };
+            let mut output = vec![];
+            write_accounts_with(&accounts, &mut output, &options).unwrap();
+            String::from_utf8(output)
+                .unwrap()
+                .lines()
+                .skip(1)
+                .map(str::to_string)
+                .collect()
+        };
+        assert_eq!(
+            clients(OutputOptions {
+                only_locked: true,
+                ..OutputOptions::default()
+            }),
+            ["3"]
+        );
+        assert_eq!(
+            clients(OutputOptions {
+                skip_zero_balances: true,
+                ..OutputOptions::default()
+            }),
+            ["1", "4", "150"]
+        );
+        assert_eq!(
+            clients(OutputOptions {
+                clients: vec![2..=3, 100..=200],
+                ..OutputOptions::default()
+            }),
+            ["2", "3", "150"]
+        );
+    }
+
+    #[test]
+    fn written_accounts_read_back() {
+        let data = "type,client,tx,amount
+deposit,1,1,1.5
+deposit,2,2,2.0
+dispute,2,2,
+chargeback,2,2,";
+        let accounts = process_transactions(&mut csv::Reader::from_reader(data.as_bytes()));
+        for schema in [OutputSchema::V1, OutputSchema::V2] {
+            let options = OutputOptions {
+                schema,
+                ..OutputOptions::default()
+            };
+            let mut output = vec![];
+            write_accounts_with(&accounts, &mut output, &options).unwrap();
+            let read = read_accounts(&mut csv::Reader::from_reader(&output[..])).unwrap();
+            assert_eq!(read.len(), 2);
+            assert_eq!(read[&1].available, accounts[&1].available);
+            assert_eq!(read[&2].held, accounts[&2].held);
+            assert!(read[&2].locked());
+        }
+        let options = OutputOptions {
+            status: true,
+            omit: vec![Column::Locked],
+            ..OutputOptions::default()
+        };
+        let mut output = vec![];
+        write_accounts_with(&accounts, &mut output, &options).unwrap();
+        assert_eq!(
+            String::from_utf8(output.clone()).unwrap(),
+            "client,available,held,balance,status
+1,1.5000,0.0000,1.5000,active
+2,0.0000,0.0000,0.0000,frozen
+"
+        );
+        let read = read_accounts(&mut csv::Reader::from_reader(&output[..])).unwrap();
+        assert_eq!(read[&2].status, Status::Frozen);
+        let missing = "client,available,held\n1,1,0\n";
+        assert!(read_accounts(&mut csv::Reader::from_reader(missing.as_bytes())).is_err());
+
+        let options = OutputOptions {
+            extended: true,
+            scale: Some(2),
+            ..OutputOptions::default()
+        };
+        let mut output = vec![];
+        write_accounts_with(&accounts, &mut output, &options).unwrap();
+        let read = read_accounts(&mut csv::Reader::from_reader(&output[..])).unwrap();
+        assert_eq!(read[&1].activity(), accounts[&1].activity());
+        assert_eq!(read[&2].activity().deposits, 1);
+
+        let rounded = "client,available,held,locked,balance\n1,0.005,0.005,false,0.01\n";
+        assert!(read_accounts(&mut csv::Reader::from_reader(rounded.as_bytes())).is_ok());
+        let edited = "client,available,held,locked,balance\n1,1.5,0,false,2.5\n";
+        let err = read_accounts(&mut csv::Reader::from_reader(edited.as_bytes())).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2: total 2.5 isn't available plus held"
+        );
+
+        let max = Decimal::MAX;
+        let overflowing = format!("client,available,held,locked\n1,{max},{max},false\n");
+        let err = read_accounts(&mut csv::Reader::from_reader(overflowing.as_bytes())).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("line 2: available "), "{err}");
+        let far_off = format!("client,available,held,locked,balance\n1,{max},0,false,-{max}\n");
+        let err = read_accounts(&mut csv::Reader::from_reader(far_off.as_bytes())).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("line 2: total -{max} isn't available plus held")
+        );
+    }
+
+    #[test]
+    fn output_is_buffered_until_flushed() {
+        struct Full;
+        impl std::io::Write for Full {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::StorageFull,
+                    "disk full",
+                ))
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let data = "type,client,tx,amount
+deposit,1,1,1.5
+deposit,2,2,2
+dispute,2,2,";
+        let accounts = process_transactions(&mut csv::Reader::from_reader(data.as_bytes()));
+        let write = |buffer_size| {
+            let options = OutputOptions {
+                buffer_size,
+                disputed: Some(DisputedColumn::List),
+                ..OutputOptions::default()
+            };
+            let mut output = vec![];
+            write_accounts_with(&accounts, &mut output, &options).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(write(1), write(64 * 1024));
+        assert!(write(0).ends_with("2,0.0000,2.0000,false,2.0000,2\n"));
+
+        // The whole output fits the buffer, the final flush reports the failure
+        let err = write_accounts_with(&accounts, Full, &OutputOptions::default()).unwrap_err();
+        assert!(err.to_string().contains("disk full"));
+    }
+}
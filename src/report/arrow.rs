@@ -0,0 +1,278 @@
+//! Accounts and account updates as Arrow record batches, for analytics tooling.
+//!
+//! Amounts are `Decimal128(38, 4)`, rounded half away from zero to four decimal places
+//! like the CSV output, client and tx ids `UInt64` whatever their width in the build.
+//! [`write_accounts_ipc`] and [`UpdateLogWriter`] write Arrow IPC files, which pyarrow,
+//! polars or DuckDB map without parsing.
+use crate::engine::minor::{rounded_minor_units as amount, SCALE};
+use crate::engine::{sorted_accounts, AccountMap};
+use crate::model::AccountUpdate;
+use arrow_array::builder::{
+    ArrayBuilder, BooleanBuilder, Decimal128Builder, StringBuilder, UInt64Builder,
+};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_ipc::writer::FileWriter;
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef};
+use std::io::Write;
+use std::sync::Arc;
+
+/// Rows of the update log written at once by [`UpdateLogWriter`]
+const BATCH_ROWS: usize = 64 * 1024;
+
+fn amount_type() -> DataType {
+    DataType::Decimal128(38, SCALE as i8)
+}
+
+/// Client and tx ids, whatever the width of [`crate::ClientId`] and [`crate::TxId`]
+fn id(id: impl Into<u64>) -> u64 {
+    id.into()
+}
+
+fn amounts() -> Decimal128Builder {
+    Decimal128Builder::new().with_data_type(amount_type())
+}
+
+/// `client, available, held, total, locked, status, deposits, withdrawals, deposited, withdrawn`
+pub fn accounts_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("client", DataType::UInt64, false),
+        Field::new("available", amount_type(), false),
+        Field::new("held", amount_type(), false),
+        Field::new("total", amount_type(), false),
+        Field::new("locked", DataType::Boolean, false),
+        Field::new("status", DataType::Utf8, false),
+        Field::new("deposits", DataType::UInt64, false),
+        Field::new("withdrawals", DataType::UInt64, false),
+        Field::new("deposited", amount_type(), false),
+        Field::new("withdrawn", amount_type(), false),
+    ]))
+}
+
+/// The accounts ordered by client id, one row each
+pub fn accounts_batch(accounts: &AccountMap) -> Result<RecordBatch, ArrowError> {
+    let mut client = UInt64Builder::new();
+    let (mut available, mut held, mut total) = (amounts(), amounts(), amounts());
+    let mut locked = BooleanBuilder::new();
+    let mut status = StringBuilder::new();
+    let (mut deposits, mut withdrawals) = (UInt64Builder::new(), UInt64Builder::new());
+    let (mut deposited, mut withdrawn) = (amounts(), amounts());
+    for account in sorted_accounts(accounts) {
+        let activity = account.activity();
+        client.append_value(id(account.client));
+        available.append_value(amount(account.available));
+        held.append_value(amount(account.held));
+        total.append_value(amount(account.total()));
+        locked.append_value(account.locked());
+        status.append_value(account.status.name());
+        deposits.append_value(activity.deposits);
+        withdrawals.append_value(activity.withdrawals);
+        deposited.append_value(amount(activity.deposited));
+        withdrawn.append_value(amount(activity.withdrawn));
+    }
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(client.finish()),
+        Arc::new(available.finish()),
+        Arc::new(held.finish()),
+        Arc::new(total.finish()),
+        Arc::new(locked.finish()),
+        Arc::new(status.finish()),
+        Arc::new(deposits.finish()),
+        Arc::new(withdrawals.finish()),
+        Arc::new(deposited.finish()),
+        Arc::new(withdrawn.finish()),
+    ];
+    RecordBatch::try_new(accounts_schema(), columns)
+}
+
+/// Writes the accounts as an Arrow IPC file of one batch
+pub fn write_accounts_ipc(accounts: &AccountMap, writer: impl Write) -> Result<(), ArrowError> {
+    let mut writer = FileWriter::try_new(writer, &accounts_schema())?;
+    writer.write(&accounts_batch(accounts)?)?;
+    writer.finish()
+}
+
+/// `client, tx, type, amount, available, held, locked, status`, the fields of an update
+pub fn updates_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("client", DataType::UInt64, false),
+        Field::new("tx", DataType::UInt64, false),
+        Field::new("type", DataType::Utf8, false),
+        Field::new("amount", amount_type(), false),
+        Field::new("available", amount_type(), false),
+        Field::new("held", amount_type(), false),
+        Field::new("locked", DataType::Boolean, false),
+        Field::new("status", DataType::Utf8, false),
+    ]))
+}
+
+/// The log of applied transactions, [`AccountUpdate`]s collected into a record batch
+pub struct UpdateLog {
+    client: UInt64Builder,
+    tx: UInt64Builder,
+    transaction_type: StringBuilder,
+    amount: Decimal128Builder,
+    available: Decimal128Builder,
+    held: Decimal128Builder,
+    locked: BooleanBuilder,
+    status: StringBuilder,
+}
+
+impl Default for UpdateLog {
+    fn default() -> Self {
+        UpdateLog {
+            client: UInt64Builder::new(),
+            tx: UInt64Builder::new(),
+            transaction_type: StringBuilder::new(),
+            amount: amounts(),
+            available: amounts(),
+            held: amounts(),
+            locked: BooleanBuilder::new(),
+            status: StringBuilder::new(),
+        }
+    }
+}
+
+impl UpdateLog {
+    pub fn new() -> Self {
+        UpdateLog::default()
+    }
+
+    pub fn push(&mut self, update: &AccountUpdate) {
+        self.client.append_value(id(update.client));
+        self.tx.append_value(id(update.tx));
+        self.transaction_type
+            .append_value(update.transaction_type.name());
+        self.amount.append_value(amount(update.amount));
+        self.available.append_value(amount(update.available));
+        self.held.append_value(amount(update.held));
+        self.locked.append_value(update.status.locked());
+        self.status.append_value(update.status.name());
+    }
+
+    /// Updates pushed since the last [`UpdateLog::finish`]
+    pub fn len(&self) -> usize {
+        self.client.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The updates pushed so far in their order, leaving the log empty
+    pub fn finish(&mut self) -> Result<RecordBatch, ArrowError> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.client.finish()),
+            Arc::new(self.tx.finish()),
+            Arc::new(self.transaction_type.finish()),
+            Arc::new(self.amount.finish()),
+            Arc::new(self.available.finish()),
+            Arc::new(self.held.finish()),
+            Arc::new(self.locked.finish()),
+            Arc::new(self.status.finish()),
+        ];
+        RecordBatch::try_new(updates_schema(), columns)
+    }
+}
+
+/// Writes updates to an Arrow IPC file as they come, in batches of 65536
+pub struct UpdateLogWriter<W: Write> {
+    writer: FileWriter<W>,
+    log: UpdateLog,
+}
+
+impl<W: Write> UpdateLogWriter<W> {
+    /// Writes the schema to `writer`
+    pub fn new(writer: W) -> Result<Self, ArrowError> {
+        Ok(UpdateLogWriter {
+            writer: FileWriter::try_new(writer, &updates_schema())?,
+            log: UpdateLog::new(),
+        })
+    }
+
+    pub fn write(&mut self, update: &AccountUpdate) -> Result<(), ArrowError> {
+        self.log.push(update);
+        if self.log.len() == BATCH_ROWS {
+            self.writer.write(&self.log.finish()?)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the last batch and the footer, which readers need to open the file
+    pub fn finish(mut self) -> Result<W, ArrowError> {
+        if !self.log.is_empty() {
+            self.writer.write(&self.log.finish()?)?;
+        }
+        self.writer.finish()?;
+        self.writer.into_inner()
+    }
+}
+
+#[cfg(all(test, feature = "csv"))]
+mod tests {
+    use super::*;
+    use crate::io::csv::{process_transactions_with_updates, ParseOptions};
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Decimal128Type, UInt64Type};
+    use arrow_ipc::reader::FileReader;
+    use std::io::Cursor;
+
+    const INPUT: &str = "\
+type,client,tx,amount
+deposit,1,1,1.00005
+deposit,2,2,2.0
+dispute,2,2,
+chargeback,2,2,
+withdrawal,1,3,0.5
+";
+
+    #[test]
+    fn accounts_and_updates_read_back_from_ipc() {
+        let mut updates = vec![];
+        let accounts = process_transactions_with_updates(
+            &mut csv::Reader::from_reader(INPUT.as_bytes()),
+            &ParseOptions::default(),
+            |update| updates.push(update),
+        )
+        .unwrap()
+        .accounts;
+        let mut file = vec![];
+        write_accounts_ipc(&accounts, &mut file).unwrap();
+        let batches: Vec<_> = FileReader::try_new(Cursor::new(file), None)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        assert_eq!(batch.schema(), accounts_schema());
+        let clients = batch.column(0).as_primitive::<UInt64Type>();
+        assert_eq!(clients.values(), &[1, 2]);
+        let available = batch.column(1).as_primitive::<Decimal128Type>();
+        assert_eq!(available.value_as_string(0), "0.5001");
+        // The chargeback takes the held 2.0, leaving nothing available
+        assert_eq!(available.value_as_string(1), "0.0000");
+        assert_eq!(batch.column(5).as_string::<i32>().value(1), "frozen");
+
+        let mut writer = UpdateLogWriter::new(vec![]).unwrap();
+        for update in &updates {
+            writer.write(update).unwrap();
+        }
+        let file = writer.finish().unwrap();
+        let batch = FileReader::try_new(Cursor::new(file), None)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(batch.num_rows(), 5);
+        let types: Vec<_> = batch
+            .column(2)
+            .as_string::<i32>()
+            .iter()
+            .flatten()
+            .collect();
+        assert_eq!(
+            types,
+            ["deposit", "deposit", "dispute", "chargeback", "withdrawal"]
+        );
+        assert!(batch.column(6).as_boolean().value(3));
+    }
+}
@@ -0,0 +1,277 @@
+//! A tamper-evident log of every account update, for settlement records.
+//!
+//! Every record holds the SHA-256 of the one before it, `prev`, and its own `hash` over
+//! everything in its line but the hash, so changing, inserting or removing a record breaks
+//! the chain from there on, see [`verify_audit_log`]. Records are only ever appended, across
+//! runs too with [`AuditLog::resume`]. Dropping records off the end leaves a valid, shorter
+//! chain: keep the [`AuditHead`] of every run somewhere else to tell.
+//!
+//! Lines read `seq,client,tx,type,amount,available,held,locked,status,prev,hash` with amounts
+//! as exact as the engine keeps them, after a header line of these names.
+use crate::model::AccountUpdate;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+
+/// The first line of a log
+pub const AUDIT_HEADER: &str = "seq,client,tx,type,amount,available,held,locked,status,prev,hash";
+
+/// Longest line [`last_record`] reads back, a record takes about 250 bytes
+const MAX_LINE: u64 = 4096;
+
+/// The last record of a log, or the start of an empty one with `seq` 0 and a zero hash
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AuditHead {
+    pub seq: u64,
+    pub hash: [u8; 32],
+}
+
+/// `seq:hash` with the hash in hex, as `verify-audit` prints it
+impl fmt::Display for AuditHead {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.seq, hex(&self.hash))
+    }
+}
+
+fn hex(hash: &[u8; 32]) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex(text: &str) -> Option<[u8; 32]> {
+    if text.len() != 64 || !text.is_ascii() {
+        return None;
+    }
+    let mut hash = [0; 32];
+    for (byte, pair) in hash.iter_mut().zip(text.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(hash)
+}
+
+/// The hash of a record's line without its hash field
+fn hash(content: &str) -> [u8; 32] {
+    Sha256::digest(content.as_bytes()).into()
+}
+
+/// Appends updates to a log, one hash-chained record each
+pub struct AuditLog<W: Write> {
+    output: W,
+    head: AuditHead,
+}
+
+impl<W: Write> AuditLog<W> {
+    /// Starts a new log in `output`, writing the header
+    pub fn new(mut output: W) -> io::Result<Self> {
+        writeln!(output, "{}", AUDIT_HEADER)?;
+        Ok(AuditLog::resume(output, AuditHead::default()))
+    }
+
+    /// Continues the log `output` appends to, whose last record is `head`,
+    /// see [`last_record`]
+    pub fn resume(output: W, head: AuditHead) -> Self {
+        AuditLog { output, head }
+    }
+
+    pub fn append(&mut self, update: &AccountUpdate) -> io::Result<()> {
+        let seq = self.head.seq + 1;
+        let content = format!(
+            "{},{},{},{},{},{},{},{},{},{}",
+            seq,
+            update.client,
+            update.tx,
+            update.transaction_type.name(),
+            update.amount,
+            update.available,
+            update.held,
+            update.status.locked(),
+            update.status.name(),
+            hex(&self.head.hash)
+        );
+        let hash = hash(&content);
+        writeln!(self.output, "{},{}", content, hex(&hash))?;
+        self.head = AuditHead { seq, hash };
+        Ok(())
+    }
+
+    /// The last record appended
+    pub fn head(&self) -> AuditHead {
+        self.head
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+/// The last record of the log in `input`, `None` if it's empty
+pub fn last_record(mut input: impl Read + Seek) -> io::Result<Option<AuditHead>> {
+    let len = input.seek(SeekFrom::End(0))?;
+    let start = len.saturating_sub(MAX_LINE);
+    input.seek(SeekFrom::Start(start))?;
+    let mut tail = String::new();
+    input.read_to_string(&mut tail)?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not an audit log");
+    let mut lines = tail.lines().rev().filter(|line| !line.is_empty());
+    let Some(last) = lines.next() else {
+        return Ok(None);
+    };
+    if last == AUDIT_HEADER {
+        return Ok(Some(AuditHead::default()));
+    }
+    // The last line must be whole, not the end of a longer one
+    if start > 0 && lines.next().is_none() {
+        return Err(invalid());
+    }
+    let fields: Vec<&str> = last.split(',').collect();
+    match (fields.first(), fields.last()) {
+        (Some(seq), Some(hash)) if fields.len() == 11 => {
+            let seq = seq.parse().map_err(|_| invalid())?;
+            let hash = parse_hex(hash).ok_or_else(invalid)?;
+            Ok(Some(AuditHead { seq, hash }))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Where a log stops adding up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditError {
+    /// Line of the record, the header is line 1
+    pub line: u64,
+    pub message: String,
+}
+
+impl fmt::Display for AuditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AuditError {}
+
+/// Checks every record of the log in `input` follows from the one before, returning the last.
+/// Fails at the first record that was changed, inserted or removed, or at the first line
+/// after one that was.
+pub fn verify_audit_log(input: impl BufRead) -> Result<AuditHead, AuditError> {
+    let mut head = AuditHead::default();
+    let mut lines = input.lines();
+    let error = |line, message: &str| AuditError {
+        line,
+        message: message.to_string(),
+    };
+    match lines.next() {
+        Some(Ok(header)) if header == AUDIT_HEADER => {}
+        Some(Err(err)) => return Err(error(1, &err.to_string())),
+        _ => return Err(error(1, "not an audit log, the header is missing")),
+    }
+    for (index, line) in lines.enumerate() {
+        let number = index as u64 + 2;
+        let line = line.map_err(|err| error(number, &err.to_string()))?;
+        let fields: Vec<&str> = line.split(',').collect();
+        if fields.len() != 11 {
+            return Err(error(number, "expected 11 fields"));
+        }
+        let (content, recorded) = line.rsplit_once(',').expect("11 fields");
+        if fields[0].parse::<u64>().ok() != Some(head.seq + 1) {
+            return Err(error(
+                number,
+                &format!("seq {} doesn't follow {}", fields[0], head.seq),
+            ));
+        }
+        if parse_hex(fields[9]) != Some(head.hash) {
+            return Err(error(number, "prev isn't the hash of the record before"));
+        }
+        let hash = hash(content);
+        if parse_hex(recorded) != Some(hash) {
+            return Err(error(
+                number,
+                "hash doesn't match the record, it was modified",
+            ));
+        }
+        head = AuditHead {
+            seq: head.seq + 1,
+            hash,
+        };
+    }
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Status, TransactionType};
+    use rust_decimal::Decimal;
+    use std::io::Cursor;
+
+    fn update(tx: crate::TxId, amount: i64) -> AccountUpdate {
+        AccountUpdate {
+            client: 1,
+            tx,
+            transaction_type: TransactionType::Deposit,
+            amount: Decimal::new(amount, 2),
+            fee: Decimal::ZERO,
+            available: Decimal::new(amount * tx as i64, 2),
+            held: Decimal::ZERO,
+            status: Status::Active,
+        }
+    }
+
+    fn log(txs: std::ops::RangeInclusive<crate::TxId>, output: &mut Vec<u8>) -> AuditHead {
+        let mut log = match last_record(Cursor::new(&*output)).unwrap() {
+            Some(head) => AuditLog::resume(&mut *output, head),
+            None => AuditLog::new(&mut *output).unwrap(),
+        };
+        for tx in txs {
+            log.append(&update(tx, 150)).unwrap();
+        }
+        log.head()
+    }
+
+    #[test]
+    fn chains_across_runs() {
+        let mut output = vec![];
+        log(1..=3, &mut output);
+        let head = log(4..=5, &mut output);
+        assert_eq!(head.seq, 5);
+        assert_eq!(verify_audit_log(&output[..]), Ok(head));
+        let text = String::from_utf8(output).unwrap();
+        assert_eq!(text.lines().count(), 6);
+        assert!(text
+            .lines()
+            .nth(2)
+            .unwrap()
+            .starts_with("2,1,2,deposit,1.50,3.00,0,false,active,"));
+    }
+
+    #[test]
+    fn detects_tampering() {
+        let mut output = vec![];
+        log(1..=4, &mut output);
+        let text = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        let verify = |lines: &[&str]| verify_audit_log((lines.join("\n") + "\n").as_bytes());
+
+        let mut changed = lines.clone();
+        let amended = lines[2].replacen("1.50", "1.00", 1);
+        changed[2] = &amended;
+        let error = verify(&changed).unwrap_err();
+        assert_eq!(error.line, 3);
+        assert!(error.message.contains("modified"));
+
+        let mut removed = lines.clone();
+        removed.remove(2);
+        assert_eq!(verify(&removed).unwrap_err().line, 3);
+
+        // Rehashing the changed record still breaks the link to the next
+        let (content, _) = amended.rsplit_once(',').unwrap();
+        let rehashed = format!("{},{}", content, hex(&hash(content)));
+        changed[2] = &rehashed;
+        let error = verify(&changed).unwrap_err();
+        assert_eq!(error.line, 4);
+        assert!(error.message.contains("prev"));
+    }
+}
@@ -0,0 +1,164 @@
+//! Publishing to a Kafka topic, with the `kafka` feature.
+//!
+//! [`KafkaSink`] produces one message per account update, keyed by client id so the updates
+//! of a client stay in order on one partition, with the JSON object of
+//! `--updates-format json` as payload. librdkafka queues the messages and sends them in the
+//! background, [`KafkaSink::flush`] waits until every one was acknowledged or failed.
+use crate::model::{Account, AccountUpdate};
+use crate::report::sink::AccountSink;
+use rdkafka::config::ClientConfig;
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::producer::{BaseProducer, BaseRecord, DeliveryResult, Producer, ProducerContext};
+use rdkafka::ClientContext;
+use std::io;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+/// How long [`KafkaSink::flush`] waits for the brokers to acknowledge what was queued
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Produces account updates, or any message with [`KafkaSink::send`], to one topic
+pub struct KafkaSink {
+    producer: BaseProducer<Deliveries>,
+    topic: String,
+}
+
+impl KafkaSink {
+    /// Produces to `topic` of the cluster of `brokers`, e.g. `broker1:9092,broker2:9092`
+    pub fn new(brokers: &str, topic: impl Into<String>) -> io::Result<Self> {
+        let mut config = ClientConfig::new();
+        config.set("bootstrap.servers", brokers);
+        Self::with_config(&config, topic)
+    }
+
+    /// Produces with librdkafka settings of its own, e.g. for TLS, SASL or `acks`
+    pub fn with_config(config: &ClientConfig, topic: impl Into<String>) -> io::Result<Self> {
+        let producer = config
+            .create_with_context(Deliveries::default())
+            .map_err(io::Error::other)?;
+        Ok(KafkaSink {
+            producer,
+            topic: topic.into(),
+        })
+    }
+
+    /// Publishes the balances an update left the account with and the transaction behind it
+    pub fn write_update(&mut self, update: &AccountUpdate) -> io::Result<()> {
+        let payload = serde_json::to_vec(update)?;
+        self.send(&update.client.to_string(), &payload)
+    }
+
+    /// Queues `payload` keyed by `key`, waiting for room while librdkafka's queue is full.
+    /// Failing to deliver it is only known by [`KafkaSink::flush`].
+    pub fn send(&mut self, key: &str, payload: &[u8]) -> io::Result<()> {
+        let mut record = BaseRecord::to(&self.topic).key(key).payload(payload);
+        loop {
+            match self.producer.send(record) {
+                Ok(()) => break,
+                Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), unsent)) => {
+                    record = unsent;
+                    self.producer.poll(Duration::from_millis(100));
+                }
+                Err((err, _)) => return Err(self.error(err)),
+            }
+        }
+        // Serves the delivery reports of earlier messages without waiting
+        self.producer.poll(Duration::ZERO);
+        Ok(())
+    }
+
+    /// Waits until everything queued was delivered, failing if some of it wasn't
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.producer
+            .flush(FLUSH_TIMEOUT)
+            .map_err(|err| self.error(err))?;
+        match self.producer.context().take_failure() {
+            Some((count, err)) => Err(io::Error::other(format!(
+                "topic {}: {} messages weren't delivered: {}",
+                self.topic, count, err
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    fn error(&self, err: KafkaError) -> io::Error {
+        io::Error::other(format!("topic {}: {}", self.topic, err))
+    }
+}
+
+/// Publishes every account, e.g. a snapshot for consumers starting out
+impl AccountSink for KafkaSink {
+    fn write_account(&mut self, account: &Account) -> io::Result<()> {
+        let payload = serde_json::to_vec(account)?;
+        self.send(&account.client.to_string(), &payload)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+/// Counts the messages the brokers didn't take, with the last reason
+#[derive(Default)]
+struct Deliveries {
+    failed: Mutex<Option<(u64, KafkaError)>>,
+}
+
+impl Deliveries {
+    fn take_failure(&self) -> Option<(u64, KafkaError)> {
+        self.failed
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+    }
+}
+
+impl ClientContext for Deliveries {}
+
+impl ProducerContext for Deliveries {
+    type DeliveryOpaque = ();
+
+    fn delivery(&self, result: &DeliveryResult<'_>, _: ()) {
+        if let Err((err, _)) = result {
+            let mut failed = self.failed.lock().unwrap_or_else(PoisonError::into_inner);
+            let count = failed.as_ref().map_or(0, |(count, _)| *count);
+            *failed = Some((count + 1, err.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Status, TransactionType};
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn undelivered_messages_fail_the_flush() {
+        // Nothing listens on port 9, the messages time out
+        let mut config = ClientConfig::new();
+        config
+            .set("bootstrap.servers", "127.0.0.1:9")
+            .set("message.timeout.ms", "200");
+        let mut sink = KafkaSink::with_config(&config, "account-updates").unwrap();
+        let update = AccountUpdate {
+            client: 1,
+            tx: 1,
+            transaction_type: TransactionType::Deposit,
+            amount: Decimal::ONE,
+            fee: Decimal::ZERO,
+            available: Decimal::ONE,
+            held: Decimal::ZERO,
+            status: Status::Active,
+        };
+        sink.write_update(&update).unwrap();
+        sink.write_update(&update).unwrap();
+        let err = sink.flush().unwrap_err().to_string();
+        assert!(
+            err.starts_with("topic account-updates: 2 messages weren't delivered"),
+            "{err}"
+        );
+        // Reported once
+        sink.flush().unwrap();
+    }
+}
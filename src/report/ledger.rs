@@ -0,0 +1,301 @@
+//! Double-entry bookkeeping of the account updates.
+//!
+//! Every movement of client funds is booked with an offsetting entry against a system
+//! account, so the entries of every transaction, and the whole ledger, balance to zero.
+//! Amounts are credits, debits are negative: client accounts hold what is owed to the client,
+//! so a deposit credits the client's available funds and debits [`LedgerAccount::Cash`].
+use crate::engine::AccountMap;
+use crate::model::{AccountUpdate, ClientId, TransactionType, TxId};
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+use std::fmt;
+#[cfg(feature = "csv")]
+use std::io;
+
+/// Where an entry is booked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LedgerAccount {
+    /// Available funds of a client
+    Available(ClientId),
+    /// Funds of a client held by open disputes
+    DisputesHeld(ClientId),
+    /// Money deposited and not withdrawn or charged back
+    Cash,
+    /// What the dispute policy charges on top of moving the disputed amount
+    Fees,
+}
+
+impl fmt::Display for LedgerAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LedgerAccount::Available(client) => write!(f, "client:{}:available", client),
+            LedgerAccount::DisputesHeld(client) => write!(f, "client:{}:disputes_held", client),
+            LedgerAccount::Cash => f.write_str("cash"),
+            LedgerAccount::Fees => f.write_str("fees"),
+        }
+    }
+}
+
+/// One side of a booking
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LedgerEntry {
+    pub tx: TxId,
+    pub transaction_type: TransactionType,
+    pub account: LedgerAccount,
+    /// Credit, negative for a debit
+    pub amount: Decimal,
+}
+
+/// Balances of the ledger accounts, kept up to date by [`Ledger::record`]
+#[derive(Debug, Clone, Default)]
+pub struct Ledger {
+    balances: BTreeMap<LedgerAccount, Decimal>,
+}
+
+impl Ledger {
+    pub fn new() -> Self {
+        Ledger::default()
+    }
+
+    /// Books `update` and returns its entries, which balance to zero
+    pub fn record(&mut self, update: &AccountUpdate) -> Vec<LedgerEntry> {
+        use LedgerAccount::*;
+
+        let (client, amount) = (update.client, update.amount);
+        let mut postings = match update.transaction_type {
+            TransactionType::Deposit => vec![(Available(client), amount), (Cash, -amount)],
+            TransactionType::Withdrawal => vec![(Available(client), -amount), (Cash, amount)],
+            TransactionType::Dispute => {
+                vec![(Available(client), -amount), (DisputesHeld(client), amount)]
+            }
+            TransactionType::Resolve => {
+                vec![(DisputesHeld(client), -amount), (Available(client), amount)]
+            }
+            TransactionType::Chargeback => vec![(DisputesHeld(client), -amount), (Cash, amount)],
+            TransactionType::ChargebackReversal => {
+                vec![(Cash, -amount), (Available(client), amount)]
+            }
+            TransactionType::Unlock
+            | TransactionType::Review
+            | TransactionType::Reinstate
+            | TransactionType::Close => vec![],
+        };
+        // Booked as the engine reports it, not worked out from the balances, so any other
+        // difference to the account shows up in the discrepancies
+        if !update.fee.is_zero() {
+            postings.extend([(Available(client), -update.fee), (Fees, update.fee)]);
+        }
+        postings
+            .into_iter()
+            .map(|(account, amount)| {
+                let balance = self.balances.entry(account).or_default();
+                *balance = balance.saturating_add(amount);
+                LedgerEntry {
+                    tx: update.tx,
+                    transaction_type: update.transaction_type,
+                    account,
+                    amount,
+                }
+            })
+            .collect()
+    }
+
+    /// Balance of `account`, zero if nothing was booked on it
+    pub fn balance(&self, account: LedgerAccount) -> Decimal {
+        self.balances.get(&account).copied().unwrap_or_default()
+    }
+
+    /// Every account something was booked on, in a stable order
+    pub fn balances(&self) -> impl Iterator<Item = (LedgerAccount, Decimal)> + '_ {
+        self.balances
+            .iter()
+            .map(|(account, balance)| (*account, *balance))
+    }
+
+    /// What doesn't add up: the balances not summing to zero, or client balances differing
+    /// from `accounts`. Empty if the ledger is consistent with them.
+    pub fn discrepancies(&self, accounts: &AccountMap) -> Vec<String> {
+        let mut discrepancies = vec![];
+        let sum: Decimal = self.balances.values().sum();
+        if !sum.is_zero() {
+            discrepancies.push(format!("ledger balances sum to {} instead of 0", sum));
+        }
+        for account in crate::engine::sorted_accounts(accounts) {
+            let client = account.client;
+            let expected = [
+                (LedgerAccount::Available(client), account.available),
+                (LedgerAccount::DisputesHeld(client), account.held),
+            ];
+            for (ledger_account, actual) in expected {
+                let booked = self.balance(ledger_account);
+                if booked != actual {
+                    discrepancies.push(format!(
+                        "{} is {} in the ledger, {} in the account",
+                        ledger_account, booked, actual
+                    ));
+                }
+            }
+        }
+        discrepancies
+    }
+}
+
+/// Writes ledger entries as CSV with a `tx,type,account,amount` header
+#[cfg(feature = "csv")]
+pub struct LedgerWriter<W: io::Write> {
+    writer: csv::Writer<W>,
+}
+
+#[cfg(feature = "csv")]
+impl<W: io::Write> LedgerWriter<W> {
+    pub fn new(output: W) -> csv::Result<Self> {
+        let mut writer = csv::Writer::from_writer(output);
+        writer.write_record(["tx", "type", "account", "amount"])?;
+        Ok(LedgerWriter { writer })
+    }
+
+    pub fn write(&mut self, entries: &[LedgerEntry]) -> csv::Result<()> {
+        for entry in entries {
+            self.writer.write_record([
+                entry.tx.to_string(),
+                entry.transaction_type.to_string(),
+                entry.account.to_string(),
+                entry.amount.to_string(),
+            ])?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Flushes and returns the output
+    pub fn into_inner(self) -> io::Result<W> {
+        self.writer.into_inner().map_err(|err| err.into_error())
+    }
+}
+
+#[cfg(all(test, feature = "csv"))]
+mod tests {
+    use crate::engine::policy::{DisputeAction, DisputePolicy, DisputedTx, StandardDisputePolicy};
+    use crate::model::{ClientId, Status, Transaction, TransactionType, TxId};
+    use crate::report::ledger::{Ledger, LedgerAccount, LedgerWriter};
+    use crate::Engine;
+    use rust_decimal::Decimal;
+
+    fn run(
+        engine: &mut Engine,
+        ledger: &mut Ledger,
+        rows: &[(TransactionType, ClientId, TxId, i64)],
+    ) {
+        for &(transaction_type, client, tx, amount) in rows {
+            let transaction = Transaction {
+                transaction_type,
+                client,
+                tx,
+                amount: Some(Decimal::new(amount, 0)).filter(|_| amount > 0),
+            };
+            let _ = engine.apply_each(transaction, |update| {
+                let entries = ledger.record(&update);
+                let sum: Decimal = entries.iter().map(|entry| entry.amount).sum();
+                assert_eq!(sum, Decimal::ZERO, "entries of tx {}", tx);
+            });
+        }
+    }
+
+    #[test]
+    fn ledger_balances_and_matches_the_accounts() {
+        use TransactionType::*;
+
+        let mut engine = Engine::new();
+        let mut ledger = Ledger::new();
+        run(
+            &mut engine,
+            &mut ledger,
+            &[
+                (Deposit, 1, 1, 10),
+                (Withdrawal, 1, 2, 3),
+                (Dispute, 1, 1, 0),
+                (Resolve, 1, 1, 0),
+                (Deposit, 2, 3, 5),
+                (Dispute, 2, 3, 0),
+                (Chargeback, 2, 3, 0),
+            ],
+        );
+        assert!(ledger.discrepancies(engine.accounts()).is_empty());
+        // The charged back 5 went back out of cash with the held funds
+        assert_eq!(ledger.balance(LedgerAccount::Cash), Decimal::new(-7, 0));
+        assert_eq!(ledger.balance(LedgerAccount::Available(2)), Decimal::ZERO);
+
+        // Accounts the ledger didn't see don't add up
+        let mut other = Engine::new();
+        run(&mut other, &mut Ledger::new(), &[(Deposit, 1, 1, 9)]);
+        assert_eq!(
+            ledger.discrepancies(other.accounts()),
+            ["client:1:available is 7 in the ledger, 9 in the account"]
+        );
+
+        // A chargeback that took the available funds as well isn't booked away
+        let mut accounts = engine.accounts().clone();
+        accounts.get_mut(&2).unwrap().available = Decimal::new(-5, 0);
+        assert_eq!(
+            ledger.discrepancies(&accounts),
+            ["client:2:available is 0 in the ledger, -5 in the account"]
+        );
+    }
+
+    #[test]
+    fn dispute_fees_are_booked() {
+        struct WithFee;
+
+        impl DisputePolicy for WithFee {
+            fn decide(
+                &mut self,
+                transaction: &Transaction,
+                referenced: &DisputedTx,
+            ) -> Option<DisputeAction> {
+                let action = StandardDisputePolicy.decide(transaction, referenced)?;
+                Some(DisputeAction {
+                    fee: Decimal::ONE,
+                    ..action
+                })
+            }
+        }
+
+        let mut engine = Engine::new();
+        engine.set_dispute_policy(WithFee);
+        let mut ledger = Ledger::new();
+        run(
+            &mut engine,
+            &mut ledger,
+            &[
+                (TransactionType::Deposit, 1, 1, 10),
+                (TransactionType::Dispute, 1, 1, 0),
+            ],
+        );
+        assert!(ledger.discrepancies(engine.accounts()).is_empty());
+        assert_eq!(ledger.balance(LedgerAccount::Fees), Decimal::ONE);
+
+        let mut output = vec![];
+        let mut writer = LedgerWriter::new(&mut output).unwrap();
+        let mut ledger = Ledger::new();
+        let update = crate::model::AccountUpdate {
+            client: 1,
+            tx: 7,
+            transaction_type: TransactionType::Deposit,
+            amount: Decimal::new(15, 1),
+            fee: Decimal::ZERO,
+            available: Decimal::new(15, 1),
+            held: Decimal::ZERO,
+            status: Status::Active,
+        };
+        writer.write(&ledger.record(&update)).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "tx,type,account,amount\n7,deposit,client:1:available,1.5\n7,deposit,cash,-1.5\n"
+        );
+    }
+}
@@ -0,0 +1,202 @@
+//! Volumes per client and day or month.
+//!
+//! Transactions carry no time of their own, so [`PeriodReport::record`] takes the timestamp
+//! of the row an update comes from, e.g. a `date` column kept as [`crate::Metadata`].
+//! Timestamps are ISO 8601 (`2024-03-01`, `2024-03-01T12:30:00Z`), only the date is used.
+use crate::model::{AccountUpdate, ClientId, TransactionType};
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+#[cfg(feature = "csv")]
+use std::io;
+
+/// How long the periods of a [`PeriodReport`] are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    Day,
+    Month,
+}
+
+impl Period {
+    /// The period `timestamp` falls into, `2024-03-01` or `2024-03` for days or months,
+    /// `None` if it doesn't start with a date
+    pub fn of(self, timestamp: &str) -> Option<&str> {
+        let date = timestamp.trim().get(..10)?;
+        let bytes = date.as_bytes();
+        let digits = [0, 1, 2, 3, 5, 6, 8, 9];
+        if bytes[4] != b'-'
+            || bytes[7] != b'-'
+            || !digits.iter().all(|&i| bytes[i].is_ascii_digit())
+        {
+            return None;
+        }
+        match self {
+            Period::Day => Some(date),
+            Period::Month => Some(&date[..7]),
+        }
+    }
+}
+
+/// What one client did in one period
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeriodStats {
+    pub deposits: u64,
+    pub deposited: Decimal,
+    pub withdrawals: u64,
+    pub withdrawn: Decimal,
+    pub disputes: u64,
+    pub resolves: u64,
+    pub chargebacks: u64,
+    pub chargeback_reversals: u64,
+    pub unlocks: u64,
+    /// Reviews, reinstatements and closures
+    pub status_changes: u64,
+}
+
+/// Applied transactions summed up per period and client
+#[derive(Debug, Clone)]
+pub struct PeriodReport {
+    period: Period,
+    stats: BTreeMap<(String, ClientId), PeriodStats>,
+}
+
+impl PeriodReport {
+    pub fn new(period: Period) -> Self {
+        PeriodReport {
+            period,
+            stats: BTreeMap::new(),
+        }
+    }
+
+    /// Counts `update` in the period of `timestamp`. Returns false and counts nothing
+    /// if `timestamp` isn't a date.
+    pub fn record(&mut self, timestamp: &str, update: &AccountUpdate) -> bool {
+        let Some(period) = self.period.of(timestamp) else {
+            return false;
+        };
+        let stats = self
+            .stats
+            .entry((period.to_string(), update.client))
+            .or_default();
+        match update.transaction_type {
+            TransactionType::Deposit => {
+                stats.deposits += 1;
+                stats.deposited = stats.deposited.saturating_add(update.amount);
+            }
+            TransactionType::Withdrawal => {
+                stats.withdrawals += 1;
+                stats.withdrawn = stats.withdrawn.saturating_add(update.amount);
+            }
+            TransactionType::Dispute => stats.disputes += 1,
+            TransactionType::Resolve => stats.resolves += 1,
+            TransactionType::Chargeback => stats.chargebacks += 1,
+            TransactionType::ChargebackReversal => stats.chargeback_reversals += 1,
+            TransactionType::Unlock => stats.unlocks += 1,
+            TransactionType::Review | TransactionType::Reinstate | TransactionType::Close => {
+                stats.status_changes += 1
+            }
+        }
+        true
+    }
+
+    /// Stats per period and client, ordered by period then client
+    pub fn stats(&self) -> impl Iterator<Item = (&str, ClientId, &PeriodStats)> {
+        self.stats
+            .iter()
+            .map(|((period, client), stats)| (period.as_str(), *client, stats))
+    }
+
+    /// Writes one CSV row per period and client, with a `period,client,deposits,...` header
+    #[cfg(feature = "csv")]
+    pub fn write_csv<W: io::Write>(&self, output: W) -> csv::Result<()> {
+        let mut writer = csv::Writer::from_writer(output);
+        writer.write_record([
+            "period",
+            "client",
+            "deposits",
+            "deposited",
+            "withdrawals",
+            "withdrawn",
+            "disputes",
+            "resolves",
+            "chargebacks",
+            "chargeback_reversals",
+            "unlocks",
+            "status_changes",
+        ])?;
+        for (period, client, stats) in self.stats() {
+            writer.write_record([
+                period.to_string(),
+                client.to_string(),
+                stats.deposits.to_string(),
+                stats.deposited.to_string(),
+                stats.withdrawals.to_string(),
+                stats.withdrawn.to_string(),
+                stats.disputes.to_string(),
+                stats.resolves.to_string(),
+                stats.chargebacks.to_string(),
+                stats.chargeback_reversals.to_string(),
+                stats.unlocks.to_string(),
+                stats.status_changes.to_string(),
+            ])?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "csv"))]
+mod tests {
+    use crate::model::{AccountUpdate, Status, TransactionType};
+    use crate::report::periods::{Period, PeriodReport};
+    use rust_decimal::Decimal;
+
+    fn update(
+        client: crate::model::ClientId,
+        transaction_type: TransactionType,
+        amount: i64,
+    ) -> AccountUpdate {
+        AccountUpdate {
+            client,
+            tx: 1,
+            transaction_type,
+            amount: Decimal::new(amount, 0),
+            fee: Decimal::ZERO,
+            available: Decimal::ZERO,
+            held: Decimal::ZERO,
+            status: Status::Active,
+        }
+    }
+
+    #[test]
+    fn timestamps_fall_into_periods() {
+        assert_eq!(Period::Day.of("2024-03-01T12:30:00Z"), Some("2024-03-01"));
+        assert_eq!(Period::Month.of(" 2024-03-01"), Some("2024-03"));
+        assert_eq!(Period::Day.of("01/03/2024"), None);
+        assert_eq!(Period::Day.of("2024-3-1"), None);
+        assert_eq!(Period::Month.of(""), None);
+    }
+
+    #[test]
+    fn updates_are_summed_per_period_and_client() {
+        let mut report = PeriodReport::new(Period::Month);
+        assert!(report.record("2024-03-01", &update(1, TransactionType::Deposit, 5)));
+        assert!(report.record("2024-03-20", &update(1, TransactionType::Deposit, 2)));
+        assert!(report.record("2024-03-21", &update(1, TransactionType::Withdrawal, 3)));
+        assert!(report.record("2024-03-02", &update(2, TransactionType::Dispute, 4)));
+        assert!(report.record("2024-04-01", &update(1, TransactionType::Chargeback, 5)));
+        assert!(report.record("2024-04-02", &update(1, TransactionType::Review, 0)));
+        assert!(!report.record("yesterday", &update(1, TransactionType::Deposit, 1)));
+
+        let mut output = vec![];
+        report.write_csv(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "period,client,deposits,deposited,withdrawals,withdrawn,disputes,resolves,chargebacks,\
+chargeback_reversals,unlocks,status_changes
+2024-03,1,2,7,1,3,0,0,0,0,0,0
+2024-03,2,0,0,0,0,1,0,0,0,0,0
+2024-04,1,0,0,0,0,0,0,1,0,0,1
+"
+        );
+    }
+}
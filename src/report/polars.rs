@@ -0,0 +1,209 @@
+//! Accounts, transactions and skipped rows as Polars `DataFrame`s, to join against other
+//! frames without writing CSV in between.
+//!
+//! The columns are those of [`crate::report::arrow`]: amounts `Decimal(38, 4)` rounded
+//! like the CSV output, client and tx ids `UInt64`, and names such as `frozen` or
+//! `charged_back` as strings.
+use crate::engine::minor::{rounded_minor_units, SCALE};
+use crate::engine::{sorted_accounts, AccountMap, TransactionIndex};
+use crate::io::RowError;
+use crate::model::Account;
+use crate::report::ProcessReport;
+use polars::prelude::{Column, DataFrame, Int128Chunked, IntoColumn, PolarsResult};
+use rust_decimal::Decimal;
+
+/// Conversion into a [`DataFrame`], one row per account, transaction or skipped row
+pub trait ToPolars {
+    fn to_polars(&self) -> PolarsResult<DataFrame>;
+}
+
+/// Client and tx ids, whatever the width of [`crate::ClientId`] and [`crate::TxId`]
+fn id(id: impl Into<u64>) -> u64 {
+    id.into()
+}
+
+fn amounts(name: &str, values: impl Iterator<Item = Decimal>) -> Column {
+    let units = values.map(rounded_minor_units).collect();
+    Int128Chunked::from_vec(name.into(), units)
+        .into_decimal_unchecked(Some(38), SCALE as usize)
+        .into_column()
+}
+
+/// `client, available, held, total, locked, status, deposits, withdrawals, deposited, withdrawn`,
+/// ordered by client id
+impl ToPolars for AccountMap {
+    fn to_polars(&self) -> PolarsResult<DataFrame> {
+        let accounts = sorted_accounts(self);
+        let column = |name: &str, value: fn(&Account) -> u64| {
+            Column::new(
+                name.into(),
+                accounts.iter().map(|a| value(a)).collect::<Vec<_>>(),
+            )
+        };
+        DataFrame::new(vec![
+            column("client", |account| id(account.client)),
+            amounts(
+                "available",
+                accounts.iter().map(|account| account.available),
+            ),
+            amounts("held", accounts.iter().map(|account| account.held)),
+            amounts("total", accounts.iter().map(|account| account.total())),
+            Column::new(
+                "locked".into(),
+                accounts
+                    .iter()
+                    .map(|account| account.locked())
+                    .collect::<Vec<_>>(),
+            ),
+            Column::new(
+                "status".into(),
+                accounts
+                    .iter()
+                    .map(|account| account.status.name())
+                    .collect::<Vec<_>>(),
+            ),
+            column("deposits", |account| account.activity().deposits),
+            column("withdrawals", |account| account.activity().withdrawals),
+            amounts(
+                "deposited",
+                accounts.iter().map(|account| account.activity().deposited),
+            ),
+            amounts(
+                "withdrawn",
+                accounts.iter().map(|account| account.activity().withdrawn),
+            ),
+        ])
+    }
+}
+
+/// `tx, client, type, amount, held, state`, the deposits and withdrawals disputes can
+/// reference ordered by tx id
+impl ToPolars for TransactionIndex {
+    fn to_polars(&self) -> PolarsResult<DataFrame> {
+        let mut transactions: Vec<_> = self.iter().collect();
+        transactions.sort_unstable_by_key(|(tx, _)| *tx);
+        let records = || transactions.iter().map(|(_, record)| record);
+        DataFrame::new(vec![
+            Column::new(
+                "tx".into(),
+                transactions
+                    .iter()
+                    .map(|(tx, _)| id(*tx))
+                    .collect::<Vec<_>>(),
+            ),
+            Column::new(
+                "client".into(),
+                records()
+                    .map(|record| id(record.client))
+                    .collect::<Vec<_>>(),
+            ),
+            Column::new(
+                "type".into(),
+                records()
+                    .map(|record| record.transaction_type.name())
+                    .collect::<Vec<_>>(),
+            ),
+            amounts("amount", records().map(|record| record.amount)),
+            amounts("held", records().map(|record| record.held)),
+            Column::new(
+                "state".into(),
+                records()
+                    .map(|record| record.state.name())
+                    .collect::<Vec<_>>(),
+            ),
+        ])
+    }
+}
+
+/// `line, code, message, record`, as the errors CSV of `--mode collecting`
+impl ToPolars for [RowError] {
+    fn to_polars(&self) -> PolarsResult<DataFrame> {
+        DataFrame::new(vec![
+            Column::new(
+                "line".into(),
+                self.iter().map(|error| error.line).collect::<Vec<_>>(),
+            ),
+            Column::new(
+                "code".into(),
+                self.iter()
+                    .map(|error| error.code.as_str())
+                    .collect::<Vec<_>>(),
+            ),
+            Column::new(
+                "message".into(),
+                self.iter()
+                    .map(|error| error.message.as_str())
+                    .collect::<Vec<_>>(),
+            ),
+            Column::new(
+                "record".into(),
+                self.iter()
+                    .map(|error| error.record.as_str())
+                    .collect::<Vec<_>>(),
+            ),
+        ])
+    }
+}
+
+/// The accounts, as `report.accounts.to_polars()`; `report.transactions` and
+/// `report.errors` convert on their own
+impl ToPolars for ProcessReport {
+    fn to_polars(&self) -> PolarsResult<DataFrame> {
+        self.accounts.to_polars()
+    }
+}
+
+#[cfg(all(test, feature = "csv"))]
+mod tests {
+    use super::*;
+    use crate::io::csv::{process_transactions_with, ParseOptions};
+    use crate::ParseMode;
+    use polars::prelude::AnyValue;
+
+    const INPUT: &str = "\
+type,client,tx,amount
+deposit,2,1,1.00005
+deposit,1,2,2.0
+dispute,1,2,
+withdrawal,2,3,x
+chargeback,1,2,
+";
+
+    #[test]
+    fn report_converts_to_frames() {
+        let options = ParseOptions {
+            mode: ParseMode::Collecting,
+            ..ParseOptions::default()
+        };
+        let report =
+            process_transactions_with(&mut csv::Reader::from_reader(INPUT.as_bytes()), &options)
+                .unwrap();
+        let accounts = report.to_polars().unwrap();
+        assert_eq!(accounts.shape(), (2, 10));
+        let clients: Vec<_> = accounts["client"]
+            .u64()
+            .unwrap()
+            .into_no_null_iter()
+            .collect();
+        assert_eq!(clients, [1, 2]);
+        assert_eq!(
+            accounts["available"].get(1).unwrap().to_string(),
+            AnyValue::Decimal(10_001, 4).to_string()
+        );
+        assert_eq!(
+            accounts["status"].get(0).unwrap(),
+            AnyValue::String("frozen")
+        );
+
+        let transactions = report.transactions.to_polars().unwrap();
+        assert_eq!(transactions.height(), 2);
+        assert_eq!(
+            transactions["state"].get(1).unwrap(),
+            AnyValue::String("charged_back")
+        );
+
+        let errors = report.errors.to_polars().unwrap();
+        assert_eq!(errors.height(), 1);
+        assert_eq!(errors["line"].get(0).unwrap(), AnyValue::UInt64(5));
+    }
+}
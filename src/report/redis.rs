@@ -0,0 +1,165 @@
+//! Upserting balances into Redis hashes on a server, with the `redis` feature.
+//!
+//! [`RedisSink`] keeps a hash per client, `HSET <prefix><client> available .. held .. locked ..
+//! total .. status ..`, the same as [`RedisCommandWriter`](crate::RedisCommandWriter) writes
+//! to a file, so a customer-facing API can read live balances. Commands are pipelined in
+//! batches and every reply is checked.
+use crate::model::{Account, AccountUpdate, ClientId, Status};
+use crate::report::sink::{balance_fields, AccountSink};
+use redis::{Client, Connection, Pipeline};
+use rust_decimal::Decimal;
+use std::io;
+
+/// Commands sent to the server at once
+const BATCH: usize = 256;
+
+/// Upserts the balances of accounts into hashes on a Redis server
+pub struct RedisSink {
+    connection: Connection,
+    key_prefix: String,
+    pipeline: Pipeline,
+    queued: usize,
+}
+
+impl RedisSink {
+    /// Connects to `url`, e.g. `redis://cache:6379/0` or `rediss://` for TLS if the redis
+    /// crate was built with it. Keys are `client:<id>`.
+    pub fn connect(url: &str) -> io::Result<Self> {
+        Self::connect_with_key_prefix(url, "client:")
+    }
+
+    pub fn connect_with_key_prefix(url: &str, key_prefix: impl Into<String>) -> io::Result<Self> {
+        let connection = Client::open(url)
+            .and_then(|client| client.get_connection())
+            .map_err(io::Error::other)?;
+        Ok(RedisSink {
+            connection,
+            key_prefix: key_prefix.into(),
+            pipeline: redis::pipe(),
+            queued: 0,
+        })
+    }
+
+    /// Upserts the balances an update left the account with, to keep the hashes live
+    /// while processing
+    pub fn write_update(&mut self, update: &AccountUpdate) -> io::Result<()> {
+        self.write_balances(update.client, update.available, update.held, update.status)
+    }
+
+    fn write_balances(
+        &mut self,
+        client: ClientId,
+        available: Decimal,
+        held: Decimal,
+        status: Status,
+    ) -> io::Result<()> {
+        let fields = balance_fields(client, available, held, status)?;
+        self.pipeline
+            .cmd("HSET")
+            .arg(format!("{}{}", self.key_prefix, client))
+            .arg(&fields)
+            .ignore();
+        self.queued += 1;
+        if self.queued == BATCH {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Sends the commands queued so far, failing if the server refused any
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.queued == 0 {
+            return Ok(());
+        }
+        let sent = self.pipeline.query::<()>(&mut self.connection);
+        self.pipeline.clear();
+        self.queued = 0;
+        sent.map_err(io::Error::other)
+    }
+}
+
+impl AccountSink for RedisSink {
+    fn write_account(&mut self, account: &Account) -> io::Result<()> {
+        self.write_balances(
+            account.client,
+            account.available,
+            account.held,
+            account.status,
+        )
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+#[cfg(all(test, feature = "csv"))]
+mod tests {
+    use super::*;
+    use crate::io::csv::process_transactions;
+    use crate::report::sink::write_to_sink;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Answers every command of one connection with `+OK`, returning the commands
+    fn fake_server(listener: TcpListener) -> Vec<Vec<String>> {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut replies = stream;
+        let mut commands = vec![];
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 0 {
+            let count: usize = line.trim_end().trim_start_matches('*').parse().unwrap();
+            let mut command = vec![];
+            for _ in 0..count * 2 {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+                if !line.starts_with('$') {
+                    command.push(line.trim_end().to_string());
+                }
+            }
+            commands.push(command);
+            replies.write_all(b"+OK\r\n").unwrap();
+            line.clear();
+        }
+        commands
+    }
+
+    #[test]
+    fn redis_sink_sends_hset_commands() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("redis://{}/", listener.local_addr().unwrap());
+        let server = thread::spawn(move || fake_server(listener));
+
+        let data = "type,client,tx,amount\ndeposit,2,1,1.5\ndeposit,1,2,2.0\ndispute,1,2,";
+        let accounts = process_transactions(&mut csv::Reader::from_reader(data.as_bytes()));
+        let mut sink = RedisSink::connect_with_key_prefix(&url, "balance:").unwrap();
+        write_to_sink(&accounts, &mut sink).unwrap();
+        drop(sink);
+
+        let commands = server.join().unwrap();
+        let hsets: Vec<_> = commands
+            .iter()
+            .filter(|command| command[0] == "HSET")
+            .collect();
+        assert_eq!(
+            hsets[0],
+            &[
+                "HSET",
+                "balance:1",
+                "available",
+                "0",
+                "held",
+                "2.0",
+                "locked",
+                "false",
+                "total",
+                "2.0",
+                "status",
+                "active"
+            ]
+        );
+        assert_eq!(hsets.len(), 2);
+    }
+}
@@ -0,0 +1,287 @@
+//! Where accounts go once processing is done, independent of how they were computed.
+//!
+//! [`write_to_sink`] hands the accounts to an [`AccountSink`]. [`CsvSink`], [`JsonLinesSink`],
+//! [`RedisCommandWriter`] and [`MemorySink`] ship with the crate, with the `redis` feature
+//! also [`crate::report::redis::RedisSink`], other destinations only need to implement
+//! the trait.
+use crate::engine::{sorted_accounts, AccountMap};
+use crate::model::{Account, AccountUpdate, ClientId, Status};
+#[cfg(feature = "csv")]
+use crate::report::{AccountWriter, OutputOptions};
+use rust_decimal::Decimal;
+use std::io;
+
+/// Receives accounts one at a time
+pub trait AccountSink {
+    fn write_account(&mut self, account: &Account) -> io::Result<()>;
+
+    /// Called once after the last account, e.g. to flush buffered output
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Hands `accounts` to `sink` ordered by client id, then finishes it
+pub fn write_to_sink(accounts: &AccountMap, sink: &mut dyn AccountSink) -> io::Result<()> {
+    for account in sorted_accounts(accounts) {
+        sink.write_account(account)?;
+    }
+    sink.finish()
+}
+
+/// Writes accounts as CSV with the columns and filters of [`OutputOptions`],
+/// like [`crate::write_accounts_with`]
+#[cfg(feature = "csv")]
+pub struct CsvSink<W: io::Write> {
+    writer: AccountWriter<W>,
+    options: OutputOptions,
+    header_written: bool,
+}
+
+#[cfg(feature = "csv")]
+impl<W: io::Write> CsvSink<W> {
+    pub fn new(output: W, options: OutputOptions) -> Self {
+        CsvSink {
+            writer: AccountWriter::new(output, &options),
+            options,
+            header_written: false,
+        }
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        if !self.header_written {
+            self.writer.write_header(self.options.schema)?;
+            self.header_written = true;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "csv")]
+impl<W: io::Write> AccountSink for CsvSink<W> {
+    fn write_account(&mut self, account: &Account) -> io::Result<()> {
+        self.write_header()?;
+        if !self.options.includes(account) {
+            return Ok(());
+        }
+        self.writer.write(account, &self.options)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        // Without accounts the header is still written
+        self.write_header()?;
+        self.writer.flush()
+    }
+}
+
+/// Writes accounts as JSON Lines, one object per account,
+/// e.g. `{"client":1,"available":"1.5","held":"0","locked":false,"balance":"1.5",
+/// "status":"active"}`
+pub struct JsonLinesSink<W: io::Write> {
+    output: W,
+}
+
+impl<W: io::Write> JsonLinesSink<W> {
+    pub fn new(output: W) -> Self {
+        JsonLinesSink { output }
+    }
+}
+
+impl<W: io::Write> AccountSink for JsonLinesSink<W> {
+    fn write_account(&mut self, account: &Account) -> io::Result<()> {
+        serde_json::to_writer(&mut self.output, account)?;
+        self.output.write_all(b"\n")
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+impl<S: AccountSink + ?Sized> AccountSink for &mut S {
+    fn write_account(&mut self, account: &Account) -> io::Result<()> {
+        (**self).write_account(account)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+}
+
+/// Writes a file of Redis commands upserting every account into a hash, `HSET <prefix><client>
+/// available .. held .. locked .. total .. status ..` in the Redis protocol (RESP), for
+/// `redis-cli --pipe` to send. It doesn't connect to Redis itself, see
+/// [`crate::report::redis::RedisSink`] for that.
+pub struct RedisCommandWriter<W: io::Write> {
+    output: W,
+    key_prefix: String,
+}
+
+impl<W: io::Write> RedisCommandWriter<W> {
+    /// Keys are `client:<id>`
+    pub fn new(output: W) -> Self {
+        Self::with_key_prefix(output, "client:")
+    }
+
+    pub fn with_key_prefix(output: W, key_prefix: impl Into<String>) -> Self {
+        RedisCommandWriter {
+            output,
+            key_prefix: key_prefix.into(),
+        }
+    }
+
+    /// Upserts the balances an update left the account with, to keep the hashes live
+    /// while processing
+    pub fn write_update(&mut self, update: &AccountUpdate) -> io::Result<()> {
+        self.write_balances(update.client, update.available, update.held, update.status)
+    }
+
+    fn write_balances(
+        &mut self,
+        client: ClientId,
+        available: Decimal,
+        held: Decimal,
+        status: Status,
+    ) -> io::Result<()> {
+        let key = format!("{}{}", self.key_prefix, client);
+        let fields = balance_fields(client, available, held, status)?;
+        write!(self.output, "*{}\r\n", 2 + 2 * fields.len())?;
+        let args = fields.iter().flat_map(|(field, value)| [*field, value]);
+        for arg in ["HSET", &key].into_iter().chain(args) {
+            write!(self.output, "${}\r\n{}\r\n", arg.len(), arg)?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+/// Fields and values of the Redis hash of an account, failing if its total overflows
+pub(crate) fn balance_fields(
+    client: ClientId,
+    available: Decimal,
+    held: Decimal,
+    status: Status,
+) -> io::Result<[(&'static str, String); 5]> {
+    let total = available
+        .checked_add(held)
+        .ok_or_else(|| io::Error::other(format!("total balance of client {} overflows", client)))?;
+    Ok([
+        ("available", available.to_string()),
+        ("held", held.to_string()),
+        ("locked", status.locked().to_string()),
+        ("total", total.to_string()),
+        ("status", status.name().to_string()),
+    ])
+}
+
+impl<W: io::Write> AccountSink for RedisCommandWriter<W> {
+    fn write_account(&mut self, account: &Account) -> io::Result<()> {
+        self.write_balances(
+            account.client,
+            account.available,
+            account.held,
+            account.status,
+        )
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Keeps copies of the accounts, e.g. for tests
+#[derive(Debug, Default)]
+pub struct MemorySink {
+    pub accounts: Vec<Account>,
+}
+
+impl AccountSink for MemorySink {
+    fn write_account(&mut self, account: &Account) -> io::Result<()> {
+        self.accounts.push(account.clone());
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "csv"))]
+mod tests {
+    use crate::io::csv::process_transactions;
+    use crate::model::Status;
+    use crate::report::sink::{
+        write_to_sink, CsvSink, JsonLinesSink, MemorySink, RedisCommandWriter,
+    };
+    use crate::report::OutputOptions;
+    use rust_decimal::Decimal;
+
+    const DATA: &str = "type,client,tx,amount
+deposit,2,1,1.5
+deposit,1,2,2.0
+dispute,1,2,";
+
+    #[test]
+    fn sinks_receive_accounts_ordered_by_client() {
+        let accounts = process_transactions(&mut csv::Reader::from_reader(DATA.as_bytes()));
+
+        let mut memory = MemorySink::default();
+        write_to_sink(&accounts, &mut memory).unwrap();
+        let clients: Vec<crate::model::ClientId> = memory
+            .accounts
+            .iter()
+            .map(|account| account.client)
+            .collect();
+        assert_eq!(clients, [1, 2]);
+
+        let mut json = JsonLinesSink::new(vec![]);
+        write_to_sink(&accounts, &mut json).unwrap();
+        assert_eq!(
+            String::from_utf8(json.output).unwrap(),
+            r#"{"client":1,"available":"0","held":"2.0","locked":false,"balance":"2.0","status":"active"}
+{"client":2,"available":"1.5","held":"0","locked":false,"balance":"1.5","status":"active"}
+"#
+        );
+    }
+
+    #[test]
+    fn csv_sink_writes_like_write_accounts() {
+        let accounts = process_transactions(&mut csv::Reader::from_reader(DATA.as_bytes()));
+        let mut csv = CsvSink::new(vec![], OutputOptions::default());
+        write_to_sink(&accounts, &mut csv).unwrap();
+        let mut expected = vec![];
+        crate::write_accounts(&accounts, &mut expected).unwrap();
+        assert_eq!(csv.writer.into_inner().unwrap(), expected);
+
+        // The header is written even without accounts
+        let mut csv = CsvSink::new(vec![], OutputOptions::default());
+        write_to_sink(&Default::default(), &mut csv).unwrap();
+        assert_eq!(
+            csv.writer.into_inner().unwrap(),
+            b"client,available,held,locked,balance\n"
+        );
+    }
+
+    #[test]
+    fn redis_sink_writes_hset_commands() {
+        let accounts = process_transactions(&mut csv::Reader::from_reader(DATA.as_bytes()));
+        let mut redis = RedisCommandWriter::with_key_prefix(vec![], "balance:");
+        write_to_sink(&accounts, &mut redis).unwrap();
+        let output = String::from_utf8(redis.output.clone()).unwrap();
+        let first = "*12\r\n$4\r\nHSET\r\n$9\r\nbalance:1\r\n$9\r\navailable\r\n$1\r\n0\r\n\
+                     $4\r\nheld\r\n$3\r\n2.0\r\n$6\r\nlocked\r\n$5\r\nfalse\r\n\
+                     $5\r\ntotal\r\n$3\r\n2.0\r\n$6\r\nstatus\r\n$6\r\nactive\r\n";
+        assert!(output.starts_with(first), "{output:?}");
+        assert_eq!(output.matches("HSET").count(), 2);
+
+        let overflowing = redis.write_balances(1, Decimal::MAX, Decimal::ONE, Status::Active);
+        assert_eq!(
+            overflowing.unwrap_err().to_string(),
+            "total balance of client 1 overflows"
+        );
+    }
+}
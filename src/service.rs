@@ -0,0 +1,12 @@
+//! Long-running services that keep one engine up to date as transactions arrive.
+//!
+//! - [`feed`]: applying transactions read on other threads, from a file that keeps growing
+//!   with [`feed::follow`], with periodic refreshes and checkpoints
+//! - [`listen`]: reading the transactions the clients of a Unix socket send, and answering
+//!   balance queries on another
+//! - [`watch`]: picking up the files dropped into a directory once they are complete
+pub mod feed;
+#[cfg(unix)]
+pub mod listen;
+#[cfg(feature = "fs")]
+pub mod watch;
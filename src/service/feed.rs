@@ -0,0 +1,360 @@
+//! Applying transactions read on other threads to one engine as they arrive.
+//!
+//! Readers send a [`Feed`] per row through a channel holding at most [`FEED_CAPACITY`], so
+//! they can't run far ahead of the engine. [`FeedLoop::run`] applies them until every sender
+//! is gone, handing the updates, the skipped rows, and the refreshes and checkpoints due while
+//! it waits to a [`FeedHandler`].
+use crate::engine::Engine;
+use crate::io::csv::ParseOptions;
+use crate::io::source::{CsvSource, TransactionSource};
+use crate::io::{decode_input, Follow, ParseMode, RowError};
+use crate::model::{AccountUpdate, Transaction};
+#[cfg(unix)]
+use crate::service::listen::Connection;
+use std::io::{self, Read, Seek};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+#[cfg(unix)]
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How many transactions a reading thread can be ahead of the engine
+pub const FEED_CAPACITY: usize = 1024;
+
+/// A transaction read on another thread with its line, or why a line couldn't be read
+pub struct Feed {
+    pub item: Result<(Transaction, u64), RowError>,
+    /// Where the input continues after it, for checkpoints
+    pub position: Option<csv::Position>,
+    /// The client of a socket that sent it
+    #[cfg(unix)]
+    pub connection: Option<Arc<Connection>>,
+}
+
+impl Feed {
+    pub fn new(item: Result<(Transaction, u64), RowError>) -> Self {
+        Feed {
+            item,
+            position: None,
+            #[cfg(unix)]
+            connection: None,
+        }
+    }
+}
+
+/// Sends everything `source` hands out until it is exhausted or the engine is gone
+pub fn send_all(mut source: impl TransactionSource, sender: &SyncSender<Feed>) {
+    while let Some(item) = source.next_transaction() {
+        let item = item.map(|transaction| (transaction, source.line().unwrap_or(0)));
+        if sender.send(Feed::new(item)).is_err() {
+            return;
+        }
+    }
+}
+
+/// Like [`send_all`], with the position after every row
+pub fn send_positioned<R: Read>(mut source: CsvSource<R>, sender: &SyncSender<Feed>) {
+    while let Some(item) = source.next_transaction() {
+        let item = item.map(|transaction| (transaction, source.line().unwrap_or(0)));
+        let feed = Feed {
+            position: Some(source.position().clone()),
+            ..Feed::new(item)
+        };
+        if sender.send(feed).is_err() {
+            return;
+        }
+    }
+}
+
+/// Reads the CSV rows of `input` and those appended to it later on a thread of its own, like
+/// `tail -f` checking for more every `poll`. Input in another `encoding` is transcoded, see
+/// [`decode_input`].
+pub fn follow(
+    input: impl Read + Send + 'static,
+    poll: Duration,
+    encoding: Option<&str>,
+    options: &ParseOptions,
+) -> io::Result<Receiver<Feed>> {
+    let input = decode_input(Follow::new(input, poll), encoding)?;
+    let options = options.clone();
+    let (sender, receiver) = mpsc::sync_channel(FEED_CAPACITY);
+    thread::spawn(move || {
+        match CsvSource::new(csv::Reader::from_reader(input), &options) {
+            Ok(source) => send_all(source, &sender),
+            Err(err) => {
+                let _ = sender.send(Feed::new(Err(err)));
+            }
+        };
+    });
+    Ok(receiver)
+}
+
+/// Like [`follow`], from `position` on if given, e.g. that of a
+/// [`ResumeToken`](crate::checkpoint::ResumeToken), with the position after every row.
+/// The input is read as it is, the offsets of transcoded input wouldn't be those of the file.
+pub fn follow_from(
+    input: impl Read + Seek + Send + 'static,
+    poll: Duration,
+    options: &ParseOptions,
+    position: Option<csv::Position>,
+) -> Receiver<Feed> {
+    let options = options.clone();
+    let (sender, receiver) = mpsc::sync_channel(FEED_CAPACITY);
+    thread::spawn(move || {
+        let source = CsvSource::new(csv::Reader::from_reader(Follow::new(input, poll)), &options)
+            .and_then(|mut source| match position {
+                Some(position) => source.seek(position).map(|()| source),
+                None => Ok(source),
+            });
+        match source {
+            Ok(source) => send_positioned(source, &sender),
+            Err(err) => {
+                let _ = sender.send(Feed::new(Err(err)));
+            }
+        };
+    });
+    receiver
+}
+
+/// What is done with the results of a [`FeedLoop`], nothing by default
+pub trait FeedHandler {
+    /// An update of a transaction applied, read from `line`
+    fn update(&mut self, _update: AccountUpdate, _line: u64) {}
+
+    /// A row skipped in [`ParseMode::Collecting`]
+    fn skipped(&mut self, _error: &RowError) {}
+
+    /// A socket client dropped for sending `error` in [`ParseMode::Strict`]
+    fn dropped(&mut self, _connection: u64, _error: &RowError) {}
+
+    /// Called once per refresh interval at most, when the accounts changed, e.g. to write a
+    /// snapshot
+    fn refresh(&mut self, _engine: &Engine) {}
+
+    /// Called once per checkpoint interval at most, when rows were applied since the last,
+    /// with where the input continues after them
+    fn checkpoint(&mut self, _engine: &Engine, _position: &csv::Position) {}
+}
+
+/// Applies [`Feed`]s to an engine as they arrive
+#[derive(Debug, Clone)]
+pub struct FeedLoop {
+    mode: ParseMode,
+    refresh: Duration,
+    checkpoint_interval: Option<Duration>,
+}
+
+impl FeedLoop {
+    /// Handles bad rows by `mode`, refreshing at most every `refresh`
+    pub fn new(mode: ParseMode, refresh: Duration) -> Self {
+        FeedLoop {
+            mode,
+            refresh,
+            checkpoint_interval: None,
+        }
+    }
+
+    /// Hands checkpoints to [`FeedHandler::checkpoint`] at most every `interval`
+    pub fn checkpoint_every(mut self, interval: Duration) -> Self {
+        self.checkpoint_interval = Some(interval);
+        self
+    }
+
+    /// Applies what `receiver` gets until every sender is gone, returning the position after
+    /// the last row applied if the feeds had one, to checkpoint at the end. A bad row stops
+    /// it in [`ParseMode::Strict`], unless a socket client sent it: that client is dropped
+    /// and the others are still served.
+    pub fn run(
+        &self,
+        engine: &mut Engine,
+        receiver: Receiver<Feed>,
+        handler: &mut impl FeedHandler,
+    ) -> Result<Option<csv::Position>, RowError> {
+        let mut changed = true;
+        let mut refreshed = Instant::now();
+        // Where the input continues after the rows applied, and after those of the last checkpoint
+        let (mut position, mut checkpointed) = (None::<csv::Position>, None);
+        let mut checkpoint_written = Instant::now();
+        loop {
+            let error = match receiver.recv_timeout(self.refresh) {
+                #[cfg(unix)]
+                Ok(feed)
+                    if feed
+                        .connection
+                        .as_ref()
+                        .is_some_and(|client| client.dropped()) =>
+                {
+                    None
+                }
+                Ok(feed) => {
+                    position = feed.position.or(position);
+                    let error = match feed.item {
+                        Ok((transaction, line)) => engine
+                            .apply_each(transaction, |update| {
+                                changed = true;
+                                handler.update(update, line)
+                            })
+                            .err()
+                            .map(|rejection| RowError {
+                                line,
+                                record: transaction.to_string(),
+                                code: rejection.code(),
+                                message: rejection.to_string(),
+                            }),
+                        Err(error) => Some(error),
+                    };
+                    // One client's bad record doesn't stop the others
+                    #[cfg(unix)]
+                    let error = match (self.mode, feed.connection, error) {
+                        (ParseMode::Strict, Some(connection), Some(error)) => {
+                            connection.drop_client();
+                            handler.dropped(connection.id(), &error);
+                            None
+                        }
+                        (_, _, error) => error,
+                    };
+                    error
+                }
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => return Ok(position),
+            };
+            if let Some(error) = error {
+                match self.mode {
+                    ParseMode::Strict => return Err(error),
+                    ParseMode::Collecting => handler.skipped(&error),
+                    ParseMode::Lenient => {}
+                }
+            }
+            if changed && refreshed.elapsed() >= self.refresh {
+                handler.refresh(engine);
+                changed = false;
+                refreshed = Instant::now();
+            }
+            if let (Some(interval), Some(position)) = (self.checkpoint_interval, &position) {
+                let advanced = checkpointed != Some(position.byte());
+                if advanced && checkpoint_written.elapsed() >= interval {
+                    handler.checkpoint(engine, position);
+                    checkpointed = Some(position.byte());
+                    checkpoint_written = Instant::now();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::NegativeBalanceBehavior;
+    use crate::io::ErrorCode;
+    use crate::model::TransactionType;
+    use rust_decimal::Decimal;
+    use std::io::Cursor;
+
+    #[derive(Default)]
+    struct Recorder {
+        updates: Vec<(TransactionType, u64)>,
+        skipped: Vec<ErrorCode>,
+        refreshes: usize,
+        checkpoints: Vec<u64>,
+    }
+
+    impl FeedHandler for Recorder {
+        fn update(&mut self, update: AccountUpdate, line: u64) {
+            self.updates.push((update.transaction_type, line));
+        }
+
+        fn skipped(&mut self, error: &RowError) {
+            self.skipped.push(error.code);
+        }
+
+        fn refresh(&mut self, _engine: &Engine) {
+            self.refreshes += 1;
+        }
+
+        fn checkpoint(&mut self, _engine: &Engine, position: &csv::Position) {
+            self.checkpoints.push(position.line());
+        }
+    }
+
+    const ROWS: &str = "\
+type,client,tx,amount
+deposit,1,1,10.0
+withdrawal,1,2,50.0
+dispute,1,1,
+";
+
+    /// Rejecting the withdrawal of ROWS
+    fn engine() -> Engine {
+        let mut engine = Engine::new();
+        engine.set_negative_balance_behavior(NegativeBalanceBehavior::Reject);
+        engine
+    }
+
+    fn feeds(rows: &str) -> Receiver<Feed> {
+        let options = ParseOptions::default();
+        let (sender, receiver) = mpsc::sync_channel(FEED_CAPACITY);
+        let source = CsvSource::new(csv::Reader::from_reader(rows.as_bytes()), &options).unwrap();
+        send_positioned(source, &sender);
+        receiver
+    }
+
+    #[test]
+    fn feeds_are_applied_until_the_senders_are_gone() {
+        let mut engine = engine();
+        let mut recorder = Recorder::default();
+        let position = FeedLoop::new(ParseMode::Collecting, Duration::ZERO)
+            .checkpoint_every(Duration::ZERO)
+            .run(&mut engine, feeds(ROWS), &mut recorder)
+            .unwrap();
+        assert_eq!(
+            recorder.updates,
+            [(TransactionType::Deposit, 2), (TransactionType::Dispute, 4)]
+        );
+        assert_eq!(recorder.skipped, [ErrorCode::InsufficientFunds]);
+        // Not after the rejected withdrawal, which changed nothing
+        assert_eq!(recorder.refreshes, 2);
+        assert_eq!(recorder.checkpoints, [3, 4, 5]);
+        assert_eq!(position.map(|position| position.line()), Some(5));
+        assert_eq!(engine.accounts()[&1].held, Decimal::TEN);
+    }
+
+    #[test]
+    fn strict_loops_stop_at_the_first_bad_row() {
+        let mut engine = engine();
+        let mut recorder = Recorder::default();
+        let error = FeedLoop::new(ParseMode::Strict, Duration::from_secs(60))
+            .run(&mut engine, feeds(ROWS), &mut recorder)
+            .unwrap_err();
+        assert_eq!((error.line, error.code), (3, ErrorCode::InsufficientFunds));
+        assert_eq!(recorder.updates.len(), 1);
+        assert_eq!(recorder.refreshes, 0);
+    }
+
+    #[test]
+    fn followed_input_resumes_at_a_position() {
+        let options = ParseOptions::default();
+        let receiver = follow_from(
+            Cursor::new(ROWS.as_bytes().to_vec()),
+            Duration::from_millis(10),
+            &options,
+            None,
+        );
+        let positions: Vec<_> = receiver
+            .iter()
+            .take(3)
+            .map(|feed| feed.position.unwrap())
+            .collect();
+
+        let resumed = follow_from(
+            Cursor::new(ROWS.as_bytes().to_vec()),
+            Duration::from_millis(10),
+            &options,
+            Some(positions[0].clone()),
+        );
+        let feed = resumed.recv().unwrap();
+        let (transaction, line) = feed.item.unwrap();
+        assert_eq!((transaction.tx, line), (2, 3));
+        assert_eq!(feed.position, Some(positions[1].clone()));
+    }
+}
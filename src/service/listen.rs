@@ -0,0 +1,332 @@
+//! Serving the clients of Unix sockets: reading the transactions they send with [`listen`],
+//! and answering balance queries with [`serve_queries`].
+//!
+//! Every connection is served on a thread of its own, at most as many at once as a
+//! [`ConnectionLimit`] allows; those over it are closed right away.
+use crate::io::csv::ParseOptions;
+use crate::io::source::{CsvSource, JsonLinesSource, TransactionSource};
+use crate::io::WireFormat;
+use crate::service::feed::{Feed, FEED_CAPACITY};
+#[cfg(feature = "dashmap")]
+use crate::{ids::text_id, IdFormat, SharedAccounts};
+use std::fs;
+use std::io;
+#[cfg(feature = "dashmap")]
+use std::io::{BufRead, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread;
+
+/// Counts the connections being served, to turn away those over the limit
+#[derive(Debug, Clone)]
+pub struct ConnectionLimit {
+    active: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> Self {
+        ConnectionLimit {
+            active: Arc::default(),
+            max,
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Connections being served
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// Runs `serve` on a thread of its own, unless `max` connections are being served already
+    pub fn spawn(&self, serve: impl FnOnce() + Send + 'static) -> bool {
+        /// Counts the connection as served once its thread ends, even by a panic
+        struct Served(Arc<AtomicUsize>);
+
+        impl Drop for Served {
+            fn drop(&mut self) {
+                self.0.fetch_sub(1, Ordering::AcqRel);
+            }
+        }
+
+        if self.active.fetch_add(1, Ordering::AcqRel) >= self.max {
+            self.active.fetch_sub(1, Ordering::AcqRel);
+            return false;
+        }
+        let served = Served(Arc::clone(&self.active));
+        thread::spawn(move || {
+            let _served = served;
+            serve();
+        });
+        true
+    }
+}
+
+/// A client sending transactions, which can be dropped on its first bad record
+#[derive(Debug)]
+pub struct Connection {
+    id: u64,
+    dropped: AtomicBool,
+    stream: UnixStream,
+}
+
+impl Connection {
+    /// The `id`th connection, reading `stream`
+    pub fn new(id: u64, stream: UnixStream) -> Self {
+        Connection {
+            id,
+            dropped: AtomicBool::new(false),
+            stream,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Stops reading from the client, the records it sent before are skipped
+    pub fn drop_client(&self) {
+        self.dropped.store(true, Ordering::Relaxed);
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+    }
+
+    pub fn dropped(&self) -> bool {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Listens on the Unix socket `socket`, replacing a socket left behind by a previous run
+pub fn bind(socket: &Path) -> io::Result<UnixListener> {
+    if fs::metadata(socket).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        fs::remove_file(socket)?;
+    }
+    UnixListener::bind(socket)
+}
+
+/// Reads the transactions every client of `listener` sends in `format`, numbering the
+/// connections from 1, and hands them to the [`FeedLoop`](crate::service::feed::FeedLoop)
+/// of the returned receiver
+pub fn listen(
+    listener: UnixListener,
+    format: WireFormat,
+    options: &ParseOptions,
+    limit: ConnectionLimit,
+) -> Receiver<Feed> {
+    let options = options.clone();
+    let (sender, receiver) = mpsc::sync_channel(FEED_CAPACITY);
+    thread::spawn(move || {
+        for (id, stream) in (1..).zip(listener.incoming()) {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    tracing::warn!("failed to accept a connection: {}", err);
+                    continue;
+                }
+            };
+            let connection = match stream.try_clone() {
+                Ok(clone) => Arc::new(Connection::new(id, clone)),
+                Err(err) => {
+                    tracing::warn!("failed to accept a connection: {}", err);
+                    continue;
+                }
+            };
+            let options = options.clone();
+            let sender = sender.clone();
+            let served = limit.spawn(move || match format {
+                WireFormat::Csv => {
+                    let reader = csv::ReaderBuilder::new()
+                        .has_headers(false)
+                        .from_reader(stream);
+                    if let Ok(source) = CsvSource::new(reader, &options) {
+                        send_from(source, &sender, &connection);
+                    }
+                }
+                WireFormat::Json => {
+                    let source = JsonLinesSource::new(io::BufReader::new(stream))
+                        .client_ids(options.client_ids)
+                        .tx_ids(options.tx_ids);
+                    match options.pseudonyms {
+                        Some(pseudonyms) => {
+                            send_from(source.pseudonymize(pseudonyms), &sender, &connection)
+                        }
+                        None => send_from(source, &sender, &connection),
+                    }
+                }
+            });
+            if !served {
+                tracing::warn!(
+                    connection = id,
+                    "closed a connection over the limit of {}",
+                    limit.max
+                );
+            }
+        }
+    });
+    receiver
+}
+
+/// Sends what `connection` sent until it is exhausted or dropped, or the engine is gone
+fn send_from(
+    mut source: impl TransactionSource,
+    sender: &SyncSender<Feed>,
+    connection: &Arc<Connection>,
+) {
+    while let Some(item) = source.next_transaction() {
+        let item = item.map(|transaction| (transaction, source.line().unwrap_or(0)));
+        let feed = Feed {
+            connection: Some(Arc::clone(connection)),
+            ..Feed::new(item)
+        };
+        if connection.dropped() || sender.send(feed).is_err() {
+            return;
+        }
+    }
+}
+
+/// Answers balance queries on `listener` from `accounts` on a thread of its own: every
+/// connection sends client ids, text ones with [`IdFormat::Text`] `client_ids`, one per line
+/// and gets each account back as a JSON line, `null` for an unknown client or a line that
+/// isn't a client id
+#[cfg(feature = "dashmap")]
+pub fn serve_queries(
+    listener: UnixListener,
+    accounts: SharedAccounts,
+    client_ids: IdFormat,
+    limit: ConnectionLimit,
+) {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    tracing::warn!("failed to accept a connection: {}", err);
+                    continue;
+                }
+            };
+            let accounts = accounts.clone();
+            let text = client_ids.names().is_some();
+            let served = limit.spawn(move || {
+                let mut writer = io::BufWriter::new(&stream);
+                for line in io::BufReader::new(&stream).lines() {
+                    let Ok(line) = line else { return };
+                    let client = match text {
+                        true => Some(text_id(line.trim())),
+                        false => line.trim().parse().ok(),
+                    };
+                    let account = client.and_then(|client| accounts.get(client));
+                    let answered = serde_json::to_writer(&mut writer, &account)
+                        .map_err(io::Error::from)
+                        .and_then(|()| writeln!(writer))
+                        .and_then(|()| writer.flush());
+                    if answered.is_err() {
+                        return;
+                    }
+                }
+            });
+            if !served {
+                tracing::warn!("closed a query connection over the limit of {}", limit.max);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Engine, NegativeBalanceBehavior};
+    use crate::io::{ErrorCode, ParseMode, RowError};
+    use crate::model::{AccountUpdate, ClientId};
+    use crate::service::feed::{FeedHandler, FeedLoop};
+    use std::io::{Read, Write};
+    use std::sync::mpsc::{channel, Sender};
+    use std::time::Duration;
+
+    /// A socket path of its own for every test
+    fn socket(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "transaction-parser-{}-{}.sock",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn connections_over_the_limit_are_turned_away() {
+        let limit = ConnectionLimit::new(1);
+        let (release, wait) = channel::<()>();
+        assert!(limit.spawn(move || {
+            let _ = wait.recv();
+        }));
+        assert!(!limit.spawn(|| {}));
+        assert_eq!(limit.active(), 1);
+        release.send(()).unwrap();
+        while limit.active() > 0 {
+            thread::yield_now();
+        }
+        assert!(limit.spawn(|| {}));
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Update(ClientId),
+        Dropped(u64, ErrorCode),
+    }
+
+    /// Tells the test what the loop did
+    struct Events(Sender<Event>);
+
+    impl FeedHandler for Events {
+        fn update(&mut self, update: AccountUpdate, _line: u64) {
+            let _ = self.0.send(Event::Update(update.client));
+        }
+
+        fn dropped(&mut self, connection: u64, error: &RowError) {
+            let _ = self.0.send(Event::Dropped(connection, error.code));
+        }
+    }
+
+    #[test]
+    fn strict_loops_only_drop_the_client_with_a_bad_row() {
+        let path = socket("listen");
+        let receiver = listen(
+            bind(&path).unwrap(),
+            WireFormat::Csv,
+            &ParseOptions::default(),
+            ConnectionLimit::new(4),
+        );
+        let (events, seen) = channel();
+        // The listener runs until the process ends, the loop with it
+        thread::spawn(move || {
+            let mut engine = Engine::new();
+            engine.set_negative_balance_behavior(NegativeBalanceBehavior::Reject);
+            let run = FeedLoop::new(ParseMode::Strict, Duration::from_millis(10));
+            run.run(&mut engine, receiver, &mut Events(events))
+        });
+        let mut bad = UnixStream::connect(&path).unwrap();
+        bad.write_all(b"withdrawal,1,1,5.0\ndeposit,1,2,1.0\n")
+            .unwrap();
+        let timeout = Duration::from_secs(10);
+        assert_eq!(
+            seen.recv_timeout(timeout),
+            Ok(Event::Dropped(1, ErrorCode::InsufficientFunds))
+        );
+        // Hung up on, what it sent after the bad row is skipped
+        let mut rest = vec![];
+        assert_eq!(bad.read_to_end(&mut rest).unwrap(), 0);
+
+        let mut good = UnixStream::connect(&path).unwrap();
+        good.write_all(b"deposit,2,3,2.0\n").unwrap();
+        assert_eq!(seen.recv_timeout(timeout), Ok(Event::Update(2)));
+        assert!(seen.recv_timeout(Duration::from_millis(50)).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
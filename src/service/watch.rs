@@ -0,0 +1,201 @@
+//! Picking up the CSV files dropped into a directory, e.g. by an SFTP upload, once they are
+//! complete.
+//!
+//! A file counts as complete when its size didn't change between two scans of the
+//! directory, so one still being written is left for a later scan. [`Watcher::next_file`]
+//! hands them out in name order and [`Watcher::archive`] moves the ones that are done
+//! out of the way, or [`Watcher::skip`] passes over those that can't be applied until they
+//! are replaced.
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Scans a directory for complete CSV files
+#[derive(Debug)]
+pub struct Watcher {
+    dir: PathBuf,
+    archive: PathBuf,
+    interval: Duration,
+    /// Sizes seen by the previous scan, a file still growing is left for the next one
+    sizes: HashMap<PathBuf, u64>,
+    /// Complete files of the last scan not handed out yet
+    settled: VecDeque<PathBuf>,
+    scanned: bool,
+    /// Size and modification time of the files skipped, handed out again once either changes
+    skipped: HashMap<PathBuf, (u64, Option<SystemTime>)>,
+}
+
+impl Watcher {
+    /// Watches `dir`, scanning it every `interval`, and moves the files handled into
+    /// `archive`, which is created if it's missing
+    pub fn new(
+        dir: impl Into<PathBuf>,
+        archive: impl Into<PathBuf>,
+        interval: Duration,
+    ) -> io::Result<Self> {
+        let archive = archive.into();
+        fs::create_dir_all(&archive)?;
+        Ok(Watcher {
+            dir: dir.into(),
+            archive,
+            interval,
+            sizes: HashMap::new(),
+            settled: VecDeque::new(),
+            scanned: false,
+            skipped: HashMap::new(),
+        })
+    }
+
+    /// The next complete file, waiting for one if there is none. A file that isn't
+    /// [archived](Watcher::archive) is handed out again by a later scan.
+    pub fn next_file(&mut self) -> io::Result<PathBuf> {
+        loop {
+            if let Some(path) = self.settled.pop_front() {
+                return Ok(path);
+            }
+            if self.scanned {
+                thread::sleep(self.interval);
+            }
+            self.scanned = true;
+            self.settled = self.scan()?.into();
+        }
+    }
+
+    /// CSV files in the directory whose size is the same as in the previous scan, ordered by
+    /// name
+    pub fn scan(&mut self) -> io::Result<Vec<PathBuf>> {
+        let mut current = HashMap::new();
+        let mut skipped = HashMap::new();
+        for entry in fs::read_dir(&self.dir)?.flatten() {
+            let path = entry.path();
+            let is_csv = path
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+            match entry.metadata() {
+                Ok(metadata) if is_csv && metadata.is_file() => {
+                    let version = (metadata.len(), metadata.modified().ok());
+                    match self.skipped.remove(&path) {
+                        Some(skipped_version) if skipped_version == version => {
+                            skipped.insert(path, version);
+                        }
+                        _ => {
+                            current.insert(path, metadata.len());
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.skipped = skipped;
+        let mut settled: Vec<PathBuf> = current
+            .iter()
+            .filter(|(path, size)| self.sizes.get(*path) == Some(size))
+            .map(|(path, _)| path.clone())
+            .collect();
+        settled.sort();
+        self.sizes = current;
+        Ok(settled)
+    }
+
+    /// Moves `path` into the archive, adding a counter to the name if it is taken, and
+    /// returns where it went
+    pub fn archive(&mut self, path: &Path) -> io::Result<PathBuf> {
+        let name = path.file_name().unwrap_or_default();
+        let mut target = self.archive.join(name);
+        let mut counter = 1;
+        while target.exists() {
+            let mut numbered = name.to_owned();
+            numbered.push(format!(".{}", counter));
+            target = self.archive.join(numbered);
+            counter += 1;
+        }
+        // rename doesn't work across file systems
+        fs::rename(path, &target)
+            .or_else(|_| fs::copy(path, &target).and_then(|_| fs::remove_file(path)))?;
+        self.sizes.remove(path);
+        Ok(target)
+    }
+
+    /// Leaves `path` where it is without handing it out again until it is replaced or
+    /// changes
+    pub fn skip(&mut self, path: &Path) -> io::Result<()> {
+        let metadata = fs::metadata(path)?;
+        self.sizes.remove(path);
+        self.skipped.insert(
+            path.to_path_buf(),
+            (metadata.len(), metadata.modified().ok()),
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_are_handed_out_once_they_stop_growing() {
+        let dir = tempfile::tempdir().unwrap();
+        let incoming = dir.path().join("incoming");
+        fs::create_dir(&incoming).unwrap();
+        let mut watcher = Watcher::new(&incoming, dir.path().join("done"), Duration::ZERO).unwrap();
+        fs::write(incoming.join("b.csv"), "type,client,tx,amount\n").unwrap();
+        fs::write(incoming.join("a.CSV"), "type,client,tx,amount\n").unwrap();
+        fs::write(incoming.join("notes.txt"), "not a csv").unwrap();
+        assert!(watcher.scan().unwrap().is_empty());
+
+        fs::write(
+            incoming.join("b.csv"),
+            "type,client,tx,amount\ndeposit,1,1,1\n",
+        )
+        .unwrap();
+        assert_eq!(watcher.scan().unwrap(), [incoming.join("a.CSV")]);
+        assert_eq!(
+            watcher.scan().unwrap(),
+            [incoming.join("a.CSV"), incoming.join("b.csv")]
+        );
+        assert_eq!(watcher.next_file().unwrap(), incoming.join("a.CSV"));
+    }
+
+    #[test]
+    fn archived_names_are_kept_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher =
+            Watcher::new(dir.path(), dir.path().join("done"), Duration::ZERO).unwrap();
+        for content in ["first", "second"] {
+            fs::write(dir.path().join("day.csv"), content).unwrap();
+            watcher.scan().unwrap();
+            watcher.scan().unwrap();
+            let path = watcher.next_file().unwrap();
+            watcher.archive(&path).unwrap();
+        }
+        let done = dir.path().join("done");
+        assert_eq!(fs::read_to_string(done.join("day.csv")).unwrap(), "first");
+        assert_eq!(
+            fs::read_to_string(done.join("day.csv.1")).unwrap(),
+            "second"
+        );
+        assert!(watcher.scan().unwrap().is_empty());
+    }
+
+    #[test]
+    fn skipped_files_come_back_once_they_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher =
+            Watcher::new(dir.path(), dir.path().join("done"), Duration::ZERO).unwrap();
+        let path = dir.path().join("day.csv");
+        fs::write(&path, "bad").unwrap();
+        watcher.scan().unwrap();
+        assert_eq!(watcher.scan().unwrap(), std::slice::from_ref(&path));
+        watcher.skip(&path).unwrap();
+        assert!(watcher.scan().unwrap().is_empty());
+        assert!(watcher.scan().unwrap().is_empty());
+
+        fs::write(&path, "fixed").unwrap();
+        watcher.scan().unwrap();
+        assert_eq!(watcher.scan().unwrap(), [path]);
+    }
+}
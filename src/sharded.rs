@@ -0,0 +1,237 @@
+//! Applies transactions on all cores, one thread per shard of clients.
+//!
+//! Every shard is an actor: a thread with an [`Engine`] of its own, owning the accounts of its
+//! clients and the deposits and withdrawals they made, fed through a channel. A transaction
+//! only ever touches its own client, so the shards share nothing and every client's
+//! transactions are applied in input order. The shards are merged into one report at the end.
+//!
+//! The shards can't see each other's tx ids, so the reading thread remembers which shard
+//! each deposit and withdrawal tx id went to and rejects one reusing it for a client of another
+//! shard as [`Rejection::DuplicateTx`] itself. Unlike on one engine that holds even if the
+//! first use was rejected, which would have left the tx id free.
+use crate::engine::{Engine, Rejection};
+use crate::io::source::TransactionSource;
+use crate::io::{ParseMode, RowError};
+use crate::model::{ClientId, Transaction, TransactionType, TxId};
+use crate::report::ProcessReport;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::panic;
+use std::sync::mpsc;
+use std::thread;
+
+/// Transactions sent to a shard at once
+const BATCH: usize = 256;
+
+/// Batches queued for a shard before reading waits for it to catch up
+const QUEUE: usize = 16;
+
+/// Same as [`Engine::process_source_with`] on `shards` threads, each applying the transactions
+/// of its clients to an engine made by `engine`. Every engine starts with the accounts
+/// and transactions of its own clients only, so `engine` may restore a whole state.
+/// It is called once more for the tx ids of that state. Observers are called from the
+/// thread of their shard.
+///
+/// Errors are reported in input order, in [`ParseMode::Strict`] the first of them.
+pub fn process_sharded(
+    shards: usize,
+    engine: impl Fn() -> Engine + Sync,
+    mut source: impl TransactionSource,
+    mode: ParseMode,
+) -> Result<ProcessReport, RowError> {
+    let shards = shards.max(1);
+    thread::scope(|scope| {
+        let (senders, workers): (Vec<_>, Vec<_>) = (0..shards)
+            .map(|shard| {
+                let (sender, receiver) = mpsc::sync_channel::<Vec<(u64, Transaction)>>(QUEUE);
+                let engine = &engine;
+                let worker = scope.spawn(move || {
+                    let mut engine = engine();
+                    engine.retain_clients(|client| shard_of(client, shards) == shard);
+                    let items = receiver
+                        .into_iter()
+                        .flatten()
+                        .map(|(line, transaction)| (line, Ok(transaction)));
+                    let errors = engine.process_lines(items, mode)?;
+                    Ok::<_, RowError>(ProcessReport {
+                        errors,
+                        ..engine.into()
+                    })
+                });
+                (sender, worker)
+            })
+            .collect();
+
+        // The shard of every deposit and withdrawal tx id so far
+        let mut owners: HashMap<TxId, usize> = engine()
+            .transaction_clients()
+            .map(|(tx, client)| (tx, shard_of(client, shards)))
+            .collect();
+        let mut batches = vec![Vec::with_capacity(BATCH); shards];
+        let mut errors = vec![];
+        let mut failed = None;
+        let mut index = 0;
+        while let Some(item) = source.next_transaction() {
+            index += 1;
+            let line = source.line().unwrap_or(index);
+            let item = item.and_then(|transaction| {
+                let shard = shard_of(transaction.client, shards);
+                if !matches!(
+                    transaction.transaction_type,
+                    TransactionType::Deposit | TransactionType::Withdrawal
+                ) {
+                    return Ok((shard, transaction));
+                }
+                match owners.entry(transaction.tx) {
+                    Entry::Occupied(owner) if *owner.get() != shard => {
+                        let rejection = Rejection::DuplicateTx(transaction.tx);
+                        Err(RowError {
+                            line,
+                            record: transaction.to_string(),
+                            code: rejection.code(),
+                            message: rejection.to_string(),
+                        })
+                    }
+                    Entry::Occupied(_) => Ok((shard, transaction)),
+                    Entry::Vacant(owner) => {
+                        owner.insert(shard);
+                        Ok((shard, transaction))
+                    }
+                }
+            });
+            match item {
+                Ok((shard, transaction)) => {
+                    batches[shard].push((line, transaction));
+                    if batches[shard].len() == BATCH {
+                        let batch =
+                            std::mem::replace(&mut batches[shard], Vec::with_capacity(BATCH));
+                        // The shard stopped at a strict error
+                        if senders[shard].send(batch).is_err() {
+                            break;
+                        }
+                    }
+                }
+                Err(error) => {
+                    if let Err(error) = mode.reject(error, &mut errors) {
+                        failed = Some(error);
+                        break;
+                    }
+                }
+            }
+        }
+        for (sender, batch) in senders.into_iter().zip(batches) {
+            // A shard that stopped has its error already
+            let _ = sender.send(batch);
+        }
+
+        let mut report = ProcessReport {
+            errors,
+            ..ProcessReport::default()
+        };
+        for worker in workers {
+            match worker
+                .join()
+                .unwrap_or_else(|err| panic::resume_unwind(err))
+            {
+                Ok(shard) => merge(&mut report, shard),
+                Err(error) => {
+                    if failed
+                        .as_ref()
+                        .is_none_or(|failed| error.line < failed.line)
+                    {
+                        failed = Some(error);
+                    }
+                }
+            }
+        }
+        match failed {
+            Some(error) => Err(error),
+            None => {
+                report.errors.sort_by_key(|error| error.line);
+                Ok(report)
+            }
+        }
+    })
+}
+
+fn shard_of(client: ClientId, shards: usize) -> usize {
+    client as usize % shards
+}
+
+/// Adds the accounts, transactions and errors of `shard`, which has clients of its own
+fn merge(report: &mut ProcessReport, shard: ProcessReport) {
+    report.accounts.extend(shard.accounts);
+    // Tx ids were checked across shards as they were read
+    for (tx, transaction) in shard.transactions {
+        report.transactions.insert(tx, transaction);
+    }
+    report.errors.extend(shard.errors);
+    report.lost_to_eviction += shard.lost_to_eviction;
+}
+
+#[cfg(all(test, feature = "csv"))]
+mod tests {
+    use super::*;
+    use crate::io::csv::ParseOptions;
+    use crate::io::source::CsvSource;
+    use crate::io::ErrorCode;
+
+    const INPUT: &str = "\
+type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+deposit,3,3,7.0
+withdrawal,2,4,1.0
+dispute,1,1,
+withdrawal,3,5,100.0
+chargeback,1,1,
+deposit,4,6,1.0
+resolve,2,2,
+deposit,4,6,1.0
+deposit,5,6,3.0
+withdrawal,6,1,1.0
+";
+
+    fn sharded(shards: usize, mode: ParseMode) -> Result<ProcessReport, RowError> {
+        let options = ParseOptions::default();
+        let source = CsvSource::new(csv::Reader::from_reader(INPUT.as_bytes()), &options).unwrap();
+        let engine = || {
+            let mut engine = Engine::new();
+            engine.set_negative_balance_behavior(crate::NegativeBalanceBehavior::Reject);
+            engine
+        };
+        process_sharded(shards, engine, source, mode)
+    }
+
+    #[test]
+    fn shards_give_the_same_result_as_one_engine() {
+        let options = ParseOptions::default();
+        let source = CsvSource::new(csv::Reader::from_reader(INPUT.as_bytes()), &options).unwrap();
+        let mut engine = Engine::new();
+        engine.set_negative_balance_behavior(crate::NegativeBalanceBehavior::Reject);
+        let expected = engine.process_source(source);
+        for shards in [1, 2, 3, 8] {
+            let report = sharded(shards, ParseMode::Collecting).unwrap();
+            assert_eq!(report.state_digest(), expected.state_digest());
+            assert_eq!(report.transactions, expected.transactions);
+            assert_eq!(report.errors, expected.errors);
+            let codes: Vec<_> = report.errors.iter().map(|error| error.code).collect();
+            assert_eq!(
+                codes,
+                [
+                    ErrorCode::InsufficientFunds,
+                    ErrorCode::DuplicateTx,
+                    ErrorCode::DuplicateTx,
+                    ErrorCode::DuplicateTx
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn strict_mode_stops_at_the_first_error() {
+        let error = sharded(4, ParseMode::Strict).unwrap_err();
+        assert_eq!(error.line, 7);
+        assert_eq!(error.code, ErrorCode::InsufficientFunds);
+    }
+}
@@ -0,0 +1,131 @@
+//! Quick overview of a transactions file.
+//!
+//! Every row is parsed but nothing is applied, so a file can be inspected
+//! before committing to a full processing run.
+use crate::io::csv::{ParseOptions, RowParser};
+use crate::model::{ClientId, TransactionType, TxId};
+use csv::{ByteRecord, Reader};
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::ops::RangeInclusive;
+
+/// Rows of one transaction type
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TypeStats {
+    pub rows: u64,
+    /// Smallest and largest amount of the rows that have one
+    pub min: Option<Decimal>,
+    pub max: Option<Decimal>,
+    /// Saturates instead of overflowing
+    pub sum: Decimal,
+}
+
+/// What [`file_stats`] found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileStats {
+    /// Rows read, including the ones failing to parse
+    pub rows: u64,
+    pub types: BTreeMap<TransactionType, TypeStats>,
+    /// Rows of an unknown type per type, only counted with [`crate::UnknownTypes::Skip`]
+    pub unknown_types: BTreeMap<String, u64>,
+    pub clients: BTreeSet<ClientId>,
+    /// Smallest and largest tx id
+    pub tx_ids: Option<RangeInclusive<TxId>>,
+    /// Rows failing to parse, see [`crate::validate`] for why
+    pub malformed: u64,
+}
+
+/// Scans every row of `reader` and sums them up. The mode of `options` is ignored.
+pub fn file_stats<R: io::Read>(reader: &mut Reader<R>, options: &ParseOptions) -> FileStats {
+    let mut stats = FileStats::default();
+    let headers = match reader.has_headers() {
+        true => match reader.byte_headers() {
+            Ok(headers) => Some(headers.clone()),
+            Err(_) => return stats,
+        },
+        false => None,
+    };
+    let mut parser = RowParser::new(options, headers.as_ref());
+    let mut record = ByteRecord::new();
+    loop {
+        match reader.read_byte_record(&mut record) {
+            Ok(true) => stats.rows += 1,
+            Ok(false) => break,
+            Err(err) => {
+                stats.rows += 1;
+                stats.malformed += 1;
+                if err.is_io_error() {
+                    break;
+                }
+                continue;
+            }
+        }
+        let transaction = match parser.parse(&record) {
+            Ok(transaction) => transaction,
+            Err(_) => {
+                match parser.parse_unknown(&record) {
+                    Some(unknown) => {
+                        *stats
+                            .unknown_types
+                            .entry(unknown.transaction_type)
+                            .or_default() += 1
+                    }
+                    None => stats.malformed += 1,
+                }
+                continue;
+            }
+        };
+        stats.clients.insert(transaction.client);
+        let tx = transaction.tx;
+        stats.tx_ids = Some(match stats.tx_ids {
+            Some(ids) => *ids.start().min(&tx)..=*ids.end().max(&tx),
+            None => tx..=tx,
+        });
+        let of_type = stats.types.entry(transaction.transaction_type).or_default();
+        of_type.rows += 1;
+        if let Some(amount) = transaction.amount {
+            of_type.min = Some(of_type.min.map_or(amount, |min| min.min(amount)));
+            of_type.max = Some(of_type.max.map_or(amount, |max| max.max(amount)));
+            of_type.sum = of_type.sum.saturating_add(amount);
+        }
+    }
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::io::csv::ParseOptions;
+    use crate::model::TransactionType;
+    use crate::stats::{file_stats, TypeStats};
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn sums_up_rows_per_type() {
+        let data = "type,client,tx,amount
+deposit,1,4,1.5
+deposit,2,2,3.0
+withdrawal,1,9,0.5
+dispute,1,4,
+teleport,3,5,1.0
+deposit,x,1,1.0";
+        let stats = file_stats(
+            &mut csv::Reader::from_reader(data.as_bytes()),
+            &ParseOptions::default(),
+        );
+        assert_eq!(stats.rows, 6);
+        assert_eq!(stats.malformed, 2);
+        assert_eq!(stats.clients.into_iter().collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(stats.tx_ids, Some(2..=9));
+        assert_eq!(
+            stats.types[&TransactionType::Deposit],
+            TypeStats {
+                rows: 2,
+                min: Some(Decimal::new(15, 1)),
+                max: Some(Decimal::new(30, 1)),
+                sum: Decimal::new(45, 1),
+            }
+        );
+        assert_eq!(stats.types[&TransactionType::Dispute].min, None);
+    }
+}
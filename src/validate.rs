@@ -0,0 +1,303 @@
+//! Dry run over a transactions file.
+//!
+//! Every row is parsed and checked against the rules the engine applies,
+//! without computing any balances, so all problems of a file can be reported at once.
+use crate::engine::policy::DisputeState;
+use crate::engine::Rejection;
+use crate::io::csv::{raw_record, ErrorCode, ParseOptions, RowError, RowParser, COLUMNS};
+use crate::model::{ClientId, Transaction, TransactionType, TxId};
+use csv::{ByteRecord, Reader};
+use std::collections::HashMap;
+use std::io;
+
+/// What validation keeps of a deposit or withdrawal
+struct Seen {
+    client: ClientId,
+    state: DisputeState,
+}
+
+/// Checks every row of `reader` and returns all problems found, in input order:
+/// - rows that don't parse, or a header missing one of the [`COLUMNS`]
+/// - deposits and withdrawals without an amount or with a negative one,
+///   resolves, chargebacks, their reversals and unlocks with one
+/// - deposits and withdrawals reusing a tx id
+/// - disputes referencing an unknown tx or another client's tx
+/// - disputes of a tx that is already under dispute
+/// - resolves and chargebacks of a tx that isn't under dispute
+/// - chargeback reversals of a tx that wasn't charged back
+/// - anything but a reversal referencing a tx that was charged back,
+///   anything referencing a reversed one
+///
+/// The mode of `options` is ignored, nothing is skipped silently.
+pub fn validate_transactions<R: io::Read>(
+    reader: &mut Reader<R>,
+    options: &ParseOptions,
+) -> Vec<RowError> {
+    let mut errors = vec![];
+    let mut record = ByteRecord::new();
+    let headers = match reader.has_headers() {
+        true => match reader.byte_headers() {
+            Ok(headers) => Some(headers.clone()),
+            Err(err) => return vec![RowError::new(&err, &record)],
+        },
+        false => None,
+    };
+    let mut parser = RowParser::new(options, headers.as_ref());
+    if let (Some(headers), Some(renamed)) = (&headers, parser.headers()) {
+        for column in COLUMNS {
+            if !renamed.iter().any(|header| header == column.as_bytes()) {
+                errors.push(RowError {
+                    line: 1,
+                    record: raw_record(headers),
+                    code: ErrorCode::MissingColumn,
+                    message: format!("missing column `{}`", column),
+                });
+            }
+        }
+    }
+
+    let mut seen: HashMap<TxId, Seen> = HashMap::new();
+    loop {
+        match reader.read_byte_record(&mut record) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) => {
+                errors.push(RowError::new(&err, &record));
+                if err.is_io_error() {
+                    break;
+                }
+                continue;
+            }
+        }
+        let problem = match parser.parse(&record) {
+            Ok(transaction) => check(&transaction, &mut seen),
+            Err(error) => {
+                errors.push(error);
+                continue;
+            }
+        };
+        if let Some((code, message)) = problem {
+            errors.push(RowError::invalid(&record, code, message));
+        }
+    }
+    errors
+}
+
+/// Checks `transaction` against the rows seen so far and records it
+fn check(transaction: &Transaction, seen: &mut HashMap<TxId, Seen>) -> Option<(ErrorCode, String)> {
+    let tx = transaction.tx;
+    let amount_problem = transaction
+        .amount_problem()
+        .map(|problem| (ErrorCode::BadAmount, problem));
+    match transaction.transaction_type {
+        // Whether the account is locked depends on the engine's settings
+        TransactionType::Unlock
+        | TransactionType::Review
+        | TransactionType::Reinstate
+        | TransactionType::Close => amount_problem,
+        TransactionType::Deposit | TransactionType::Withdrawal => {
+            if seen.contains_key(&tx) {
+                let rejection = Rejection::DuplicateTx(tx);
+                return Some((rejection.code(), rejection.to_string()));
+            }
+            seen.insert(
+                tx,
+                Seen {
+                    client: transaction.client,
+                    state: DisputeState::Processed,
+                },
+            );
+            amount_problem
+        }
+        transaction_type => {
+            let Some(referenced) = seen.get_mut(&tx) else {
+                return Some((
+                    ErrorCode::UnknownRefTx,
+                    format!("{} of unknown tx id {}", transaction_type, tx),
+                ));
+            };
+            if referenced.client != transaction.client {
+                return Some((
+                    ErrorCode::ClientMismatch,
+                    format!(
+                        "{} of tx id {} which belongs to client {}",
+                        transaction_type, tx, referenced.client
+                    ),
+                ));
+            }
+            match (transaction_type, referenced.state) {
+                (TransactionType::ChargebackReversal, DisputeState::ChargedBack) => {
+                    referenced.state = DisputeState::Reversed;
+                    amount_problem
+                }
+                (TransactionType::ChargebackReversal, _) => {
+                    let rejection = Rejection::NotChargedBack(tx);
+                    Some((rejection.code(), rejection.to_string()))
+                }
+                (_, DisputeState::ChargedBack | DisputeState::Reversed) => {
+                    let rejection = Rejection::ChargedBack(tx);
+                    Some((rejection.code(), rejection.to_string()))
+                }
+                (TransactionType::Dispute, DisputeState::Disputed) => Some((
+                    ErrorCode::AlreadyDisputed,
+                    format!(
+                        "{} of tx id {} which is already under dispute",
+                        transaction_type, tx
+                    ),
+                )),
+                (TransactionType::Dispute, _) => {
+                    referenced.state = DisputeState::Disputed;
+                    amount_problem
+                }
+                (TransactionType::Chargeback, DisputeState::Disputed) => {
+                    referenced.state = DisputeState::ChargedBack;
+                    amount_problem
+                }
+                (_, DisputeState::Disputed) => {
+                    referenced.state = DisputeState::Resolved;
+                    amount_problem
+                }
+                _ => Some((
+                    ErrorCode::NotDisputed,
+                    format!(
+                        "{} of tx id {} which is not under dispute",
+                        transaction_type, tx
+                    ),
+                )),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::io::csv::{process_transactions_with_engine, ErrorCode, ParseMode, ParseOptions};
+    use crate::validate::validate_transactions;
+    use crate::Engine;
+    use rust_decimal::Decimal;
+
+    fn validate(data: &str) -> Vec<(u64, String)> {
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        validate_transactions(&mut reader, &ParseOptions::default())
+            .into_iter()
+            .map(|error| (error.line, error.message))
+            .collect()
+    }
+
+    #[test]
+    fn valid_file_has_no_problems() {
+        let data = "type,client,tx,amount
+deposit,1,1,1.0
+withdrawal,1,2,0.5
+dispute,1,1,
+chargeback,1,1,";
+        assert!(validate(data).is_empty());
+    }
+
+    #[test]
+    fn reports_every_problem_with_its_line() {
+        let data = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,1,2.0
+teleport,1,2,1.0
+withdrawal,1,3,
+deposit,2,4,-1
+dispute,2,1,
+dispute,1,9,
+resolve,1,1,
+dispute,1,1,1.0
+dispute,1,1,";
+        let problems = validate(data);
+        let lines: Vec<u64> = problems.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, [3, 4, 5, 6, 7, 8, 9, 11]);
+        assert_eq!(problems[0].1, "duplicate tx id 1");
+        assert_eq!(
+            problems[6].1,
+            "resolve of tx id 1 which is not under dispute"
+        );
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let codes: Vec<ErrorCode> = validate_transactions(&mut reader, &ParseOptions::default())
+            .into_iter()
+            .map(|error| error.code)
+            .collect();
+        use ErrorCode::*;
+        assert_eq!(
+            codes,
+            [
+                DuplicateTx,
+                MalformedRow,
+                BadAmount,
+                BadAmount,
+                ClientMismatch,
+                UnknownRefTx,
+                NotDisputed,
+                AlreadyDisputed
+            ]
+        );
+        assert_eq!(serde_json::to_string(&codes[0]).unwrap(), r#""DUP_TX_ID""#);
+    }
+
+    #[test]
+    fn charged_back_transactions_are_final() {
+        let data = "type,client,tx,amount
+deposit,1,1,1.0
+dispute,1,1,
+chargeback,1,1,
+resolve,1,1,
+dispute,1,1,
+chargeback_reversal,1,1,
+chargeback_reversal,1,1,
+dispute,1,1,";
+        let problems = validate(data);
+        assert_eq!(
+            problems,
+            [
+                (5, "tx id 1 was charged back".to_string()),
+                (6, "tx id 1 was charged back".to_string()),
+                (8, "tx id 1 wasn't charged back".to_string()),
+                (9, "tx id 1 was charged back".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_missing_columns() {
+        let problems = validate("type,client,tx\ndeposit,1,1");
+        assert_eq!(problems[0], (1, "missing column `amount`".to_string()));
+    }
+
+    #[test]
+    fn negative_amounts_are_skipped_when_processed_too() {
+        let data = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,-50
+withdrawal,1,3,-100";
+        let options = ParseOptions {
+            mode: ParseMode::Collecting,
+            ..ParseOptions::default()
+        };
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let report =
+            process_transactions_with_engine(&mut reader, &options, Engine::new()).unwrap();
+        let processed: Vec<_> = report
+            .errors
+            .iter()
+            .map(|error| (error.line, error.code, error.message.as_str()))
+            .collect();
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let validated = validate_transactions(&mut reader, &options);
+        let validated: Vec<_> = validated
+            .iter()
+            .map(|error| (error.line, error.code, error.message.as_str()))
+            .collect();
+        assert_eq!(processed, validated);
+        assert_eq!(
+            processed,
+            [
+                (3, ErrorCode::BadAmount, "negative amount -50"),
+                (4, ErrorCode::BadAmount, "negative amount -100"),
+            ]
+        );
+        assert_eq!(report.accounts[&1].available, Decimal::TEN);
+    }
+}
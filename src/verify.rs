@@ -0,0 +1,301 @@
+//! Trial balance over an accounts file this crate wrote.
+//!
+//! The accounts are read back as written, so a file can be checked after it was moved around
+//! or edited: every total has to be its available and held funds, held funds can't be negative
+//! and, given the net deposits of the input, the totals have to add up to them.
+//!
+//! The net deposits are summed from the input rows by [`net_deposits`], without an engine,
+//! so a total that doesn't match also catches an engine that moved the wrong amounts.
+use crate::model::{ClientId, Transaction, TransactionType, TxId};
+use crate::report::Column;
+use csv::{Reader, StringRecord};
+use rust_decimal::Decimal;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::str::FromStr;
+
+/// What [`trial_balance`] found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrialBalance {
+    /// Accounts read
+    pub accounts: u64,
+    /// Sum of their totals
+    pub total: Decimal,
+    /// Everything that doesn't add up, with its line
+    pub discrepancies: Vec<String>,
+    // Most decimal places of an amount, amounts are rounded when written
+    scale: u32,
+}
+
+impl TrialBalance {
+    /// Checks the totals add up to `net_deposits`, see [`net_deposits`],
+    /// within the rounding of the file
+    pub fn check_total(&mut self, net_deposits: Decimal) {
+        let tolerance = self.unit().saturating_mul(Decimal::from(self.accounts));
+        let off = self.total.checked_sub(net_deposits).map(|off| off.abs());
+        if off.is_none_or(|off| off > tolerance) {
+            self.discrepancies.push(format!(
+                "totals sum to {}, net deposits are {}",
+                self.total, net_deposits
+            ));
+        }
+    }
+
+    pub fn is_balanced(&self) -> bool {
+        self.discrepancies.is_empty()
+    }
+
+    /// Adds the total of the account on `line`, unless the sum would overflow
+    fn add(&mut self, line: u64, total: Decimal) {
+        match self.total.checked_add(total) {
+            Some(sum) => self.total = sum,
+            None => self.discrepancies.push(format!("line {}: overflow", line)),
+        }
+    }
+
+    /// One in the last decimal place written
+    fn unit(&self) -> Decimal {
+        Decimal::new(1, self.scale)
+    }
+}
+
+/// Reads the accounts of `reader`, with a header in any [`crate::OutputSchema`], and checks
+/// every total is its available plus held funds, give or take rounding, that no held funds
+/// are negative and that no client appears twice. A file without a total column only gets
+/// the other checks. Fails if the file can't be read.
+pub fn trial_balance<R: io::Read>(reader: &mut Reader<R>) -> csv::Result<TrialBalance> {
+    let mut balance = TrialBalance::default();
+    let headers = reader.headers()?.clone();
+    let index = |column: Column| {
+        headers
+            .iter()
+            .position(|header| Column::from_str(header.trim()) == Ok(column))
+    };
+    let (client, available, held, total) = (
+        index(Column::Client),
+        index(Column::Available),
+        index(Column::Held),
+        index(Column::Total),
+    );
+    let (Some(client), Some(available), Some(held)) = (client, available, held) else {
+        balance
+            .discrepancies
+            .push("line 1: client, available and held columns expected".to_string());
+        return Ok(balance);
+    };
+    let mut clients = HashSet::new();
+    let mut record = StringRecord::new();
+    while reader.read_record(&mut record)? {
+        let line = record.position().map_or(0, |position| position.line());
+        let field = |index: usize| record.get(index).unwrap_or_default().trim();
+        let amount = |index: usize| Decimal::from_str(field(index)).ok();
+        let (Ok(id), Some(available), Some(held)) = (
+            ClientId::from_str(field(client)),
+            amount(available),
+            amount(held),
+        ) else {
+            balance
+                .discrepancies
+                .push(format!("line {}: not an account", line));
+            continue;
+        };
+        balance.accounts += 1;
+        balance.scale = balance.scale.max(available.scale()).max(held.scale());
+        if !clients.insert(id) {
+            balance
+                .discrepancies
+                .push(format!("line {}: client {} appears again", line, id));
+        }
+        if held.is_sign_negative() && !held.is_zero() {
+            balance
+                .discrepancies
+                .push(format!("line {}: held {} is negative", line, held));
+        }
+        let Some(sum) = available.checked_add(held) else {
+            balance
+                .discrepancies
+                .push(format!("line {}: overflow", line));
+            continue;
+        };
+        let Some(index) = total else {
+            balance.add(line, sum);
+            continue;
+        };
+        let Some(total) = amount(index) else {
+            balance
+                .discrepancies
+                .push(format!("line {}: not an account", line));
+            continue;
+        };
+        balance.scale = balance.scale.max(total.scale());
+        balance.add(line, total);
+        let off = total.checked_sub(sum).map(|off| off.abs());
+        if off.is_none_or(|off| off > balance.unit()) {
+            balance.discrepancies.push(format!(
+                "line {}: total {} isn't available {} plus held {}",
+                line, total, available, held
+            ));
+        }
+    }
+    Ok(balance)
+}
+
+/// Where a deposit or withdrawal stands in [`net_deposits`]
+enum Referenced {
+    Open,
+    Disputed(Decimal),
+    ChargedBack(Decimal),
+    Reversed,
+}
+
+/// Deposits less withdrawals and charged back amounts of `transactions`, summed straight
+/// from the rows as the specification has them. A repeated deposit or withdrawal tx id
+/// counts once, a chargeback takes what the dispute of the client's own transaction held
+/// and a reversal gives it back.
+///
+/// Options that make an engine reject or change rows, such as limits or
+/// `NegativeBalanceBehavior::Reject`, and fees of a custom dispute policy aren't known here:
+/// accounts computed with them don't add up to the sum.
+pub fn net_deposits(transactions: impl IntoIterator<Item = Transaction>) -> Decimal {
+    let mut net = Decimal::ZERO;
+    let mut referenced: HashMap<TxId, (ClientId, Decimal, Referenced)> = HashMap::new();
+    for transaction in transactions {
+        let amount = transaction.amount();
+        if let TransactionType::Deposit | TransactionType::Withdrawal = transaction.transaction_type
+        {
+            if referenced.contains_key(&transaction.tx) {
+                continue;
+            }
+            net = match transaction.transaction_type {
+                TransactionType::Deposit => net.saturating_add(amount),
+                _ => net.saturating_sub(amount),
+            };
+            referenced.insert(
+                transaction.tx,
+                (transaction.client, amount, Referenced::Open),
+            );
+            continue;
+        }
+        let Some((client, original, state)) = referenced.get_mut(&transaction.tx) else {
+            continue;
+        };
+        if *client != transaction.client {
+            continue;
+        }
+        *state = match (transaction.transaction_type, &*state) {
+            (TransactionType::Dispute, Referenced::Open) => {
+                let held = transaction
+                    .amount
+                    .map_or(*original, |amount| amount.min(*original).max(Decimal::ZERO));
+                Referenced::Disputed(held)
+            }
+            (TransactionType::Resolve, Referenced::Disputed(_)) => Referenced::Open,
+            (TransactionType::Chargeback, Referenced::Disputed(held)) => {
+                net = net.saturating_sub(*held);
+                Referenced::ChargedBack(*held)
+            }
+            (TransactionType::ChargebackReversal, Referenced::ChargedBack(held)) => {
+                net = net.saturating_add(*held);
+                Referenced::Reversed
+            }
+            _ => continue,
+        };
+    }
+    net
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::io::csv::ParseOptions;
+    use crate::io::source::{CsvSource, TransactionSource};
+    use crate::verify::{net_deposits, trial_balance};
+    use rust_decimal::Decimal;
+    use std::iter;
+
+    #[test]
+    fn consistent_accounts_balance() {
+        let data = "client,available,held,locked,balance
+1,1.5000,0.0000,false,1.5000
+2,0.3333,0.3333,false,0.6667";
+        let mut balance = trial_balance(&mut csv::Reader::from_reader(data.as_bytes())).unwrap();
+        assert!(balance.is_balanced(), "{:?}", balance.discrepancies);
+        assert_eq!(balance.accounts, 2);
+        balance.check_total(Decimal::new(21667, 4));
+        assert!(balance.is_balanced());
+        balance.check_total(Decimal::new(3, 0));
+        assert_eq!(
+            balance.discrepancies,
+            ["totals sum to 2.1667, net deposits are 3"]
+        );
+    }
+
+    #[test]
+    fn discrepancies_are_reported_by_line() {
+        let data = "client,available,held,total,locked
+1,1.0,0.0,2.0,false
+2,1.0,-0.5,0.5,false
+1,0,0,0,false
+x,0,0,0,false";
+        let balance = trial_balance(&mut csv::Reader::from_reader(data.as_bytes())).unwrap();
+        assert_eq!(
+            balance.discrepancies,
+            [
+                "line 2: total 2.0 isn't available 1.0 plus held 0.0",
+                "line 3: held -0.5 is negative",
+                "line 4: client 1 appears again",
+                "line 5: not an account",
+            ]
+        );
+        let balance = trial_balance(&mut csv::Reader::from_reader("a,b\n1,2".as_bytes())).unwrap();
+        assert!(!balance.is_balanced());
+    }
+
+    #[test]
+    fn overflowing_amounts_are_discrepancies() {
+        let max = Decimal::MAX;
+        let data = format!(
+            "client,available,held,balance,locked
+1,{max},{max},0,false
+2,{max},0,{max},false
+3,{max},0,{max},false
+4,1,0,-{max},false"
+        );
+        let mut balance = trial_balance(&mut csv::Reader::from_reader(data.as_bytes())).unwrap();
+        assert_eq!(
+            balance.discrepancies,
+            [
+                "line 2: overflow".to_string(),
+                "line 4: overflow".to_string(),
+                format!("line 5: total -{max} isn't available 1 plus held 0"),
+            ]
+        );
+        balance.check_total(-max);
+        assert_eq!(balance.discrepancies.len(), 4);
+    }
+
+    #[test]
+    fn net_deposits_come_from_the_rows() {
+        let data = "type,client,tx,amount
+deposit,1,1,5.0
+dispute,1,1,
+chargeback,1,1,
+deposit,2,2,10
+withdrawal,2,3,4
+deposit,2,2,7
+dispute,3,2,
+dispute,2,2,3
+resolve,2,2,
+dispute,2,2,2
+chargeback,2,2,
+chargeback_reversal,2,2,
+deposit,3,4,1
+dispute,3,4,
+chargeback,3,4,";
+        let options = ParseOptions::default();
+        let mut source =
+            CsvSource::new(csv::Reader::from_reader(data.as_bytes()), &options).unwrap();
+        let rows = iter::from_fn(|| source.next_transaction()).map(Result::unwrap);
+        // Client 1 ends with nothing, client 2 with 6 after the reversal, client 3 with 0
+        assert_eq!(net_deposits(rows), Decimal::new(6, 0));
+    }
+}
@@ -0,0 +1,255 @@
+//! Posting JSON bodies to webhooks, e.g. alerts for a risk team, with the `webhook` feature.
+//!
+//! [`WebhookClient`] posts through ureq, so chunked responses and kept-alive connections are
+//! handled, and follows redirects itself to post the body again, which ureq only does for
+//! requests without one. `https://` URLs need the `tls` feature. [`Webhook`] posts on a
+//! background thread, so a slow endpoint doesn't hold up processing.
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use url::Url;
+
+/// How long connecting, sending and reading an answer may take together
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Redirects followed for one post
+const MAX_REDIRECTS: usize = 5;
+
+/// An `http://` URL, or `https://` with the `tls` feature
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookUrl(Url);
+
+impl WebhookUrl {
+    pub fn host(&self) -> &str {
+        self.0.host_str().unwrap_or_default()
+    }
+
+    fn check(url: Url) -> Result<Self, String> {
+        match url.scheme() {
+            "http" => {}
+            "https" if cfg!(feature = "tls") => {}
+            "https" => return Err("https:// URLs need the tls feature".to_string()),
+            _ => return Err("only http:// and https:// URLs are supported".to_string()),
+        }
+        if url.host_str().is_none_or(str::is_empty) {
+            return Err("missing host".to_string());
+        }
+        Ok(WebhookUrl(url))
+    }
+}
+
+impl FromStr for WebhookUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        WebhookUrl::check(Url::parse(s).map_err(|err| err.to_string())?)
+    }
+}
+
+impl fmt::Display for WebhookUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Posts JSON bodies to one URL, reusing its connections
+#[derive(Debug, Clone)]
+pub struct WebhookClient {
+    agent: ureq::Agent,
+    url: WebhookUrl,
+}
+
+impl WebhookClient {
+    pub fn new(url: WebhookUrl) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(TIMEOUT)
+            .redirects(0)
+            .build();
+        WebhookClient { agent, url }
+    }
+
+    pub fn url(&self) -> &WebhookUrl {
+        &self.url
+    }
+
+    /// Posts `body` as `application/json`, failing unless the endpoint answers 2xx or
+    /// `303 See Other`. On `301`, `302`, `307` and `308` the body is posted again to the new
+    /// location, up to 5 times.
+    pub fn post(&self, body: &str) -> io::Result<()> {
+        let mut url = self.url.clone();
+        for _ in 0..=MAX_REDIRECTS {
+            let sent = self
+                .agent
+                .post(url.0.as_str())
+                .set("Content-Type", "application/json")
+                .send_string(body);
+            let response = match sent {
+                Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+                Err(err) => return Err(io::Error::other(err)),
+            };
+            match response.status() {
+                200..=299 | 303 => {
+                    // Read to the end, so the connection can be reused
+                    let _ = response.into_string();
+                    return Ok(());
+                }
+                301 | 302 | 307 | 308 => {
+                    let location = response
+                        .header("Location")
+                        .ok_or_else(|| io::Error::other(format!("{} redirected nowhere", url)))?;
+                    url = url
+                        .0
+                        .join(location)
+                        .map_err(|err| err.to_string())
+                        .and_then(WebhookUrl::check)
+                        .map_err(|err| {
+                            io::Error::other(format!("bad redirect to {}: {}", location, err))
+                        })?;
+                }
+                status => {
+                    return Err(io::Error::other(format!(
+                        "{} answered {} {}",
+                        url,
+                        status,
+                        response.status_text()
+                    )))
+                }
+            }
+        }
+        Err(io::Error::other(format!(
+            "{} redirected more than {} times",
+            self.url, MAX_REDIRECTS
+        )))
+    }
+}
+
+/// Posts JSON bodies to a webhook on a background thread, so a slow endpoint doesn't hold up
+/// processing. Failed posts are logged and dropped.
+pub struct Webhook {
+    sender: mpsc::Sender<String>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Webhook {
+    pub fn spawn(url: WebhookUrl) -> Self {
+        let client = WebhookClient::new(url);
+        let (sender, receiver) = mpsc::channel::<String>();
+        let thread = thread::spawn(move || {
+            for body in receiver {
+                if let Err(err) = client.post(&body) {
+                    tracing::warn!(host = client.url().host(), "webhook failed: {}", err);
+                }
+            }
+        });
+        Webhook { sender, thread }
+    }
+
+    /// Queues bodies to post
+    pub fn sender(&self) -> mpsc::Sender<String> {
+        self.sender.clone()
+    }
+
+    /// Waits for the pending posts. Blocks until every sender handed out is dropped.
+    pub fn finish(self) {
+        drop(self.sender);
+        let _ = self.thread.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    /// Answers the requests of `answers` in turn, one per connection, returning what was
+    /// posted to which path
+    fn server(answers: Vec<String>) -> (String, thread::JoinHandle<Vec<(String, String)>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let thread = thread::spawn(move || {
+            let mut requests = vec![];
+            for answer in answers {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let path = line.split(' ').nth(1).unwrap().to_string();
+                let mut length = 0;
+                loop {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                requests.push((path, String::from_utf8(body).unwrap()));
+                stream.write_all(answer.as_bytes()).unwrap();
+            }
+            requests
+        });
+        (base, thread)
+    }
+
+    #[test]
+    fn posts_follow_redirects_with_the_body() {
+        let (base, server) = server(vec![
+            "HTTP/1.1 307 Temporary Redirect\r\nLocation: /moved\r\n\
+             Content-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_string(),
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+             2\r\nok\r\n0\r\n\r\n"
+                .to_string(),
+        ]);
+        let client = WebhookClient::new(format!("{}/hook", base).parse().unwrap());
+        client.post(r#"{"event":"account_locked"}"#).unwrap();
+        let requests = server.join().unwrap();
+        assert_eq!(
+            requests,
+            [
+                (
+                    "/hook".to_string(),
+                    r#"{"event":"account_locked"}"#.to_string()
+                ),
+                (
+                    "/moved".to_string(),
+                    r#"{"event":"account_locked"}"#.to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn other_answers_fail_the_post() {
+        let (base, server) = server(vec![
+            "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\
+             Connection: close\r\n\r\n"
+                .to_string(),
+        ]);
+        let client = WebhookClient::new(base.parse().unwrap());
+        let err = client.post("{}").unwrap_err().to_string();
+        assert_eq!(err, format!("{}/ answered 500 Internal Server Error", base));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn urls_are_checked() {
+        assert!("http://example.com:8080/hook".parse::<WebhookUrl>().is_ok());
+        assert_eq!(
+            "ftp://example.com".parse::<WebhookUrl>(),
+            Err("only http:// and https:// URLs are supported".to_string())
+        );
+        assert_eq!(
+            "https://example.com".parse::<WebhookUrl>().is_ok(),
+            cfg!(feature = "tls")
+        );
+    }
+}
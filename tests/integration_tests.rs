@@ -1,15 +1,18 @@
 use rust_decimal::Decimal;
-use transaction_parser::process_transactions;
+use transaction_parser::{
+    process_transactions, process_transactions_with_engine, read_accounts, write_accounts, Engine,
+    EngineState, ParseOptions,
+};
 
 #[test]
 fn processes_file1() {
     let mut reader = csv::Reader::from_path("./tests/fixtures/test.csv").unwrap();
     let accounts = process_transactions(&mut reader);
     assert_eq!(accounts.len(), 4);
-    assert_eq!(accounts.get(&2u16).unwrap().total(), Decimal::new(-1, 0));
-    assert_eq!(accounts.get(&1u16).unwrap().total(), Decimal::new(15, 1));
-    assert_eq!(accounts.get(&3u16).unwrap().total(), Decimal::new(15, 1));
-    assert_eq!(accounts.get(&4u16).unwrap().total(), Decimal::new(4, 0));
+    assert_eq!(accounts.get(&2).unwrap().total(), Decimal::new(-1, 0));
+    assert_eq!(accounts.get(&1).unwrap().total(), Decimal::new(15, 1));
+    assert_eq!(accounts.get(&3).unwrap().total(), Decimal::new(15, 1));
+    assert_eq!(accounts.get(&4).unwrap().total(), Decimal::new(4, 0));
 }
 
 #[test]
@@ -17,9 +20,61 @@ fn processes_file2() {
     let mut reader = csv::Reader::from_path("./tests/fixtures/test2.csv").unwrap();
     let accounts = process_transactions(&mut reader);
     assert_eq!(accounts.len(), 4);
-    assert_eq!(accounts.get(&2u16).unwrap().total(), Decimal::new(-5, 0));
-    assert_eq!(accounts.get(&2u16).unwrap().locked, true);
-    assert_eq!(accounts.get(&1u16).unwrap().total(), Decimal::new(15, 1));
-    assert_eq!(accounts.get(&3u16).unwrap().total(), Decimal::new(15, 1));
-    assert_eq!(accounts.get(&4u16).unwrap().total(), Decimal::new(4, 0));
+    assert_eq!(accounts.get(&2).unwrap().total(), Decimal::new(-3, 0));
+    assert!(accounts.get(&2).unwrap().locked());
+    assert_eq!(accounts.get(&1).unwrap().total(), Decimal::new(15, 1));
+    assert_eq!(accounts.get(&3).unwrap().total(), Decimal::new(15, 1));
+    assert_eq!(accounts.get(&4).unwrap().total(), Decimal::new(4, 0));
+}
+
+#[test]
+fn writes_accounts_to_any_writer() {
+    let mut reader = csv::Reader::from_path("./tests/fixtures/test2.csv").unwrap();
+    let accounts = process_transactions(&mut reader);
+    let mut output = vec![];
+    write_accounts(&accounts, &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,locked,balance
+1,1.5000,0.0000,false,1.5000
+2,-3.0000,0.0000,true,-3.0000
+3,1.5000,0.0000,false,1.5000
+4,4.0000,0.0000,false,4.0000
+"
+    );
+}
+
+#[test]
+fn days_chain_through_their_output() {
+    let days = [
+        "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,3.3333\nwithdrawal,1,3,2.5\n",
+        "type,client,tx,amount\ndeposit,1,4,1.25\nwithdrawal,2,5,1.0\ndeposit,3,6,7\n",
+        "type,client,tx,amount\nwithdrawal,3,7,0.0001\ndeposit,1,8,0.1\n",
+    ];
+    let mut output = vec![];
+    for day in days {
+        let mut engine = Engine::new();
+        if !output.is_empty() {
+            let accounts = read_accounts(&mut csv::Reader::from_reader(&output[..])).unwrap();
+            engine.restore(EngineState {
+                accounts,
+                ..EngineState::default()
+            });
+        }
+        let report = process_transactions_with_engine(
+            &mut csv::Reader::from_reader(day.as_bytes()),
+            &ParseOptions::default(),
+            engine,
+        )
+        .unwrap();
+        output.clear();
+        write_accounts(&report.accounts, &mut output).unwrap();
+    }
+
+    let all = days.concat().replace("type,client,tx,amount\n", "");
+    let all = format!("type,client,tx,amount\n{}", all);
+    let mut expected = vec![];
+    let accounts = process_transactions(&mut csv::Reader::from_reader(all.as_bytes()));
+    write_accounts(&accounts, &mut expected).unwrap();
+    assert_eq!(String::from_utf8(output), String::from_utf8(expected));
 }
@@ -0,0 +1,63 @@
+//! Property tests for the engine invariants documented in the crate root.
+//! Run with `cargo test --features arbitrary`.
+#![cfg(feature = "arbitrary")]
+
+use arbitrary::{Arbitrary, Unstructured};
+use proptest::prelude::*;
+use rust_decimal::prelude::Zero;
+use rust_decimal::Decimal;
+use std::iter;
+use transaction_parser::verify::net_deposits;
+use transaction_parser::{
+    process_transactions, CsvSource, Engine, MinorUnitsEngine, ParseOptions, Transaction,
+    TransactionSource,
+};
+
+/// Serialize transactions back to the input CSV format
+fn to_csv(transactions: &[Transaction]) -> String {
+    let mut data = String::from("type,client,tx,amount\n");
+    for t in transactions {
+        let amount = t.amount.map(|a| a.to_string()).unwrap_or_default();
+        data.push_str(&format!(
+            "{},{},{},{}\n",
+            t.transaction_type, t.client, t.tx, amount
+        ));
+    }
+    data
+}
+
+proptest! {
+    #[test]
+    fn balances_hold_invariants(bytes in proptest::collection::vec(any::<u8>(), 0..4096)) {
+        let mut u = Unstructured::new(&bytes);
+        let transactions = Vec::<Transaction>::arbitrary(&mut u).unwrap();
+        let data = to_csv(&transactions);
+        let accounts = process_transactions(&mut csv::Reader::from_reader(data.as_bytes()));
+        for account in accounts.values() {
+            prop_assert!(account.held >= Decimal::zero(), "negative held for {:?}", account);
+            if account.disputed().next().is_none() {
+                prop_assert!(account.held.is_zero(), "held without a dispute for {:?}", account);
+            }
+        }
+        // Funds are conserved: summed from the rows without the engine, see verify
+        let options = ParseOptions::default();
+        let mut source = CsvSource::new(csv::Reader::from_reader(data.as_bytes()), &options).unwrap();
+        let rows: Vec<Transaction> = iter::from_fn(|| source.next_transaction())
+            .filter_map(Result::ok)
+            .collect();
+        let total: Decimal = accounts.values().map(|account| account.total()).sum();
+        prop_assert_eq!(total, net_deposits(rows));
+    }
+
+    #[test]
+    fn minor_units_give_the_same_results(bytes in proptest::collection::vec(any::<u8>(), 0..4096)) {
+        let mut u = Unstructured::new(&bytes);
+        let transactions = Vec::<Transaction>::arbitrary(&mut u).unwrap();
+        let items = || transactions.iter().map(|t| Ok::<_, String>(*t));
+        let expected = Engine::new().process_iter(items());
+        let report = MinorUnitsEngine::new().process_iter(items());
+        prop_assert_eq!(report.accounts, expected.accounts);
+        prop_assert_eq!(report.transactions, expected.transactions);
+        prop_assert_eq!(report.errors, expected.errors);
+    }
+}